@@ -0,0 +1,808 @@
+use crate::common::CPU_CLOCK_HZ;
+use crate::resample::{HighPass, Resampler};
+use crate::savestate::{Savable, StateError, StateReader, StateWriter};
+use crate::step::Step;
+
+// Audio processing unit, see https://gbdev.io/pandocs/Audio.html
+// Four channels: two pulse waves (the first with a frequency sweep), a
+// programmable wave and noise. A frame sequencer running at 512 Hz clocks
+// length counters, envelopes and the sweep. Channels produce 4-bit levels
+// that the DACs turn into -1.0..1.0, mixed to left and right by NR51 and
+// scaled by NR50.
+//
+// With a sample rate set, the mixer output is resampled band-limited to it,
+// stepping exactly to each channel's next waveform change. Stems do the
+// same with each channel's DAC output before NR50/NR51, and the register
+// log timestamps writes for replay as VGM.
+
+pub const APU_START: u16 = 0xFF10;
+pub const APU_END: u16 = 0xFF3F;
+pub const NR10: u16 = 0xFF10;
+pub const NR11: u16 = 0xFF11;
+pub const NR12: u16 = 0xFF12;
+pub const NR13: u16 = 0xFF13;
+pub const NR14: u16 = 0xFF14;
+pub const NR21: u16 = 0xFF16;
+pub const NR22: u16 = 0xFF17;
+pub const NR23: u16 = 0xFF18;
+pub const NR24: u16 = 0xFF19;
+pub const NR30: u16 = 0xFF1A;
+pub const NR31: u16 = 0xFF1B;
+pub const NR32: u16 = 0xFF1C;
+pub const NR33: u16 = 0xFF1D;
+pub const NR34: u16 = 0xFF1E;
+pub const NR41: u16 = 0xFF20;
+pub const NR42: u16 = 0xFF21;
+pub const NR43: u16 = 0xFF22;
+pub const NR44: u16 = 0xFF23;
+pub const NR50: u16 = 0xFF24;
+pub const NR51: u16 = 0xFF25;
+pub const NR52: u16 = 0xFF26;
+pub const WAVE_RAM_START: u16 = 0xFF30;
+
+const REGISTER_COUNT: usize = (NR52 - NR10) as usize + 1;
+const WAVE_RAM_SIZE: usize = 16;
+
+// Bits that always read as 1, from NR10 to NR52 (0xFF27-0xFF2F read 0xFF)
+const READ_MASKS: [u8; REGISTER_COUNT] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF,  // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF,  // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF,  // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF,  // NR40-NR44
+    0x00, 0x00, 0x70,              // NR50-NR52
+];
+
+// 512 Hz
+const FRAME_SEQUENCER_CYCLES: u32 = CPU_CLOCK_HZ / 512;
+
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+pub const CHANNELS: usize = 4;
+
+
+/// A write to 0xFF10-0xFF3F, `cycle` counted from when logging started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterWrite {
+    pub cycle: u64,
+    pub addr: u16,
+    pub value: u8,
+}
+
+
+#[derive(Debug, Clone, Default)]
+struct Envelope {
+    volume: u8,
+    timer: u8,
+}
+impl Envelope {
+    fn trigger(&mut self, nrx2: u8) {
+        self.volume = nrx2 >> 4;
+        self.timer = nrx2 & 0x07;
+    }
+
+    fn clock(&mut self, nrx2: u8) {
+        let pace = nrx2 & 0x07;
+        if pace == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = pace;
+        match nrx2 & 0x08 != 0 {
+            true if self.volume < 15 => self.volume += 1,
+            false if self.volume > 0 => self.volume -= 1,
+            _ => {}
+        }
+    }
+}
+
+// What the four channels share: on/off, length counter and frequency timer
+#[derive(Debug, Clone, Default)]
+struct Channel {
+    enabled: bool,
+    length: u16,
+    timer: u32,   // Cycles until the next waveform step
+    position: u8, // Duty step, wave sample, unused for noise
+    envelope: Envelope,
+}
+impl Channel {
+    fn clock_length(&mut self, length_enabled: bool) {
+        if length_enabled && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    // Counts down `cycles`, returns how many times the timer ran out
+    fn run(&mut self, cycles: u32, period: u32) -> u32 {
+        let mut steps = 0;
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = period;
+            steps += 1;
+        }
+        self.timer -= cycles;
+        steps
+    }
+
+    fn save(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u16(self.length);
+        w.u32(self.timer);
+        w.u8(self.position);
+        w.u8(self.envelope.volume);
+        w.u8(self.envelope.timer);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.length = r.u16()?.min(256);
+        self.timer = r.u32()?;
+        self.position = r.u8()?;
+        self.envelope.volume = r.u8()? & 0x0F;
+        self.envelope.timer = r.u8()? & 0x07;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Sweep {
+    enabled: bool,
+    shadow: u16,
+    timer: u8,
+}
+
+
+pub struct Apu {
+    registers: [u8; REGISTER_COUNT],
+    wave_ram: [u8; WAVE_RAM_SIZE],
+    powered: bool,
+    channels: [Channel; CHANNELS],
+    sweep: Sweep,
+    lfsr: u16,
+    frame_step: u8,
+    frame_cycles: u32,
+
+    // Output
+    sample_rate: Option<u32>,
+    high_pass: HighPass,
+    rate_ratio: f64,
+    output: Option<[Resampler; 2]>,  // Left and right
+    stems_enabled: bool,
+    stems: Option<[Resampler; CHANNELS]>,
+    register_log: Option<Vec<RegisterWrite>>,
+    log_cycles: u64,
+}
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Apu {
+    /// Powered off, as at power on before the boot ROM runs.
+    pub fn new() -> Self {
+        Apu {
+            registers: [0; REGISTER_COUNT],
+            wave_ram: [0; WAVE_RAM_SIZE],
+            powered: false,
+            channels: Default::default(),
+            sweep: Sweep::default(),
+            lfsr: 0x7FFF,
+            frame_step: 0,
+            frame_cycles: 0,
+            sample_rate: None,
+            high_pass: HighPass::default(),
+            rate_ratio: 1.0,
+            output: None,
+            stems_enabled: false,
+            stems: None,
+            register_log: None,
+            log_cycles: 0,
+        }
+    }
+
+    /// Starts producing stereo samples at `rate` Hz, `None` stops.
+    pub fn set_sample_rate(&mut self, rate: Option<u32>) {
+        self.sample_rate = rate.filter(|&rate| rate > 0);
+        self.output = self.sample_rate.map(|rate| [(); 2].map(|_| self.resampler(rate)));
+        self.set_stems(self.stems_enabled);
+    }
+    pub fn sample_rate(&self) -> Option<u32> {
+        self.sample_rate
+    }
+    /// Samples produced since the last call, left and right interleaved.
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        let Some([left, right]) = self.output.as_mut() else {
+            return Vec::new();
+        };
+        left.read().into_iter().zip(right.read())
+            .flat_map(|(left, right)| [to_i16(left), to_i16(right)])
+            .collect()
+    }
+
+    pub fn high_pass(&self) -> HighPass {
+        self.high_pass
+    }
+    /// Picks the model's DC-blocking filter, `Dmg` by default.
+    pub fn set_high_pass(&mut self, high_pass: HighPass) {
+        self.high_pass = high_pass;
+        for resampler in self.output.iter_mut().flatten().chain(self.stems.iter_mut().flatten()) {
+            resampler.set_high_pass(high_pass);
+        }
+    }
+
+    /// Dynamic rate control: produces `ratio` times the sample rate's worth
+    /// of samples per emulated second, see `resample::dynamic_rate_ratio`.
+    pub fn set_rate_ratio(&mut self, ratio: f64) {
+        self.rate_ratio = ratio;
+        for resampler in self.output.iter_mut().flatten().chain(self.stems.iter_mut().flatten()) {
+            resampler.set_ratio(ratio);
+        }
+    }
+
+    /// Also produces a mono stream per channel, before panning and volume.
+    pub fn set_stems(&mut self, enabled: bool) {
+        self.stems_enabled = enabled;
+        self.stems = match (enabled, self.sample_rate) {
+            (true, Some(rate)) => Some([(); CHANNELS].map(|_| self.resampler(rate))),
+            _ => None,
+        };
+    }
+    /// Stem samples produced since the last call, `None` when stems are off.
+    pub fn take_stems(&mut self) -> Option<[Vec<i16>; CHANNELS]> {
+        let stems = self.stems.as_mut()?;
+        Some(stems.each_mut().map(|stem| stem.read().into_iter().map(to_i16).collect()))
+    }
+
+    fn resampler(&self, rate: u32) -> Resampler {
+        let mut resampler = Resampler::new(CPU_CLOCK_HZ, rate);
+        resampler.set_high_pass(self.high_pass);
+        resampler.set_ratio(self.rate_ratio);
+        resampler
+    }
+
+    /// Logs register and wave RAM writes. The log starts with writes that
+    /// recreate the current registers, without retriggering channels.
+    pub fn set_register_log(&mut self, enabled: bool) {
+        self.log_cycles = 0;
+        self.register_log = enabled.then(|| self.current_registers());
+    }
+    /// Writes logged since the last call.
+    pub fn take_register_writes(&mut self) -> Vec<RegisterWrite> {
+        self.register_log.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+    /// Cycles since the register log was started.
+    pub fn log_cycles(&self) -> u64 {
+        self.log_cycles
+    }
+
+    fn current_registers(&self) -> Vec<RegisterWrite> {
+        let write = |addr: u16, value: u8| RegisterWrite { cycle: 0, addr, value };
+        // Power first, the other registers ignore writes while it's off
+        let mut writes = vec![write(NR52, (self.powered as u8) << 7)];
+        writes.extend((WAVE_RAM_START..=APU_END).map(|addr| write(addr, self.read(addr))));
+        if self.powered {
+            writes.extend((NR10..NR52).map(|addr| match addr {
+                NR14 | NR24 | NR34 | NR44 => write(addr, self.reg(addr) & 0x7F),
+                _ => write(addr, self.reg(addr)),
+            }));
+        }
+        writes
+    }
+
+    // The DMG boot ROM plays its chime on channel 1, which is still on with
+    // its volume faded to 0 when the cartridge starts
+    pub(crate) fn finish_boot_sound(&mut self) {
+        self.channels[0].enabled = true;
+        self.channels[0].envelope.volume = 0;
+    }
+
+    fn reg(&self, addr: u16) -> u8 {
+        self.registers[(addr - NR10) as usize]
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            NR52 => {
+                let status = self.channels.iter().enumerate()
+                    .fold(0, |status, (i, channel)| status | ((channel.enabled as u8) << i));
+                READ_MASKS[REGISTER_COUNT - 1] | ((self.powered as u8) << 7) | status
+            }
+            NR10..=NR51 => self.reg(addr) | READ_MASKS[(addr - NR10) as usize],
+            WAVE_RAM_START..=APU_END => self.wave_ram[(addr - WAVE_RAM_START) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        if let Some(log) = self.register_log.as_mut() {
+            log.push(RegisterWrite { cycle: self.log_cycles, addr, value });
+        }
+        match addr {
+            NR52 => self.set_power(value & 0x80 != 0),
+            WAVE_RAM_START..=APU_END => self.wave_ram[(addr - WAVE_RAM_START) as usize] = value,
+            // Registers are read only while the APU is off
+            NR10..=NR51 if self.powered => {
+                self.registers[(addr - NR10) as usize] = value;
+                self.register_written(addr, value);
+            }
+            _ => {}
+        }
+    }
+
+    fn set_power(&mut self, on: bool) {
+        if on && !self.powered {
+            self.frame_step = 0;
+            self.frame_cycles = 0;
+        } else if !on && self.powered {
+            self.registers = [0; REGISTER_COUNT];
+            self.channels = Default::default();
+            self.sweep = Sweep::default();
+        }
+        self.powered = on;
+    }
+
+    fn register_written(&mut self, addr: u16, value: u8) {
+        match addr {
+            NR11 => self.channels[0].length = 64 - (value & 0x3F) as u16,
+            NR21 => self.channels[1].length = 64 - (value & 0x3F) as u16,
+            NR31 => self.channels[2].length = 256 - value as u16,
+            NR41 => self.channels[3].length = 64 - (value & 0x3F) as u16,
+            // Turning a DAC off silences its channel for good
+            NR12 | NR22 | NR30 | NR42 => {
+                let channel = match addr { NR12 => 0, NR22 => 1, NR30 => 2, _ => 3 };
+                if !self.dac_enabled(channel) {
+                    self.channels[channel].enabled = false;
+                }
+            }
+            NR14 | NR24 | NR34 | NR44 if value & 0x80 != 0 => {
+                let channel = match addr { NR14 => 0, NR24 => 1, NR34 => 2, _ => 3 };
+                self.trigger(channel);
+            }
+            _ => {}
+        }
+    }
+
+    fn dac_enabled(&self, channel: usize) -> bool {
+        match channel {
+            0 => self.reg(NR12) & 0xF8 != 0,
+            1 => self.reg(NR22) & 0xF8 != 0,
+            2 => self.reg(NR30) & 0x80 != 0,
+            _ => self.reg(NR42) & 0xF8 != 0,
+        }
+    }
+
+    fn frequency(&self, channel: usize) -> u16 {
+        let (low, high) = match channel {
+            0 => (NR13, NR14),
+            1 => (NR23, NR24),
+            _ => (NR33, NR34),
+        };
+        u16::from_le_bytes([self.reg(low), self.reg(high) & 0x07])
+    }
+
+    fn set_frequency(&mut self, frequency: u16) {
+        self.registers[(NR13 - NR10) as usize] = frequency as u8;
+        let high = &mut self.registers[(NR14 - NR10) as usize];
+        *high = (*high & !0x07) | ((frequency >> 8) as u8 & 0x07);
+    }
+
+    // Cycles between waveform steps
+    fn period(&self, channel: usize) -> u32 {
+        match channel {
+            0 | 1 => (2048 - self.frequency(channel) as u32) * 4,
+            2 => (2048 - self.frequency(channel) as u32) * 2,
+            _ => {
+                let nr43 = self.reg(NR43);
+                NOISE_DIVISORS[(nr43 & 0x07) as usize] << (nr43 >> 4)
+            }
+        }
+    }
+
+    fn length_enabled(&self, channel: usize) -> bool {
+        let nrx4 = [NR14, NR24, NR34, NR44][channel];
+        self.reg(nrx4) & 0x40 != 0
+    }
+
+    fn trigger(&mut self, channel: usize) {
+        let max_length = if channel == 2 { 256 } else { 64 };
+        let period = self.period(channel);
+        let nrx2 = [self.reg(NR12), self.reg(NR22), 0, self.reg(NR42)][channel];
+        let dac = self.dac_enabled(channel);
+        let ch = &mut self.channels[channel];
+        ch.enabled = dac;
+        if ch.length == 0 {
+            ch.length = max_length;
+        }
+        ch.timer = period;
+        ch.envelope.trigger(nrx2);
+        match channel {
+            0 => {
+                let nr10 = self.reg(NR10);
+                self.sweep.shadow = self.frequency(0);
+                self.sweep.timer = sweep_pace(nr10);
+                self.sweep.enabled = nr10 & 0x77 != 0;
+                if nr10 & 0x07 != 0 {
+                    self.sweep_frequency();
+                }
+            }
+            2 => self.channels[2].position = 0,
+            3 => self.lfsr = 0x7FFF,
+            _ => {}
+        }
+    }
+
+    // Next sweep frequency, turns channel 1 off when it goes past 2047
+    fn sweep_frequency(&mut self) -> u16 {
+        let nr10 = self.reg(NR10);
+        let delta = self.sweep.shadow >> (nr10 & 0x07);
+        let frequency = match nr10 & 0x08 != 0 {
+            true => self.sweep.shadow.wrapping_sub(delta),
+            false => self.sweep.shadow + delta,
+        };
+        if frequency > 2047 {
+            self.channels[0].enabled = false;
+        }
+        frequency
+    }
+
+    fn clock_sweep(&mut self) {
+        self.sweep.timer = self.sweep.timer.saturating_sub(1);
+        if self.sweep.timer > 0 {
+            return;
+        }
+        let nr10 = self.reg(NR10);
+        self.sweep.timer = sweep_pace(nr10);
+        if !self.sweep.enabled || nr10 & 0x70 == 0 {
+            return;
+        }
+        let frequency = self.sweep_frequency();
+        if frequency <= 2047 && nr10 & 0x07 != 0 {
+            self.sweep.shadow = frequency;
+            self.set_frequency(frequency);
+            self.sweep_frequency();
+        }
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        let step = self.frame_step;
+        self.frame_step = (self.frame_step + 1) % 8;
+        if step.is_multiple_of(2) {
+            for channel in 0..CHANNELS {
+                let enabled = self.length_enabled(channel);
+                self.channels[channel].clock_length(enabled);
+            }
+        }
+        if step == 2 || step == 6 {
+            self.clock_sweep();
+        }
+        if step == 7 {
+            let (nr12, nr22, nr42) = (self.reg(NR12), self.reg(NR22), self.reg(NR42));
+            self.channels[0].envelope.clock(nr12);
+            self.channels[1].envelope.clock(nr22);
+            self.channels[3].envelope.clock(nr42);
+        }
+    }
+
+    fn run_channels(&mut self, cycles: u32) {
+        for channel in 0..CHANNELS {
+            let period = self.period(channel).max(1);
+            let steps = self.channels[channel].run(cycles, period);
+            match channel {
+                0 | 1 => {
+                    let ch = &mut self.channels[channel];
+                    ch.position = ((ch.position as u32 + steps) % 8) as u8;
+                }
+                2 => {
+                    let ch = &mut self.channels[channel];
+                    ch.position = ((ch.position as u32 + steps) % 32) as u8;
+                }
+                _ => {
+                    let width7 = self.reg(NR43) & 0x08 != 0;
+                    for _ in 0..steps.min(0x8000) {
+                        let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+                        self.lfsr = (self.lfsr >> 1) | (bit << 14);
+                        if width7 {
+                            self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// What each channel's DAC puts out right now, -1.0 to 1.0, before panning.
+    pub fn channel_outputs(&self) -> [f32; CHANNELS] {
+        let mut outputs = [0.0; CHANNELS];
+        for (channel, output) in outputs.iter_mut().enumerate() {
+            if !self.dac_enabled(channel) {
+                continue;
+            }
+            let level = match self.channels[channel].enabled {
+                true => self.channel_level(channel),
+                false => 0,
+            };
+            *output = 1.0 - level as f32 / 7.5;
+        }
+        outputs
+    }
+
+    // 4-bit digital output of an enabled channel
+    fn channel_level(&self, channel: usize) -> u8 {
+        let ch = &self.channels[channel];
+        match channel {
+            0 | 1 => {
+                let duty = [self.reg(NR11), self.reg(NR21)][channel] >> 6;
+                let high = DUTY_PATTERNS[duty as usize] >> (7 - ch.position) & 1 != 0;
+                if high { ch.envelope.volume } else { 0 }
+            }
+            2 => {
+                let byte = self.wave_ram[ch.position as usize / 2];
+                let sample = if ch.position.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F };
+                match (self.reg(NR32) >> 5) & 0x03 {
+                    0 => 0,
+                    shift => sample >> (shift - 1),
+                }
+            }
+            _ => if self.lfsr & 1 == 0 { ch.envelope.volume } else { 0 },
+        }
+    }
+
+    /// Left and right output, -1.0 to 1.0, after panning and master volume.
+    pub fn mix(&self) -> (f32, f32) {
+        let outputs = self.channel_outputs();
+        let (nr50, nr51) = (self.reg(NR50), self.reg(NR51));
+        let (mut left, mut right) = (0.0, 0.0);
+        for (channel, output) in outputs.iter().enumerate() {
+            if nr51 & (0x10 << channel) != 0 {
+                left += output;
+            }
+            if nr51 & (0x01 << channel) != 0 {
+                right += output;
+            }
+        }
+        let volume = |bits: u8| (bits & 0x07) as f32 + 1.0;
+        let scale = 1.0 / (CHANNELS as f32 * 8.0);
+        (left * volume(nr50 >> 4) * scale, right * volume(nr50) * scale)
+    }
+
+    // Cycles until the next enabled channel changes its waveform
+    fn next_waveform_step(&self) -> u32 {
+        self.channels.iter().filter(|ch| ch.enabled).map(|ch| ch.timer).min().unwrap_or(u32::MAX)
+    }
+
+    // Passes the current output levels to the resamplers
+    fn update_output(&mut self) {
+        let (left_level, right_level) = self.mix();
+        if let Some([left, right]) = self.output.as_mut() {
+            left.set_level(left_level);
+            right.set_level(right_level);
+        }
+        if self.stems.is_some() {
+            let outputs = self.channel_outputs();
+            for (stem, output) in self.stems.iter_mut().flatten().zip(outputs) {
+                stem.set_level(output);
+            }
+        }
+    }
+
+    fn advance_output(&mut self, cycles: u32) {
+        for resampler in self.output.iter_mut().flatten().chain(self.stems.iter_mut().flatten()) {
+            resampler.advance(cycles);
+        }
+    }
+}
+
+fn to_i16(value: f32) -> i16 {
+    (value * i16::MAX as f32).clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+fn sweep_pace(nr10: u8) -> u8 {
+    match (nr10 >> 4) & 0x07 {
+        0 => 8,
+        pace => pace,
+    }
+}
+
+// Takes cycles on the 4 MHz base clock, like the PPU
+impl Step for Apu {
+    fn step(&mut self, cycles: u8) {
+        if self.register_log.is_some() {
+            self.log_cycles += cycles as u64;
+        }
+        let output = self.sample_rate.is_some();
+        if output {
+            // Register writes since the last step take effect now
+            self.update_output();
+        }
+        let mut remaining = cycles as u32;
+        while remaining > 0 {
+            // Run up to the next frame sequencer tick, or waveform change when
+            // producing samples, whichever is first
+            let mut chunk = remaining.min(FRAME_SEQUENCER_CYCLES - self.frame_cycles);
+            if output && self.powered {
+                chunk = chunk.min(self.next_waveform_step().max(1));
+            }
+            if self.powered {
+                self.run_channels(chunk);
+                self.frame_cycles += chunk;
+                if self.frame_cycles == FRAME_SEQUENCER_CYCLES {
+                    self.frame_cycles = 0;
+                    self.clock_frame_sequencer();
+                }
+            }
+            if output {
+                self.advance_output(chunk);
+                self.update_output();
+            }
+            remaining -= chunk;
+        }
+    }
+}
+
+// Output settings and pending samples belong to the frontend, not the state
+impl Savable for Apu {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.registers);
+        w.bytes(&self.wave_ram);
+        w.bool(self.powered);
+        for channel in &self.channels {
+            channel.save(w);
+        }
+        w.bool(self.sweep.enabled);
+        w.u16(self.sweep.shadow);
+        w.u8(self.sweep.timer);
+        w.u16(self.lfsr);
+        w.u8(self.frame_step);
+        w.u32(self.frame_cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes_into(&mut self.registers)?;
+        r.bytes_into(&mut self.wave_ram)?;
+        self.powered = r.bool()?;
+        for channel in self.channels.iter_mut() {
+            channel.load(r)?;
+        }
+        self.channels[0].position &= 0x07;
+        self.channels[1].position &= 0x07;
+        self.channels[2].position &= 0x1F;
+        self.sweep.enabled = r.bool()?;
+        self.sweep.shadow = r.u16()? & 0x07FF;
+        self.sweep.timer = r.u8()?.min(8);
+        self.lfsr = r.u16()? & 0x7FFF;
+        self.frame_step = r.u8()? & 0x07;
+        self.frame_cycles = r.u32()?.min(FRAME_SEQUENCER_CYCLES - 1);
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn powered() -> Apu {
+        let mut apu = Apu::new();
+        apu.write(NR52, 0x80);
+        apu
+    }
+
+    fn run(apu: &mut Apu, cycles: u32) {
+        for _ in 0..cycles / 128 {
+            apu.step(128);
+        }
+    }
+
+    fn status(apu: &Apu) -> u8 {
+        apu.read(NR52) & 0x0F
+    }
+
+    #[test]
+    fn trigger_needs_the_dac() {
+        let mut apu = powered();
+        apu.write(NR24, 0x80);
+        assert_eq!(status(&apu), 0x00, "DAC off");
+
+        apu.write(NR22, 0xF0);
+        apu.write(NR24, 0x80);
+        assert_eq!(status(&apu), 0x02);
+        assert_eq!(apu.channels[1].length, 64, "an expired length reloads");
+        apu.write(NR30, 0x80);
+        apu.write(NR34, 0x80);
+        assert_eq!(status(&apu), 0x06);
+
+        apu.write(NR22, 0x00);
+        assert_eq!(status(&apu), 0x04, "DAC off stops the channel");
+    }
+
+    #[test]
+    fn length_counter_stops_the_channel() {
+        let mut apu = powered();
+        apu.write(NR21, 0x3E);
+        apu.write(NR22, 0xF0);
+        apu.write(NR24, 0xC0);
+        // Length clocks on every other frame sequencer step, starting now
+        run(&mut apu, FRAME_SEQUENCER_CYCLES);
+        assert_eq!(status(&apu), 0x02);
+        run(&mut apu, 2 * FRAME_SEQUENCER_CYCLES);
+        assert_eq!(status(&apu), 0x00);
+
+        // Without the length enable bit it plays on
+        apu.write(NR21, 0x3F);
+        apu.write(NR24, 0x80);
+        run(&mut apu, 8 * FRAME_SEQUENCER_CYCLES);
+        assert_eq!(status(&apu), 0x02);
+    }
+
+    #[test]
+    fn envelope_steps_at_its_pace() {
+        let mut apu = powered();
+        apu.write(NR12, 0xF1);
+        apu.write(NR14, 0x80);
+        assert_eq!(apu.channels[0].envelope.volume, 15);
+        // Envelopes clock on step 7, 64 times a second
+        run(&mut apu, 8 * FRAME_SEQUENCER_CYCLES);
+        assert_eq!(apu.channels[0].envelope.volume, 14);
+        run(&mut apu, 20 * 8 * FRAME_SEQUENCER_CYCLES);
+        assert_eq!(apu.channels[0].envelope.volume, 0);
+        assert_eq!(status(&apu), 0x01, "silent but still on");
+
+        apu.write(NR42, 0x0A);
+        apu.write(NR44, 0x80);
+        run(&mut apu, 8 * 8 * FRAME_SEQUENCER_CYCLES);
+        assert_eq!(apu.channels[3].envelope.volume, 4, "pace 2 going up");
+    }
+
+    #[test]
+    fn sweep_raises_the_frequency_until_it_overflows() {
+        let mut apu = powered();
+        apu.write(NR10, 0x11);
+        apu.write(NR12, 0xF0);
+        apu.write(NR13, 0x00);
+        apu.write(NR14, 0x81);
+        // Sweep clocks on steps 2 and 6
+        run(&mut apu, 3 * FRAME_SEQUENCER_CYCLES);
+        assert_eq!(apu.frequency(0), 0x180);
+        run(&mut apu, 4 * FRAME_SEQUENCER_CYCLES);
+        assert_eq!(apu.frequency(0), 0x240);
+        assert_eq!(status(&apu), 0x01);
+        // 0x360, 0x510, 0x798, then 0xB64 is past 2047
+        run(&mut apu, 12 * FRAME_SEQUENCER_CYCLES);
+        assert_eq!(apu.frequency(0), 0x798);
+        assert_eq!(status(&apu), 0x00);
+
+        // Overflowing right at the trigger
+        apu.write(NR10, 0x01);
+        apu.write(NR13, 0xFF);
+        apu.write(NR14, 0x87);
+        assert_eq!(status(&apu), 0x00);
+    }
+
+    #[test]
+    fn power_off_clears_registers_but_not_wave_ram() {
+        let mut apu = powered();
+        apu.write(NR50, 0x77);
+        apu.write(NR22, 0xF0);
+        apu.write(NR24, 0x80);
+        apu.write(WAVE_RAM_START, 0x12);
+
+        apu.write(NR52, 0x00);
+        assert_eq!(apu.read(NR52), 0x70);
+        assert_eq!(apu.read(NR50), 0x00);
+        apu.write(NR50, 0x77);
+        assert_eq!(apu.read(NR50), 0x00, "read only while off");
+        apu.write(WAVE_RAM_START + 1, 0x34);
+        assert_eq!([apu.read(WAVE_RAM_START), apu.read(WAVE_RAM_START + 1)], [0x12, 0x34]);
+
+        apu.write(NR52, 0x80);
+        assert_eq!(apu.read(NR52), 0xF0, "channels stay off");
+        assert_eq!(apu.read(NR22), 0x00);
+    }
+}
@@ -0,0 +1,777 @@
+use std::collections::HashMap;
+use std::fmt;
+use crate::disasm::mnemonic_name;
+use crate::opcode::{Mnemonic, Opcode, OpcodeTables};
+
+// Two pass assembler for RGBDS-like source, encoding through the opcode tables
+// in reverse. Supported syntax:
+//   label:  label::  .local:          labels, locals scoped to the last global
+//   name EQU expr                     constants
+//   SECTION "name", ROM0[$0150]       sections, the address is optional
+//   db 1, "text"  dw $1234  ds 16, $FF
+// Expressions take $hex, 0xhex, %bin, decimal and 'c' literals, labels, @ for
+// the current address, + - * / & | << >>, parentheses, HIGH() and LOW().
+
+// Largest block `ds` may reserve, the whole address space
+const MAX_DS_SIZE: i64 = 0x10000;
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub sections: Vec<Section>,
+    pub symbols: HashMap<String, u16>,
+}
+impl Program {
+    /// Lowest address covered by any section.
+    pub fn origin(&self) -> u16 {
+        self.sections.iter().filter(|s| !s.bytes.is_empty()).map(|s| s.origin).min().unwrap_or(0)
+    }
+    /// Flattens all sections into one image starting at `origin()`, gaps are zero filled.
+    pub fn to_image(&self) -> Vec<u8> {
+        let origin = self.origin() as usize;
+        let end = self.sections.iter().map(|s| s.origin as usize + s.bytes.len()).max().unwrap_or(origin);
+        let mut image = vec![0; end.saturating_sub(origin)];
+        for section in &self.sections {
+            let start = section.origin as usize - origin.min(section.origin as usize);
+            if section.bytes.is_empty() {
+                continue;
+            }
+            image[start..start + section.bytes.len()].copy_from_slice(&section.bytes);
+        }
+        image
+    }
+}
+
+/// Assembles `source` into a flat image, see `Program::to_image`.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    assemble_program(source).map(|p| p.to_image())
+}
+
+/// Assembles `source`, keeping sections and symbols apart.
+pub fn assemble_program(source: &str) -> Result<Program, AsmError> {
+    let mut assembler = Assembler::new();
+    assembler.run(source, Pass::Layout)?;
+    assembler.run(source, Pass::Emit)?;
+    Ok(Program { sections: assembler.sections, symbols: assembler.symbols })
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pass {
+    Layout, // Assigns addresses to labels
+    Emit,   // Encodes with all symbols known
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(i64),
+    Symbol(String),
+    Here,
+    Negate(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+    High(Box<Expr>),
+    Low(Box<Expr>),
+}
+
+#[derive(Debug, Clone)]
+enum Arg {
+    Register(String),   // Register or condition
+    Indirect(String),   // [hl], [hl+], [hl-], [bc], [de], [c]
+    Memory(Expr),       // [expr]
+    SpOffset(Expr),     // sp+expr
+    Value(Expr),
+}
+
+// How an operand's value ends up in the encoding
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    N8,
+    N16,
+    HighAddress,
+    Relative,
+    Signed,
+}
+
+// Table entry selected for a source line, with the operand values to encode
+type Encoding = (bool, &'static Opcode, Vec<(Field, Expr)>);
+
+const REGISTERS: [&str; 15] = [
+    "a", "b", "c", "d", "e", "h", "l", "af", "bc", "de", "hl", "sp", "nz", "z", "nc",
+];
+
+struct Assembler {
+    tables: &'static OpcodeTables,
+    mnemonics: HashMap<String, Mnemonic>,
+    symbols: HashMap<String, u16>,
+    sections: Vec<Section>,
+    pass: Pass,
+    addr: u16,
+    scope: String,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        let tables = OpcodeTables::global();
+        let mnemonics = tables.unprefixed.values()
+            .chain(tables.cbprefixed.values())
+            .filter(|op| !matches!(op.mnemonic, Mnemonic::Prefix) && !format!("{:?}", op.mnemonic).starts_with("Illegal"))
+            .map(|op| (mnemonic_name(op.mnemonic), op.mnemonic))
+            .collect();
+
+        Assembler {
+            tables,
+            mnemonics,
+            symbols: HashMap::new(),
+            sections: Vec::new(),
+            pass: Pass::Layout,
+            addr: 0,
+            scope: String::new(),
+        }
+    }
+
+    fn run(&mut self, source: &str, pass: Pass) -> Result<(), AsmError> {
+        self.pass = pass;
+        self.addr = 0;
+        self.scope.clear();
+        self.sections.clear();
+
+        for (number, line) in source.lines().enumerate() {
+            self.line(line).map_err(|message| AsmError { line: number + 1, message })?;
+        }
+        Ok(())
+    }
+
+    fn line(&mut self, line: &str) -> Result<(), String> {
+        let mut rest = strip_comment(line).trim();
+        if rest.is_empty() {
+            return Ok(());
+        }
+
+        // Leading label definitions
+        while let Some(colon) = label_end(rest) {
+            let name = rest[..colon].trim();
+            self.define_label(name)?;
+            rest = rest[colon..].trim_start_matches(':').trim();
+        }
+        if rest.is_empty() {
+            return Ok(());
+        }
+
+        let (keyword, args) = match rest.find(char::is_whitespace) {
+            Some(i) => (&rest[..i], rest[i..].trim()),
+            None => (rest, ""),
+        };
+
+        // name EQU expr
+        if let Some((name, value)) = split_equ(rest) {
+            let value = self.eval(&parse_expr(value)?, true)?;
+            self.symbols.insert(name.to_string(), value as u16);
+            return Ok(());
+        }
+
+        match keyword.to_lowercase().as_str() {
+            "section" => self.section(args),
+            "db" => {
+                for item in split_args(args) {
+                    if let Some(text) = string_literal(&item) {
+                        self.emit(text.as_bytes());
+                    } else {
+                        let value = self.eval(&parse_expr(&item)?, false)?;
+                        self.emit(&[value as u8]);
+                    }
+                }
+                Ok(())
+            }
+            "dw" => {
+                for item in split_args(args) {
+                    let value = self.eval(&parse_expr(&item)?, false)?;
+                    self.emit(&(value as u16).to_le_bytes());
+                }
+                Ok(())
+            }
+            "ds" => {
+                let items = split_args(args);
+                let count = match items.first() {
+                    Some(item) => self.eval(&parse_expr(item)?, true)?,
+                    None => return Err("ds needs a size".to_string()),
+                };
+                if !(0..=MAX_DS_SIZE).contains(&count) {
+                    return Err(format!("ds size {} outside 0-{}", count, MAX_DS_SIZE));
+                }
+                let fill = match items.get(1) {
+                    Some(item) => self.eval(&parse_expr(item)?, false)? as u8,
+                    None => 0,
+                };
+                self.emit(&vec![fill; count as usize]);
+                Ok(())
+            }
+            mnemonic => self.instruction(mnemonic, args),
+        }
+    }
+
+    fn define_label(&mut self, name: &str) -> Result<(), String> {
+        let full = self.qualify(name);
+        if !name.starts_with('.') {
+            self.scope = name.to_string();
+        }
+        if self.pass == Pass::Layout && self.symbols.contains_key(&full) {
+            return Err(format!("label '{}' defined twice", full));
+        }
+        self.symbols.insert(full, self.addr);
+        Ok(())
+    }
+
+    fn qualify(&self, name: &str) -> String {
+        match name.starts_with('.') {
+            true => format!("{}{}", self.scope, name),
+            false => name.to_string(),
+        }
+    }
+
+    // SECTION "name", TYPE[$addr]
+    fn section(&mut self, args: &str) -> Result<(), String> {
+        let items = split_args(args);
+        let name = items.first().and_then(|s| string_literal(s)).unwrap_or_default();
+        let origin = match items.get(1).and_then(|s| s.find('[').map(|i| &s[i + 1..])) {
+            Some(addr) => {
+                let addr = addr.trim_end().trim_end_matches(']');
+                self.eval(&parse_expr(addr)?, true)? as u16
+            }
+            None => self.addr,
+        };
+        self.addr = origin;
+        self.sections.push(Section { name, origin, bytes: Vec::new() });
+        Ok(())
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        if self.sections.is_empty() {
+            self.sections.push(Section { name: String::new(), origin: self.addr, bytes: Vec::new() });
+        }
+        if let Some(section) = self.sections.last_mut() {
+            section.bytes.extend_from_slice(bytes);
+        }
+        self.addr = self.addr.wrapping_add(bytes.len() as u16);
+    }
+
+    fn eval(&self, expr: &Expr, required: bool) -> Result<i64, String> {
+        match expr {
+            Expr::Number(n) => Ok(*n),
+            Expr::Here => Ok(self.addr as i64),
+            Expr::Symbol(name) => {
+                let name = self.qualify(name);
+                match self.symbols.get(&name) {
+                    Some(value) => Ok(*value as i64),
+                    None if self.pass == Pass::Layout && !required => Ok(0),
+                    None => Err(format!("unknown symbol '{}'", name)),
+                }
+            }
+            Expr::Negate(inner) => Ok(-self.eval(inner, required)?),
+            Expr::High(inner) => Ok((self.eval(inner, required)? >> 8) & 0xFF),
+            Expr::Low(inner) => Ok(self.eval(inner, required)? & 0xFF),
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.eval(lhs, required)?;
+                let rhs = self.eval(rhs, required)?;
+                Ok(match op {
+                    '+' => lhs.wrapping_add(rhs),
+                    '-' => lhs.wrapping_sub(rhs),
+                    '*' => lhs.wrapping_mul(rhs),
+                    '/' if rhs == 0 => return Err("division by zero".to_string()),
+                    '/' => lhs / rhs,
+                    '&' => lhs & rhs,
+                    '|' => lhs | rhs,
+                    '<' => lhs << (rhs & 63),
+                    _ => lhs >> (rhs & 63),
+                })
+            }
+        }
+    }
+
+    fn instruction(&mut self, mnemonic_text: &str, args: &str) -> Result<(), String> {
+        let mut mnemonic_text = mnemonic_text;
+        let mut args: Vec<Arg> = split_args(args).iter().map(|a| parse_arg(a)).collect::<Result<_, _>>()?;
+
+        // `ld [c], a` and `ld a, [c]` are spelled ldh in the tables
+        if mnemonic_text == "ld" && args.iter().any(|a| matches!(a, Arg::Indirect(r) if r == "c")) {
+            mnemonic_text = "ldh";
+        }
+        let mnemonic = *self.mnemonics.get(mnemonic_text)
+            .ok_or_else(|| format!("unknown mnemonic '{}'", mnemonic_text))?;
+
+        // STOP's padding byte is optional in source
+        if mnemonic == Mnemonic::Stop && args.is_empty() {
+            args.push(Arg::Value(Expr::Number(0)));
+        }
+
+        let found = self.find_opcode(mnemonic, &args);
+        // The accumulator may be omitted for 8-bit ALU instructions
+        let found = match found {
+            None if args.len() == 1 && is_alu(mnemonic) => {
+                args.insert(0, Arg::Register("a".to_string()));
+                self.find_opcode(mnemonic, &args)
+            }
+            found => found,
+        };
+        let (prefixed, opcode, fields) = found.ok_or_else(|| format!("invalid operands for '{}'", mnemonic_text))?;
+
+        let start = self.addr;
+        let mut bytes = match prefixed {
+            true => vec![0xCB, opcode.code],
+            false => vec![opcode.code],
+        };
+
+        for (field, expr) in fields {
+            let value = self.eval(&expr, false)?;
+            match field {
+                Field::N8 => {
+                    check_range(value, -128, 255)?;
+                    bytes.push(value as u8);
+                }
+                Field::N16 => {
+                    check_range(value, -32768, 65535)?;
+                    bytes.extend_from_slice(&(value as u16).to_le_bytes());
+                }
+                Field::HighAddress => {
+                    let offset = match value {
+                        0xFF00..=0xFFFF => value - 0xFF00,
+                        0x00..=0xFF => value,
+                        _ => return Err(format!("ldh address ${:x} out of range", value)),
+                    };
+                    bytes.push(offset as u8);
+                }
+                Field::Signed => {
+                    check_range(value, -128, 127)?;
+                    bytes.push(value as u8);
+                }
+                Field::Relative => {
+                    let offset = value - (start as i64 + opcode.bytes as i64);
+                    if self.pass == Pass::Emit {
+                        check_range(offset, -128, 127).map_err(|_| "jr target out of range".to_string())?;
+                    }
+                    bytes.push(offset as u8);
+                }
+            }
+        }
+
+        self.emit(&bytes);
+        Ok(())
+    }
+
+    // Finds the table entry whose operand list matches the source operands
+    fn find_opcode(&self, mnemonic: Mnemonic, args: &[Arg]) -> Option<Encoding> {
+        let tables: &'static OpcodeTables = self.tables;
+        let mut candidates: Vec<(bool, &'static Opcode)> = tables.unprefixed.values().map(|op| (false, op))
+            .chain(tables.cbprefixed.values().map(|op| (true, op)))
+            .filter(|(_, op)| op.mnemonic == mnemonic)
+            .collect();
+        candidates.sort_by_key(|(prefixed, op)| (*prefixed, op.code));
+
+        'candidates: for (prefixed, opcode) in candidates {
+            let mut fields = Vec::new();
+            let mut operands = opcode.operands.iter();
+            let mut args = args.iter();
+
+            loop {
+                let (operand, arg) = match (operands.next(), args.next()) {
+                    (None, None) => return Some((prefixed, opcode, fields)),
+                    (Some(operand), Some(arg)) => (operand, arg),
+                    _ => continue 'candidates,
+                };
+                let name = operand.name.to_lowercase();
+
+                let matched = match (name.as_str(), arg) {
+                    // LD HL, SP+e8 consumes two table operands
+                    ("sp", Arg::SpOffset(expr)) if operand.is_increment => {
+                        operands.next();
+                        fields.push((Field::Signed, expr.clone()));
+                        true
+                    }
+                    ("n8", Arg::Value(expr)) => { fields.push((Field::N8, expr.clone())); true }
+                    ("n16", Arg::Value(expr)) => { fields.push((Field::N16, expr.clone())); true }
+                    ("a16", Arg::Value(expr)) if operand.is_immediate => { fields.push((Field::N16, expr.clone())); true }
+                    ("a16", Arg::Memory(expr)) if !operand.is_immediate => { fields.push((Field::N16, expr.clone())); true }
+                    ("a8", Arg::Memory(expr)) => { fields.push((Field::HighAddress, expr.clone())); true }
+                    ("e8", Arg::Value(expr)) if mnemonic == Mnemonic::Jr => { fields.push((Field::Relative, expr.clone())); true }
+                    ("e8", Arg::Value(expr)) => { fields.push((Field::Signed, expr.clone())); true }
+                    // Bit indices and RST vectors are part of the opcode
+                    (constant, Arg::Value(expr)) if constant.starts_with('$') || constant.chars().all(|c| c.is_ascii_digit()) => {
+                        let expected = match constant.strip_prefix('$') {
+                            Some(hex) => i64::from_str_radix(hex, 16).ok(),
+                            None => constant.parse().ok(),
+                        };
+                        expected.is_some() && self.eval(expr, true).ok() == expected
+                    }
+                    (register, Arg::Register(r)) if operand.is_immediate => register == r,
+                    (register, Arg::Indirect(r)) if !operand.is_immediate => {
+                        let suffix = match (operand.is_increment, operand.is_decrement) {
+                            (true, _) => "+",
+                            (_, true) => "-",
+                            _ => "",
+                        };
+                        format!("{}{}", register, suffix) == *r
+                    }
+                    _ => false,
+                };
+                if !matched {
+                    continue 'candidates;
+                }
+            }
+        }
+        None
+    }
+}
+
+fn is_alu(mnemonic: Mnemonic) -> bool {
+    matches!(mnemonic, Mnemonic::Add | Mnemonic::Adc | Mnemonic::Sub | Mnemonic::Sbc
+        | Mnemonic::And | Mnemonic::Xor | Mnemonic::Or | Mnemonic::Cp)
+}
+
+fn check_range(value: i64, min: i64, max: i64) -> Result<(), String> {
+    match value >= min && value <= max {
+        true => Ok(()),
+        false => Err(format!("value {} out of range", value)),
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+// Byte offset of the colon ending a leading label, if the line starts with one
+fn label_end(line: &str) -> Option<usize> {
+    let end = line.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))?;
+    match line[end..].starts_with(':') && end > 0 {
+        true => Some(end),
+        false => None,
+    }
+}
+
+fn split_equ(line: &str) -> Option<(&str, &str)> {
+    let mut parts = line.splitn(3, char::is_whitespace);
+    let name = parts.next()?;
+    let keyword = parts.next()?;
+    match keyword.eq_ignore_ascii_case("equ") {
+        true => Some((name, parts.next().unwrap_or("").trim())),
+        false => None,
+    }
+}
+
+fn string_literal(text: &str) -> Option<String> {
+    let text = text.trim();
+    match text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+        true => Some(text[1..text.len() - 1].to_string()),
+        false => None,
+    }
+}
+
+// Splits on top level commas, leaving strings, brackets and parentheses intact
+fn split_args(args: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut in_string = false;
+
+    for c in args.chars() {
+        match c {
+            '"' => in_string = !in_string,
+            '[' | '(' if !in_string => depth += 1,
+            ']' | ')' if !in_string => depth -= 1,
+            ',' if !in_string && depth == 0 => {
+                items.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    if !current.trim().is_empty() {
+        items.push(current.trim().to_string());
+    }
+    items
+}
+
+fn parse_arg(text: &str) -> Result<Arg, String> {
+    let lower = text.to_lowercase();
+    let compact: String = lower.chars().filter(|c| !c.is_whitespace()).collect();
+
+    if let Some(inner) = compact.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+        let register = match inner {
+            "hli" => Some("hl+"),
+            "hld" => Some("hl-"),
+            "$ff00+c" => Some("c"),
+            "hl" | "hl+" | "hl-" | "bc" | "de" | "c" => Some(inner),
+            _ => None,
+        };
+        return match register {
+            Some(r) => Ok(Arg::Indirect(r.to_string())),
+            None => {
+                let start = text.find('[').unwrap_or(0) + 1;
+                let end = text.rfind(']').unwrap_or(text.len());
+                Ok(Arg::Memory(parse_expr(&text[start..end])?))
+            }
+        };
+    }
+
+    if REGISTERS.contains(&compact.as_str()) {
+        return Ok(Arg::Register(compact));
+    }
+
+    if compact.starts_with("sp+") || compact.starts_with("sp-") {
+        let offset = &text[text.to_lowercase().find("sp").unwrap_or(0) + 2..];
+        return Ok(Arg::SpOffset(parse_expr(offset)?));
+    }
+
+    Ok(Arg::Value(parse_expr(text)?))
+}
+
+
+// Recursive descent over a small token stream
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Op(char),
+    Open,
+    Close,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            ' ' | '\t' => i += 1,
+            '(' => { tokens.push(Token::Open); i += 1; }
+            ')' => { tokens.push(Token::Close); i += 1; }
+            '<' | '>' => {
+                if chars.get(i + 1) != Some(&c) {
+                    return Err(format!("unexpected '{}'", c));
+                }
+                tokens.push(Token::Op(c));
+                i += 2;
+            }
+            '+' | '-' | '*' | '/' | '&' | '|' => { tokens.push(Token::Op(c)); i += 1; }
+            '@' => { tokens.push(Token::Ident("@".to_string())); i += 1; }
+            '\'' => {
+                let value = chars.get(i + 1).ok_or("unterminated character")?;
+                if chars.get(i + 2) != Some(&'\'') {
+                    return Err("unterminated character".to_string());
+                }
+                tokens.push(Token::Number(*value as i64));
+                i += 3;
+            }
+            '$' | '%' | '0'..='9' => {
+                let start = i;
+                i += 1;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let literal: String = chars[start..i].iter().filter(|&&c| c != '_').collect();
+                tokens.push(Token::Number(parse_number(&literal)?));
+            }
+            _ if c.is_alphabetic() || c == '_' || c == '.' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.') {
+                    i += 1;
+                }
+                tokens.push(Token::Ident(chars[start..i].iter().collect()));
+            }
+            _ => return Err(format!("unexpected '{}'", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(literal: &str) -> Result<i64, String> {
+    let lower = literal.to_lowercase();
+    let parsed = if let Some(hex) = lower.strip_prefix('$').or_else(|| lower.strip_prefix("0x")) {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(bin) = lower.strip_prefix('%').or_else(|| lower.strip_prefix("0b")) {
+        i64::from_str_radix(bin, 2)
+    } else {
+        lower.parse()
+    };
+    parsed.map_err(|_| format!("invalid number '{}'", literal))
+}
+
+fn parse_expr(text: &str) -> Result<Expr, String> {
+    let tokens = tokenize(text)?;
+    if tokens.is_empty() {
+        return Err("expected expression".to_string());
+    }
+    let mut pos = 0;
+    let expr = parse_binary(&tokens, &mut pos, 0)?;
+    match pos == tokens.len() {
+        true => Ok(expr),
+        false => Err(format!("unexpected trailing input in '{}'", text.trim())),
+    }
+}
+
+fn precedence(op: char) -> u8 {
+    match op {
+        '|' => 1,
+        '&' => 2,
+        '<' | '>' => 3,
+        '+' | '-' => 4,
+        _ => 5,
+    }
+}
+
+fn parse_binary(tokens: &[Token], pos: &mut usize, min_precedence: u8) -> Result<Expr, String> {
+    let mut lhs = parse_unary(tokens, pos)?;
+    while let Some(Token::Op(op)) = tokens.get(*pos) {
+        let op = *op;
+        if precedence(op) < min_precedence.max(1) {
+            break;
+        }
+        *pos += 1;
+        let rhs = parse_binary(tokens, pos, precedence(op) + 1)?;
+        lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+    }
+    Ok(lhs)
+}
+
+fn parse_unary(tokens: &[Token], pos: &mut usize) -> Result<Expr, String> {
+    let token = tokens.get(*pos).ok_or("expected expression")?.clone();
+    *pos += 1;
+    match token {
+        Token::Op('-') => Ok(Expr::Negate(Box::new(parse_unary(tokens, pos)?))),
+        Token::Op('+') => parse_unary(tokens, pos),
+        Token::Number(n) => Ok(Expr::Number(n)),
+        Token::Open => {
+            let inner = parse_binary(tokens, pos, 0)?;
+            match tokens.get(*pos) {
+                Some(Token::Close) => { *pos += 1; Ok(inner) }
+                _ => Err("expected ')'".to_string()),
+            }
+        }
+        Token::Ident(name) if name == "@" => Ok(Expr::Here),
+        Token::Ident(name) => {
+            let function = name.to_lowercase();
+            if (function == "high" || function == "low") && tokens.get(*pos) == Some(&Token::Open) {
+                let inner = parse_unary(tokens, pos)?;
+                return Ok(match function.as_str() {
+                    "high" => Expr::High(Box::new(inner)),
+                    _ => Expr::Low(Box::new(inner)),
+                });
+            }
+            Ok(Expr::Symbol(name))
+        }
+        other => Err(format!("unexpected {:?}", other)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::IO;
+    use crate::cpu::CPU;
+    use crate::disasm;
+    use crate::sst::FlatRam;
+
+    const PROGRAM: &str = r#"
+        SECTION "code", ROM0[$0100]
+        start:
+            ld hl, $c000
+            ld b, 10
+            xor a
+        .loop:
+            add a, b
+            ld [hl+], a
+            dec b
+            jr nz, .loop
+            ld [$c100], a
+            ldh [$ff80], a
+            push af
+            pop de
+            swap e
+            bit 7, e
+            call done
+            halt
+        done:
+            ret
+    "#;
+
+    #[test]
+    fn ds_size_is_checked() {
+        assert!(assemble("ds -1").is_err());
+        assert!(assemble("ds $10001").is_err());
+        assert_eq!(assemble("ds 4, $ff").unwrap(), vec![0xFF; 4]);
+    }
+
+    #[test]
+    fn disassembly_assembles_back_to_the_same_bytes() {
+        let program = assemble_program(PROGRAM).unwrap();
+        let origin = program.origin();
+        let image = program.to_image();
+
+        let mut listing = format!("SECTION \"code\", ROM0[${:04x}]\n", origin);
+        let mut offset = 0;
+        while offset < image.len() {
+            let instruction = disasm::decode(&image[offset..], origin + offset as u16).unwrap();
+            assert!(!instruction.is_illegal(), "{}", instruction);
+            listing.push_str(&format!("{}\n", instruction));
+            offset += instruction.len() as usize;
+        }
+        assert_eq!(assemble(&listing).unwrap(), image, "{}", listing);
+    }
+
+    #[test]
+    fn assembled_program_runs() {
+        let program = assemble_program(PROGRAM).unwrap();
+        let mut ram = FlatRam::new();
+        for (i, &byte) in program.to_image().iter().enumerate() {
+            ram.write(program.origin() + i as u16, byte);
+        }
+        let mut cpu = CPU::new();
+        cpu.reg.pc = program.symbols["start"];
+        cpu.reg.sp = 0xFFFE;
+        for _ in 0..1000 {
+            if cpu.halted {
+                break;
+            }
+            cpu.step(&mut ram);
+        }
+        assert!(cpu.halted);
+        // 10 + 9 + ... + 1, stored after each addition
+        assert_eq!(cpu.reg.a, 55);
+        assert_eq!(ram.read(0xC000), Some(10));
+        assert_eq!(ram.read(0xC009), Some(55));
+        assert_eq!(ram.read(0xC100), Some(55));
+        assert_eq!(ram.read(0xFF80), Some(55));
+        assert_eq!(cpu.reg.d, 55);
+        // Z and N from the last DEC, swapped into the low nibble
+        assert_eq!(cpu.reg.e, 0x0C);
+    }
+}
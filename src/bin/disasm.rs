@@ -0,0 +1,23 @@
+use gbc_emulator_core::cartridge::Cartridge;
+use gbc_emulator_core::disasm;
+
+// Usage: disasm <rom> [--linear]
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let path = match args.iter().find(|a| !a.starts_with("--")) {
+        Some(p) => p,
+        None => {
+            eprintln!("Usage: disasm <rom> [--linear]");
+            std::process::exit(2);
+        }
+    };
+
+    let cartridge = Cartridge::new_from_file(path)
+        .unwrap_or_else(|e| panic!("Failed to load ROM {}: {}", path, e));
+
+    let disassembly = match args.iter().any(|a| a == "--linear") {
+        true => disasm::disassemble_linear(&cartridge),
+        false => disasm::disassemble_recursive(&cartridge),
+    };
+    print!("{}", disassembly);
+}
@@ -0,0 +1,296 @@
+use std::io::{self, BufRead, Write};
+use gbc_emulator_core::cartridge::Cartridge;
+use gbc_emulator_core::cpu::{FLAG_C, FLAG_H, FLAG_N, FLAG_Z};
+use gbc_emulator_core::debugger::{parse_number, Condition, Debugger, RegisterName, StopReason, WatchKind};
+use gbc_emulator_core::gameboy::GameBoy;
+use gbc_emulator_core::rewind::RewindConfig;
+
+// Usage: gbdb <rom>
+// Numbers are hex unless prefixed with '#'.
+
+const HELP: &str = "\
+commands:
+  s [n]                     step n instructions (default 1)
+  n                         step over calls
+  finish                    run until the current routine returns
+  c                         continue
+  frame [n]                 run to the end of the frame (n times)
+  rframe [n]                go back n frames (default 1)
+  b [bank:]addr [if cond]   breakpoint, e.g. `b 1:4000 if a == $10`
+  w[r|w|x] start[-end]      watchpoint on reads+writes, reads, writes or execution
+  d id / en id / dis id     delete, enable or disable a break/watchpoint
+  l                         list break/watchpoints
+  sbp on|off                stop on ld b, b
+  r                         registers
+  set reg value             change a register
+  x addr [len]              hexdump memory
+  u [addr] [n]              disassemble
+  stack [n]                 show the top of the stack
+  q                         quit";
+
+fn main() {
+    let path = match std::env::args().nth(1) {
+        Some(p) => p,
+        None => {
+            eprintln!("Usage: gbdb <rom>");
+            std::process::exit(2);
+        }
+    };
+    let cartridge = Cartridge::new_from_file(&path)
+        .unwrap_or_else(|e| panic!("Failed to load ROM {}: {}", path, e));
+    cartridge.print_info();
+
+    let mut debugger = Debugger::new(GameBoy::new(cartridge));
+    debugger.enable_rewind(RewindConfig::default());
+    show_state(&debugger);
+
+    let stdin = io::stdin();
+    loop {
+        print!("(gbdb) ");
+        io::stdout().flush().ok();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some(&command) = words.first() else {
+            continue;
+        };
+        let args = &words[1..];
+
+        match command {
+            "q" | "quit" => break,
+            "h" | "help" => println!("{}", HELP),
+            "s" | "step" => {
+                let count = args.first().and_then(|n| parse_number(n)).unwrap_or(1);
+                let mut reason = StopReason::Step;
+                for _ in 0..count {
+                    reason = debugger.step_in();
+                    if reason != StopReason::Step {
+                        break;
+                    }
+                }
+                stopped(&debugger, reason);
+            }
+            "n" | "next" => {
+                let reason = debugger.step_over();
+                stopped(&debugger, reason);
+            }
+            "finish" => {
+                let reason = debugger.step_out();
+                stopped(&debugger, reason);
+            }
+            "c" | "continue" => {
+                let reason = debugger.resume();
+                stopped(&debugger, reason);
+            }
+            "frame" => {
+                let count = args.first().and_then(|n| parse_number(n)).unwrap_or(1);
+                let mut reason = StopReason::FrameEnd;
+                for _ in 0..count {
+                    reason = debugger.run_to_frame();
+                    if reason != StopReason::FrameEnd {
+                        break;
+                    }
+                }
+                stopped(&debugger, reason);
+            }
+            "rframe" => {
+                let count = args.first().and_then(|n| parse_number(n)).unwrap_or(1);
+                match debugger.reverse_frames(count as u64) {
+                    Ok(0) => println!("nothing to rewind"),
+                    Ok(rewound) => {
+                        println!("rewound {} frame(s)", rewound);
+                        show_state(&debugger);
+                    }
+                    Err(e) => println!("rewind failed: {}", e),
+                }
+            }
+            "b" | "break" => add_breakpoint(&mut debugger, args),
+            "w" | "wr" | "ww" | "wx" => {
+                let kind = match command {
+                    "wr" => WatchKind::Read,
+                    "ww" => WatchKind::Write,
+                    "wx" => WatchKind::Execute,
+                    _ => WatchKind::ReadWrite,
+                };
+                match args.first().and_then(|r| parse_range(r)) {
+                    Some((start, end)) => {
+                        let id = debugger.add_watchpoint(start..=end, kind);
+                        println!("watchpoint {} on ${:04X}-${:04X}", id, start, end);
+                    }
+                    None => println!("usage: {} start[-end]", command),
+                }
+            }
+            "d" | "delete" | "en" | "enable" | "dis" | "disable" => {
+                let Some(id) = args.first().and_then(|n| n.parse::<usize>().ok()) else {
+                    println!("usage: {} id", command);
+                    continue;
+                };
+                let found = match command {
+                    "d" | "delete" => debugger.remove(id),
+                    "en" | "enable" => debugger.set_enabled(id, true),
+                    _ => debugger.set_enabled(id, false),
+                };
+                if !found {
+                    println!("no break/watchpoint {}", id);
+                }
+            }
+            "l" | "list" => list_points(&debugger),
+            "sbp" => {
+                debugger.software_breakpoints = args.first() != Some(&"off");
+                println!("ld b, b breakpoints {}", if debugger.software_breakpoints { "on" } else { "off" });
+            }
+            "r" | "regs" => print_registers(&debugger),
+            "set" => {
+                let register = args.first().and_then(|r| RegisterName::parse(r));
+                let value = args.get(1).and_then(|v| parse_number(v));
+                match (register, value) {
+                    (Some(register), Some(value)) => register.set(&mut debugger.gb.cpu.reg, value),
+                    _ => println!("usage: set reg value"),
+                }
+            }
+            "x" => {
+                let addr = args.first().and_then(|a| parse_number(a)).unwrap_or(debugger.gb.cpu.reg.pc);
+                let len = args.get(1).and_then(|n| parse_number(n)).unwrap_or(0x40);
+                hexdump(&debugger, addr, len);
+            }
+            "u" => {
+                let addr = args.first().and_then(|a| parse_number(a)).unwrap_or(debugger.gb.cpu.reg.pc);
+                let count = args.get(1).and_then(|n| parse_number(n)).unwrap_or(10);
+                disassemble(&debugger, addr, count as usize);
+            }
+            "stack" => {
+                let count = args.first().and_then(|n| parse_number(n)).unwrap_or(8);
+                print_stack(&debugger, count);
+            }
+            _ => println!("unknown command '{}', try 'help'", command),
+        }
+    }
+}
+
+fn parse_range(text: &str) -> Option<(u16, u16)> {
+    match text.split_once('-') {
+        Some((start, end)) => Some((parse_number(start)?, parse_number(end)?)),
+        None => parse_number(text).map(|addr| (addr, addr)),
+    }
+}
+
+fn add_breakpoint(debugger: &mut Debugger, args: &[&str]) {
+    let Some(location) = args.first() else {
+        println!("usage: b [bank:]addr [if cond]");
+        return;
+    };
+    let (bank, addr) = match location.split_once(':') {
+        Some((bank, addr)) => (usize::from_str_radix(bank, 16).ok(), parse_number(addr)),
+        None => (None, parse_number(location)),
+    };
+    let Some(addr) = addr else {
+        println!("invalid address '{}'", location);
+        return;
+    };
+
+    let condition = match args.get(1) {
+        Some(&"if") => match Condition::parse(&args[2..].join(" ")) {
+            Some(c) => Some(c),
+            None => {
+                println!("invalid condition, expected e.g. `a == $10`");
+                return;
+            }
+        },
+        _ => None,
+    };
+
+    let id = debugger.add_breakpoint(bank, addr, condition);
+    println!("breakpoint {} at {}", id, location);
+}
+
+fn list_points(debugger: &Debugger) {
+    for b in debugger.breakpoints() {
+        let bank = b.bank.map(|bank| format!("{:02X}:", bank)).unwrap_or_default();
+        let condition = b.condition.map(|c| format!(" if {:?} {:?} ${:X}", c.register, c.op, c.value)).unwrap_or_default();
+        println!("{:>3} break {}{:04X}{}{}", b.id, bank, b.addr, condition, if b.enabled { "" } else { " (disabled)" });
+    }
+    for w in debugger.watchpoints() {
+        println!("{:>3} watch {:?} {:04X}-{:04X}{}", w.id, w.kind, w.range.start(), w.range.end(),
+            if w.enabled { "" } else { " (disabled)" });
+    }
+}
+
+fn stopped(debugger: &Debugger, reason: StopReason) {
+    if reason != StopReason::Step {
+        println!("stopped: {}", reason);
+    }
+    show_state(debugger);
+}
+
+fn show_state(debugger: &Debugger) {
+    print_registers(debugger);
+    let history: Vec<u16> = debugger.history().copied().collect();
+    for &addr in &history[history.len().saturating_sub(3)..] {
+        print_instruction(debugger, addr, false);
+    }
+    let mut addr = debugger.gb.cpu.reg.pc;
+    for i in 0..5 {
+        addr = addr.wrapping_add(print_instruction(debugger, addr, i == 0));
+    }
+    print_stack(debugger, 4);
+}
+
+fn print_registers(debugger: &Debugger) {
+    let cpu = &debugger.gb.cpu;
+    let reg = &cpu.reg;
+    let flag = |mask: u8, name: char| if reg.flag(mask) { name } else { '-' };
+    println!("AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:02X}:{:04X} [{}{}{}{}] IME={} {}{}",
+        reg.get_af(), reg.get_bc(), reg.get_de(), reg.get_hl(), reg.sp,
+        debugger.gb.rom_bank(reg.pc), reg.pc,
+        flag(FLAG_Z, 'Z'), flag(FLAG_N, 'N'), flag(FLAG_H, 'H'), flag(FLAG_C, 'C'),
+        cpu.ime as u8,
+        if cpu.halted { "HALT " } else { "" },
+        format_args!("LY={:02X} frame={}", debugger.gb.read(0xFF44), debugger.gb.frame_count()));
+}
+
+// Returns the instruction length so callers can walk forward
+fn print_instruction(debugger: &Debugger, addr: u16, current: bool) -> u16 {
+    let marker = if current { "=>" } else { "  " };
+    let bank = debugger.gb.rom_bank(addr);
+    match debugger.decode_at(addr) {
+        Some(instruction) => {
+            let bytes: Vec<String> = instruction.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            println!("{} {:02X}:{:04X}  {:<9} {}", marker, bank, addr, bytes.join(" "), instruction);
+            instruction.len()
+        }
+        None => {
+            println!("{} {:02X}:{:04X}  {:02X}        ???", marker, bank, addr, debugger.gb.read(addr));
+            1
+        }
+    }
+}
+
+fn disassemble(debugger: &Debugger, mut addr: u16, count: usize) {
+    let pc = debugger.gb.cpu.reg.pc;
+    for _ in 0..count {
+        addr = addr.wrapping_add(print_instruction(debugger, addr, addr == pc));
+    }
+}
+
+fn print_stack(debugger: &Debugger, count: u16) {
+    let sp = debugger.gb.cpu.reg.sp;
+    // 0x8000 words already cover the whole address space
+    for i in 0..count.min(0x8000) {
+        let addr = sp.wrapping_add(i * 2);
+        let value = u16::from_le_bytes([debugger.gb.read(addr), debugger.gb.read(addr.wrapping_add(1))]);
+        println!("   SP+{:02X} {:04X}: {:04X}", i * 2, addr, value);
+    }
+}
+
+fn hexdump(debugger: &Debugger, start: u16, len: u16) {
+    for row in (0..len).step_by(16) {
+        let addr = start.wrapping_add(row);
+        let bytes: Vec<u8> = (0..16.min(len - row)).map(|i| debugger.gb.read(addr.wrapping_add(i))).collect();
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let ascii: String = bytes.iter().map(|&b| if (0x20..0x7F).contains(&b) { b as char } else { '.' }).collect();
+        println!("{:04X}: {:<48} {}", addr, hex.join(" "), ascii);
+    }
+}
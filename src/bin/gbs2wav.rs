@@ -0,0 +1,42 @@
+use gbc_emulator_core::gbs::{Gbs, GbsError, GbsPlayer};
+use gbc_emulator_core::wav;
+
+// Usage:
+//   gbs2wav <file.gbs> <out.wav> [song] [seconds] [sample rate]
+//
+// Songs are numbered from 1, the file's first song by default. Renders two
+// minutes at 44100 Hz unless told otherwise.
+
+const DEFAULT_SECONDS: u32 = 120;
+const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if !(3..=6).contains(&args.len()) {
+        eprintln!("Usage: gbs2wav <file.gbs> <out.wav> [song] [seconds] [sample rate]");
+        std::process::exit(2);
+    }
+    let number = |i: usize| args.get(i).map(|arg| arg.parse::<u32>().unwrap_or_else(|_| {
+        eprintln!("Not a number: {}", arg);
+        std::process::exit(2);
+    }));
+    if let Err(e) = convert(&args[1], &args[2], number(3), number(4).unwrap_or(DEFAULT_SECONDS), number(5).unwrap_or(DEFAULT_SAMPLE_RATE)) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn convert(path: &str, out: &str, song: Option<u32>, seconds: u32, sample_rate: u32) -> Result<(), GbsError> {
+    let gbs = Gbs::load(path)?;
+    let song = song.unwrap_or(gbs.first_song.max(1) as u32);
+    if song == 0 || song > gbs.song_count as u32 {
+        return Err(GbsError::NoSuchSong(song.saturating_sub(1).min(u8::MAX as u32) as u8));
+    }
+    println!("{} - {} ({}), song {} of {}", gbs.title, gbs.author, gbs.copyright, song, gbs.song_count);
+
+    let mut player = GbsPlayer::new(&gbs, (song - 1) as u8, sample_rate)?;
+    let samples = player.render((seconds as u64 * sample_rate as u64) as usize);
+    wav::save(out, sample_rate, 2, &samples)?;
+    println!("{} seconds written to {}", seconds, out);
+    Ok(())
+}
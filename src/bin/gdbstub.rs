@@ -0,0 +1,38 @@
+use gbc_emulator_core::cartridge::Cartridge;
+use gbc_emulator_core::debugger::Debugger;
+use gbc_emulator_core::gameboy::GameBoy;
+use gbc_emulator_core::gdb::GdbStub;
+
+// Usage: gdbstub <rom> [--port N | --pipe]
+// Then `target remote :2345` (or `target remote | gdbstub <rom> --pipe`) from gdb.
+
+const DEFAULT_PORT: u16 = 2345;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 {
+        eprintln!("Usage: gdbstub <rom> [--port N | --pipe]");
+        std::process::exit(2);
+    }
+    let pipe = args.iter().any(|a| a == "--pipe");
+    let port = args.iter()
+        .position(|a| a == "--port")
+        .and_then(|i| args.get(i + 1))
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(DEFAULT_PORT);
+
+    let cartridge = Cartridge::new_from_file(&args[1])
+        .unwrap_or_else(|e| panic!("Failed to load ROM {}: {}", args[1], e));
+    let debugger = Debugger::new(GameBoy::new(cartridge));
+
+    let result = if pipe {
+        GdbStub::stdio(debugger).run()
+    } else {
+        eprintln!("Waiting for gdb on 127.0.0.1:{}", port);
+        GdbStub::listen(debugger, ("127.0.0.1", port)).and_then(|mut stub| stub.run())
+    };
+    if let Err(e) = result {
+        eprintln!("gdbstub: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,44 @@
+use gbc_emulator_core::cartridge::Cartridge;
+use gbc_emulator_core::movie::{Movie, MovieError, Player};
+
+// Usage:
+//   movie play <rom> <movie>              replay and check every recorded frame hash
+//   movie import <rom> <file.bk2> <out>   convert a BizHawk movie
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let result = match args.get(1).map(String::as_str) {
+        Some("play") if args.len() == 4 => play(&args[2], &args[3]),
+        Some("import") if args.len() == 5 => import(&args[2], &args[3], &args[4]),
+        _ => {
+            eprintln!("Usage: movie play <rom> <movie> | movie import <rom> <file.bk2> <out>");
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn load_rom(path: &str) -> Cartridge {
+    Cartridge::new_from_file(path).unwrap_or_else(|e| panic!("Failed to load ROM {}: {}", path, e))
+}
+
+fn play(rom: &str, path: &str) -> Result<(), MovieError> {
+    let movie = Movie::load(path)?;
+    let mut gb = movie.start(load_rom(rom))?;
+    let mut player = Player::new(&movie);
+    while player.frame(&mut gb)? {}
+
+    let checked = if movie.hashes.is_empty() { "no hashes to check" } else { "all hashes match" };
+    println!("{} frames played, {}", player.position(), checked);
+    Ok(())
+}
+
+fn import(rom: &str, path: &str, out: &str) -> Result<(), MovieError> {
+    let movie = Movie::import_bk2(&std::fs::read(path)?, &load_rom(rom))?;
+    movie.save(out)?;
+    println!("{} frames imported", movie.len());
+    Ok(())
+}
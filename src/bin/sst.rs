@@ -0,0 +1,29 @@
+use std::path::Path;
+use gbc_emulator_core::opcode::OpcodeTables;
+use gbc_emulator_core::sst;
+
+// Usage: sst <file.json | directory>...
+fn main() {
+    let paths: Vec<String> = std::env::args().skip(1).collect();
+    if paths.is_empty() {
+        eprintln!("Usage: sst <file.json | directory>...");
+        std::process::exit(2);
+    }
+
+    let tables = OpcodeTables::load();
+    let mut report = sst::Report::default();
+    for path in &paths {
+        match sst::run_path(Path::new(path), &tables) {
+            Ok(r) => report.merge(r),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    print!("{}", report);
+    if report.failed > 0 {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,75 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use gbc_emulator_core::cartridge::Cartridge;
+use gbc_emulator_core::debugger::parse_number;
+use gbc_emulator_core::gameboy::GameBoy;
+use gbc_emulator_core::trace::{self, CompareError, TraceFilter, Tracer, DOCTOR_LY};
+
+// Usage: trace <rom> [options]
+//   --out <file>         write the log to a file instead of stdout
+//   --compare <file>     run against a reference log, stop at the first divergence
+//   --count <n>          stop after n instructions (decimal)
+//   --pc <start-end>     only trace this PC range
+//   --bank <n>           only trace code running from this ROM bank
+//   --doctor             LY reads 0x90, as Gameboy Doctor logs expect
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 {
+        eprintln!("Usage: trace <rom> [--out file] [--compare file] [--count n] [--pc start-end] [--bank n] [--doctor]");
+        std::process::exit(2);
+    }
+    let option = |name: &str| args.iter().position(|a| a == name).and_then(|i| args.get(i + 1));
+
+    let mut filter = TraceFilter::default();
+    if let Some(range) = option("--pc") {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        match (parse_number(start), parse_number(end)) {
+            (Some(start), Some(end)) => filter.pc_range = Some(start..=end),
+            _ => panic!("Invalid PC range: {}", range),
+        }
+    }
+    filter.bank = option("--bank").map(|b| usize::from_str_radix(b, 16).expect("Invalid bank"));
+    let count: Option<usize> = option("--count").map(|n| n.parse().expect("Invalid count"));
+
+    let cartridge = Cartridge::new_from_file(&args[1])
+        .unwrap_or_else(|e| panic!("Failed to load ROM {}: {}", args[1], e));
+    let mut gb = GameBoy::new(cartridge);
+    if args.iter().any(|a| a == "--doctor") {
+        gb.mmu.ppu.set_ly_override(Some(DOCTOR_LY));
+    }
+
+    if let Some(reference) = option("--compare") {
+        let file = File::open(reference).unwrap_or_else(|e| panic!("Failed to open {}: {}", reference, e));
+        match trace::compare(&mut gb, BufReader::new(file), &filter, count) {
+            Ok(matched) => println!("{} lines match", matched),
+            Err(e @ CompareError::Diverged(_)) => {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        }
+        return;
+    }
+
+    let output: Box<dyn Write> = match option("--out") {
+        Some(path) => Box::new(File::create(path).unwrap_or_else(|e| panic!("Failed to create {}: {}", path, e))),
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut tracer = Tracer::new(BufWriter::new(output), filter);
+    let mut steps = 0;
+    while count.is_none_or(|count| steps < count) && !gb.cpu.locked {
+        if let Err(e) = tracer.step(&mut gb) {
+            // Closed pipe, e.g. `| head`
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                eprintln!("trace: {}", e);
+            }
+            return;
+        }
+        steps += 1;
+    }
+    tracer.into_inner().flush().ok();
+}
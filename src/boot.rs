@@ -0,0 +1,288 @@
+use crate::apu::NR52;
+use crate::common::Model;
+use crate::cpu::CPU;
+use crate::mmu::MMU;
+
+// What the boot ROM leaves behind, for starting without one.
+// See https://gbdev.io/pandocs/Power_Up_Sequence.html
+//
+// CGB compatibility palettes follow the tables in the CGB boot ROM: DMG
+// games published by Nintendo get colours picked by the sum of their title
+// bytes, with the 4th title letter breaking ties, everything else gets the
+// default green and red.
+
+pub const DMG_BOOT_ROM_SIZE: usize = 0x100;
+pub const CGB_BOOT_ROM_SIZE: usize = 0x900;  // 0x0000-0x00FF and 0x0200-0x08FF
+
+const HEADER_LOGO: usize = 0x0104;
+const HEADER_TITLE: usize = 0x0134;
+const HEADER_CGB_FLAG: usize = 0x0143;
+const HEADER_NEW_LICENSEE: usize = 0x0144;
+const HEADER_OLD_LICENSEE: usize = 0x014B;
+const HEADER_CHECKSUM: usize = 0x014D;
+
+// Logo tiles go to tile 1 onwards, the ® to tile 0x19
+const LOGO_TILES: u16 = 0x8010;
+const REGISTERED_TILE: u16 = 0x8190;
+const REGISTERED: [u8; 8] = [0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C];
+const LOGO_MAP_TOP: u16 = 0x9904;
+const LOGO_MAP_BOTTOM: u16 = 0x9924;
+const REGISTERED_MAP: u16 = 0x9910;
+
+// I/O registers as the boot ROMs leave them. Audio registers are written
+// with the APU on, NRx4 without the trigger bit so no channel restarts.
+const POST_BOOT_IO: [(u16, u8); 26] = [
+    (0xFF00, 0xCF), (0xFF01, 0x00), (0xFF05, 0x00), (0xFF06, 0x00), (0xFF07, 0xF8),
+    (0xFF0F, 0xE1), (0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3), (0xFF13, 0xFF),
+    (0xFF14, 0x3F), (0xFF16, 0x3F), (0xFF17, 0x00), (0xFF18, 0xFF), (0xFF19, 0x3F),
+    (0xFF1A, 0x7F), (0xFF1B, 0xFF), (0xFF1C, 0x9F), (0xFF1D, 0xFF), (0xFF1E, 0x3F),
+    (0xFF20, 0xFF), (0xFF21, 0x00), (0xFF22, 0x00), (0xFF23, 0x3F), (0xFF24, 0x77),
+    (0xFF25, 0xF3),
+];
+const POST_BOOT_LCD: [(u16, u8); 9] = [
+    (0xFF40, 0x91), (0xFF42, 0x00), (0xFF43, 0x00), (0xFF45, 0x00), (0xFF47, 0xFC),
+    (0xFF48, 0xFF), (0xFF49, 0xFF), (0xFF4A, 0x00), (0xFF4B, 0x00),
+];
+const SC: u16 = 0xFF02;
+const DMG_DIV_COUNTER: u16 = 0xABCC;
+
+
+/// Boot ROM size for a model, 256 bytes except on CGB.
+pub fn boot_rom_size(model: Model) -> usize {
+    if model.is_cgb() { CGB_BOOT_ROM_SIZE } else { DMG_BOOT_ROM_SIZE }
+}
+
+/// Puts the machine in the state the boot ROM hands over to the cartridge.
+pub fn skip_boot(cpu: &mut CPU, mmu: &mut MMU, model: Model) {
+    let rom = mmu.cartridge().map(|cart| cart.get_rom_data().clone()).unwrap_or_default();
+    let header = |offset: usize| rom.get(offset).copied().unwrap_or(0);
+    let cgb_game = header(HEADER_CGB_FLAG) & 0x80 != 0;
+    // DMG and MGB set H and C unless the header checksum is zero
+    let flags = if header(HEADER_CHECKSUM) == 0 { 0x80 } else { 0xB0 };
+
+    let (af, bc, de, hl) = match model {
+        Model::Dmg => (0x0100 | flags, 0x0013, 0x00D8, 0x014D),
+        Model::Mgb => (0xFF00 | flags, 0x0013, 0x00D8, 0x014D),
+        Model::Sgb => (0x0100, 0x0014, 0x0000, 0xC060),
+        Model::Sgb2 => (0xFF00, 0x0014, 0x0000, 0xC060),
+        Model::Cgb if cgb_game => (0x1180, 0x0000, 0xFF56, 0x000D),
+        Model::Cgb => {
+            let b = if nintendo_licensed(&rom) { title_checksum(&rom) } else { 0 };
+            (0x1180, (b as u16) << 8, 0x0008, 0x007C)
+        }
+    };
+    cpu.reg.set_af(af);
+    cpu.reg.set_bc(bc);
+    cpu.reg.set_de(de);
+    cpu.reg.set_hl(hl);
+    cpu.reg.sp = 0xFFFE;
+    cpu.reg.pc = 0x0100;
+
+    mmu.poke(NR52, 0x80);
+    for (addr, value) in POST_BOOT_IO.iter().chain(&POST_BOOT_LCD) {
+        mmu.poke(*addr, *value);
+    }
+    mmu.poke(SC, if model.is_cgb() { 0x7F } else { 0x7E });
+    // The SGB boot ROM stays silent, the others leave the chime's channel on
+    if !model.is_sgb() {
+        mmu.apu.finish_boot_sound();
+    }
+    if matches!(model, Model::Dmg | Model::Mgb) {
+        mmu.timer.set_counter(DMG_DIV_COUNTER);
+    }
+
+    load_logo(mmu, &rom);
+}
+
+// Decompresses the header logo into VRAM bank 0 the way the boot ROMs do,
+// every bit doubled horizontally and every row doubled vertically. The CGB
+// boot ROM leaves them behind as well.
+fn load_logo(mmu: &mut MMU, rom: &[u8]) {
+    let logo = rom.get(HEADER_LOGO..HEADER_LOGO + 48).unwrap_or(&[0; 48]);
+    let mut addr = LOGO_TILES;
+    for &byte in logo {
+        for nibble in [byte >> 4, byte & 0x0F] {
+            let row = (0..4).fold(0u8, |row, bit| row | (((nibble >> bit) & 1) * (0b11 << (bit * 2))));
+            for _ in 0..2 {
+                mmu.poke(addr, row);
+                mmu.poke(addr + 1, 0);
+                addr += 2;
+            }
+        }
+    }
+    for (i, &row) in REGISTERED.iter().enumerate() {
+        mmu.poke(REGISTERED_TILE + i as u16 * 2, row);
+        mmu.poke(REGISTERED_TILE + i as u16 * 2 + 1, 0);
+    }
+
+    for i in 0..12 {
+        mmu.poke(LOGO_MAP_TOP + i, 0x01 + i as u8);
+        mmu.poke(LOGO_MAP_BOTTOM + i, 0x0D + i as u8);
+    }
+    mmu.poke(REGISTERED_MAP, 0x19);
+}
+
+/// Sum of the 16 title bytes 0x134-0x143, what the CGB boot ROM keys palettes on.
+pub fn title_checksum(rom: &[u8]) -> u8 {
+    rom.get(HEADER_TITLE..=HEADER_CGB_FLAG)
+        .map(|title| title.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)))
+        .unwrap_or(0)
+}
+
+// Old licensee 0x01, or 0x33 with new licensee "01"
+fn nintendo_licensed(rom: &[u8]) -> bool {
+    match rom.get(HEADER_OLD_LICENSEE) {
+        Some(0x01) => true,
+        Some(0x33) => rom.get(HEADER_NEW_LICENSEE..HEADER_NEW_LICENSEE + 2) == Some(b"01"),
+        _ => false,
+    }
+}
+
+
+/// Colours the CGB gives a DMG game, as RGB555 for each of the 4 shades.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompatPalette {
+    pub bg: [u16; 4],
+    pub obj0: [u16; 4],
+    pub obj1: [u16; 4],
+}
+
+/// Palette a CGB picks for a DMG cartridge, the default one for unknown
+/// or third party games.
+pub fn compat_palette(rom: &[u8]) -> CompatPalette {
+    let combination = if nintendo_licensed(rom) {
+        let checksum = title_checksum(rom);
+        let fourth = rom.get(HEADER_TITLE + 3).copied().unwrap_or(0);
+        TITLE_CHECKSUMS.iter().enumerate()
+            .find(|&(i, &sum)| {
+                // Checksums shared by several games also need the 4th letter to match
+                sum == checksum && (i < FIRST_DUPLICATE || DUPLICATE_LETTERS[i - FIRST_DUPLICATE] == fourth)
+            })
+            .map(|(i, _)| CHECKSUM_COMBINATIONS[i] as usize)
+            .unwrap_or(0)
+    } else {
+        0
+    };
+
+    let (obj0, obj1, bg) = COMBINATIONS[combination];
+    CompatPalette { bg: palette_at(bg), obj0: palette_at(obj0), obj1: palette_at(obj1) }
+}
+
+// Combinations index colours rather than palettes, a few start mid-palette
+fn palette_at(color: usize) -> [u16; 4] {
+    let color_at = |i: usize| PALETTES[i / 4][i % 4];
+    [color_at(color), color_at(color + 1), color_at(color + 2), color_at(color + 3)]
+}
+
+// Title checksums of the games with their own palette. From index
+// FIRST_DUPLICATE on the 4th title letter has to match too
+const FIRST_DUPLICATE: usize = 65;
+const TITLE_CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B,
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+    0xB3,
+];
+const DUPLICATE_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// Palette combination for each entry of TITLE_CHECKSUMS
+const CHECKSUM_COMBINATIONS: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44,
+    21, 32, 31, 20, 5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34,
+    5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0,
+    39,
+    36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50,
+    17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18,
+    29,
+];
+
+// (OBJ0, OBJ1, BG) as indexes into the colours of PALETTES
+const COMBINATIONS: [(usize, usize, usize); 51] = [
+    (4 * 4, 4 * 4, 29 * 4), (18 * 4, 18 * 4, 18 * 4), (20 * 4, 20 * 4, 20 * 4), (24 * 4, 24 * 4, 24 * 4),
+    (9 * 4, 9 * 4, 9 * 4), (0, 0, 0), (27 * 4, 27 * 4, 27 * 4), (5 * 4, 5 * 4, 5 * 4),
+    (12 * 4, 12 * 4, 12 * 4), (26 * 4, 26 * 4, 26 * 4), (16 * 4, 8 * 4, 8 * 4), (4 * 4, 28 * 4, 28 * 4),
+    (4 * 4, 2 * 4, 2 * 4), (3 * 4, 4 * 4, 4 * 4), (4 * 4, 29 * 4, 29 * 4), (28 * 4, 4 * 4, 28 * 4),
+    (2 * 4, 17 * 4, 2 * 4), (16 * 4, 16 * 4, 8 * 4), (4 * 4, 4 * 4, 7 * 4), (4 * 4, 4 * 4, 18 * 4),
+    (4 * 4, 4 * 4, 20 * 4), (19 * 4, 19 * 4, 9 * 4), (4 * 4 - 1, 4 * 4 - 1, 11 * 4), (17 * 4, 17 * 4, 2 * 4),
+    (4 * 4, 4 * 4, 2 * 4), (4 * 4, 4 * 4, 3 * 4), (28 * 4, 28 * 4, 0), (3 * 4, 3 * 4, 0),
+    (0, 0, 4), (18 * 4, 22 * 4, 18 * 4), (20 * 4, 22 * 4, 20 * 4), (24 * 4, 22 * 4, 24 * 4),
+    (16 * 4, 22 * 4, 8 * 4), (17 * 4, 4 * 4, 13 * 4), (28 * 4 - 1, 0, 14 * 4), (28 * 4 - 1, 4 * 4, 15 * 4),
+    (19 * 4, 22 * 4, 9 * 4), (16 * 4, 28 * 4, 10 * 4), (4 * 4, 23 * 4, 28 * 4), (17 * 4, 22 * 4, 2 * 4),
+    (4 * 4, 0, 2 * 4), (4 * 4, 28 * 4, 3 * 4), (28 * 4, 3 * 4, 0), (3 * 4, 28 * 4, 4 * 4),
+    (21 * 4, 28 * 4, 4 * 4), (3 * 4, 28 * 4, 0), (25 * 4, 3 * 4, 28 * 4), (0, 28 * 4, 8 * 4),
+    (4 * 4, 3 * 4, 28 * 4), (28 * 4, 3 * 4, 6 * 4), (4 * 4, 28 * 4, 29 * 4),
+];
+
+const PALETTES: [[u16; 4]; 30] = [
+    [0x7FFF, 0x32BF, 0x00D0, 0x0000],
+    [0x639F, 0x4279, 0x15B0, 0x04CB],
+    [0x7FFF, 0x6E31, 0x454A, 0x0000],
+    [0x7FFF, 0x1BEF, 0x0200, 0x0000],
+    [0x7FFF, 0x421F, 0x1CF2, 0x0000],
+    [0x7FFF, 0x5294, 0x294A, 0x0000],
+    [0x7FFF, 0x03FF, 0x012F, 0x0000],
+    [0x7FFF, 0x03EF, 0x01D6, 0x0000],
+    [0x7FFF, 0x42B5, 0x3DC8, 0x0000],
+    [0x7E74, 0x03FF, 0x0180, 0x0000],
+    [0x67FF, 0x77AC, 0x1A13, 0x2D6B],
+    [0x7ED6, 0x4BFF, 0x2175, 0x0000],
+    [0x53FF, 0x4A5F, 0x7E52, 0x0000],
+    [0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0],
+    [0x03ED, 0x7FFF, 0x255F, 0x0000],
+    [0x036A, 0x021F, 0x03FF, 0x7FFF],
+    [0x7FFF, 0x01DF, 0x0112, 0x0000],
+    [0x231F, 0x035F, 0x00F2, 0x0009],
+    [0x7FFF, 0x03EA, 0x011F, 0x0000],
+    [0x299F, 0x001A, 0x000C, 0x0000],
+    [0x7FFF, 0x027F, 0x001F, 0x0000],
+    [0x7FFF, 0x03E0, 0x0206, 0x0120],
+    [0x7FFF, 0x7EEB, 0x001F, 0x7C00],
+    [0x7FFF, 0x3FFF, 0x7E00, 0x001F],
+    [0x7FFF, 0x03FF, 0x001F, 0x0000],
+    [0x03FF, 0x001F, 0x000C, 0x0000],
+    [0x7FFF, 0x033F, 0x0193, 0x0000],
+    [0x0000, 0x4200, 0x037F, 0x7FFF],
+    [0x7FFF, 0x7E8C, 0x7C00, 0x0000],
+    [0x7FFF, 0x1BEF, 0x6180, 0x0000],
+];
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::gameboy::GameBoy;
+
+    const LOGO: [u8; 48] = [
+        0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+        0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+        0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+    ];
+
+    fn machine(model: Model, cgb_flag: u8) -> GameBoy {
+        let mut rom = vec![0; 0x8000];
+        rom[HEADER_LOGO..HEADER_LOGO + 48].copy_from_slice(&LOGO);
+        rom[HEADER_CGB_FLAG] = cgb_flag;
+        GameBoy::with_model(Cartridge::new(rom).unwrap(), model)
+    }
+
+    #[test]
+    fn logo_is_left_in_vram_on_every_model() {
+        for (model, cgb_flag) in [(Model::Dmg, 0x00), (Model::Sgb, 0x00), (Model::Cgb, 0x00), (Model::Cgb, 0x80)] {
+            let gb = machine(model, cgb_flag);
+            let vram = |addr: u16| gb.mmu.peek(addr).unwrap();
+            // 0xCE: nibble 0xC doubles to 0xF0 over two rows, then 0xE to 0xFC
+            let rows: Vec<u8> = (0..8).map(|i| vram(LOGO_TILES + i)).collect();
+            assert_eq!(rows, [0xF0, 0x00, 0xF0, 0x00, 0xFC, 0x00, 0xFC, 0x00], "{:?}", model);
+            assert_eq!(vram(REGISTERED_TILE), REGISTERED[0]);
+            assert_eq!(vram(LOGO_MAP_TOP), 0x01);
+            assert_eq!(vram(LOGO_MAP_BOTTOM + 11), 0x18);
+            assert_eq!(vram(REGISTERED_MAP), 0x19);
+        }
+    }
+}
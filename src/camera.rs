@@ -0,0 +1,515 @@
+use std::io;
+use std::path::Path;
+use crate::mbc::{self, Mapper};
+use crate::savestate::{Savable, StateError, StateReader, StateWriter};
+use crate::step::Step;
+
+// Pocket Camera, see https://gbdev.io/pandocs/Gameboy_Camera.html
+// An MBC with 16 RAM banks and a Mitsubishi M64282FP sensor. Writing a
+// value with bit 4 set to 0x4000 maps the sensor registers over RAM:
+//
+//   A000       bit 0 starts a capture and reads 1 until it is done
+//   A001       bit 7 N, bits 5-6 VH edge mode, bits 0-4 gain
+//   A002-A003  exposure time, high byte first, in 16 µs steps
+//   A004       bits 5-7 edge enhancement ratio, bit 3 invert
+//   A005       offset and reference voltage, not emulated
+//   A006-A035  4x4 dither matrix, three thresholds per pixel
+//
+// A finished capture is written to RAM bank 0 at 0xA100 as 16x14 tiles,
+// already dithered to the four shades.
+
+pub const IMAGE_WIDTH: usize = 128;
+pub const IMAGE_HEIGHT: usize = 112;
+
+const REGISTER_COUNT: usize = 0x36;
+const REGISTER_BANK: u8 = 0x10;
+
+const REG_CAPTURE: usize = 0x00;
+const REG_GAIN: usize = 0x01;
+const REG_EXPOSURE_HIGH: usize = 0x02;
+const REG_EXPOSURE_LOW: usize = 0x03;
+const REG_EDGE: usize = 0x04;
+const REG_DITHER: usize = 0x06;
+
+// Where the image lands in RAM bank 0
+const IMAGE_OFFSET: usize = 0x100;
+const TILE_BYTES: usize = 16;
+const TILES_PER_ROW: usize = IMAGE_WIDTH / 8;
+
+// Capture time in CPU cycles: a fixed part, an extra stretch when N is
+// clear, and the exposure itself
+const CAPTURE_CYCLES: u32 = 32446 * 4;
+const CAPTURE_CYCLES_NOT_N: u32 = 512 * 4;
+const CYCLES_PER_EXPOSURE_STEP: u32 = 16 * 4;
+
+// Exposure and gain that leave the source brightness unchanged. The sensor
+// amplifies by roughly 14 dB plus 1.5 dB per gain step.
+const NEUTRAL_EXPOSURE: f32 = 0x1000 as f32;
+const NEUTRAL_GAIN_DB: f32 = 26.0;
+const EDGE_RATIOS: [f32; 8] = [0.5, 0.75, 1.0, 1.25, 2.0, 3.0, 4.0, 5.0];
+
+
+/// What the camera sees, for example a picture or a generated pattern.
+pub trait ImageSource {
+    /// Fills `frame` with IMAGE_WIDTH x IMAGE_HEIGHT brightness values, row
+    /// by row, 0 black and 255 white. Called once per capture.
+    fn capture(&mut self, frame: &mut [u8]);
+}
+
+/// Any `FnMut(x, y) -> brightness` works as a source.
+impl<F: FnMut(usize, usize) -> u8> ImageSource for F {
+    fn capture(&mut self, frame: &mut [u8]) {
+        for (i, pixel) in frame.iter_mut().enumerate() {
+            *pixel = self(i % IMAGE_WIDTH, i / IMAGE_WIDTH);
+        }
+    }
+}
+
+/// The same picture for every capture, scaled to fit the sensor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticImage {
+    pixels: Vec<u8>,
+}
+impl StaticImage {
+    /// Grayscale pixels, one byte each, row by row.
+    pub fn new(width: usize, height: usize, pixels: &[u8]) -> Option<Self> {
+        let size = width.checked_mul(height)?;
+        if size == 0 || pixels.len() < size {
+            return None;
+        }
+        let mut scaled = vec![0; IMAGE_WIDTH * IMAGE_HEIGHT];
+        for y in 0..IMAGE_HEIGHT {
+            for x in 0..IMAGE_WIDTH {
+                scaled[y * IMAGE_WIDTH + x] = pixels[(y * height / IMAGE_HEIGHT) * width + x * width / IMAGE_WIDTH];
+            }
+        }
+        Some(StaticImage { pixels: scaled })
+    }
+
+    /// Reads a binary PGM or PPM file (P5 or P6), colour is averaged to gray.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Not a binary PGM or PPM image");
+        let data = std::fs::read(path)?;
+        let (channels, header) = match data.get(..2) {
+            Some(b"P5") => (1, pnm_header(&data).ok_or_else(invalid)?),
+            Some(b"P6") => (3, pnm_header(&data).ok_or_else(invalid)?),
+            _ => return Err(invalid()),
+        };
+        let (width, height, max, offset) = header;
+        // Sizes come from the file, so they can be anything
+        let size = width.checked_mul(height).and_then(|pixels| pixels.checked_mul(channels)).ok_or_else(invalid)?;
+        let samples = offset.checked_add(size).and_then(|end| data.get(offset..end)).ok_or_else(invalid)?;
+        let gray: Vec<u8> = samples.chunks(channels)
+            // Samples above the declared maximum are clipped to white
+            .map(|pixel| (pixel.iter().map(|&c| c as usize).sum::<usize>() * 255 / (channels * max)).min(255) as u8)
+            .collect();
+        StaticImage::new(width, height, &gray).ok_or_else(invalid)
+    }
+}
+impl ImageSource for StaticImage {
+    fn capture(&mut self, frame: &mut [u8]) {
+        frame.copy_from_slice(&self.pixels);
+    }
+}
+
+// Width, height, maximum sample value and where the samples start. Fields
+// are separated by whitespace, `#` starts a comment, samples must be bytes.
+fn pnm_header(data: &[u8]) -> Option<(usize, usize, usize, usize)> {
+    let mut fields = [0usize; 3];
+    let mut offset = 2;
+    for field in fields.iter_mut() {
+        loop {
+            match *data.get(offset)? {
+                b'#' => offset += data[offset..].iter().position(|&c| c == b'\n')?,
+                c if c.is_ascii_whitespace() => offset += 1,
+                _ => break,
+            }
+        }
+        let digits = data[offset..].iter().take_while(|c| c.is_ascii_digit()).count();
+        *field = std::str::from_utf8(&data[offset..offset + digits]).ok()?.parse().ok()?;
+        offset += digits;
+    }
+    // A single whitespace byte ends the header
+    let [width, height, max] = fields;
+    (max > 0 && max < 256).then_some((width, height, max, offset + 1))
+}
+
+/// Moving diagonal bands over a left to right gradient, different on
+/// every capture so games that compare frames see motion.
+#[derive(Debug, Clone, Default)]
+pub struct TestPattern {
+    frame: usize,
+}
+impl TestPattern {
+    pub fn new() -> Self {
+        TestPattern { frame: 0 }
+    }
+}
+impl ImageSource for TestPattern {
+    fn capture(&mut self, frame: &mut [u8]) {
+        for (i, pixel) in frame.iter_mut().enumerate() {
+            let (x, y) = (i % IMAGE_WIDTH, i / IMAGE_WIDTH);
+            let gradient = x * 255 / (IMAGE_WIDTH - 1);
+            let band = ((x + y + self.frame * 2) / 16).is_multiple_of(2);
+            *pixel = if band { gradient as u8 } else { 255 - gradient as u8 };
+        }
+        self.frame += 1;
+    }
+}
+
+
+/// Pocket Camera mapper. Without an image source it photographs a gray card.
+pub struct PocketCamera {
+    ram_enabled: bool,
+    rom_bank: u8,
+    ram_bank: u8,
+    registers_mapped: bool,
+    registers: [u8; REGISTER_COUNT],
+    capture_cycles: u32,  // Left until the running capture finishes
+    source: Option<Box<dyn ImageSource>>,
+}
+impl PocketCamera {
+    pub fn new() -> Self {
+        PocketCamera {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            registers_mapped: false,
+            registers: [0; REGISTER_COUNT],
+            capture_cycles: 0,
+            source: None,
+        }
+    }
+
+    fn exposure(&self) -> u16 {
+        u16::from_be_bytes([self.registers[REG_EXPOSURE_HIGH], self.registers[REG_EXPOSURE_LOW]])
+    }
+
+    fn capture_time(&self) -> u32 {
+        let n = self.registers[REG_GAIN] & 0x80 != 0;
+        CAPTURE_CYCLES + if n { 0 } else { CAPTURE_CYCLES_NOT_N } + self.exposure() as u32 * CYCLES_PER_EXPOSURE_STEP
+    }
+
+    // Runs the sensor and writes the dithered tiles into RAM bank 0
+    fn finish_capture(&mut self, ram: &mut [u8]) {
+        let mut frame = vec![0x80; IMAGE_WIDTH * IMAGE_HEIGHT];
+        if let Some(source) = self.source.as_mut() {
+            source.capture(&mut frame);
+        }
+        let image = self.process(&frame);
+        let Some(tiles) = ram.get_mut(IMAGE_OFFSET..IMAGE_OFFSET + IMAGE_WIDTH * IMAGE_HEIGHT / 4) else { return };
+        tiles.fill(0);
+        for (i, &shade) in image.iter().enumerate() {
+            let (x, y) = (i % IMAGE_WIDTH, i / IMAGE_WIDTH);
+            let row = ((y / 8) * TILES_PER_ROW + x / 8) * TILE_BYTES + (y % 8) * 2;
+            let bit = 7 - (x % 8);
+            tiles[row] |= (shade & 0x01) << bit;
+            tiles[row + 1] |= ((shade >> 1) & 0x01) << bit;
+        }
+    }
+
+    // Brightness through exposure, gain and edge enhancement, then dithered
+    // to shades 0-3 with 3 as black
+    fn process(&self, frame: &[u8]) -> Vec<u8> {
+        let gain_db = 14.0 + 1.5 * (self.registers[REG_GAIN] & 0x1F) as f32;
+        let scale = self.exposure() as f32 / NEUTRAL_EXPOSURE * 10f32.powf((gain_db - NEUTRAL_GAIN_DB) / 20.0);
+        let invert = self.registers[REG_EDGE] & 0x08 != 0;
+        let sensed: Vec<f32> = frame.iter()
+            .map(|&pixel| if invert { 255 - pixel } else { pixel })
+            .map(|pixel| pixel as f32 * scale)
+            .collect();
+
+        let at = |x: isize, y: isize| {
+            let x = x.clamp(0, IMAGE_WIDTH as isize - 1) as usize;
+            let y = y.clamp(0, IMAGE_HEIGHT as isize - 1) as usize;
+            sensed[y * IMAGE_WIDTH + x]
+        };
+        let ratio = EDGE_RATIOS[(self.registers[REG_EDGE] >> 5) as usize];
+        let (horizontal, vertical) = match (self.registers[REG_GAIN] >> 5) & 0x03 {
+            1 => (false, true),
+            2 => (true, false),
+            3 => (true, true),
+            _ => (false, false),
+        };
+
+        let mut image = vec![0; IMAGE_WIDTH * IMAGE_HEIGHT];
+        for y in 0..IMAGE_HEIGHT {
+            for x in 0..IMAGE_WIDTH {
+                let (xi, yi) = (x as isize, y as isize);
+                let mut value = at(xi, yi);
+                if horizontal {
+                    value += ratio * (2.0 * at(xi, yi) - at(xi - 1, yi) - at(xi + 1, yi));
+                }
+                if vertical {
+                    value += ratio * (2.0 * at(xi, yi) - at(xi, yi - 1) - at(xi, yi + 1));
+                }
+                let value = value.clamp(0.0, 255.0) as u8;
+
+                let cell = REG_DITHER + ((y % 4) * 4 + x % 4) * 3;
+                let thresholds = &self.registers[cell..cell + 3];
+                image[y * IMAGE_WIDTH + x] = match thresholds.iter().position(|&t| value < t) {
+                    Some(level) => 3 - level as u8,
+                    None => 0,
+                };
+            }
+        }
+        image
+    }
+}
+impl Default for PocketCamera {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Mapper for PocketCamera {
+    fn read_rom(&self, rom: &[u8], _ram: &[u8], addr: u16) -> u8 {
+        mbc::rom_byte(rom, self.rom_bank(addr), addr)
+    }
+    fn write_rom(&mut self, _ram: &mut [u8], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank = value & 0x3F,
+            0x4000..=0x5FFF => {
+                self.registers_mapped = value & REGISTER_BANK != 0;
+                self.ram_bank = value & 0x0F;
+            }
+            _ => {}
+        }
+    }
+    // Only the capture register can be read back, RAM reads don't need enabling
+    fn read_ram(&self, ram: &[u8], addr: u16) -> Option<u8> {
+        if self.registers_mapped {
+            return match addr as usize & 0x7F {
+                REG_CAPTURE => Some(self.registers[REG_CAPTURE] & 0x07),
+                _ => Some(0x00),
+            };
+        }
+        mbc::ram_offset(ram, self.ram_bank as usize, addr).map(|o| ram[o])
+    }
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8) {
+        if !self.registers_mapped {
+            if let Some(o) = mbc::ram_offset(ram, self.ram_bank as usize, addr).filter(|_| self.ram_enabled) {
+                ram[o] = value;
+            }
+            return;
+        }
+        let register = addr as usize & 0x7F;
+        match register {
+            REG_CAPTURE => {
+                // Clearing bit 0 cancels a running capture
+                let start = value & 0x01 != 0;
+                if start && self.capture_cycles == 0 {
+                    self.capture_cycles = self.capture_time();
+                } else if !start {
+                    self.capture_cycles = 0;
+                }
+                self.registers[REG_CAPTURE] = value & 0x07;
+            }
+            _ if register < REGISTER_COUNT => self.registers[register] = value,
+            _ => {}
+        }
+    }
+    fn rom_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        }
+    }
+    fn set_image_source(&mut self, source: Box<dyn ImageSource>) -> bool {
+        self.source = Some(source);
+        true
+    }
+    fn step_with_ram(&mut self, ram: &mut [u8], cycles: u8) {
+        if self.capture_cycles == 0 {
+            return;
+        }
+        self.capture_cycles = self.capture_cycles.saturating_sub(cycles as u32);
+        if self.capture_cycles == 0 {
+            self.finish_capture(ram);
+            self.registers[REG_CAPTURE] &= !0x01;
+        }
+    }
+}
+impl Step for PocketCamera {
+    fn step(&mut self, _cycles: u8) {}
+}
+impl Savable for PocketCamera {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.ram_enabled);
+        w.u8(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.bool(self.registers_mapped);
+        w.bytes(&self.registers);
+        w.u32(self.capture_cycles);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enabled = r.bool()?;
+        self.rom_bank = r.u8()? & 0x3F;
+        self.ram_bank = r.u8()? & 0x0F;
+        self.registers_mapped = r.bool()?;
+        r.bytes_into(&mut self.registers)?;
+        self.capture_cycles = r.u32()?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn static_images_load_and_scale() {
+        let dir = std::env::temp_dir().join(format!("gbc_camera_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let load = |name: &str, data: &[u8]| {
+            let path = dir.join(name);
+            fs::write(&path, data).unwrap();
+            StaticImage::load(path)
+        };
+
+        // Left half black, right half white, as gray and as colour
+        let gray = load("gray.pgm", &[&b"P5\n# comment\n2 1\n255\n"[..], &[0x00, 0xFF]].concat()).unwrap();
+        let colour = load("colour.ppm", &[&b"P6 2 1 15 "[..], &[0, 0, 0, 15, 15, 15]].concat()).unwrap();
+        assert_eq!(gray, colour);
+        assert_eq!(gray.pixels[IMAGE_WIDTH / 2 - 1], 0x00);
+        assert_eq!(gray.pixels[IMAGE_WIDTH / 2], 0xFF);
+
+        let errors = [
+            load("short.pgm", b"P5 2 2 255 \x00\x00\x00"),
+            load("huge.pgm", b"P5 4294967296 4294967296 255 \x00"),
+            load("huger.ppm", b"P6 18446744073709551615 1 255 \x00"),
+            load("empty.pgm", b"P5 0 0 255 "),
+            load("text.pgm", b"P2 1 1 255 0"),
+        ];
+        fs::remove_dir_all(&dir).unwrap();
+        for error in errors {
+            assert_eq!(error.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        assert_eq!(StaticImage::new(usize::MAX, 2, &[0; 4]), None);
+    }
+
+    #[test]
+    fn samples_above_the_maximum_are_white() {
+        let path = std::env::temp_dir().join(format!("gbc_camera_clip_test_{}.pgm", std::process::id()));
+        fs::write(&path, [&b"P5 2 1 15 "[..], &[15, 200]].concat()).unwrap();
+        let image = StaticImage::load(&path);
+        fs::remove_file(&path).unwrap();
+        let image = image.unwrap();
+        assert_eq!(image.pixels[0], 0xFF);
+        assert_eq!(image.pixels[IMAGE_WIDTH - 1], 0xFF);
+    }
+
+    // Registers mapped, RAM enabled and a camera with 16 RAM banks
+    fn setup() -> (PocketCamera, Vec<u8>) {
+        let mut camera = PocketCamera::new();
+        let mut ram = vec![0; 16 * 0x2000];
+        camera.write_rom(&mut ram, 0x0000, 0x0A);
+        camera.write_rom(&mut ram, 0x4000, REGISTER_BANK);
+        (camera, ram)
+    }
+
+    fn run_capture(camera: &mut PocketCamera, ram: &mut [u8]) {
+        camera.write_ram(ram, 0xA000, 0x01);
+        while camera.read_ram(ram, 0xA000) == Some(0x01) {
+            camera.step_with_ram(ram, 255);
+        }
+    }
+
+    #[test]
+    fn capture_time_follows_n_and_exposure() {
+        let (mut camera, mut ram) = setup();
+        camera.write_ram(&mut ram, 0xA001, 0x80);
+        assert_eq!(camera.capture_time(), CAPTURE_CYCLES);
+
+        camera.write_ram(&mut ram, 0xA001, 0x00);
+        camera.write_ram(&mut ram, 0xA002, 0x01);
+        camera.write_ram(&mut ram, 0xA003, 0x02);
+        assert_eq!(camera.capture_time(), CAPTURE_CYCLES + CAPTURE_CYCLES_NOT_N + 0x102 * CYCLES_PER_EXPOSURE_STEP);
+    }
+
+    #[test]
+    fn the_busy_bit_clears_when_the_capture_is_done() {
+        let (mut camera, mut ram) = setup();
+        camera.write_ram(&mut ram, 0xA001, 0x80);
+        camera.write_ram(&mut ram, 0xA000, 0x03);
+        assert_eq!(camera.read_ram(&ram, 0xA000), Some(0x03));
+
+        // One cycle short of the end the capture is still running
+        let mut left = CAPTURE_CYCLES - 1;
+        while left > 0 {
+            let cycles = left.min(255) as u8;
+            camera.step_with_ram(&mut ram, cycles);
+            left -= cycles as u32;
+        }
+        assert_eq!(camera.read_ram(&ram, 0xA000), Some(0x03));
+        camera.step_with_ram(&mut ram, 1);
+        assert_eq!(camera.read_ram(&ram, 0xA000), Some(0x02));
+
+        // Clearing bit 0 cancels a capture without writing an image
+        ram.fill(0xAA);
+        camera.write_ram(&mut ram, 0xA000, 0x01);
+        camera.write_ram(&mut ram, 0xA000, 0x00);
+        camera.step_with_ram(&mut ram, 255);
+        assert_eq!(camera.read_ram(&ram, 0xA000), Some(0x00));
+        assert!(ram.iter().all(|&b| b == 0xAA));
+    }
+
+    #[test]
+    fn captures_land_in_ram_bank_0_as_tiles() {
+        let (mut camera, mut ram) = setup();
+        // Neutral exposure and gain, so the source reaches the dither as is
+        camera.write_ram(&mut ram, 0xA001, 0x80 | 8);
+        camera.write_ram(&mut ram, 0xA002, 0x10);
+        for cell in 0..16 {
+            for (i, threshold) in [0x40, 0x80, 0xC0].into_iter().enumerate() {
+                camera.write_ram(&mut ram, 0xA000 + (REG_DITHER + cell * 3 + i) as u16, threshold);
+            }
+        }
+        // White everywhere but a black pixel at (9, 10) and a gray one at (0, 0)
+        camera.set_image_source(Box::new(|x, y| match (x, y) {
+            (9, 10) => 0x00,
+            (0, 0) => 0x90,
+            _ => 0xFF,
+        }));
+        camera.write_rom(&mut ram, 0x4000, REGISTER_BANK | 0x02);
+        run_capture(&mut camera, &mut ram);
+
+        // Tile 17 (second tile row, second column), row 2, bit 6, shade 3
+        let black = IMAGE_OFFSET + 17 * TILE_BYTES + 2 * 2;
+        assert_eq!(ram[black..black + 2], [0x40, 0x40]);
+        // Between the second and third threshold is shade 1
+        assert_eq!(ram[IMAGE_OFFSET..IMAGE_OFFSET + 2], [0x80, 0x00]);
+        let lit = ram[IMAGE_OFFSET..IMAGE_OFFSET + IMAGE_WIDTH * IMAGE_HEIGHT / 4].iter().filter(|&&b| b != 0).count();
+        assert_eq!(lit, 3);
+        // Bank 2 was selected but the image always goes to bank 0
+        assert!(ram[0x4000..0x6000].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn registers_and_ram_banks_share_the_window() {
+        let (mut camera, mut ram) = setup();
+        camera.write_ram(&mut ram, 0xA004, 0x28);
+        assert_eq!(camera.registers[REG_EDGE], 0x28);
+        // Only A000 reads back, the rest read 0, and the registers repeat every 0x80
+        assert_eq!(camera.read_ram(&ram, 0xA004), Some(0x00));
+        camera.write_ram(&mut ram, 0xA080, 0x06);
+        assert_eq!(camera.read_ram(&ram, 0xA000), Some(0x06));
+        assert!(ram.iter().all(|&b| b == 0));
+
+        camera.write_rom(&mut ram, 0x4000, 0x03);
+        camera.write_ram(&mut ram, 0xA123, 0x55);
+        assert_eq!(ram[3 * 0x2000 + 0x123], 0x55);
+        assert_eq!(camera.read_ram(&ram, 0xA123), Some(0x55));
+
+        // Writes need RAM enabled, reads don't
+        camera.write_rom(&mut ram, 0x0000, 0x00);
+        camera.write_ram(&mut ram, 0xA123, 0x66);
+        assert_eq!(camera.read_ram(&ram, 0xA123), Some(0x55));
+
+        camera.write_rom(&mut ram, 0x2000, 0x45);
+        assert_eq!(camera.rom_bank(0x4000), 5);
+        assert_eq!(camera.rom_bank(0x0000), 0);
+    }
+}
@@ -0,0 +1,224 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use crate::apu::CHANNELS;
+use crate::gameboy::GameBoy;
+use crate::video::{FrameFormat, FrameWriter, Picture, VideoOptions, FRAME_RATE};
+use crate::vgm;
+use crate::wav::WavWriter;
+
+// Records what the APU plays while a machine runs, for audio regression
+// tests and ripping music out of games. Next to the mixed "name.wav" it can
+// write "name.ch1.wav" to "name.ch4.wav" with each channel alone, and a
+// "name.vgm" log of every sound register write.
+//
+// Video goes to a frame sequence kept in step with the audio, so the two
+// can be put together into a video later.
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureOptions {
+    pub sample_rate: u32,
+    pub stems: bool,
+    pub register_log: bool,
+}
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        CaptureOptions { sample_rate: 44_100, stems: false, register_log: false }
+    }
+}
+
+
+/// Writes the APU output to files until `finish` is called.
+pub struct AudioCapture {
+    sample_rate: u32,
+    mix: WavWriter<BufWriter<File>>,
+    stems: Option<Vec<WavWriter<BufWriter<File>>>>,
+    vgm_path: Option<PathBuf>,
+}
+impl AudioCapture {
+    /// Starts producing samples on `gb` and opens the files next to `path`.
+    pub fn start<P: AsRef<Path>>(gb: &mut GameBoy, path: P, options: CaptureOptions) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mix = WavWriter::create(path, options.sample_rate, 2)?;
+        let stems = match options.stems {
+            true => Some((1..=CHANNELS)
+                .map(|channel| WavWriter::create(path.with_extension(format!("ch{}.wav", channel)), options.sample_rate, 1))
+                .collect::<io::Result<Vec<_>>>()?),
+            false => None,
+        };
+
+        let apu = &mut gb.mmu.apu;
+        apu.set_sample_rate(Some(options.sample_rate));
+        apu.set_stems(options.stems);
+        apu.set_register_log(options.register_log);
+        Ok(AudioCapture { sample_rate: options.sample_rate, mix, stems, vgm_path: options.register_log.then(|| path.with_extension("vgm")) })
+    }
+
+    /// Writes out what was played since the last call, best called every
+    /// frame. Returns the mixed samples so they can be played as well.
+    pub fn update(&mut self, gb: &mut GameBoy) -> io::Result<Vec<i16>> {
+        let samples = gb.mmu.apu.take_samples();
+        self.mix.write(&samples)?;
+        if let (Some(writers), Some(stems)) = (self.stems.as_mut(), gb.mmu.apu.take_stems()) {
+            for (writer, stem) in writers.iter_mut().zip(stems) {
+                writer.write(&stem)?;
+            }
+        }
+        Ok(samples)
+    }
+
+    /// Seconds of audio written so far.
+    pub fn seconds(&self) -> f64 {
+        self.mix.samples() as f64 / 2.0 / self.sample_rate as f64
+    }
+
+    /// Writes out the rest and closes the files. The APU stops producing
+    /// stems and logging writes but keeps its sample rate.
+    pub fn finish(mut self, gb: &mut GameBoy) -> io::Result<()> {
+        self.update(gb)?;
+        self.mix.finish()?;
+        for writer in self.stems.into_iter().flatten() {
+            writer.finish()?;
+        }
+        let apu = &mut gb.mmu.apu;
+        if let Some(path) = self.vgm_path {
+            vgm::save(path, &apu.take_register_writes(), apu.log_cycles())?;
+        }
+        apu.set_stems(false);
+        apu.set_register_log(false);
+        Ok(())
+    }
+}
+
+
+/// Writes the screen to a frame sequence until `finish` is called.
+pub struct VideoCapture {
+    writer: FrameWriter<BufWriter<File>>,
+    options: VideoOptions,
+}
+impl VideoCapture {
+    /// Opens `path` for pictures the size `gb` gives with `options`, as Y4M
+    /// for `.y4m` paths and raw RGB otherwise.
+    pub fn start<P: AsRef<Path>>(gb: &GameBoy, path: P, options: VideoOptions) -> io::Result<Self> {
+        let (width, height) = options.size(gb);
+        let writer = FrameWriter::create(path.as_ref(), FrameFormat::for_path(path.as_ref()), width, height)?;
+        Ok(VideoCapture { writer, options })
+    }
+
+    /// Writes the screen as it is now, once.
+    pub fn frame(&mut self, gb: &GameBoy) -> io::Result<()> {
+        self.writer.write(&Picture::capture(gb, &self.options))
+    }
+
+    /// Writes the screen as often as it takes to match the length of
+    /// `audio`, call after `AudioCapture::update`. Frames get repeated or
+    /// dropped when the machine doesn't run whole frames, like while the
+    /// LCD is being switched on.
+    pub fn sync(&mut self, gb: &GameBoy, audio: &AudioCapture) -> io::Result<()> {
+        let due = (audio.seconds() * FRAME_RATE).round() as u64;
+        if self.writer.frames() >= due {
+            return Ok(());
+        }
+        let picture = Picture::capture(gb, &self.options);
+        while self.writer.frames() < due {
+            self.writer.write(&picture)?;
+        }
+        Ok(())
+    }
+
+    /// Frames written so far.
+    pub fn frames(&self) -> u64 {
+        self.writer.frames()
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.writer.finish().map(drop)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::asm;
+    use crate::cartridge::{self, Cartridge};
+    use crate::common::Model;
+
+    // A square wave on channel 1 with the given master volume and panning
+    const PROGRAM: &str = r#"
+        SECTION "code", ROM0[$0150]
+        start:
+            ld a, $80
+            ldh [$26], a
+            ld a, {nr50}
+            ldh [$24], a
+            ld a, {nr51}
+            ldh [$25], a
+            ld a, $80
+            ldh [$11], a
+            ld a, $F0
+            ldh [$12], a
+            ld a, $00
+            ldh [$13], a
+            ld a, $87
+            ldh [$14], a
+        spin:
+            jr spin
+    "#;
+
+    fn samples(wav: &[u8]) -> Vec<i16> {
+        assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()) as usize, wav.len() - 44);
+        wav[44..].chunks_exact(2).map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]])).collect()
+    }
+
+    // Mixed samples, the four stems and the register log
+    fn capture(nr50: u8, nr51: u8) -> (Vec<i16>, Vec<Vec<i16>>, Vec<u8>) {
+        let source = PROGRAM.replace("{nr50}", &format!("${:02X}", nr50)).replace("{nr51}", &format!("${:02X}", nr51));
+        let rom = cartridge::test_rom(&asm::assemble_program(&source).unwrap());
+        let mut gb = GameBoy::with_model(Cartridge::new(rom).unwrap(), Model::Dmg);
+
+        let dir = std::env::temp_dir().join(format!("gbc_capture_test_{}_{:02x}{:02x}", std::process::id(), nr50, nr51));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("song.wav");
+        let options = CaptureOptions { sample_rate: 32_768, stems: true, register_log: true };
+        let mut capture = AudioCapture::start(&mut gb, &path, options).unwrap();
+        for _ in 0..4 {
+            gb.run_frame();
+            capture.update(&mut gb).unwrap();
+        }
+        let seconds = capture.seconds();
+        capture.finish(&mut gb).unwrap();
+
+        let mix = samples(&fs::read(&path).unwrap());
+        assert_eq!(mix.len() as f64, seconds * 2.0 * 32_768.0);
+        let stems = (1..=CHANNELS).map(|channel| samples(&fs::read(dir.join(format!("song.ch{}.wav", channel))).unwrap())).collect();
+        let vgm = fs::read(dir.join("song.vgm")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        (mix, stems, vgm)
+    }
+
+    #[test]
+    fn stems_come_before_panning_and_volume() {
+        let (loud, loud_stems, _) = capture(0x77, 0xFF);
+        let (silent, silent_stems, _) = capture(0x00, 0x00);
+        assert!(loud.iter().any(|&sample| sample != 0));
+        // Once what the boot ROM left playing has faded
+        assert!(silent[silent.len() / 2..].iter().all(|&sample| sample == 0));
+
+        assert_eq!(loud_stems, silent_stems);
+        assert!(loud_stems[0].iter().any(|&sample| sample != 0));
+        assert!(loud_stems[1..].iter().flatten().all(|&sample| sample == 0));
+        assert!(loud_stems.iter().all(|stem| stem.len() * 2 == loud.len()));
+    }
+
+    #[test]
+    fn register_log_is_written_on_finish() {
+        let (_, _, vgm) = capture(0x77, 0xFF);
+        assert_eq!(&vgm[..4], b"Vgm ");
+        // Channel 1 triggered with NR14
+        assert!(vgm[0x100..].windows(3).any(|write| write == [0xB3, 0x04, 0x87]));
+        assert_eq!(vgm.last(), Some(&0x66));
+    }
+}
@@ -1,8 +1,78 @@
 use std::fmt;
+use std::io;
 use crate::common::*;
+use crate::camera::ImageSource;
+use crate::mbc::{self, AccelerometerSource, Mapper, Mbc2, Mbc6, Mbc7, NoMbc, Tama5};
+use crate::gzip;
+use crate::patch::{self, PatchError};
+use crate::zip::ZipArchive;
+use crate::savestate::{Savable, StateError, StateReader, StateWriter};
+use crate::step::Step;
 
 const ROM_HEADER_START_ADDRESS: usize = 0x0100;
 const ROM_HEADER_END_ADDRESS: usize = 0x014F;
+const MMM01_MENU_SIZE: usize = 0x8000;
+
+// Zip local file header, the first thing in any non-empty archive
+const ZIP_MAGIC: [u8; 4] = *b"PK\x03\x04";
+// Entries picked from an archive when no name is given
+const ROM_EXTENSIONS: [&str; 3] = [".gb", ".gbc", ".cgb"];
+
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Archive(String),   // What is wrong with the zip or gzip container
+    Patch(PatchError),
+    InvalidRom,        // Too short for a header
+}
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "{}", e),
+            LoadError::Archive(what) => write!(f, "Archive: {}", what),
+            LoadError::Patch(e) => write!(f, "{}", e),
+            LoadError::InvalidRom => write!(f, "Not a Game Boy ROM"),
+        }
+    }
+}
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+impl From<PatchError> for LoadError {
+    fn from(e: PatchError) -> Self {
+        LoadError::Patch(e)
+    }
+}
+
+/// Reads a ROM dump, decompressing gzip files and zip archives in memory.
+/// `entry` names the file to take from a zip, by default the first one with
+/// a ROM extension.
+pub fn read_rom_file(file_path: &str, entry: Option<&str>) -> Result<Vec<u8>, LoadError> {
+    let data = std::fs::read(file_path)?;
+    if gzip::is_gzip(&data) {
+        return gzip::gunzip(&data).ok_or_else(|| LoadError::Archive("corrupt gzip data".to_string()));
+    }
+    if !data.starts_with(&ZIP_MAGIC) {
+        return Ok(data);
+    }
+
+    let archive = ZipArchive::parse(&data).ok_or_else(|| LoadError::Archive("corrupt zip archive".to_string()))?;
+    let found = match entry {
+        Some(name) => archive.find(name),
+        None => archive.entries().iter().find(|e| {
+            let name = e.name.to_ascii_lowercase();
+            !e.is_dir() && ROM_EXTENSIONS.iter().any(|extension| name.ends_with(extension))
+        }),
+    };
+    let found = found.ok_or_else(|| LoadError::Archive(match entry {
+        Some(name) => format!("no entry named \"{}\"", name),
+        None => "no .gb, .gbc or .cgb file".to_string(),
+    }))?;
+    archive.extract(found).ok_or_else(|| LoadError::Archive(format!("can't extract \"{}\"", found.name)))
+}
 
 
 #[repr(C, packed)]
@@ -43,8 +113,14 @@ pub fn from_bytes(bytes: &[u8]) -> Option<&Self> {
     pub fn get_cgb_flag(&self) -> u8 {
         self.cgb_flag
     }
+    pub fn get_sgb_flag(&self) -> u8 {
+        self.sgb_flag
+    }
     pub fn get_new_licensee_code(&self) -> u16 {
-        return self.new_licensee_code;
+        self.new_licensee_code
+    }
+    pub fn get_cartridge_type_code(&self) -> u8 {
+        self.cartridge_type
     }
     pub fn get_cartridge_type(&self) -> &str {
         match get_cartridge_types().get(&self.cartridge_type) {
@@ -132,46 +208,192 @@ impl fmt::Display for ROMHeader {
 pub struct Cartridge {
     header: ROMHeader,
     rom_data: Vec<u8>,
+    ram: Vec<u8>,
+    mapper: Box<dyn Mapper>,
 }
 impl Cartridge {
     pub fn new(rom_data: Vec<u8>) -> Option<Self> {
-        let header_bytes = &rom_data[ROM_HEADER_START_ADDRESS..=ROM_HEADER_END_ADDRESS];
-        let header = match ROMHeader::from_bytes(header_bytes) {
-            Some(h) => h,
-            None => return None, // Not enough data for header
+        // MMM01 multicarts boot into the menu in the last 32 KB, which
+        // carries the header describing the cartridge
+        let header_start = match rom_data.len().checked_sub(MMM01_MENU_SIZE) {
+            Some(menu) if matches!(rom_data.get(menu + 0x147), Some(0x0B..=0x0D)) => menu,
+            _ => 0,
+        };
+        let header_bytes = rom_data.get(header_start + ROM_HEADER_START_ADDRESS..=header_start + ROM_HEADER_END_ADDRESS)?;
+        let header = *ROMHeader::from_bytes(header_bytes)?; // Not enough data for header
+
+        // Unsupported mappers fall back to plain ROM access
+        let mapper = mbc::mapper_for(header.cartridge_type).unwrap_or_else(|| Box::new(NoMbc));
+        let ram = match header.cartridge_type {
+            0x05 | 0x06 => vec![0; Mbc2::RAM_SIZE],
+            // Erased flash reads all ones
+            0x20 => [vec![0; Mbc6::RAM_SIZE], vec![0xFF; Mbc6::FLASH_SIZE]].concat(),
+            0x22 => vec![0xFF; Mbc7::RAM_SIZE],
+            0xFD => vec![0; Tama5::RAM_SIZE],
+            _ => vec![0; header.get_ram_size_kb().unwrap_or(0) as usize * 1024],
         };
 
-        Some(Cartridge { header: *header, rom_data })
+        Some(Cartridge { header, rom_data, ram, mapper })
     }
-    pub fn new_from_file(file_path: &str) -> Option<Self> {
-        let rom_data = std::fs::read(file_path).ok()?; // Convert Result to Option
-        match Cartridge::new(rom_data) {
-            Some(cart) => Some(cart),
-            None => None,  
+    /// Loads a ROM file, which may be zipped or gzipped, soft-patched by a
+    /// `.bps`, `.ups` or `.ips` file of the same name if there is one. A
+    /// patch that doesn't apply is an error rather than silently skipped.
+    pub fn new_from_file(file_path: &str) -> Result<Self, LoadError> {
+        Cartridge::new_from_file_patched(file_path, None)
+    }
+    /// Loads a ROM file with `patch_path` applied, or with a patch found next
+    /// to it when that is `None`.
+    pub fn new_from_file_patched(file_path: &str, patch_path: Option<&str>) -> Result<Self, LoadError> {
+        Cartridge::load(file_path, None, patch_path)
+    }
+    /// Loads the entry called `entry` from a zip archive, patched like `new_from_file`.
+    pub fn new_from_archive(file_path: &str, entry: &str) -> Result<Self, LoadError> {
+        Cartridge::load(file_path, Some(entry), None)
+    }
+    fn load(file_path: &str, entry: Option<&str>, patch_path: Option<&str>) -> Result<Self, LoadError> {
+        let rom_data = read_rom_file(file_path, entry)?;
+        let patch_path = match patch_path {
+            Some(path) => Some(path.into()),
+            None => patch::find_sibling(file_path),
+        };
+        match patch_path {
+            Some(path) => Cartridge::new_patched(rom_data, &std::fs::read(path)?),
+            None => Cartridge::new(rom_data).ok_or(LoadError::InvalidRom),
         }
     }
+    /// Applies an IPS, UPS or BPS patch before reading the header, so the
+    /// patched header decides the mapper and RAM size.
+    pub fn new_patched(rom_data: Vec<u8>, patch: &[u8]) -> Result<Self, LoadError> {
+        let rom_data = patch::apply(&rom_data, patch)?;
+        Cartridge::new(rom_data).ok_or(LoadError::InvalidRom)
+    }
     pub fn get_header(&self) -> &ROMHeader {
         &self.header
     }
     pub fn get_rom_data(&self) -> &Vec<u8> {
         &self.rom_data
     }
+    pub fn get_ram(&self) -> &[u8] {
+        &self.ram
+    }
+    pub fn get_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+    /// ROM bank currently mapped at `addr` (0x0000-0x7FFF).
+    pub fn rom_bank(&self, addr: u16) -> usize {
+        self.mapper.rom_bank(addr)
+    }
+    /// Connects a tilt sensor, returns false if the cartridge has none.
+    pub fn set_accelerometer(&mut self, source: Box<dyn AccelerometerSource>) -> bool {
+        self.mapper.set_accelerometer(source)
+    }
+    /// Connects a camera sensor, returns false if the cartridge has none.
+    pub fn set_image_source(&mut self, source: Box<dyn ImageSource>) -> bool {
+        self.mapper.set_image_source(source)
+    }
     pub fn print_info(&self) {
         println!("{}", self.header);
     }
 }
 impl IO for Cartridge {
     fn read(&self, addr: u16) -> Option<u8> {
-        let addr = addr as usize;
-        if addr < self.rom_data.len() {
-            Some(self.rom_data[addr])
-        } else {
-            None
+        match addr {
+            0x0000..=0x7FFF => Some(self.mapper.read_rom(&self.rom_data, &self.ram, addr)),
+            0xA000..=0xBFFF => self.mapper.read_ram(&self.ram, addr),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) -> bool {
+        match addr {
+            // ROM is read-only, writes go to the mapper registers
+            0x0000..=0x7FFF => self.mapper.write_rom(&mut self.ram, addr, value),
+            0xA000..=0xBFFF => self.mapper.write_ram(&mut self.ram, addr, value),
+            _ => return false,
         }
+        true
+    }
+}
+impl Step for Cartridge {
+    fn step(&mut self, cycles: u8) {
+        self.mapper.step_with_ram(&mut self.ram, cycles);
+    }
+}
+impl Savable for Cartridge {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        self.mapper.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes_into(&mut self.ram)?;
+        self.mapper.load_state(r)
+    }
+}
+
+/// 32 KB ROM only image of an assembled program for tests, entered at its
+/// `start` label. Callers patch the header for other cartridge types.
+#[cfg(test)]
+pub(crate) fn test_rom(program: &crate::asm::Program) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    for section in &program.sections {
+        let origin = section.origin as usize;
+        rom[origin..origin + section.bytes.len()].copy_from_slice(&section.bytes);
+    }
+    let [low, high] = program.symbols["start"].to_le_bytes();
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, low, high]);  // nop, jp start
+    rom
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn sibling_patches_are_applied_or_reported() {
+        let dir = std::env::temp_dir().join(format!("gbc_patch_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("game.gb");
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x138].copy_from_slice(b"GAME");
+        fs::write(&rom_path, &rom).unwrap();
+        let path = rom_path.to_str().unwrap();
+
+        assert_eq!(Cartridge::new_from_file(path).unwrap().get_header().get_title(), "GAME");
+
+        // Renames the game through the header
+        let ips = [&b"PATCH\x00\x01\x34\x00\x04"[..], b"HACK", b"EOF"].concat();
+        fs::write(dir.join("game.ips"), &ips).unwrap();
+        assert_eq!(Cartridge::new_from_file(path).unwrap().get_header().get_title(), "HACK");
+
+        fs::write(dir.join("game.ips"), b"PATCH\x00\x01").unwrap();
+        let result = Cartridge::new_from_file(path);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(result, Err(LoadError::Patch(PatchError::Truncated))));
     }
 
-    fn write(&mut self, _addr: u16, _value: u8) -> bool {
-        // ROM is read-only; writes are ignored
-        false
+    #[test]
+    fn roms_load_from_archives() {
+        let dir = std::env::temp_dir().join(format!("gbc_archive_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let rom = vec![0x42; 0x8000];
+
+        fs::write(path("game.gb.gz"), gzip::test_member(Some("game.gb"), &rom)).unwrap();
+        assert_eq!(read_rom_file(&path("game.gb.gz"), None).unwrap(), rom);
+
+        let archive = crate::zip::test_archive(&[("readme.txt", b"hi", false), ("Game.GBC", &rom, true), ("other.gb", &[1; 4], false)]);
+        fs::write(path("game.zip"), archive).unwrap();
+        // The first ROM by extension, unless an entry is named
+        assert_eq!(read_rom_file(&path("game.zip"), None).unwrap(), rom);
+        assert_eq!(read_rom_file(&path("game.zip"), Some("other.gb")).unwrap(), [1; 4]);
+        assert!(matches!(read_rom_file(&path("game.zip"), Some("missing.gb")), Err(LoadError::Archive(_))));
+
+        fs::write(path("notes.zip"), crate::zip::test_archive(&[("readme.txt", b"hi", false)])).unwrap();
+        let result = read_rom_file(&path("notes.zip"), None);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(result, Err(LoadError::Archive(_))));
     }
-}
\ No newline at end of file
+}
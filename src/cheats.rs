@@ -0,0 +1,304 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+// Cheat codes, see https://gbdev.io/pandocs/Game_Genie_and_GameShark.html
+//
+// Game Genie codes (ABC-DEF or ABC-DEF-GHI) sit between the cartridge and
+// the bus and replace a ROM byte, only while it holds the compare value if
+// there is one. GameShark codes (TTVVAAAA) write a RAM byte every VBlank.
+//
+// Cheat files are plain text, one code per line with an optional name:
+//
+//   # comment
+//   on  01FF3CD1 Infinite health
+//   off 00A-17B-C49
+
+const SHARK_WRAM_BANK: u8 = 0x90;  // 0x90-0x97 write to that CGB WRAM bank
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheatCode {
+    /// Replaces the ROM byte at `addr`, only when it reads `compare`.
+    GameGenie { addr: u16, value: u8, compare: Option<u8> },
+    /// Writes `value` to `addr` every frame. `bank` picks the CGB WRAM bank
+    /// behind 0xD000-0xDFFF, `None` writes to whatever is mapped.
+    GameShark { bank: Option<u8>, addr: u16, value: u8 },
+}
+impl CheatCode {
+    /// Parses either kind of code, dashes and case don't matter.
+    pub fn parse(code: &str) -> Option<CheatCode> {
+        let digits: String = code.chars().filter(|&c| c != '-').collect();
+        if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        match (digits.len(), code.contains('-')) {
+            (6 | 9, _) => CheatCode::game_genie(&digits),
+            (8, false) => CheatCode::game_shark(&digits),
+            _ => None,
+        }
+    }
+
+    // AB is the value, FCDE the address with F inverted, GI the compare
+    // value rotated and scrambled, H a check digit nobody verifies
+    fn game_genie(digits: &str) -> Option<CheatCode> {
+        let nibble = |i: usize| u8::from_str_radix(&digits[i..i + 1], 16).ok();
+        let value = u8::from_str_radix(&digits[0..2], 16).ok()?;
+        let addr = ((nibble(5)? as u16 ^ 0x0F) << 12)
+            | ((nibble(2)? as u16) << 8)
+            | ((nibble(3)? as u16) << 4)
+            | nibble(4)? as u16;
+        if addr > 0x7FFF {
+            return None;
+        }
+        let compare = match digits.len() {
+            9 => Some(((nibble(6)? << 4) | nibble(8)?).rotate_right(2) ^ 0xBA),
+            _ => None,
+        };
+        Some(CheatCode::GameGenie { addr, value, compare })
+    }
+
+    // TT type or bank, VV value, AAAA address with the low byte first
+    fn game_shark(digits: &str) -> Option<CheatCode> {
+        let byte = |i: usize| u8::from_str_radix(&digits[i..i + 2], 16).ok();
+        let kind = byte(0)?;
+        let value = byte(2)?;
+        let addr = u16::from_le_bytes([byte(4)?, byte(6)?]);
+        let bank = (kind & 0xF8 == SHARK_WRAM_BANK).then_some(kind & 0x07);
+        Some(CheatCode::GameShark { bank, addr, value })
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cheat {
+    pub code: String,  // As entered, uppercased
+    pub name: String,
+    pub enabled: bool,
+    pub kind: CheatCode,
+}
+
+#[derive(Debug)]
+pub enum CheatError {
+    Io(io::Error),
+    InvalidCode(String),
+    InvalidLine(usize),  // 1-based line number in a cheat file
+}
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheatError::Io(e) => write!(f, "{}", e),
+            CheatError::InvalidCode(code) => write!(f, "Invalid cheat code \"{}\"", code),
+            CheatError::InvalidLine(line) => write!(f, "Invalid cheat on line {}", line),
+        }
+    }
+}
+impl From<io::Error> for CheatError {
+    fn from(e: io::Error) -> Self {
+        CheatError::Io(e)
+    }
+}
+
+/// A RAM write due this frame, see `Cheats::vblank`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RamWrite {
+    pub bank: Option<u8>,
+    pub addr: u16,
+    pub value: u8,
+}
+
+
+/// The cheat list the MMU consults, in the order the codes were added.
+#[derive(Debug, Clone, Default)]
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+    frame: u64,  // Last frame the RAM codes were applied on
+}
+impl Cheats {
+    pub fn new() -> Self {
+        Cheats { cheats: Vec::new(), frame: 0 }
+    }
+
+    /// Adds an enabled code, returns its index.
+    pub fn add(&mut self, code: &str, name: &str) -> Result<usize, CheatError> {
+        let code = code.trim().to_ascii_uppercase();
+        let kind = CheatCode::parse(&code).ok_or_else(|| CheatError::InvalidCode(code.clone()))?;
+        self.cheats.push(Cheat { code, name: name.to_string(), enabled: true, kind });
+        Ok(self.cheats.len() - 1)
+    }
+    pub fn remove(&mut self, index: usize) -> Option<Cheat> {
+        (index < self.cheats.len()).then(|| self.cheats.remove(index))
+    }
+    pub fn clear(&mut self) {
+        self.cheats.clear();
+    }
+    /// Returns false if there is no cheat at `index`.
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        match self.cheats.get_mut(index) {
+            Some(cheat) => {
+                cheat.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &Cheat> {
+        self.cheats.iter()
+    }
+    pub fn len(&self) -> usize {
+        self.cheats.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty()
+    }
+
+    /// What the bus sees instead of the cartridge's `value` at `addr`.
+    pub fn patch_rom(&self, addr: u16, value: u8) -> u8 {
+        for cheat in self.cheats.iter().filter(|cheat| cheat.enabled) {
+            if let CheatCode::GameGenie { addr: at, value: patched, compare } = cheat.kind {
+                if at == addr && compare.is_none_or(|compare| compare == value) {
+                    return patched;
+                }
+            }
+        }
+        value
+    }
+
+    /// The RAM writes to make, once per frame `frame_count`.
+    pub fn vblank(&mut self, frame_count: u64) -> Vec<RamWrite> {
+        if frame_count == self.frame {
+            return Vec::new();
+        }
+        self.frame = frame_count;
+        self.cheats.iter()
+            .filter(|cheat| cheat.enabled)
+            .filter_map(|cheat| match cheat.kind {
+                CheatCode::GameShark { bank, addr, value } => Some(RamWrite { bank, addr, value }),
+                CheatCode::GameGenie { .. } => None,
+            })
+            .collect()
+    }
+
+    /// Reads a cheat file, see the top of this file for the format.
+    pub fn parse(text: &str) -> Result<Self, CheatError> {
+        let mut cheats = Cheats::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // Fields may be padded, as `to_text` does after "on"
+            let (state, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim_start();
+            let (code, name) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            let enabled = match state {
+                "on" => true,
+                "off" => false,
+                _ => return Err(CheatError::InvalidLine(i + 1)),
+            };
+            if code.is_empty() {
+                return Err(CheatError::InvalidLine(i + 1));
+            }
+            let name = name.trim();
+            let index = cheats.add(code, name).map_err(|_| CheatError::InvalidLine(i + 1))?;
+            cheats.set_enabled(index, enabled);
+        }
+        Ok(cheats)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for cheat in &self.cheats {
+            let state = if cheat.enabled { "on" } else { "off" };
+            let line = format!("{:<3} {} {}", state, cheat.code, cheat.name);
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, CheatError> {
+        Cheats::parse(&std::fs::read_to_string(path)?)
+    }
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        std::fs::write(path, self.to_text())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn game_genie_codes_are_decoded() {
+        // F is inverted into the top address nibble
+        assert_eq!(
+            CheatCode::parse("01a-23f"),
+            Some(CheatCode::GameGenie { addr: 0x0A23, value: 0x01, compare: None }),
+        );
+        // GI is the compare byte rotated right by 2 and XORed with 0xBA
+        assert_eq!(
+            CheatCode::parse("00A-17B-C49"),
+            Some(CheatCode::GameGenie { addr: 0x4A17, value: 0x00, compare: Some(0xC8) }),
+        );
+        assert_eq!(CheatCode::parse("3E9-E9F-4CA"), Some(CheatCode::GameGenie { addr: 0x09E9, value: 0x3E, compare: Some(0x28) }));
+
+        // Only ROM can be patched
+        assert_eq!(CheatCode::parse("01A-237"), None);
+        assert_eq!(CheatCode::parse("01A-23G"), None);
+        assert_eq!(CheatCode::parse("01A-23F-C4"), None);
+    }
+
+    #[test]
+    fn gameshark_codes_pick_a_wram_bank() {
+        assert_eq!(CheatCode::parse("01FF3CD1"), Some(CheatCode::GameShark { bank: None, addr: 0xD13C, value: 0xFF }));
+        assert_eq!(CheatCode::parse("9063A0D0"), Some(CheatCode::GameShark { bank: Some(0), addr: 0xD0A0, value: 0x63 }));
+        assert_eq!(CheatCode::parse("97010ec0"), Some(CheatCode::GameShark { bank: Some(7), addr: 0xC00E, value: 0x01 }));
+        assert_eq!(CheatCode::parse("98010EC0"), Some(CheatCode::GameShark { bank: None, addr: 0xC00E, value: 0x01 }));
+        assert_eq!(CheatCode::parse("01FF-3CD1"), None);
+        assert_eq!(CheatCode::parse("01FF3CD"), None);
+    }
+
+    #[test]
+    fn cheat_files_round_trip() {
+        let text = "# Saved cheats\n\non  01FF3CD1 Infinite health\noff 00A-17B-C49\non  91630ED0  Bank one\n";
+        let cheats = Cheats::parse(text).unwrap();
+        assert_eq!(cheats.len(), 3);
+        let names: Vec<&str> = cheats.iter().map(|cheat| cheat.name.as_str()).collect();
+        assert_eq!(names, ["Infinite health", "", "Bank one"]);
+        assert!(!cheats.iter().nth(1).unwrap().enabled);
+        assert_eq!(cheats.to_text(), "on  01FF3CD1 Infinite health\noff 00A-17B-C49\non  91630ED0 Bank one\n");
+        assert_eq!(Cheats::parse(&cheats.to_text()).unwrap().to_text(), cheats.to_text());
+
+        assert!(matches!(Cheats::parse("on 01FF3CD1\nmaybe 01FF3CD1\n"), Err(CheatError::InvalidLine(2))));
+        assert!(matches!(Cheats::parse("# codes\non\n"), Err(CheatError::InvalidLine(2))));
+        assert!(matches!(Cheats::parse("\n\noff 01A-237 Too high\n"), Err(CheatError::InvalidLine(3))));
+    }
+
+    #[test]
+    fn rom_patches_honour_compare_values() {
+        let mut cheats = Cheats::new();
+        cheats.add("00A-17B-C49", "").unwrap();
+        cheats.add("01a-23f", "").unwrap();
+        assert_eq!(cheats.patch_rom(0x4A17, 0xC8), 0x00);
+        assert_eq!(cheats.patch_rom(0x4A17, 0xC9), 0xC9, "compare value differs");
+        assert_eq!(cheats.patch_rom(0x0A23, 0x55), 0x01);
+        assert_eq!(cheats.patch_rom(0x0A24, 0x55), 0x55);
+
+        cheats.set_enabled(1, false);
+        assert_eq!(cheats.patch_rom(0x0A23, 0x55), 0x55);
+        assert!(matches!(cheats.add("nope", ""), Err(CheatError::InvalidCode(_))));
+    }
+
+    #[test]
+    fn ram_writes_happen_once_per_frame() {
+        let mut cheats = Cheats::new();
+        cheats.add("01FF3CD1", "").unwrap();
+        cheats.add("00A-17B-C49", "").unwrap();
+        assert!(cheats.vblank(0).is_empty());
+        assert_eq!(cheats.vblank(1), [RamWrite { bank: None, addr: 0xD13C, value: 0xFF }]);
+        assert!(cheats.vblank(1).is_empty());
+        assert_eq!(cheats.vblank(2).len(), 1);
+    }
+}
@@ -6,6 +6,48 @@ use std::collections::HashMap;
 pub trait IO {
     fn read(&self, addr: u16) -> Option<u8>;           // returns Some(value) if handled
     fn write(&mut self, addr: u16, value: u8) -> bool; // returns true if handled
+    fn idle(&mut self) {}                              // an M-cycle the CPU spends off the bus
+}
+
+// Interrupt flag bits, shared by IE (0xFFFF) and IF (0xFF0F)
+pub const INTERRUPT_VBLANK: u8 = 0x01;
+pub const INTERRUPT_LCD_STAT: u8 = 0x02;
+pub const INTERRUPT_TIMER: u8 = 0x04;
+pub const INTERRUPT_SERIAL: u8 = 0x08;
+pub const INTERRUPT_JOYPAD: u8 = 0x10;
+
+// T-cycles per second and per frame on a DMG
+pub const CPU_CLOCK_HZ: u32 = 4_194_304;
+pub const CYCLES_PER_FRAME: u32 = 70_224;
+
+/// Hardware revision being emulated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    Dmg,
+    Mgb,   // Game Boy Pocket and Light
+    Sgb,
+    Sgb2,
+    Cgb,
+}
+impl Model {
+    pub fn is_cgb(&self) -> bool {
+        *self == Model::Cgb
+    }
+    pub fn is_sgb(&self) -> bool {
+        matches!(self, Model::Sgb | Model::Sgb2)
+    }
+}
+
+/// CRC-32 as used by zip, gzip and the UPS/BPS patch formats.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
 }
 
 
@@ -1,20 +1,29 @@
-use crate::cartridge::*;
-use crate::opcode::*;
-use std::collections::HashMap;
+use crate::common::IO;
+use crate::savestate::{Savable, StateError, StateReader, StateWriter};
 
+// Interrupt registers live on the bus, the CPU only needs their addresses
+const IF_REGISTER: u16 = 0xFF0F;
+const IE_REGISTER: u16 = 0xFFFF;
 
+// Interrupt vectors, indexed by IF/IE bit (VBlank, LCD STAT, Timer, Serial, Joypad)
+const INTERRUPT_VECTORS: [u16; 5] = [0x0040, 0x0048, 0x0050, 0x0058, 0x0060];
 
-
-struct CPU {
-    reg: Registers
-}
-
+pub const FLAG_Z: u8 = 0b1000_0000;
+pub const FLAG_N: u8 = 0b0100_0000;
+pub const FLAG_H: u8 = 0b0010_0000;
+pub const FLAG_C: u8 = 0b0001_0000;
 
 
-
-struct Instruction {
-    raw: u8,
-    opcode: Opcode,
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Default)]
+pub struct CPU {
+    pub reg: Registers,
+    pub ime: bool,           // Interrupt master enable
+    pub ime_scheduled: bool, // EI enables interrupts after the following instruction
+    pub halted: bool,
+    pub stopped: bool,
+    pub locked: bool,        // Set by illegal opcodes, the CPU hangs until reset
+    pub halt_bug: bool,      // Next opcode fetch does not increment PC
 }
 
 
@@ -29,20 +38,19 @@ struct Instruction {
 // Flags: Z - Zero, N - Subtract, H - Half Carry, C - Carry
 
 
-#[allow(unused)]
-struct Registers {
-    pub a: u8,   
-    pub b: u8,    
-    pub c: u8,    
-    pub d: u8,    
-    pub e: u8,    
-    pub f: u8,    
-    pub h: u8,   
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Registers {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub f: u8,
+    pub h: u8,
     pub l: u8,
-    pub sp: u16,                    
-    pub pc: u16,                    
+    pub sp: u16,
+    pub pc: u16,
 }
-#[allow(unused)]
 impl Registers {
     pub fn get_af(&self) -> u16 {
         ((self.a as u16) << 8) | (self.f as u16)
@@ -56,10 +64,10 @@ impl Registers {
     pub fn get_hl(&self) -> u16 {
         ((self.h as u16) << 8) | (self.l as u16)
     }
-    
+
     pub fn set_af(&mut self, value: u16) {
         self.a = (value >> 8) as u8;
-        self.f = (value & 0xF0) as u8; 
+        self.f = (value & 0xF0) as u8;
     }
     pub fn set_bc(&mut self, value: u16) {
         self.b = (value >> 8) as u8;
@@ -73,4 +81,850 @@ impl Registers {
         self.h = (value >> 8) as u8;
         self.l = value as u8;
     }
-}
\ No newline at end of file
+
+    pub fn flag(&self, flag: u8) -> bool {
+        self.f & flag != 0
+    }
+    pub fn set_flag(&mut self, flag: u8, value: bool) {
+        if value {
+            self.f |= flag;
+        } else {
+            self.f &= !flag;
+        }
+    }
+    fn set_flags(&mut self, z: bool, n: bool, h: bool, c: bool) {
+        self.f = ((z as u8) << 7) | ((n as u8) << 6) | ((h as u8) << 5) | ((c as u8) << 4);
+    }
+}
+
+
+impl CPU {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Executes a single instruction (or services a pending interrupt) and
+    /// returns the number of T-cycles it took.
+    pub fn step<B: IO>(&mut self, bus: &mut B) -> u8 {
+        if self.locked {
+            return 4;
+        }
+
+        let pending = read(bus, IE_REGISTER) & read(bus, IF_REGISTER) & 0x1F;
+        if self.halted || self.stopped {
+            if pending == 0 || self.stopped {
+                return 4;
+            }
+            self.halted = false;
+        }
+
+        if self.ime && pending != 0 {
+            return self.service_interrupt(bus, pending);
+        }
+
+        let enable_ime = self.ime_scheduled;
+        let cycles = self.execute(bus);
+        if enable_ime && self.ime_scheduled {
+            self.ime = true;
+            self.ime_scheduled = false;
+        }
+        cycles
+    }
+
+    /// Whether the next `step` runs an instruction, rather than idling or
+    /// only dispatching an interrupt.
+    pub fn will_execute<B: IO>(&self, bus: &B) -> bool {
+        if self.locked || self.stopped {
+            return false;
+        }
+        let pending = read(bus, IE_REGISTER) & read(bus, IF_REGISTER) & 0x1F;
+        if self.halted && pending == 0 {
+            return false;
+        }
+        !(self.ime && pending != 0)
+    }
+
+    fn service_interrupt<B: IO>(&mut self, bus: &mut B, pending: u8) -> u8 {
+        let bit = pending.trailing_zeros() as usize;
+        self.ime = false;
+        self.ime_scheduled = false;
+
+        let flags = read(bus, IF_REGISTER);
+        bus.write(IF_REGISTER, flags & !(1 << bit));
+
+        // Two M-cycles go by before the push and one after it
+        bus.idle();
+        let pc = self.reg.pc;
+        self.push(bus, pc);
+        self.reg.pc = INTERRUPT_VECTORS[bit];
+        bus.idle();
+        20
+    }
+
+    fn fetch<B: IO>(&mut self, bus: &mut B) -> u8 {
+        let value = read(bus, self.reg.pc);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.reg.pc = self.reg.pc.wrapping_add(1);
+        }
+        value
+    }
+
+    fn fetch16<B: IO>(&mut self, bus: &mut B) -> u16 {
+        let lo = self.fetch(bus) as u16;
+        let hi = self.fetch(bus) as u16;
+        (hi << 8) | lo
+    }
+
+    // The SP decrement takes an M-cycle of its own before the writes
+    fn push<B: IO>(&mut self, bus: &mut B, value: u16) {
+        bus.idle();
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        bus.write(self.reg.sp, (value >> 8) as u8);
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        bus.write(self.reg.sp, value as u8);
+    }
+
+    fn pop<B: IO>(&mut self, bus: &mut B) -> u16 {
+        let lo = read(bus, self.reg.sp) as u16;
+        self.reg.sp = self.reg.sp.wrapping_add(1);
+        let hi = read(bus, self.reg.sp) as u16;
+        self.reg.sp = self.reg.sp.wrapping_add(1);
+        (hi << 8) | lo
+    }
+
+    // Register operand encoding used by the opcode bit fields:
+    // 0 - B, 1 - C, 2 - D, 3 - E, 4 - H, 5 - L, 6 - (HL), 7 - A
+    fn read_r8<B: IO>(&mut self, bus: &mut B, index: u8) -> u8 {
+        match index {
+            0 => self.reg.b,
+            1 => self.reg.c,
+            2 => self.reg.d,
+            3 => self.reg.e,
+            4 => self.reg.h,
+            5 => self.reg.l,
+            6 => read(bus, self.reg.get_hl()),
+            _ => self.reg.a,
+        }
+    }
+
+    fn write_r8<B: IO>(&mut self, bus: &mut B, index: u8, value: u8) {
+        match index {
+            0 => self.reg.b = value,
+            1 => self.reg.c = value,
+            2 => self.reg.d = value,
+            3 => self.reg.e = value,
+            4 => self.reg.h = value,
+            5 => self.reg.l = value,
+            6 => { bus.write(self.reg.get_hl(), value); }
+            _ => self.reg.a = value,
+        }
+    }
+
+    // Register pair encoding for 16-bit loads and arithmetic: BC, DE, HL, SP
+    fn read_r16(&self, index: u8) -> u16 {
+        match index {
+            0 => self.reg.get_bc(),
+            1 => self.reg.get_de(),
+            2 => self.reg.get_hl(),
+            _ => self.reg.sp,
+        }
+    }
+
+    fn write_r16(&mut self, index: u8, value: u16) {
+        match index {
+            0 => self.reg.set_bc(value),
+            1 => self.reg.set_de(value),
+            2 => self.reg.set_hl(value),
+            _ => self.reg.sp = value,
+        }
+    }
+
+    // Condition encoding: NZ, Z, NC, C
+    fn condition(&self, index: u8) -> bool {
+        match index {
+            0 => !self.reg.flag(FLAG_Z),
+            1 => self.reg.flag(FLAG_Z),
+            2 => !self.reg.flag(FLAG_C),
+            _ => self.reg.flag(FLAG_C),
+        }
+    }
+
+    fn execute<B: IO>(&mut self, bus: &mut B) -> u8 {
+        let opcode = self.fetch(bus);
+
+        match opcode {
+            0x00 => 4,
+
+            // LD rr, n16
+            0x01 | 0x11 | 0x21 | 0x31 => {
+                let value = self.fetch16(bus);
+                self.write_r16(opcode >> 4, value);
+                12
+            }
+
+            // LD (rr), A
+            0x02 | 0x12 | 0x22 | 0x32 => {
+                let addr = self.indirect_address(opcode >> 4);
+                bus.write(addr, self.reg.a);
+                8
+            }
+
+            // LD A, (rr)
+            0x0A | 0x1A | 0x2A | 0x3A => {
+                let addr = self.indirect_address(opcode >> 4);
+                self.reg.a = read(bus, addr);
+                8
+            }
+
+            // INC rr / DEC rr
+            0x03 | 0x13 | 0x23 | 0x33 => {
+                let index = opcode >> 4;
+                self.write_r16(index, self.read_r16(index).wrapping_add(1));
+                bus.idle();
+                8
+            }
+            0x0B | 0x1B | 0x2B | 0x3B => {
+                let index = opcode >> 4;
+                self.write_r16(index, self.read_r16(index).wrapping_sub(1));
+                bus.idle();
+                8
+            }
+
+            // INC r
+            0x04 | 0x0C | 0x14 | 0x1C | 0x24 | 0x2C | 0x34 | 0x3C => {
+                let index = (opcode >> 3) & 0x07;
+                let value = self.read_r8(bus, index);
+                let result = value.wrapping_add(1);
+                self.write_r8(bus, index, result);
+                let carry = self.reg.flag(FLAG_C);
+                self.reg.set_flags(result == 0, false, value & 0x0F == 0x0F, carry);
+                if index == 6 { 12 } else { 4 }
+            }
+
+            // DEC r
+            0x05 | 0x0D | 0x15 | 0x1D | 0x25 | 0x2D | 0x35 | 0x3D => {
+                let index = (opcode >> 3) & 0x07;
+                let value = self.read_r8(bus, index);
+                let result = value.wrapping_sub(1);
+                self.write_r8(bus, index, result);
+                let carry = self.reg.flag(FLAG_C);
+                self.reg.set_flags(result == 0, true, value & 0x0F == 0x00, carry);
+                if index == 6 { 12 } else { 4 }
+            }
+
+            // LD r, n8
+            0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => {
+                let index = (opcode >> 3) & 0x07;
+                let value = self.fetch(bus);
+                self.write_r8(bus, index, value);
+                if index == 6 { 12 } else { 8 }
+            }
+
+            // Accumulator rotates always clear Z
+            0x07 | 0x0F | 0x17 | 0x1F => {
+                let a = self.reg.a;
+                let result = self.rotate((opcode >> 3) & 0x03, a);
+                self.reg.a = result;
+                self.reg.set_flag(FLAG_Z, false);
+                4
+            }
+
+            // LD (a16), SP
+            0x08 => {
+                let addr = self.fetch16(bus);
+                bus.write(addr, self.reg.sp as u8);
+                bus.write(addr.wrapping_add(1), (self.reg.sp >> 8) as u8);
+                20
+            }
+
+            // ADD HL, rr
+            0x09 | 0x19 | 0x29 | 0x39 => {
+                let hl = self.reg.get_hl();
+                let value = self.read_r16(opcode >> 4);
+                let (result, carry) = hl.overflowing_add(value);
+                let half = (hl & 0x0FFF) + (value & 0x0FFF) > 0x0FFF;
+                let zero = self.reg.flag(FLAG_Z);
+                self.reg.set_flags(zero, false, half, carry);
+                self.reg.set_hl(result);
+                bus.idle();
+                8
+            }
+
+            0x10 => {
+                self.fetch(bus);
+                self.stopped = true;
+                4
+            }
+
+            // JR e8
+            0x18 => {
+                let offset = self.fetch(bus) as i8;
+                self.reg.pc = self.reg.pc.wrapping_add(offset as u16);
+                bus.idle();
+                12
+            }
+
+            // JR cc, e8
+            0x20 | 0x28 | 0x30 | 0x38 => {
+                let offset = self.fetch(bus) as i8;
+                if self.condition((opcode >> 3) & 0x03) {
+                    self.reg.pc = self.reg.pc.wrapping_add(offset as u16);
+                    bus.idle();
+                    12
+                } else {
+                    8
+                }
+            }
+
+            0x27 => {
+                self.daa();
+                4
+            }
+            0x2F => {
+                self.reg.a = !self.reg.a;
+                self.reg.set_flag(FLAG_N, true);
+                self.reg.set_flag(FLAG_H, true);
+                4
+            }
+            0x37 => {
+                let zero = self.reg.flag(FLAG_Z);
+                self.reg.set_flags(zero, false, false, true);
+                4
+            }
+            0x3F => {
+                let zero = self.reg.flag(FLAG_Z);
+                let carry = self.reg.flag(FLAG_C);
+                self.reg.set_flags(zero, false, false, !carry);
+                4
+            }
+
+            0x76 => {
+                let pending = read(bus, IE_REGISTER) & read(bus, IF_REGISTER) & 0x1F;
+                if !self.ime && pending != 0 {
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                }
+                4
+            }
+
+            // LD r, r'
+            0x40..=0x7F => {
+                let dst = (opcode >> 3) & 0x07;
+                let src = opcode & 0x07;
+                let value = self.read_r8(bus, src);
+                self.write_r8(bus, dst, value);
+                if dst == 6 || src == 6 { 8 } else { 4 }
+            }
+
+            // ALU A, r
+            0x80..=0xBF => {
+                let src = opcode & 0x07;
+                let value = self.read_r8(bus, src);
+                self.alu((opcode >> 3) & 0x07, value);
+                if src == 6 { 8 } else { 4 }
+            }
+
+            // RET cc
+            0xC0 | 0xC8 | 0xD0 | 0xD8 => {
+                bus.idle();
+                if self.condition((opcode >> 3) & 0x03) {
+                    self.reg.pc = self.pop(bus);
+                    bus.idle();
+                    20
+                } else {
+                    8
+                }
+            }
+
+            // POP rr
+            0xC1 | 0xD1 | 0xE1 | 0xF1 => {
+                let value = self.pop(bus);
+                match (opcode >> 4) & 0x03 {
+                    0 => self.reg.set_bc(value),
+                    1 => self.reg.set_de(value),
+                    2 => self.reg.set_hl(value),
+                    _ => self.reg.set_af(value),
+                }
+                12
+            }
+
+            // JP cc, a16
+            0xC2 | 0xCA | 0xD2 | 0xDA => {
+                let addr = self.fetch16(bus);
+                if self.condition((opcode >> 3) & 0x03) {
+                    self.reg.pc = addr;
+                    bus.idle();
+                    16
+                } else {
+                    12
+                }
+            }
+
+            0xC3 => {
+                self.reg.pc = self.fetch16(bus);
+                bus.idle();
+                16
+            }
+
+            // CALL cc, a16
+            0xC4 | 0xCC | 0xD4 | 0xDC => {
+                let addr = self.fetch16(bus);
+                if self.condition((opcode >> 3) & 0x03) {
+                    let pc = self.reg.pc;
+                    self.push(bus, pc);
+                    self.reg.pc = addr;
+                    24
+                } else {
+                    12
+                }
+            }
+
+            // PUSH rr
+            0xC5 | 0xD5 | 0xE5 | 0xF5 => {
+                let value = match (opcode >> 4) & 0x03 {
+                    0 => self.reg.get_bc(),
+                    1 => self.reg.get_de(),
+                    2 => self.reg.get_hl(),
+                    _ => self.reg.get_af(),
+                };
+                self.push(bus, value);
+                16
+            }
+
+            // ALU A, n8
+            0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE => {
+                let value = self.fetch(bus);
+                self.alu((opcode >> 3) & 0x07, value);
+                8
+            }
+
+            // RST vec
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {
+                let pc = self.reg.pc;
+                self.push(bus, pc);
+                self.reg.pc = (opcode & 0x38) as u16;
+                16
+            }
+
+            0xC9 => {
+                self.reg.pc = self.pop(bus);
+                bus.idle();
+                16
+            }
+            0xD9 => {
+                self.reg.pc = self.pop(bus);
+                self.ime = true;
+                bus.idle();
+                16
+            }
+
+            0xCB => self.execute_cb(bus),
+
+            0xCD => {
+                let addr = self.fetch16(bus);
+                let pc = self.reg.pc;
+                self.push(bus, pc);
+                self.reg.pc = addr;
+                24
+            }
+
+            // LDH (a8), A / LDH A, (a8)
+            0xE0 => {
+                let offset = self.fetch(bus) as u16;
+                bus.write(0xFF00 | offset, self.reg.a);
+                12
+            }
+            0xF0 => {
+                let offset = self.fetch(bus) as u16;
+                self.reg.a = read(bus, 0xFF00 | offset);
+                12
+            }
+
+            // LDH (C), A / LDH A, (C)
+            0xE2 => {
+                bus.write(0xFF00 | self.reg.c as u16, self.reg.a);
+                8
+            }
+            0xF2 => {
+                self.reg.a = read(bus, 0xFF00 | self.reg.c as u16);
+                8
+            }
+
+            // ADD SP, e8
+            0xE8 => {
+                let offset = self.fetch(bus);
+                self.reg.sp = self.add_sp_offset(offset);
+                bus.idle();
+                bus.idle();
+                16
+            }
+
+            0xE9 => {
+                self.reg.pc = self.reg.get_hl();
+                4
+            }
+
+            // LD (a16), A / LD A, (a16)
+            0xEA => {
+                let addr = self.fetch16(bus);
+                bus.write(addr, self.reg.a);
+                16
+            }
+            0xFA => {
+                let addr = self.fetch16(bus);
+                self.reg.a = read(bus, addr);
+                16
+            }
+
+            0xF3 => {
+                self.ime = false;
+                self.ime_scheduled = false;
+                4
+            }
+            0xFB => {
+                self.ime_scheduled = true;
+                4
+            }
+
+            // LD HL, SP+e8
+            0xF8 => {
+                let offset = self.fetch(bus);
+                let value = self.add_sp_offset(offset);
+                self.reg.set_hl(value);
+                bus.idle();
+                12
+            }
+
+            0xF9 => {
+                self.reg.sp = self.reg.get_hl();
+                bus.idle();
+                8
+            }
+
+            // ILLEGAL_xx opcodes hang the CPU
+            0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => {
+                self.locked = true;
+                4
+            }
+        }
+    }
+
+    fn execute_cb<B: IO>(&mut self, bus: &mut B) -> u8 {
+        let opcode = self.fetch(bus);
+        let index = opcode & 0x07;
+        let bit = (opcode >> 3) & 0x07;
+        let value = self.read_r8(bus, index);
+
+        match opcode >> 6 {
+            // Rotates and shifts
+            0 => {
+                let result = self.rotate(bit, value);
+                self.write_r8(bus, index, result);
+            }
+            // BIT only reads its operand
+            1 => {
+                let carry = self.reg.flag(FLAG_C);
+                self.reg.set_flags(value & (1 << bit) == 0, false, true, carry);
+                return if index == 6 { 12 } else { 8 };
+            }
+            2 => self.write_r8(bus, index, value & !(1 << bit)),
+            _ => self.write_r8(bus, index, value | (1 << bit)),
+        }
+
+        if index == 6 { 16 } else { 8 }
+    }
+
+    // Resolves (BC), (DE), (HL+) and (HL-) for the accumulator load/store group
+    fn indirect_address(&mut self, index: u8) -> u16 {
+        match index {
+            0 => self.reg.get_bc(),
+            1 => self.reg.get_de(),
+            2 => {
+                let hl = self.reg.get_hl();
+                self.reg.set_hl(hl.wrapping_add(1));
+                hl
+            }
+            _ => {
+                let hl = self.reg.get_hl();
+                self.reg.set_hl(hl.wrapping_sub(1));
+                hl
+            }
+        }
+    }
+
+    // ALU operation encoding: ADD, ADC, SUB, SBC, AND, XOR, OR, CP
+    fn alu(&mut self, op: u8, value: u8) {
+        let a = self.reg.a;
+        let carry_in = self.reg.flag(FLAG_C) as u8;
+
+        match op {
+            0 | 1 => {
+                let carry_in = if op == 1 { carry_in } else { 0 };
+                let result = a as u16 + value as u16 + carry_in as u16;
+                let half = (a & 0x0F) + (value & 0x0F) + carry_in > 0x0F;
+                self.reg.a = result as u8;
+                self.reg.set_flags(result as u8 == 0, false, half, result > 0xFF);
+            }
+            2 | 3 | 7 => {
+                let carry_in = if op == 3 { carry_in } else { 0 };
+                let result = (a as i16) - (value as i16) - (carry_in as i16);
+                let half = ((a & 0x0F) as i16) - ((value & 0x0F) as i16) - (carry_in as i16) < 0;
+                if op != 7 {
+                    self.reg.a = result as u8;
+                }
+                self.reg.set_flags(result as u8 == 0, true, half, result < 0);
+            }
+            4 => {
+                self.reg.a = a & value;
+                self.reg.set_flags(self.reg.a == 0, false, true, false);
+            }
+            5 => {
+                self.reg.a = a ^ value;
+                self.reg.set_flags(self.reg.a == 0, false, false, false);
+            }
+            _ => {
+                self.reg.a = a | value;
+                self.reg.set_flags(self.reg.a == 0, false, false, false);
+            }
+        }
+    }
+
+    // Rotate/shift encoding: RLC, RRC, RL, RR, SLA, SRA, SWAP, SRL
+    fn rotate(&mut self, op: u8, value: u8) -> u8 {
+        let carry_in = self.reg.flag(FLAG_C) as u8;
+        let (result, carry) = match op {
+            0 => (value.rotate_left(1), value & 0x80 != 0),
+            1 => (value.rotate_right(1), value & 0x01 != 0),
+            2 => ((value << 1) | carry_in, value & 0x80 != 0),
+            3 => ((value >> 1) | (carry_in << 7), value & 0x01 != 0),
+            4 => (value << 1, value & 0x80 != 0),
+            5 => ((value >> 1) | (value & 0x80), value & 0x01 != 0),
+            6 => (value.rotate_left(4), false),
+            _ => (value >> 1, value & 0x01 != 0),
+        };
+        self.reg.set_flags(result == 0, false, false, carry);
+        result
+    }
+
+    // Shared by ADD SP, e8 and LD HL, SP+e8: flags come from the unsigned low byte add
+    fn add_sp_offset(&mut self, offset: u8) -> u16 {
+        let sp = self.reg.sp;
+        let half = (sp & 0x0F) + (offset as u16 & 0x0F) > 0x0F;
+        let carry = (sp & 0xFF) + offset as u16 > 0xFF;
+        self.reg.set_flags(false, false, half, carry);
+        sp.wrapping_add(offset as i8 as u16)
+    }
+
+    fn daa(&mut self) {
+        let mut a = self.reg.a;
+        let mut carry = self.reg.flag(FLAG_C);
+        let half = self.reg.flag(FLAG_H);
+        let subtract = self.reg.flag(FLAG_N);
+
+        if subtract {
+            if carry {
+                a = a.wrapping_sub(0x60);
+            }
+            if half {
+                a = a.wrapping_sub(0x06);
+            }
+        } else {
+            if carry || a > 0x99 {
+                a = a.wrapping_add(0x60);
+                carry = true;
+            }
+            if half || a & 0x0F > 0x09 {
+                a = a.wrapping_add(0x06);
+            }
+        }
+
+        self.reg.a = a;
+        self.reg.set_flags(a == 0, subtract, false, carry);
+    }
+}
+
+impl Savable for CPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        let reg = &self.reg;
+        for value in [reg.a, reg.f, reg.b, reg.c, reg.d, reg.e, reg.h, reg.l] {
+            w.u8(value);
+        }
+        w.u16(reg.sp);
+        w.u16(reg.pc);
+        for flag in [self.ime, self.ime_scheduled, self.halted, self.stopped, self.locked, self.halt_bug] {
+            w.bool(flag);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let reg = &mut self.reg;
+        for value in [&mut reg.a, &mut reg.f, &mut reg.b, &mut reg.c, &mut reg.d, &mut reg.e, &mut reg.h, &mut reg.l] {
+            *value = r.u8()?;
+        }
+        reg.f &= 0xF0;
+        reg.sp = r.u16()?;
+        reg.pc = r.u16()?;
+        for flag in [&mut self.ime, &mut self.ime_scheduled, &mut self.halted, &mut self.stopped, &mut self.locked, &mut self.halt_bug] {
+            *flag = r.bool()?;
+        }
+        Ok(())
+    }
+}
+
+// Unmapped reads float high on the real bus
+fn read<B: IO>(bus: &B, addr: u16) -> u8 {
+    bus.read(addr).unwrap_or(0xFF)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sst::FlatRam;
+
+    // `program` at 0x0100 with SP at 0xFFFE
+    fn setup(program: &[u8]) -> (CPU, FlatRam) {
+        let mut ram = FlatRam::new();
+        for (i, &byte) in program.iter().enumerate() {
+            ram.write(0x0100 + i as u16, byte);
+        }
+        let mut cpu = CPU::new();
+        cpu.reg.pc = 0x0100;
+        cpu.reg.sp = 0xFFFE;
+        (cpu, ram)
+    }
+
+    fn request(ram: &mut FlatRam, ie: u8, flags: u8) {
+        ram.write(IE_REGISTER, ie);
+        ram.write(IF_REGISTER, flags);
+    }
+
+    #[test]
+    fn cycle_counts_match_bus_cycles() {
+        // HALT and STOP read more than their one cycle, they never ran on the bus alone
+        let opcodes = (0x00..=0xFFu16).filter(|&op| !matches!(op, 0x10 | 0x76 | 0xCB)).chain(0xCB00..=0xCBFF);
+        for opcode in opcodes {
+            for flags in [0x00, 0xF0] {
+                let program = match opcode {
+                    0xCB00.. => vec![0xCB, opcode as u8],
+                    _ => vec![opcode as u8],
+                };
+                let (mut cpu, mut ram) = setup(&program);
+                cpu.reg.f = flags;
+                ram.take_accesses();
+                let cycles = cpu.step(&mut ram);
+                // Less the IE and IF reads of the interrupt check
+                let bus_cycles = ram.cycles() - 2;
+                assert_eq!(cycles as usize, bus_cycles * 4, "opcode {:#06X} with F = {:#04X}", opcode, flags);
+            }
+        }
+    }
+
+    #[test]
+    fn interrupts_push_pc_and_jump_to_their_vector() {
+        let (mut cpu, mut ram) = setup(&[0x00]);
+        cpu.ime = true;
+        request(&mut ram, 0x1F, 0x0C);
+        assert_eq!(cpu.step(&mut ram), 20);
+        assert_eq!(cpu.reg.pc, 0x0050, "timer before serial");
+        assert_eq!(cpu.reg.sp, 0xFFFC);
+        assert_eq!([ram.read(0xFFFC), ram.read(0xFFFD)], [Some(0x00), Some(0x01)]);
+        assert_eq!(ram.read(IF_REGISTER), Some(0x08));
+        assert!(!cpu.ime);
+    }
+
+    #[test]
+    fn ei_takes_effect_after_the_next_instruction() {
+        let (mut cpu, mut ram) = setup(&[0xFB, 0x00, 0x00]);
+        request(&mut ram, 0x01, 0x01);
+        cpu.step(&mut ram);
+        assert!(!cpu.ime);
+        cpu.step(&mut ram);
+        assert_eq!(cpu.reg.pc, 0x0102, "the NOP after EI still runs");
+        assert!(cpu.ime);
+        cpu.step(&mut ram);
+        assert_eq!(cpu.reg.pc, 0x0040);
+
+        // DI right after EI cancels it
+        let (mut cpu, mut ram) = setup(&[0xFB, 0xF3, 0x00]);
+        cpu.step(&mut ram);
+        cpu.step(&mut ram);
+        cpu.step(&mut ram);
+        assert!(!cpu.ime && !cpu.ime_scheduled);
+    }
+
+    #[test]
+    fn halt_wakes_on_a_request_even_with_ime_off() {
+        let (mut cpu, mut ram) = setup(&[0x76, 0x3C]);
+        cpu.step(&mut ram);
+        assert!(cpu.halted);
+        assert_eq!(cpu.step(&mut ram), 4);
+        assert_eq!(cpu.reg.pc, 0x0101, "still halted");
+
+        // Without IME the next instruction runs in the same step
+        request(&mut ram, 0x04, 0x04);
+        cpu.step(&mut ram);
+        assert!(!cpu.halted);
+        assert_eq!((cpu.reg.pc, cpu.reg.a), (0x0102, 1));
+        assert_eq!(ram.read(IF_REGISTER), Some(0x04), "nothing was serviced");
+    }
+
+    #[test]
+    fn halt_bug_reads_the_next_byte_twice() {
+        // HALT with a request pending and IME off, then INC A
+        let (mut cpu, mut ram) = setup(&[0x76, 0x3C, 0x00]);
+        request(&mut ram, 0x01, 0x01);
+        cpu.step(&mut ram);
+        assert!(!cpu.halted);
+        cpu.step(&mut ram);
+        cpu.step(&mut ram);
+        assert_eq!((cpu.reg.pc, cpu.reg.a), (0x0102, 2));
+    }
+
+    #[test]
+    fn alu_flags() {
+        // ADD A, $38 with A = $45 then DAA gives BCD 83
+        let (mut cpu, mut ram) = setup(&[0xC6, 0x38, 0x27]);
+        cpu.reg.a = 0x45;
+        cpu.step(&mut ram);
+        assert_eq!((cpu.reg.a, cpu.reg.f), (0x7D, 0x00));
+        cpu.step(&mut ram);
+        assert_eq!((cpu.reg.a, cpu.reg.f), (0x83, 0x00));
+
+        // SUB A, $01 with A = $10 borrows from bit 4, CP $20 borrows overall
+        let (mut cpu, mut ram) = setup(&[0xD6, 0x01, 0xFE, 0x20]);
+        cpu.reg.a = 0x10;
+        cpu.step(&mut ram);
+        assert_eq!((cpu.reg.a, cpu.reg.f), (0x0F, FLAG_N | FLAG_H));
+        cpu.step(&mut ram);
+        assert_eq!((cpu.reg.a, cpu.reg.f), (0x0F, FLAG_N | FLAG_C));
+
+        // ADD SP, -1 takes its flags from the low byte
+        let (mut cpu, mut ram) = setup(&[0xE8, 0xFF]);
+        cpu.step(&mut ram);
+        assert_eq!((cpu.reg.sp, cpu.reg.f), (0xFFFD, FLAG_H | FLAG_C));
+    }
+
+    #[test]
+    fn pop_af_drops_the_low_flag_bits() {
+        let (mut cpu, mut ram) = setup(&[0xF1]);
+        ram.write(0xFFFE, 0xFF);
+        ram.write(0xFFFF, 0x12);
+        cpu.step(&mut ram);
+        assert_eq!(cpu.reg.get_af(), 0x12F0);
+    }
+
+    #[test]
+    fn illegal_opcodes_lock_up() {
+        let (mut cpu, mut ram) = setup(&[0xD3, 0x3C]);
+        cpu.ime = true;
+        cpu.step(&mut ram);
+        request(&mut ram, 0x01, 0x01);
+        cpu.step(&mut ram);
+        assert!(cpu.locked);
+        assert_eq!(cpu.reg.pc, 0x0101, "not even interrupts get through");
+    }
+}
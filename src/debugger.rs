@@ -0,0 +1,621 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::RangeInclusive;
+use crate::cpu::Registers;
+use crate::disasm::{self, DecodedInstruction};
+use crate::gameboy::{BusObserver, GameBoy};
+use crate::opcode::Mnemonic;
+use crate::rewind::{Rewind, RewindConfig};
+use crate::savestate::StateError;
+
+// Debugger wrapping a running machine: breakpoints, watchpoints and stepping
+
+const HISTORY_LENGTH: usize = 32;
+
+// `LD B,B` is the conventional software breakpoint (BGB, SameBoy)
+const SOFTWARE_BREAKPOINT_OPCODE: u8 = 0x40;
+
+// Interrupt polling reads these before every instruction
+const IF_REGISTER: u16 = 0xFF0F;
+const IE_REGISTER: u16 = 0xFFFF;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterName {
+    A, F, B, C, D, E, H, L,
+    AF, BC, DE, HL, SP, PC,
+}
+impl RegisterName {
+    pub fn parse(name: &str) -> Option<Self> {
+        let register = match name.to_lowercase().as_str() {
+            "a" => RegisterName::A,
+            "f" => RegisterName::F,
+            "b" => RegisterName::B,
+            "c" => RegisterName::C,
+            "d" => RegisterName::D,
+            "e" => RegisterName::E,
+            "h" => RegisterName::H,
+            "l" => RegisterName::L,
+            "af" => RegisterName::AF,
+            "bc" => RegisterName::BC,
+            "de" => RegisterName::DE,
+            "hl" => RegisterName::HL,
+            "sp" => RegisterName::SP,
+            "pc" => RegisterName::PC,
+            _ => return None,
+        };
+        Some(register)
+    }
+
+    pub fn get(&self, reg: &Registers) -> u16 {
+        match self {
+            RegisterName::A => reg.a as u16,
+            RegisterName::F => reg.f as u16,
+            RegisterName::B => reg.b as u16,
+            RegisterName::C => reg.c as u16,
+            RegisterName::D => reg.d as u16,
+            RegisterName::E => reg.e as u16,
+            RegisterName::H => reg.h as u16,
+            RegisterName::L => reg.l as u16,
+            RegisterName::AF => reg.get_af(),
+            RegisterName::BC => reg.get_bc(),
+            RegisterName::DE => reg.get_de(),
+            RegisterName::HL => reg.get_hl(),
+            RegisterName::SP => reg.sp,
+            RegisterName::PC => reg.pc,
+        }
+    }
+
+    pub fn set(&self, reg: &mut Registers, value: u16) {
+        match self {
+            RegisterName::A => reg.a = value as u8,
+            RegisterName::F => reg.f = value as u8 & 0xF0,
+            RegisterName::B => reg.b = value as u8,
+            RegisterName::C => reg.c = value as u8,
+            RegisterName::D => reg.d = value as u8,
+            RegisterName::E => reg.e = value as u8,
+            RegisterName::H => reg.h = value as u8,
+            RegisterName::L => reg.l = value as u8,
+            RegisterName::AF => reg.set_af(value),
+            RegisterName::BC => reg.set_bc(value),
+            RegisterName::DE => reg.set_de(value),
+            RegisterName::HL => reg.set_hl(value),
+            RegisterName::SP => reg.sp = value,
+            RegisterName::PC => reg.pc = value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Register comparison guarding a breakpoint, e.g. `a == $10`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Condition {
+    pub register: RegisterName,
+    pub op: CompareOp,
+    pub value: u16,
+}
+impl Condition {
+    /// Parses `<register> <op> <value>`, values use `parse_number` syntax.
+    pub fn parse(text: &str) -> Option<Self> {
+        let parts: Vec<&str> = text.split_whitespace().collect();
+        let [register, op, value] = parts.as_slice() else {
+            return None;
+        };
+        let op = match *op {
+            "==" => CompareOp::Eq,
+            "!=" => CompareOp::Ne,
+            "<" => CompareOp::Lt,
+            "<=" => CompareOp::Le,
+            ">" => CompareOp::Gt,
+            ">=" => CompareOp::Ge,
+            _ => return None,
+        };
+        Some(Condition { register: RegisterName::parse(register)?, op, value: parse_number(value)? })
+    }
+
+    pub fn matches(&self, reg: &Registers) -> bool {
+        let actual = self.register.get(reg);
+        match self.op {
+            CompareOp::Eq => actual == self.value,
+            CompareOp::Ne => actual != self.value,
+            CompareOp::Lt => actual < self.value,
+            CompareOp::Le => actual <= self.value,
+            CompareOp::Gt => actual > self.value,
+            CompareOp::Ge => actual >= self.value,
+        }
+    }
+}
+
+/// Parses `$hex`, `0xhex`, `#decimal` or bare hex, the usual debugger convention.
+pub fn parse_number(text: &str) -> Option<u16> {
+    let text = text.trim();
+    if let Some(decimal) = text.strip_prefix('#') {
+        return decimal.parse().ok();
+    }
+    let hex = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")).unwrap_or(text);
+    u16::from_str_radix(hex, 16).ok()
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub bank: Option<usize>, // Only stop when this ROM bank is mapped
+    pub addr: u16,
+    pub condition: Option<Condition>,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+    Execute,
+}
+
+#[derive(Debug, Clone)]
+pub struct Watchpoint {
+    pub id: usize,
+    pub range: RangeInclusive<u16>,
+    pub kind: WatchKind,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Step,
+    Breakpoint(usize),
+    Watchpoint { id: usize, addr: u16, value: u8, write: bool },
+    SoftwareBreakpoint,
+    FrameEnd,
+    Locked,      // An illegal opcode hung the CPU
+    CycleLimit,
+}
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Step => write!(f, "step"),
+            StopReason::Breakpoint(id) => write!(f, "breakpoint {}", id),
+            StopReason::Watchpoint { id, addr, value, write: true } => {
+                write!(f, "watchpoint {}: write ${:02X} to ${:04X}", id, value, addr)
+            }
+            StopReason::Watchpoint { id, addr, value, write: false } => {
+                write!(f, "watchpoint {}: read ${:02X} from ${:04X}", id, value, addr)
+            }
+            StopReason::SoftwareBreakpoint => write!(f, "software breakpoint (ld b, b)"),
+            StopReason::FrameEnd => write!(f, "end of frame"),
+            StopReason::Locked => write!(f, "CPU locked by illegal opcode"),
+            StopReason::CycleLimit => write!(f, "cycle limit"),
+        }
+    }
+}
+
+// Collects the data accesses of one instruction, leaving out opcode fetches
+// and the interrupt poll so read watchpoints only see real loads
+struct AccessLog {
+    pc: u16,
+    length: u16,
+    fetched: bool,
+    accesses: Vec<(u16, u8, bool)>,
+}
+impl BusObserver for AccessLog {
+    fn on_read(&mut self, addr: u16, value: u8) {
+        let fetch = addr.wrapping_sub(self.pc) < self.length;
+        if fetch {
+            self.fetched = true;
+            return;
+        }
+        if !self.fetched && (addr == IF_REGISTER || addr == IE_REGISTER) {
+            return;
+        }
+        self.accesses.push((addr, value, false));
+    }
+
+    fn on_write(&mut self, addr: u16, value: u8) {
+        self.accesses.push((addr, value, true));
+    }
+}
+
+
+pub struct Debugger {
+    pub gb: GameBoy,
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    next_id: usize,
+    /// Stop on `LD B,B`
+    pub software_breakpoints: bool,
+    history: VecDeque<u16>,
+    cut_short: bool,  // Last run hit its cycle limit, PC has not been checked yet
+    rewind: Option<Rewind>,
+}
+impl Debugger {
+    pub fn new(gb: GameBoy) -> Self {
+        Debugger {
+            gb,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            next_id: 1,
+            software_breakpoints: true,
+            history: VecDeque::with_capacity(HISTORY_LENGTH),
+            cut_short: false,
+            rewind: None,
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, bank: Option<usize>, addr: u16, condition: Option<Condition>) -> usize {
+        let id = self.allocate_id();
+        self.breakpoints.push(Breakpoint { id, bank, addr, condition, enabled: true });
+        id
+    }
+
+    pub fn add_watchpoint(&mut self, range: RangeInclusive<u16>, kind: WatchKind) -> usize {
+        let id = self.allocate_id();
+        self.watchpoints.push(Watchpoint { id, range, kind, enabled: true });
+        id
+    }
+
+    /// Removes a breakpoint or watchpoint, returns false for unknown ids.
+    pub fn remove(&mut self, id: usize) -> bool {
+        let before = self.breakpoints.len() + self.watchpoints.len();
+        self.breakpoints.retain(|b| b.id != id);
+        self.watchpoints.retain(|w| w.id != id);
+        before != self.breakpoints.len() + self.watchpoints.len()
+    }
+
+    pub fn set_enabled(&mut self, id: usize, enabled: bool) -> bool {
+        if let Some(b) = self.breakpoints.iter_mut().find(|b| b.id == id) {
+            b.enabled = enabled;
+            return true;
+        }
+        if let Some(w) = self.watchpoints.iter_mut().find(|w| w.id == id) {
+            w.enabled = enabled;
+            return true;
+        }
+        false
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+    /// Addresses of the most recently executed instructions, oldest first.
+    pub fn history(&self) -> impl Iterator<Item = &u16> {
+        self.history.iter()
+    }
+
+    fn allocate_id(&mut self) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    /// Keeps a snapshot per completed frame so execution can be reversed.
+    pub fn enable_rewind(&mut self, config: RewindConfig) {
+        self.rewind = Some(Rewind::new(config));
+    }
+    pub fn rewind(&self) -> Option<&Rewind> {
+        self.rewind.as_ref()
+    }
+
+    /// Goes back `frames` frames, returns how many were actually rewound.
+    /// Does nothing unless rewind was enabled.
+    pub fn reverse_frames(&mut self, frames: u64) -> Result<u64, StateError> {
+        let Some(rewind) = self.rewind.as_mut() else {
+            return Ok(0);
+        };
+        let rewound = rewind.rewind(&mut self.gb, frames)?;
+        self.history.clear();
+        self.cut_short = false;
+        Ok(rewound)
+    }
+
+    /// Decodes the instruction at `addr` as currently mapped.
+    pub fn decode_at(&self, addr: u16) -> Option<DecodedInstruction> {
+        let bytes: Vec<u8> = (0..3).map(|i| self.gb.read(addr.wrapping_add(i))).collect();
+        disasm::decode(&bytes, addr)
+    }
+
+    /// Executes exactly one instruction.
+    pub fn step_in(&mut self) -> StopReason {
+        self.run_until(u64::MAX, |_, _, _| Some(StopReason::Step))
+    }
+
+    /// Steps over calls and restarts, stopping once they return.
+    pub fn step_over(&mut self) -> StopReason {
+        let pc = self.gb.cpu.reg.pc;
+        let instruction = match self.decode_at(pc) {
+            Some(i) if i.is_call() => i,
+            _ => return self.step_in(),
+        };
+        let return_addr = pc.wrapping_add(instruction.len());
+        let sp = self.gb.cpu.reg.sp;
+        self.run_until(u64::MAX, move |gb, _, _| {
+            (gb.cpu.reg.pc == return_addr && gb.cpu.reg.sp >= sp).then_some(StopReason::Step)
+        })
+    }
+
+    /// Runs until the current subroutine returns.
+    pub fn step_out(&mut self) -> StopReason {
+        let sp = self.gb.cpu.reg.sp;
+        self.run_until(u64::MAX, move |gb, executed, sp_before| {
+            let returned = matches!(executed, Some(Mnemonic::Ret | Mnemonic::Reti))
+                && gb.cpu.reg.sp > sp_before
+                && gb.cpu.reg.sp > sp;
+            returned.then_some(StopReason::Step)
+        })
+    }
+
+    /// Runs until the PPU completes the current frame.
+    pub fn run_to_frame(&mut self) -> StopReason {
+        let frame = self.gb.frame_count();
+        self.run_until(u64::MAX, move |gb, _, _| {
+            (gb.frame_count() != frame).then_some(StopReason::FrameEnd)
+        })
+    }
+
+    /// Runs until a breakpoint or watchpoint triggers.
+    pub fn resume(&mut self) -> StopReason {
+        self.resume_for(u64::MAX)
+    }
+
+    /// Runs for at most `cycles` T-cycles, for callers that need to regain control.
+    pub fn resume_for(&mut self, cycles: u64) -> StopReason {
+        self.run_until(cycles, |_, _, _| None)
+    }
+
+    // Main loop, `done` sees the machine after each instruction along with the
+    // executed mnemonic and the SP before it. Breakpoints are not checked for
+    // the first instruction so execution can continue from one, unless the
+    // previous run was only cut short by its cycle limit.
+    fn run_until<F>(&mut self, max_cycles: u64, done: F) -> StopReason
+    where
+        F: FnMut(&GameBoy, Option<Mnemonic>, u16) -> Option<StopReason>,
+    {
+        let reason = self.run_inner(max_cycles, done);
+        self.cut_short = reason == StopReason::CycleLimit;
+        reason
+    }
+
+    fn run_inner<F>(&mut self, max_cycles: u64, mut done: F) -> StopReason
+    where
+        F: FnMut(&GameBoy, Option<Mnemonic>, u16) -> Option<StopReason>,
+    {
+        let start = self.gb.cycles();
+        let mut first = !self.cut_short;
+
+        loop {
+            if self.gb.cpu.locked {
+                return StopReason::Locked;
+            }
+            if !first {
+                if let Some(reason) = self.check_before() {
+                    return reason;
+                }
+            }
+            first = false;
+
+            let pc = self.gb.cpu.reg.pc;
+            let sp_before = self.gb.cpu.reg.sp;
+            let instruction = self.decode_at(pc);
+            let running = !self.gb.cpu.halted && !self.gb.cpu.stopped;
+            if running {
+                if self.history.len() == HISTORY_LENGTH {
+                    self.history.pop_front();
+                }
+                self.history.push_back(pc);
+            }
+
+            let mut log = AccessLog {
+                pc,
+                length: instruction.as_ref().map_or(1, |i| i.len()),
+                fetched: false,
+                accesses: Vec::new(),
+            };
+            let frame = self.gb.frame_count();
+            self.gb.step_observed(&mut log);
+            if let Some(rewind) = self.rewind.as_mut().filter(|_| self.gb.frame_count() != frame) {
+                rewind.capture(&self.gb);
+            }
+
+            if let Some(reason) = self.check_accesses(&log.accesses) {
+                return reason;
+            }
+            let executed = instruction.filter(|_| running).map(|i| i.mnemonic);
+            if let Some(reason) = done(&self.gb, executed, sp_before) {
+                return reason;
+            }
+            if self.gb.cycles() - start >= max_cycles {
+                return StopReason::CycleLimit;
+            }
+        }
+    }
+
+    // PC breakpoints, execute watchpoints and LD B,B
+    fn check_before(&self) -> Option<StopReason> {
+        let reg = &self.gb.cpu.reg;
+        let pc = reg.pc;
+
+        for breakpoint in self.breakpoints.iter().filter(|b| b.enabled && b.addr == pc) {
+            let bank_matches = breakpoint.bank.is_none_or(|bank| bank == self.gb.rom_bank(pc));
+            let condition_matches = breakpoint.condition.is_none_or(|c| c.matches(reg));
+            if bank_matches && condition_matches {
+                return Some(StopReason::Breakpoint(breakpoint.id));
+            }
+        }
+
+        for watchpoint in &self.watchpoints {
+            if watchpoint.enabled && watchpoint.kind == WatchKind::Execute && watchpoint.range.contains(&pc) {
+                return Some(StopReason::Watchpoint { id: watchpoint.id, addr: pc, value: self.gb.read(pc), write: false });
+            }
+        }
+
+        if self.software_breakpoints && !self.gb.cpu.halted && self.gb.read(pc) == SOFTWARE_BREAKPOINT_OPCODE {
+            return Some(StopReason::SoftwareBreakpoint);
+        }
+        None
+    }
+
+    fn check_accesses(&self, accesses: &[(u16, u8, bool)]) -> Option<StopReason> {
+        for &(addr, value, write) in accesses {
+            for watchpoint in self.watchpoints.iter().filter(|w| w.enabled && w.range.contains(&addr)) {
+                let hit = match watchpoint.kind {
+                    WatchKind::Read => !write,
+                    WatchKind::Write => write,
+                    WatchKind::ReadWrite => true,
+                    WatchKind::Execute => false,
+                };
+                if hit {
+                    return Some(StopReason::Watchpoint { id: watchpoint.id, addr, value, write });
+                }
+            }
+        }
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::cartridge::{self, Cartridge};
+    use crate::common::Model;
+    use std::collections::HashMap;
+
+    const PROGRAM: &str = r#"
+        SECTION "code", ROM0[$0150]
+        start:
+            ld a, 1
+        outer_call:
+            call add_two
+        after_outer:
+            ld [$c000], a
+            ld a, [$c000]
+        spin:
+            jr spin
+        add_two:
+            inc a
+            call inner
+        after_inner:
+            ret
+        inner:
+            inc a
+            ret
+    "#;
+
+    fn setup() -> (Debugger, HashMap<String, u16>) {
+        let program = asm::assemble_program(PROGRAM).unwrap();
+        let cartridge = Cartridge::new(cartridge::test_rom(&program)).unwrap();
+        (Debugger::new(GameBoy::with_model(cartridge, Model::Dmg)), program.symbols)
+    }
+
+    fn run_to(debugger: &mut Debugger, addr: u16) {
+        let id = debugger.add_breakpoint(None, addr, None);
+        assert_eq!(debugger.resume_for(10_000), StopReason::Breakpoint(id));
+        debugger.remove(id);
+    }
+
+    #[test]
+    fn breakpoints_stop_before_the_instruction() {
+        let (mut debugger, symbols) = setup();
+        let id = debugger.add_breakpoint(None, symbols["inner"], None);
+        assert_eq!(debugger.resume_for(10_000), StopReason::Breakpoint(id));
+        assert_eq!(debugger.gb.cpu.reg.pc, symbols["inner"]);
+        assert_eq!(debugger.gb.cpu.reg.a, 2);
+
+        // Execution carries on from the breakpoint rather than hitting it again
+        assert_eq!(debugger.step_in(), StopReason::Step);
+        assert_eq!(debugger.gb.cpu.reg.pc, symbols["inner"] + 1);
+        assert_eq!(debugger.resume_for(10_000), StopReason::CycleLimit);
+    }
+
+    #[test]
+    fn breakpoint_conditions_and_banks_are_checked() {
+        let (mut debugger, symbols) = setup();
+        debugger.add_breakpoint(None, symbols["inner"], Condition::parse("a == 5"));
+        debugger.add_breakpoint(Some(1), symbols["inner"], None);
+        let disabled = debugger.add_breakpoint(None, symbols["inner"], None);
+        debugger.set_enabled(disabled, false);
+        assert_eq!(debugger.resume_for(10_000), StopReason::CycleLimit);
+
+        let (mut debugger, symbols) = setup();
+        let id = debugger.add_breakpoint(Some(0), symbols["inner"], Condition::parse("a == 2"));
+        assert_eq!(debugger.resume_for(10_000), StopReason::Breakpoint(id));
+    }
+
+    #[test]
+    fn watchpoints_report_data_accesses() {
+        let (mut debugger, symbols) = setup();
+        let write = debugger.add_watchpoint(0xC000..=0xC000, WatchKind::Write);
+        assert_eq!(
+            debugger.resume_for(10_000),
+            StopReason::Watchpoint { id: write, addr: 0xC000, value: 3, write: true },
+        );
+        debugger.remove(write);
+
+        let read = debugger.add_watchpoint(0xBFFF..=0xC001, WatchKind::Read);
+        assert_eq!(
+            debugger.resume_for(10_000),
+            StopReason::Watchpoint { id: read, addr: 0xC000, value: 3, write: false },
+        );
+        assert_eq!(debugger.gb.cpu.reg.pc, symbols["spin"]);
+    }
+
+    #[test]
+    fn opcode_fetches_do_not_trigger_read_watchpoints() {
+        let (mut debugger, symbols) = setup();
+        debugger.add_watchpoint(symbols["start"]..=symbols["spin"], WatchKind::Read);
+        assert_eq!(debugger.resume_for(10_000), StopReason::CycleLimit);
+
+        let (mut debugger, symbols) = setup();
+        let id = debugger.add_watchpoint(symbols["inner"]..=symbols["inner"], WatchKind::Execute);
+        assert!(matches!(debugger.resume_for(10_000), StopReason::Watchpoint { id: hit, write: false, .. } if hit == id));
+        assert_eq!(debugger.gb.cpu.reg.pc, symbols["inner"]);
+    }
+
+    #[test]
+    fn step_over_runs_the_whole_call() {
+        let (mut debugger, symbols) = setup();
+        run_to(&mut debugger, symbols["outer_call"]);
+        assert_eq!(debugger.step_over(), StopReason::Step);
+        assert_eq!(debugger.gb.cpu.reg.pc, symbols["after_outer"]);
+        assert_eq!(debugger.gb.cpu.reg.a, 3);
+
+        // Other instructions are a single step
+        assert_eq!(debugger.step_over(), StopReason::Step);
+        assert_eq!(debugger.gb.cpu.reg.pc, symbols["after_outer"] + 3);
+    }
+
+    #[test]
+    fn step_over_stops_at_breakpoints_inside_the_call() {
+        let (mut debugger, symbols) = setup();
+        run_to(&mut debugger, symbols["outer_call"]);
+        let id = debugger.add_breakpoint(None, symbols["inner"], None);
+        assert_eq!(debugger.step_over(), StopReason::Breakpoint(id));
+    }
+
+    #[test]
+    fn step_out_returns_one_level() {
+        let (mut debugger, symbols) = setup();
+        run_to(&mut debugger, symbols["inner"]);
+        assert_eq!(debugger.step_out(), StopReason::Step);
+        assert_eq!(debugger.gb.cpu.reg.pc, symbols["after_inner"]);
+        assert_eq!(debugger.step_out(), StopReason::Step);
+        assert_eq!(debugger.gb.cpu.reg.pc, symbols["after_outer"]);
+
+        let history: Vec<u16> = debugger.history().copied().collect();
+        assert_eq!(history[history.len() - 2..], [symbols["inner"] + 1, symbols["after_inner"]]);
+    }
+}
@@ -0,0 +1,222 @@
+use crate::inflate::{DISTANCE_BASE, DISTANCE_EXTRA, LENGTH_BASE, LENGTH_EXTRA};
+
+// Raw DEFLATE encoder (RFC 1951), used for PNG screenshots. Greedy LZ77
+// matching through hash chains, written as one block with the fixed
+// Huffman codes. Screens with a handful of colours compress well without
+// building dynamic tables.
+
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// How many earlier positions with the same hash to try
+const MAX_CHAIN: usize = 64;
+
+const HASH_BITS: u32 = 15;
+const HASH_SIZE: usize = 1 << HASH_BITS;
+const NO_POSITION: usize = usize::MAX;
+
+const END_OF_BLOCK: u16 = 256;
+
+
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter { out: Vec::new(), buffer: 0, count: 0 }
+    }
+
+    // Least significant bit first, as DEFLATE packs them
+    fn bits(&mut self, value: u32, n: u32) {
+        self.buffer |= value << self.count;
+        self.count += n;
+        while self.count >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes go most significant bit first
+    fn code(&mut self, code: u32, length: u32) {
+        self.bits(code.reverse_bits() >> (32 - length), length);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.buffer as u8);
+        }
+        self.out
+    }
+}
+
+// Fixed literal/length code for `symbol`, see RFC 1951 3.2.6
+fn fixed_literal(w: &mut BitWriter, symbol: u16) {
+    let symbol = symbol as u32;
+    match symbol {
+        0..=143 => w.code(0x30 + symbol, 8),
+        144..=255 => w.code(0x190 + symbol - 144, 9),
+        256..=279 => w.code(symbol - 256, 7),
+        _ => w.code(0xC0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(w: &mut BitWriter, length: usize, distance: usize) {
+    let code = LENGTH_BASE.iter().rposition(|&base| base as usize <= length).unwrap_or(0);
+    fixed_literal(w, 257 + code as u16);
+    w.bits((length - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA[code] as u32);
+
+    let code = DISTANCE_BASE.iter().rposition(|&base| base as usize <= distance).unwrap_or(0);
+    w.code(code as u32, 5);
+    w.bits((distance - DISTANCE_BASE[code] as usize) as u32, DISTANCE_EXTRA[code] as u32);
+}
+
+// Hash chains over the last window of input
+struct Matcher {
+    head: Vec<usize>,      // Latest position for each hash
+    previous: Vec<usize>,  // Earlier position with the same hash, by position in the window
+}
+impl Matcher {
+    fn new() -> Self {
+        Matcher { head: vec![NO_POSITION; HASH_SIZE], previous: vec![NO_POSITION; WINDOW_SIZE] }
+    }
+
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash(data, pos);
+            self.previous[pos % WINDOW_SIZE] = self.head[h];
+            self.head[h] = pos;
+        }
+    }
+
+    // Longest earlier match for the data at `pos`, as length and distance
+    fn longest(&self, data: &[u8], pos: usize) -> (usize, usize) {
+        if pos + MIN_MATCH > data.len() {
+            return (0, 0);
+        }
+        let max_length = (data.len() - pos).min(MAX_MATCH);
+        let (mut best_length, mut best_distance) = (0, 0);
+        let mut candidate = self.head[hash(data, pos)];
+        for _ in 0..MAX_CHAIN {
+            if candidate == NO_POSITION || pos - candidate > WINDOW_SIZE {
+                break;
+            }
+            let length = data[candidate..].iter().zip(&data[pos..pos + max_length])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length > best_length {
+                (best_length, best_distance) = (length, pos - candidate);
+                if length == max_length {
+                    break;
+                }
+            }
+            let next = self.previous[candidate % WINDOW_SIZE];
+            // Slots get reused once the window moves on
+            if next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
+        (best_length, best_distance)
+    }
+}
+
+fn hash(data: &[u8], pos: usize) -> usize {
+    let value = (data[pos] as u32) << 16 | (data[pos + 1] as u32) << 8 | data[pos + 2] as u32;
+    (value.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+/// Compresses `data` into a single raw DEFLATE block.
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter::new();
+    w.bits(1, 1);  // BFINAL
+    w.bits(1, 2);  // BTYPE 01, fixed codes
+
+    let mut matcher = Matcher::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let (length, distance) = matcher.longest(data, pos);
+        let step = if length >= MIN_MATCH {
+            write_match(&mut w, length, distance);
+            length
+        } else {
+            fixed_literal(&mut w, data[pos] as u16);
+            1
+        };
+        for p in pos..pos + step {
+            matcher.insert(data, p);
+        }
+        pos += step;
+    }
+    fixed_literal(&mut w, END_OF_BLOCK);
+    w.finish()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inflate::inflate;
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let compressed = deflate(data);
+        assert_eq!(inflate(&compressed).as_deref(), Some(data));
+        compressed
+    }
+
+    // Same generator as the ROM fillers elsewhere, bytes that don't repeat
+    fn noise(length: usize) -> Vec<u8> {
+        let mut x = 1u32;
+        (0..length).map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            (x >> 24) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn literals_round_trip() {
+        assert_eq!(round_trip(&[]), [0x03, 0x00]);
+        round_trip(&[0x42]);
+        round_trip(&(0..=255).collect::<Vec<u8>>());
+        round_trip(&noise(1000));
+    }
+
+    #[test]
+    fn repeats_become_matches() {
+        let fox = b"The quick brown fox jumps over the lazy dog. ".repeat(100);
+        assert!(round_trip(&fox).len() < fox.len() / 10);
+        // Runs overlap their own output, and need several maximum length matches
+        let run = vec![0xAA; 10_000];
+        assert!(round_trip(&run).len() < 100);
+        // Every length code
+        let mut lengths = Vec::new();
+        for length in MIN_MATCH..=MAX_MATCH {
+            lengths.extend(noise(length));
+            lengths.push(length as u8);
+        }
+        round_trip(&lengths);
+    }
+
+    #[test]
+    fn matches_reach_back_a_whole_window() {
+        let block = noise(WINDOW_SIZE);
+        let data = [&block[..], &block[..], &block[..100]].concat();
+        assert!(round_trip(&data).len() < WINDOW_SIZE + WINDOW_SIZE / 4);
+        // Too far back to match
+        let far = [&block[..100], &noise(WINDOW_SIZE + 1)[100..], &block[..100]].concat();
+        round_trip(&far);
+    }
+
+    #[test]
+    fn screens_compress_well() {
+        // A 160x144 RGB screen of stripes and a few solid rows
+        let screen: Vec<u8> = (0..144 * 160).flat_map(|i| match (i / 160 % 16, i % 8) {
+            (0, _) => [0x0F, 0x38, 0x0F],
+            (_, 0..=3) => [0x9B, 0xBC, 0x0F],
+            _ => [0x30, 0x62, 0x30],
+        }).collect();
+        assert!(round_trip(&screen).len() < screen.len() / 50);
+    }
+}
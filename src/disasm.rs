@@ -0,0 +1,414 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt;
+use crate::cartridge::Cartridge;
+use crate::opcode::{Mnemonic, OpcodeTables};
+
+// Disassembler producing RGBDS syntax from the opcode tables
+
+const ROM_BANK_SIZE: usize = 0x4000;
+
+// Code always starts at the header entry point, the RST and interrupt vectors
+const ENTRY_POINTS: [u16; 14] = [
+    0x0100, 0x0000, 0x0008, 0x0010, 0x0018, 0x0020, 0x0028, 0x0030, 0x0038,
+    0x0040, 0x0048, 0x0050, 0x0058, 0x0060,
+];
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodedOperand {
+    Name(String),         // Register, condition, bit index or RST vector
+    Indirect(String),     // [hl], [hl+], [c] ...
+    Imm8(u8),             // n8
+    Imm16(u16),           // n16, a16 used as a value (jp/call targets)
+    Address(u16),         // [a16]
+    HighAddress(u8),      // [a8], printed as a full 0xFFxx address
+    Target(u16),          // e8 of JR, resolved to the absolute destination
+    SpOffset(i8),         // sp+e8
+    Offset(i8),           // e8 of ADD SP
+}
+impl fmt::Display for DecodedOperand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodedOperand::Name(name) => write!(f, "{}", name),
+            DecodedOperand::Indirect(name) => write!(f, "[{}]", name),
+            DecodedOperand::Imm8(value) => write!(f, "${:02x}", value),
+            DecodedOperand::Imm16(value) => write!(f, "${:04x}", value),
+            DecodedOperand::Address(addr) => write!(f, "[${:04x}]", addr),
+            DecodedOperand::HighAddress(offset) => write!(f, "[$ff{:02x}]", offset),
+            DecodedOperand::Target(addr) => write!(f, "${:04x}", addr),
+            DecodedOperand::SpOffset(offset) if *offset < 0 => write!(f, "sp-{}", -(*offset as i16)),
+            DecodedOperand::SpOffset(offset) => write!(f, "sp+{}", offset),
+            DecodedOperand::Offset(offset) => write!(f, "{}", offset),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DecodedInstruction {
+    pub address: u16,
+    pub bytes: Vec<u8>,             // Raw encoding, prefix byte included
+    pub prefixed: bool,
+    pub code: u8,
+    pub mnemonic: Mnemonic,
+    pub operands: Vec<DecodedOperand>,
+}
+impl DecodedInstruction {
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+    pub fn is_illegal(&self) -> bool {
+        !self.prefixed && matches!(self.code,
+            0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD)
+    }
+    pub fn is_call(&self) -> bool {
+        matches!(self.mnemonic, Mnemonic::Call | Mnemonic::Rst)
+    }
+    pub fn is_conditional(&self) -> bool {
+        matches!(self.mnemonic, Mnemonic::Jp | Mnemonic::Jr | Mnemonic::Call | Mnemonic::Ret)
+            && self.operands.first().is_some_and(|op| matches!(op, DecodedOperand::Name(n)
+                if matches!(n.as_str(), "nz" | "z" | "nc" | "c")))
+    }
+    /// Static destination of a jump, call or restart, if it has one.
+    pub fn target(&self) -> Option<u16> {
+        match self.mnemonic {
+            Mnemonic::Jp | Mnemonic::Jr | Mnemonic::Call => match self.operands.last()? {
+                DecodedOperand::Imm16(addr) | DecodedOperand::Target(addr) => Some(*addr),
+                _ => None,
+            },
+            Mnemonic::Rst => Some((self.code & 0x38) as u16),
+            _ => None,
+        }
+    }
+    /// Whether execution can continue with the next instruction in memory.
+    pub fn falls_through(&self) -> bool {
+        if self.is_illegal() {
+            return false;
+        }
+        match self.mnemonic {
+            Mnemonic::Jp | Mnemonic::Jr | Mnemonic::Ret => self.is_conditional(),
+            Mnemonic::Reti => false,
+            _ => true,
+        }
+    }
+}
+impl fmt::Display for DecodedInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_illegal() {
+            return write!(f, "db ${:02x}", self.code);
+        }
+        write!(f, "{}", mnemonic_name(self.mnemonic))?;
+        for (i, operand) in self.operands.iter().enumerate() {
+            let separator = if i == 0 { " " } else { ", " };
+            write!(f, "{}{}", separator, operand)?;
+        }
+        Ok(())
+    }
+}
+
+/// Lowercase RGBDS spelling of a mnemonic.
+pub fn mnemonic_name(mnemonic: Mnemonic) -> String {
+    format!("{:?}", mnemonic).to_lowercase()
+}
+
+
+/// Decodes the instruction at the start of `bytes`, located at `addr`.
+/// Returns `None` if `bytes` ends before the instruction does.
+pub fn decode(bytes: &[u8], addr: u16) -> Option<DecodedInstruction> {
+    let tables = OpcodeTables::global();
+    let first = *bytes.first()?;
+    let (prefixed, code) = match first {
+        0xCB => (true, *bytes.get(1)?),
+        _ => (false, first),
+    };
+    let opcode = tables.get(prefixed, code)?;
+    let length = opcode.bytes as usize;
+    let raw = bytes.get(..length)?;
+
+    // Immediate data follows the opcode byte, CB opcodes have none
+    let imm8 = raw.get(1).copied().unwrap_or(0);
+    let imm16 = u16::from_le_bytes([imm8, raw.get(2).copied().unwrap_or(0)]);
+    let next = addr.wrapping_add(length as u16);
+
+    let mut operands = Vec::new();
+    let mut iter = opcode.operands.iter();
+    while let Some(operand) = iter.next() {
+        let name = operand.name.to_lowercase();
+        let decoded = match name.as_str() {
+            "n8" => DecodedOperand::Imm8(imm8),
+            "n16" => DecodedOperand::Imm16(imm16),
+            "a16" if operand.is_immediate => DecodedOperand::Imm16(imm16),
+            "a16" => DecodedOperand::Address(imm16),
+            "a8" => DecodedOperand::HighAddress(imm8),
+            "e8" if opcode.mnemonic == Mnemonic::Jr => {
+                DecodedOperand::Target(next.wrapping_add(imm8 as i8 as u16))
+            }
+            "e8" => DecodedOperand::Offset(imm8 as i8),
+            // LD HL, SP+e8 lists SP and e8 as separate operands
+            "sp" if operand.is_increment => {
+                iter.next();
+                DecodedOperand::SpOffset(imm8 as i8)
+            }
+            _ if !operand.is_immediate => {
+                let suffix = match (operand.is_increment, operand.is_decrement) {
+                    (true, _) => "+",
+                    (_, true) => "-",
+                    _ => "",
+                };
+                DecodedOperand::Indirect(format!("{}{}", name, suffix))
+            }
+            _ => DecodedOperand::Name(name),
+        };
+        operands.push(decoded);
+    }
+
+    // STOP's second byte is padding and not part of the RGBDS syntax
+    if opcode.mnemonic == Mnemonic::Stop {
+        operands.clear();
+    }
+
+    Some(DecodedInstruction {
+        address: addr,
+        bytes: raw.to_vec(),
+        prefixed,
+        code,
+        mnemonic: opcode.mnemonic,
+        operands,
+    })
+}
+
+
+/// A single disassembled line, qualified with the ROM bank it came from.
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub bank: usize,
+    pub instruction: DecodedInstruction,
+}
+
+/// Disassembly of a whole cartridge, keyed by (bank, address).
+#[derive(Debug, Default)]
+pub struct Disassembly {
+    pub lines: BTreeMap<(usize, u16), Line>,
+    pub labels: BTreeSet<(usize, u16)>,
+    pub subroutines: BTreeSet<(usize, u16)>,
+}
+impl Disassembly {
+    fn label_name(&self, bank: usize, addr: u16) -> Option<String> {
+        if self.subroutines.contains(&(bank, addr)) {
+            Some(format!("call_{:02x}_{:04x}", bank, addr))
+        } else if self.labels.contains(&(bank, addr)) {
+            Some(format!("jump_{:02x}_{:04x}", bank, addr))
+        } else {
+            None
+        }
+    }
+}
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut current_bank = None;
+        let mut expected_addr = None;
+
+        for (&(bank, addr), line) in &self.lines {
+            if current_bank != Some(bank) {
+                let section = if bank == 0 { "ROM0".to_string() } else { format!("ROMX[${:04x}], BANK[${:x}]", addr, bank) };
+                writeln!(f, "\nSECTION \"ROM Bank ${:03x}\", {}\n", bank, section)?;
+                current_bank = Some(bank);
+            } else if expected_addr != Some(addr) {
+                writeln!(f)?;
+            }
+            if let Some(label) = self.label_name(bank, addr) {
+                writeln!(f, "{}:", label)?;
+            }
+
+            let bytes: Vec<String> = line.instruction.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            let text = line.instruction.to_string();
+            writeln!(f, "    {:<24} ; {:02x}:{:04x}  {}", text, bank, addr, bytes.join(" "))?;
+            expected_addr = Some(addr.wrapping_add(line.instruction.len()));
+        }
+        Ok(())
+    }
+}
+
+fn bank_count(cartridge: &Cartridge) -> usize {
+    cartridge.get_rom_data().len().div_ceil(ROM_BANK_SIZE)
+}
+
+// Address at which `bank` is visible on the CPU bus
+fn bank_base(bank: usize) -> u16 {
+    if bank == 0 { 0x0000 } else { 0x4000 }
+}
+
+fn decode_in_bank(cartridge: &Cartridge, bank: usize, addr: u16) -> Option<DecodedInstruction> {
+    let rom = cartridge.get_rom_data();
+    let offset = bank * ROM_BANK_SIZE + (addr - bank_base(bank)) as usize;
+    let bank_end = ((bank + 1) * ROM_BANK_SIZE).min(rom.len());
+    decode(rom.get(offset..bank_end)?, addr)
+}
+
+/// Decodes every bank from start to end, treating all bytes as code.
+pub fn disassemble_linear(cartridge: &Cartridge) -> Disassembly {
+    let mut disassembly = Disassembly::default();
+
+    for bank in 0..bank_count(cartridge) {
+        let mut addr = bank_base(bank) as u32;
+        let end = bank_base(bank) as u32 + ROM_BANK_SIZE as u32;
+        while addr < end {
+            let pc = addr as u16;
+            let instruction = match decode_in_bank(cartridge, bank, pc) {
+                Some(i) => i,
+                None => break,
+            };
+            addr += instruction.len() as u32;
+            disassembly.lines.insert((bank, pc), Line { bank, instruction });
+        }
+    }
+
+    disassembly
+}
+
+/// Follows control flow from the entry points and vectors, only decoding
+/// reachable code. Calls and jumps into 0x4000-0x7FFF resolve to the bank
+/// selected by the last `ld [$2000-$3fff], a` seen on the path, when A was
+/// loaded with an immediate; code in a switchable bank keeps its own bank.
+pub fn disassemble_recursive(cartridge: &Cartridge) -> Disassembly {
+    let mut disassembly = Disassembly::default();
+    let banks = bank_count(cartridge);
+
+    // (bank, address, bank selected by the caller for 0x4000-0x7FFF)
+    let mut queue: VecDeque<(usize, u16, usize)> = ENTRY_POINTS.iter().map(|&addr| (0, addr, 1)).collect();
+    let mut visited: BTreeSet<(usize, u16)> = BTreeSet::new();
+
+    while let Some((bank, start, mut selected_bank)) = queue.pop_front() {
+        let mut addr = start;
+        let mut last_a: Option<u8> = None;
+
+        loop {
+            if bank >= banks || !visited.insert((bank, addr)) {
+                break;
+            }
+            let instruction = match decode_in_bank(cartridge, bank, addr) {
+                Some(i) => i,
+                None => break,
+            };
+
+            // Track simple `ld a, n8` / `ld [$2000], a` bank switches
+            match (&instruction.mnemonic, instruction.operands.as_slice()) {
+                (Mnemonic::Ld, [DecodedOperand::Name(reg), DecodedOperand::Imm8(value)]) if reg == "a" => {
+                    last_a = Some(*value);
+                }
+                (Mnemonic::Ld, [DecodedOperand::Address(0x2000..=0x3FFF), DecodedOperand::Name(reg)]) if reg == "a" => {
+                    if let Some(value) = last_a {
+                        selected_bank = (value as usize).max(1);
+                    }
+                }
+                (_, [DecodedOperand::Name(reg), ..]) if reg == "a" => last_a = None,
+                _ => {}
+            }
+
+            if let Some(target) = instruction.target() {
+                let target_bank = match target {
+                    0x0000..=0x3FFF => Some(0),
+                    0x4000..=0x7FFF if bank != 0 => Some(bank),
+                    0x4000..=0x7FFF => Some(selected_bank),
+                    _ => None, // RAM, not part of the ROM
+                };
+                if let Some(target_bank) = target_bank.filter(|&b| b < banks) {
+                    if instruction.is_call() {
+                        disassembly.subroutines.insert((target_bank, target));
+                    } else {
+                        disassembly.labels.insert((target_bank, target));
+                    }
+                    queue.push_back((target_bank, target, selected_bank));
+                }
+            }
+
+            let next = addr.wrapping_add(instruction.len());
+            let falls_through = instruction.falls_through();
+            disassembly.lines.insert((bank, addr), Line { bank, instruction });
+
+            // Stop at the end of the mapped window or where control leaves
+            if !falls_through || next < addr || next >= bank_base(bank) + ROM_BANK_SIZE as u16 {
+                break;
+            }
+            addr = next;
+        }
+    }
+
+    disassembly
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(bytes: &[u8], addr: u16) -> String {
+        decode(bytes, addr).unwrap().to_string()
+    }
+
+    #[test]
+    fn instructions_print_as_rgbds() {
+        assert_eq!(text(&[0x2A], 0x0100), "ld a, [hl+]");
+        assert_eq!(text(&[0x20, 0x4E], 0x0100), "jr nz, $0150");
+        assert_eq!(text(&[0xE0, 0x40], 0x0100), "ldh [$ff40], a");
+        assert_eq!(text(&[0xEA, 0x00, 0x20], 0x0100), "ld [$2000], a");
+        assert_eq!(text(&[0xCD, 0x00, 0x40], 0x0100), "call $4000");
+        assert!(decode(&[0xD3], 0x0100).unwrap().is_illegal());
+    }
+
+    #[test]
+    fn cb_prefixed_instructions_are_decoded() {
+        let bit = decode(&[0xCB, 0x7C], 0x0100).unwrap();
+        assert_eq!(bit.to_string(), "bit 7, h");
+        assert_eq!(bit.len(), 2);
+        assert_eq!(text(&[0xCB, 0x36], 0x0100), "swap [hl]");
+        assert_eq!(text(&[0xCB, 0x11], 0x0100), "rl c");
+    }
+
+    #[test]
+    fn truncated_input_is_not_decoded() {
+        assert!(decode(&[], 0x0100).is_none());
+        assert!(decode(&[0xCB], 0x0100).is_none());
+        assert!(decode(&[0x3E], 0x0100).is_none());
+        assert!(decode(&[0xC3, 0x50], 0x0100).is_none());
+    }
+
+    #[test]
+    fn recursive_disassembly_follows_flow_and_banks() {
+        let mut rom = vec![0; 4 * ROM_BANK_SIZE];
+        rom[0x147] = 0x01; // MBC1
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]); // nop, jp $0150
+        rom[0x150..0x15A].copy_from_slice(&[
+            0x3E, 0x02,       // ld a, $02
+            0xEA, 0x00, 0x20, // ld [$2000], a
+            0xCD, 0x00, 0x40, // call $4000
+            0x18, 0xF4,       // jr $0150
+        ]);
+        rom[0x15A] = 0xC9; // unreachable
+        rom[2 * ROM_BANK_SIZE..2 * ROM_BANK_SIZE + 4].copy_from_slice(&[
+            0xCD, 0x10, 0x40, // call $4010
+            0xC9,             // ret
+        ]);
+        rom[2 * ROM_BANK_SIZE + 0x10] = 0xC9; // ret
+        let cartridge = Cartridge::new(rom).unwrap();
+
+        let disassembly = disassemble_recursive(&cartridge);
+        assert!(disassembly.labels.contains(&(0, 0x0150)));
+        assert_eq!(disassembly.lines[&(0, 0x0155)].instruction.to_string(), "call $4000");
+        assert!(!disassembly.lines.contains_key(&(0, 0x015A)));
+
+        // The call lands in the bank selected just before it, and calls
+        // from there stay in that bank
+        assert!(disassembly.subroutines.contains(&(2, 0x4000)));
+        assert!(disassembly.subroutines.contains(&(2, 0x4010)));
+        assert_eq!(disassembly.lines[&(2, 0x4003)].instruction.to_string(), "ret");
+        assert!(disassembly.lines.contains_key(&(2, 0x4010)));
+        assert!(!disassembly.lines.keys().any(|&(bank, _)| bank == 1 || bank == 3));
+
+        let listing = disassembly.to_string();
+        assert!(listing.contains("call_02_4000:"));
+        assert!(listing.contains("jump_00_0150:"));
+    }
+}
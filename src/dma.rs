@@ -0,0 +1,339 @@
+use crate::savestate::{Savable, StateError, StateReader, StateWriter};
+
+// OAM DMA, see https://gbdev.io/pandocs/OAM_DMA_Transfer.html
+// Writing FF46 copies 160 bytes from XX00-XX9F into OAM, one byte per
+// M-cycle after a one M-cycle setup.
+//
+// CGB VRAM DMA, see https://gbdev.io/pandocs/CGB_Registers.html#lcd-vram-dma-transfers
+// FF51-FF55 copy 16 byte blocks into the VRAM bank VBK selects, either all
+// at once with the CPU halted (general purpose) or one block per HBlank.
+// Sources in VRAM read as 0xFF, E000-FFFF reads A000-BFFF.
+//
+// The MMU does the copying, these only keep track of where transfers are.
+
+pub const DMA_REGISTER: u16 = 0xFF46;
+pub const OAM_DMA_LENGTH: u16 = 160;
+
+pub const HDMA_SOURCE_HIGH: u16 = 0xFF51;
+pub const HDMA_SOURCE_LOW: u16 = 0xFF52;
+pub const HDMA_DEST_HIGH: u16 = 0xFF53;
+pub const HDMA_DEST_LOW: u16 = 0xFF54;
+pub const HDMA_CONTROL: u16 = 0xFF55;
+pub const HDMA_BLOCK_SIZE: u16 = 16;
+
+const SETUP_CYCLES: u8 = 1;
+// The CPU sits out 8 M-cycles per block, twice as many in double speed
+pub const HDMA_BLOCK_CYCLES: u32 = 8 * 4;
+
+
+/// Which external bus an address sits on, the CPU and DMA can't share one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaBus {
+    External,  // Cartridge and work RAM
+    Video,     // VRAM
+}
+impl DmaBus {
+    pub fn of(addr: u16) -> Option<DmaBus> {
+        match addr {
+            0x8000..=0x9FFF => Some(DmaBus::Video),
+            0x0000..=0xFDFF => Some(DmaBus::External),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct OamDma {
+    source: u8,          // FF46 as last written
+    starting: bool,      // Written by the instruction being stepped
+    setup: u8,           // M-cycles left before the first byte
+    position: Option<u16>,
+    cycles: u8,          // T-cycles not yet making up an M-cycle
+    pub value: u8,       // Last byte read, what a conflicting CPU read sees
+}
+impl OamDma {
+    pub fn new() -> Self {
+        OamDma { source: 0xFF, ..Default::default() }
+    }
+
+    pub fn read(&self) -> u8 {
+        self.source
+    }
+
+    /// Starts a transfer, restarting one that is already running.
+    pub fn write(&mut self, value: u8) {
+        self.source = value;
+        self.starting = true;
+        self.setup = SETUP_CYCLES;
+        self.cycles = 0;
+    }
+
+    /// True while the transfer owns the bus.
+    pub fn active(&self) -> bool {
+        self.position.is_some()
+    }
+
+    /// Bus the transfer reads from, `None` when idle.
+    pub fn bus(&self) -> Option<DmaBus> {
+        self.position.and_then(|_| DmaBus::of(self.source_addr()))
+    }
+
+    // Sources above DFXX read from echo RAM, which mirrors work RAM
+    fn source_addr(&self) -> u16 {
+        let page = if self.source >= 0xE0 { self.source - 0x20 } else { self.source };
+        (page as u16) << 8
+    }
+
+    /// Advances by CPU T-cycles and returns the (source, OAM offset) pairs to copy.
+    pub fn step(&mut self, cycles: u8) -> Vec<(u16, u16)> {
+        let mut copies = Vec::new();
+        // Its own instruction's cycles went by before the write
+        if self.starting {
+            self.starting = false;
+            return copies;
+        }
+        if self.setup == 0 && self.position.is_none() {
+            return copies;
+        }
+
+        self.cycles += cycles;
+        while self.cycles >= 4 {
+            self.cycles -= 4;
+            if self.setup > 0 {
+                self.setup -= 1;
+                if self.setup == 0 {
+                    self.position = Some(0);
+                }
+                continue;
+            }
+            let Some(position) = self.position else { break };
+            copies.push((self.source_addr() + position, position));
+            self.position = (position + 1 < OAM_DMA_LENGTH).then_some(position + 1);
+        }
+        copies
+    }
+}
+impl Savable for OamDma {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.source);
+        w.bool(self.starting);
+        w.u8(self.setup);
+        w.u16(self.position.unwrap_or(u16::MAX));
+        w.u8(self.cycles);
+        w.u8(self.value);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.source = r.u8()?;
+        self.starting = r.bool()?;
+        self.setup = r.u8()?;
+        self.position = match r.u16()? {
+            u16::MAX => None,
+            p if p < OAM_DMA_LENGTH => Some(p),
+            _ => return Err(StateError::Invalid("OAM DMA position")),
+        };
+        self.cycles = r.u8()? & 0x03;
+        self.value = r.u8()?;
+        Ok(())
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Hdma {
+    source: u16,    // Low 4 bits ignored
+    dest: u16,      // Offset into VRAM, low 4 bits ignored
+    remaining: u8,  // Blocks left minus one, 0x7F when finished
+    active: bool,
+    hblank: bool,   // One block per HBlank instead of all at once
+}
+impl Default for Hdma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Hdma {
+    pub fn new() -> Self {
+        Hdma { source: 0, dest: 0, remaining: 0x7F, active: false, hblank: false }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            // Bit 7 clear while an HBlank transfer is still running
+            HDMA_CONTROL => ((!self.active as u8) << 7) | self.remaining,
+            _ => 0xFF,  // The address registers are write only
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            HDMA_SOURCE_HIGH => self.source = (self.source & 0x00FF) | (value as u16) << 8,
+            HDMA_SOURCE_LOW => self.source = (self.source & 0xFF00) | (value & 0xF0) as u16,
+            HDMA_DEST_HIGH => self.dest = (self.dest & 0x00FF) | ((value & 0x1F) as u16) << 8,
+            HDMA_DEST_LOW => self.dest = (self.dest & 0xFF00) | (value & 0xF0) as u16,
+            HDMA_CONTROL => {
+                // Clearing bit 7 during an HBlank transfer cancels it
+                if self.active && self.hblank && value & 0x80 == 0 {
+                    self.active = false;
+                    return;
+                }
+                self.remaining = value & 0x7F;
+                self.active = true;
+                self.hblank = value & 0x80 != 0;
+            }
+            _ => {}
+        }
+    }
+
+    /// True while a general purpose transfer has blocks left, the CPU is halted meanwhile.
+    pub fn general_pending(&self) -> bool {
+        self.active && !self.hblank
+    }
+    /// True while an HBlank transfer waits for the next HBlank.
+    pub fn hblank_pending(&self) -> bool {
+        self.active && self.hblank
+    }
+
+    /// Source and VRAM destination of the next block, advancing past it.
+    pub fn next_block(&mut self) -> Option<(u16, u16)> {
+        if !self.active {
+            return None;
+        }
+        // Bit 14 is dropped above DFFF, so E000-FFFF reads external RAM
+        let source = if self.source >= 0xE000 { self.source - 0x4000 } else { self.source };
+        let block = (source, 0x8000 | self.dest);
+        self.source = self.source.wrapping_add(HDMA_BLOCK_SIZE);
+        self.dest = (self.dest + HDMA_BLOCK_SIZE) & 0x1FF0;
+        if self.remaining == 0 {
+            self.active = false;
+            self.remaining = 0x7F;
+        } else {
+            self.remaining -= 1;
+        }
+        Some(block)
+    }
+}
+impl Savable for Hdma {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.source);
+        w.u16(self.dest);
+        w.u8(self.remaining);
+        w.bool(self.active);
+        w.bool(self.hblank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.source = r.u16()? & 0xFFF0;
+        self.dest = r.u16()? & 0x1FF0;
+        self.remaining = r.u8()? & 0x7F;
+        self.active = r.bool()?;
+        self.hblank = r.bool()?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Steps one M-cycle at a time until the transfer ends
+    fn run(dma: &mut OamDma) -> Vec<(u16, u16)> {
+        let mut copies = Vec::new();
+        for _ in 0..1000 {
+            copies.extend(dma.step(4));
+            if !dma.active() {
+                break;
+            }
+        }
+        copies
+    }
+
+    #[test]
+    fn oam_dma_sets_up_then_copies_a_byte_per_m_cycle() {
+        let mut dma = OamDma::new();
+        dma.write(0xC1);
+        assert_eq!(dma.read(), 0xC1);
+
+        // The writing instruction's own cycles, then one M-cycle of setup
+        assert!(dma.step(4).is_empty());
+        assert!(dma.step(4).is_empty());
+        assert!(dma.active());
+        assert_eq!(dma.bus(), Some(DmaBus::External));
+
+        assert_eq!(dma.step(4), [(0xC100, 0)]);
+        assert_eq!(dma.step(2), []);
+        assert_eq!(dma.step(6), [(0xC101, 1), (0xC102, 2)]);
+        let copies = run(&mut dma);
+        assert_eq!(copies.len() as u16, OAM_DMA_LENGTH - 3);
+        assert_eq!(copies.last(), Some(&(0xC19F, OAM_DMA_LENGTH - 1)));
+        assert!(!dma.active());
+        assert_eq!(dma.bus(), None);
+        assert!(dma.step(4).is_empty());
+    }
+
+    #[test]
+    fn oam_dma_restarts_and_remaps_high_sources() {
+        let mut dma = OamDma::new();
+        dma.write(0x80);
+        dma.step(4);
+        dma.step(4);
+        assert_eq!(dma.bus(), Some(DmaBus::Video));
+        dma.step(40);
+
+        // A new write starts over from the setup cycle
+        dma.write(0xFE);
+        dma.step(4);
+        dma.step(4);
+        let copies = run(&mut dma);
+        assert_eq!(copies.len() as u16, OAM_DMA_LENGTH);
+        assert_eq!(copies[0], (0xDE00, 0));
+        assert_eq!(dma.read(), 0xFE);
+    }
+
+    fn hdma(source: u16, dest: u16, control: u8) -> Hdma {
+        let mut hdma = Hdma::new();
+        hdma.write(HDMA_SOURCE_HIGH, (source >> 8) as u8);
+        hdma.write(HDMA_SOURCE_LOW, source as u8);
+        hdma.write(HDMA_DEST_HIGH, (dest >> 8) as u8);
+        hdma.write(HDMA_DEST_LOW, dest as u8);
+        hdma.write(HDMA_CONTROL, control);
+        hdma
+    }
+
+    #[test]
+    fn hdma_addresses_are_masked_and_wrap() {
+        let mut hdma = hdma(0x123F, 0xFFFF, 0x01);
+        assert!(hdma.general_pending());
+        assert_eq!(hdma.next_block(), Some((0x1230, 0x9FF0)));
+        assert_eq!(hdma.next_block(), Some((0x1240, 0x8000)));
+        assert_eq!(hdma.next_block(), None);
+        assert_eq!(hdma.read(HDMA_SOURCE_HIGH), 0xFF);
+
+        let mut hdma = self::hdma(0xE010, 0x8000, 0x00);
+        assert_eq!(hdma.next_block(), Some((0xA010, 0x8000)));
+    }
+
+    #[test]
+    fn hdma5_reports_progress_and_cancels() {
+        let hdma = Hdma::new();
+        assert_eq!(hdma.read(HDMA_CONTROL), 0xFF);
+
+        let mut hdma = self::hdma(0xC000, 0x8000, 0x82);
+        assert!(hdma.hblank_pending() && !hdma.general_pending());
+        assert_eq!(hdma.read(HDMA_CONTROL), 0x02);
+        hdma.next_block();
+        assert_eq!(hdma.read(HDMA_CONTROL), 0x01);
+
+        // Clearing bit 7 stops it with the remaining count readable
+        hdma.write(HDMA_CONTROL, 0x00);
+        assert!(!hdma.hblank_pending());
+        assert_eq!(hdma.read(HDMA_CONTROL), 0x81);
+        assert_eq!(hdma.next_block(), None);
+
+        // Running to the end reads back 0xFF
+        hdma.write(HDMA_CONTROL, 0x80);
+        hdma.next_block();
+        assert_eq!(hdma.read(HDMA_CONTROL), 0xFF);
+    }
+}
@@ -0,0 +1,398 @@
+use std::cell::RefCell;
+use crate::boot::{self, CompatPalette};
+use crate::cartridge::Cartridge;
+use crate::common::{Model, IO, CYCLES_PER_FRAME};
+use crate::cpu::CPU;
+use crate::mmu::MMU;
+use crate::resample::HighPass;
+use crate::sgb::Sgb;
+use crate::savestate::{self, Sections, SectionTag, StateError, StateHeader, StateReader, StateWriter};
+use crate::step::Step;
+use crate::timer::TIMER_DIV;
+
+// The whole machine: CPU plus everything reachable through the MMU
+
+// STOP with KEY1 armed halts the CPU for 2050 M-cycles while the clock changes
+const SPEED_SWITCH_CYCLES: u32 = 2050 * 4;
+
+// Save state sections
+const SECTION_MACHINE: SectionTag = *b"MACH";
+const SECTION_CPU: SectionTag = *b"CPU ";
+const SECTION_MMU: SectionTag = *b"MMU ";
+const SECTION_PPU: SectionTag = *b"PPU ";
+const SECTION_APU: SectionTag = *b"APU ";
+const SECTION_TIMER: SectionTag = *b"TIMR";
+const SECTION_JOYPAD: SectionTag = *b"JOYP";
+const SECTION_SERIAL: SectionTag = *b"SERL";
+const SECTION_CARTRIDGE: SectionTag = *b"CART";
+const SECTION_SGB: SectionTag = *b"SGB ";
+
+/// Sees every bus access the CPU makes, used by the debugger and tracers.
+pub trait BusObserver {
+    fn on_read(&mut self, addr: u16, value: u8);
+    fn on_write(&mut self, addr: u16, value: u8);
+}
+
+// Forwards to the MMU while reporting accesses, reads only get `&self`
+struct ObservedBus<'a, O: BusObserver> {
+    mmu: &'a mut MMU,
+    observer: RefCell<&'a mut O>,
+}
+impl<O: BusObserver> IO for ObservedBus<'_, O> {
+    fn read(&self, addr: u16) -> Option<u8> {
+        let value = self.mmu.read(addr);
+        self.observer.borrow_mut().on_read(addr, value.unwrap_or(0xFF));
+        value
+    }
+
+    fn write(&mut self, addr: u16, value: u8) -> bool {
+        self.observer.get_mut().on_write(addr, value);
+        self.mmu.write(addr, value)
+    }
+}
+
+
+pub struct GameBoy {
+    pub cpu: CPU,
+    pub mmu: MMU,
+    model: Model,
+    compat_palette: Option<CompatPalette>,
+    cycles: u64,
+}
+impl GameBoy {
+    /// Picks CGB for cartridges that support it, DMG otherwise.
+    pub fn new(cartridge: Cartridge) -> Self {
+        let model = GameBoy::default_model(&cartridge);
+        GameBoy::with_model(cartridge, model)
+    }
+
+    /// Model `new` runs `cartridge` on.
+    pub fn default_model(cartridge: &Cartridge) -> Model {
+        match cartridge.get_header().get_cgb_flag() & 0x80 != 0 {
+            true => Model::Cgb,
+            false => Model::Dmg,
+        }
+    }
+
+    /// Starts at the cartridge entry point in the state the model's boot ROM leaves.
+    pub fn with_model(cartridge: Cartridge, model: Model) -> Self {
+        let cgb_mode = model.is_cgb() && cartridge.get_header().get_cgb_flag() & 0x80 != 0;
+        let mut gb = GameBoy::power_on(cartridge, model);
+        gb.mmu.set_cgb_mode(cgb_mode);
+        boot::skip_boot(&mut gb.cpu, &mut gb.mmu, model);
+        gb
+    }
+
+    /// Runs a boot ROM dump from 0x0000 first, `None` if its size doesn't
+    /// fit the model (256 bytes, 2304 on CGB).
+    pub fn with_boot_rom(cartridge: Cartridge, model: Model, boot_rom: Vec<u8>) -> Option<Self> {
+        if boot_rom.len() != boot::boot_rom_size(model) {
+            return None;
+        }
+        // CGB boot ROMs run in CGB mode and pick DMG mode through KEY0
+        let mut gb = GameBoy::power_on(cartridge, model);
+        gb.mmu.set_cgb_mode(model.is_cgb());
+        gb.mmu.load_boot_rom(boot_rom);
+        Some(gb)
+    }
+
+    fn power_on(cartridge: Cartridge, model: Model) -> Self {
+        // Only DMG games on a CGB get a compatibility palette
+        let dmg_game = cartridge.get_header().get_cgb_flag() & 0x80 == 0;
+        let compat_palette = (model.is_cgb() && dmg_game).then(|| boot::compat_palette(cartridge.get_rom_data()));
+        // SGB features are only unlocked for cartridges that ask for them
+        let sgb_game = cartridge.get_header().get_sgb_flag() == 0x03;
+        let mut mmu = MMU::new();
+        mmu.sgb = (model.is_sgb() && sgb_game).then(Sgb::new);
+        mmu.apu.set_high_pass(match model {
+            Model::Mgb | Model::Cgb => HighPass::Cgb,
+            _ => HighPass::Dmg,
+        });
+        mmu.load_cartridge(cartridge);
+        GameBoy { cpu: CPU::new(), mmu, model, compat_palette, cycles: 0 }
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+    /// Colours a CGB gives the DMG game being run, `None` on other models.
+    pub fn compat_palette(&self) -> Option<CompatPalette> {
+        self.compat_palette
+    }
+
+    /// Executes one instruction and advances the peripherals, returns the
+    /// CPU T-cycles taken (twice as many per real second in double speed).
+    pub fn step(&mut self) -> u8 {
+        let was_stopped = self.cpu.stopped;
+        let cycles = self.cpu.step(&mut self.mmu);
+        self.advance(cycles, was_stopped);
+        cycles
+    }
+
+    /// Like `step`, reporting the CPU's bus accesses to `observer`.
+    pub fn step_observed<O: BusObserver>(&mut self, observer: &mut O) -> u8 {
+        let was_stopped = self.cpu.stopped;
+        let mut bus = ObservedBus { mmu: &mut self.mmu, observer: RefCell::new(observer) };
+        let cycles = self.cpu.step(&mut bus);
+        self.advance(cycles, was_stopped);
+        cycles
+    }
+
+    fn advance(&mut self, cycles: u8, was_stopped: bool) {
+        self.cycles += self.mmu.real_cycles(cycles) as u64;
+        self.mmu.step(cycles);
+        self.run_dma_stall();
+
+        if self.cpu.stopped && !was_stopped {
+            self.enter_stop();
+        } else if self.cpu.stopped && self.mmu.joypad.read() & 0x0F != 0x0F {
+            // A selected button being held wakes the CPU
+            self.cpu.stopped = false;
+        }
+    }
+
+    // VRAM DMA halts the CPU while the timer and PPU keep running, and more
+    // HBlank blocks can come due in the meantime
+    fn run_dma_stall(&mut self) {
+        loop {
+            let mut remaining = self.mmu.take_stall();
+            if remaining == 0 {
+                return;
+            }
+            while remaining > 0 {
+                let chunk = remaining.min(u8::MAX as u32 & !3) as u8;
+                self.cycles += self.mmu.real_cycles(chunk) as u64;
+                self.mmu.step(chunk);
+                remaining -= chunk as u32;
+            }
+        }
+    }
+
+    // STOP resets DIV, and on CGB either switches speed or stops until a button press
+    fn enter_stop(&mut self) {
+        self.mmu.write(TIMER_DIV, 0);
+        if !self.mmu.cgb_mode() || !self.mmu.speed_switch_armed() {
+            return;
+        }
+        self.mmu.switch_speed();
+        self.cpu.stopped = false;
+
+        // The CPU and timer sit out the switch, the PPU keeps going
+        let mut remaining = SPEED_SWITCH_CYCLES;
+        while remaining > 0 {
+            let chunk = remaining.min(u8::MAX as u32 & !3) as u8;
+            self.mmu.step_paused(chunk);
+            self.cycles += chunk as u64;
+            remaining -= chunk as u32;
+        }
+    }
+
+    /// Runs until the PPU finishes a frame, or one frame's worth of cycles with the LCD off.
+    pub fn run_frame(&mut self) {
+        let frame = self.frame_count();
+        let start = self.cycles;
+        while self.frame_count() == frame && self.cycles - start < CYCLES_PER_FRAME as u64 {
+            self.step();
+        }
+    }
+
+    /// Total T-cycles executed since power on.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+    pub fn frame_count(&self) -> u64 {
+        self.mmu.ppu.frame_count()
+    }
+    pub fn framebuffer(&self) -> &[u8] {
+        self.mmu.ppu.framebuffer()
+    }
+    /// The 256x224 Super Game Boy picture in RGB555, `None` unless an SGB
+    /// game runs on an SGB model.
+    pub fn sgb_screen(&self) -> Option<&[u16]> {
+        self.mmu.sgb.as_ref().map(Sgb::screen)
+    }
+    /// Sets the held buttons, see the `BUTTON_*` flags in `joypad`.
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.mmu.joypad.set_pressed(buttons);
+    }
+    /// Buttons for SGB multiplayer, `player` 0-3 with 0 the same as `set_buttons`.
+    pub fn set_player_buttons(&mut self, player: usize, buttons: u8) {
+        match (player, &mut self.mmu.sgb) {
+            (0, _) => self.set_buttons(buttons),
+            (_, Some(sgb)) => sgb.set_buttons(player, buttons),
+            _ => {}
+        }
+    }
+
+    /// Side effect free bus read for tools, unmapped addresses read 0xFF.
+    pub fn read(&self, addr: u16) -> u8 {
+        self.mmu.peek(addr).unwrap_or(0xFF)
+    }
+    /// Bus write for tools, not subject to DMA or PPU access restrictions.
+    pub fn write(&mut self, addr: u16, value: u8) {
+        self.mmu.poke(addr, value);
+    }
+    /// ROM bank mapped at `addr`, 0 for addresses outside 0x0000-0x7FFF.
+    pub fn rom_bank(&self, addr: u16) -> usize {
+        match (addr, self.mmu.cartridge()) {
+            (0x0000..=0x7FFF, Some(cart)) => cart.rom_bank(addr),
+            _ => 0,
+        }
+    }
+    /// Snapshot of the whole machine, see `savestate` for the format.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut sections = Sections::new();
+        let mut machine = StateWriter::new();
+        machine.u64(self.cycles);
+        sections.insert(SECTION_MACHINE, machine.into_inner());
+        savestate::save_section(&mut sections, SECTION_CPU, &self.cpu);
+        savestate::save_section(&mut sections, SECTION_MMU, &self.mmu);
+        savestate::save_section(&mut sections, SECTION_PPU, &self.mmu.ppu);
+        savestate::save_section(&mut sections, SECTION_APU, &self.mmu.apu);
+        savestate::save_section(&mut sections, SECTION_TIMER, &self.mmu.timer);
+        savestate::save_section(&mut sections, SECTION_JOYPAD, &self.mmu.joypad);
+        savestate::save_section(&mut sections, SECTION_SERIAL, &self.mmu.serial);
+        if let Some(cart) = self.mmu.cartridge() {
+            savestate::save_section(&mut sections, SECTION_CARTRIDGE, cart);
+        }
+        if let Some(sgb) = &self.mmu.sgb {
+            savestate::save_section(&mut sections, SECTION_SGB, sgb);
+        }
+        savestate::write_state(&self.state_header(), &sections)
+    }
+
+    /// Restores a snapshot from `save_state`. States made with another ROM are
+    /// rejected, and the machine is left untouched on any error.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let (header, sections) = savestate::read_state(data)?;
+        let expected = self.state_header();
+        if header.title != expected.title || header.global_checksum != expected.global_checksum {
+            return Err(StateError::RomMismatch { title: header.title, global_checksum: header.global_checksum });
+        }
+
+        let backup = self.save_state();
+        let result = self.load_sections(&sections, header.version);
+        if result.is_err() {
+            let (_, sections) = savestate::read_state(&backup).expect("own state is valid");
+            self.load_sections(&sections, header.version).expect("own state is valid");
+        }
+        result
+    }
+
+    fn load_sections(&mut self, sections: &Sections, version: u16) -> Result<(), StateError> {
+        let machine = sections.get(&SECTION_MACHINE).ok_or(StateError::MissingSection(SECTION_MACHINE))?;
+        self.cycles = StateReader::new(machine, version).u64()?;
+        savestate::load_section(sections, SECTION_CPU, version, &mut self.cpu)?;
+        savestate::load_section(sections, SECTION_MMU, version, &mut self.mmu)?;
+        savestate::load_section(sections, SECTION_PPU, version, &mut self.mmu.ppu)?;
+        savestate::load_section(sections, SECTION_APU, version, &mut self.mmu.apu)?;
+        savestate::load_section(sections, SECTION_TIMER, version, &mut self.mmu.timer)?;
+        savestate::load_section(sections, SECTION_JOYPAD, version, &mut self.mmu.joypad)?;
+        savestate::load_section(sections, SECTION_SERIAL, version, &mut self.mmu.serial)?;
+        if let Some(cart) = self.mmu.cartridge_mut() {
+            savestate::load_section(sections, SECTION_CARTRIDGE, version, cart)?;
+        }
+        if let Some(sgb) = &mut self.mmu.sgb {
+            savestate::load_section(sections, SECTION_SGB, version, sgb)?;
+        }
+        Ok(())
+    }
+
+    fn state_header(&self) -> StateHeader {
+        let (title, global_checksum) = match self.mmu.cartridge() {
+            Some(cart) => (cart.get_header().get_title(), cart.get_header().get_global_checksum()),
+            None => (String::new(), 0),
+        };
+        StateHeader { version: savestate::STATE_VERSION, title, global_checksum }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::cartridge;
+
+    // Keeps the timer, APU, PPU, WRAM and cartridge RAM busy
+    const PROGRAM: &str = r#"
+        SECTION "code", ROM0[$0150]
+        start:
+            ld a, $0A
+            ld [$0000], a
+            ld a, $05
+            ldh [$07], a
+            ld a, $F0
+            ldh [$12], a
+            ld a, $87
+            ldh [$14], a
+            ld hl, $c000
+            ld de, $a000
+        loop:
+            ldh a, [$05]
+            ld [hl+], a
+            ld [de], a
+            inc de
+            res 4, h
+            res 5, d
+            ldh a, [$04]
+            ldh [$13], a
+            ldh [$43], a
+            jr loop
+    "#;
+
+    fn machine(title: &[u8]) -> GameBoy {
+        let mut rom = cartridge::test_rom(&asm::assemble_program(PROGRAM).unwrap());
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom[0x147] = 0x1B;  // MBC5+RAM+BATTERY
+        rom[0x149] = 0x03;  // 32 KB
+        GameBoy::with_model(Cartridge::new(rom).unwrap(), Model::Dmg)
+    }
+
+    fn run_frames(gb: &mut GameBoy, frames: usize) -> Vec<Vec<u8>> {
+        (0..frames).map(|_| {
+            gb.run_frame();
+            gb.framebuffer().to_vec()
+        }).collect()
+    }
+
+    #[test]
+    fn loaded_state_runs_the_same() {
+        let mut gb = machine(b"STATE");
+        run_frames(&mut gb, 3);
+        let (state, cycles) = (gb.save_state(), gb.cycles());
+        let frames = run_frames(&mut gb, 5);
+        let end = gb.save_state();
+
+        // Into the same machine and into a fresh one
+        gb.load_state(&state).unwrap();
+        assert_eq!(gb.save_state(), state);
+        assert_eq!(run_frames(&mut gb, 5), frames);
+        assert_eq!(gb.save_state(), end);
+
+        let mut fresh = machine(b"STATE");
+        fresh.load_state(&state).unwrap();
+        assert_eq!(fresh.cycles(), cycles);
+        assert_eq!(run_frames(&mut fresh, 5), frames);
+        assert_eq!(fresh.save_state(), end);
+        assert_eq!(fresh.mmu.cartridge().unwrap().get_ram(), gb.mmu.cartridge().unwrap().get_ram());
+    }
+
+    #[test]
+    fn bad_states_leave_the_machine_alone() {
+        let mut gb = machine(b"STATE");
+        run_frames(&mut gb, 2);
+        let state = gb.save_state();
+
+        let mut other = machine(b"OTHER");
+        let before = other.save_state();
+        assert!(matches!(other.load_state(&state), Err(StateError::RomMismatch { .. })));
+        assert_eq!(other.save_state(), before);
+
+        let mut same = machine(b"STATE");
+        let before = same.save_state();
+        assert!(same.load_state(&state[..state.len() - 10]).is_err());
+        assert_eq!(same.save_state(), before);
+        assert!(matches!(same.load_state(b"nope"), Err(StateError::BadMagic) | Err(StateError::Truncated)));
+    }
+}
@@ -0,0 +1,340 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+use crate::apu::{NR50, NR51};
+use crate::cartridge::Cartridge;
+use crate::common::{Model, CPU_CLOCK_HZ, CYCLES_PER_FRAME};
+use crate::gameboy::GameBoy;
+use crate::timer::{TIMER_TAC, TIMER_TMA};
+
+// GBS music rips, see https://ocremix.org/info/GBS_Format_Specification
+// A 0x70 byte header followed by the game's sound code and data:
+//
+//   0x00  "GBS", version 1
+//   0x04  song count, first song (1-based)
+//   0x06  load, init and play addresses, initial SP (u16 LE each)
+//   0x0E  timer modulo and control, TAC bit 2 plays on the timer instead
+//         of VBlank and bit 7 asks for CGB double speed
+//   0x10  title, author and copyright, 32 bytes each, zero padded
+//
+// The data goes into a ROM at the load address, behind an MBC5 so bank
+// switching works as in the game. RST vectors jump to the same offset
+// from the load address. The player calls INIT with the song in A, then
+// PLAY at the VBlank or timer rate, each returning to an idle loop.
+
+const MAGIC: &[u8; 3] = b"GBS";
+const HEADER_SIZE: usize = 0x70;
+const TEXT_SIZE: usize = 32;
+const MIN_LOAD_ADDRESS: u16 = 0x0400;
+
+// Stubs in the first page of the ROM
+const RST_VECTORS: [u16; 8] = [0x00, 0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38];
+const INTERRUPT_VECTORS: [u16; 5] = [0x40, 0x48, 0x50, 0x58, 0x60];
+const IDLE_ADDRESS: u16 = 0x0070;
+const OPCODE_JP: u8 = 0xC3;
+const OPCODE_RETI: u8 = 0xD9;
+const IDLE_LOOP: [u8; 2] = [0x18, 0xFE];  // JR -2
+
+// Header bytes for the ROM the data is put in
+const CARTRIDGE_CGB_FLAG: usize = 0x143;
+const CARTRIDGE_TYPE: usize = 0x147;
+const CARTRIDGE_RAM_SIZE: usize = 0x149;
+const MBC5_RAM_BATTERY: u8 = 0x1B;
+const RAM_8KB: u8 = 0x02;
+// Rips expect cartridge RAM to be usable from the start
+const MBC_RAM_ENABLE: u16 = 0x0000;
+const RAM_ENABLE: u8 = 0x0A;
+
+const TAC_USE_TIMER: u8 = 0x04;
+const TAC_DOUBLE_SPEED: u8 = 0x80;
+// Timer input clocks selected by TAC bits 0-1
+const TIMER_FREQUENCIES: [u32; 4] = [4096, 262_144, 65_536, 16_384];
+
+// How long INIT may take before playback starts anyway
+const INIT_TIMEOUT_CYCLES: u64 = CPU_CLOCK_HZ as u64 * 10;
+
+const IE: u16 = 0xFFFF;
+const IF: u16 = 0xFF0F;
+
+
+#[derive(Debug)]
+pub enum GbsError {
+    Io(io::Error),
+    BadMagic,
+    Truncated,
+    Invalid(&'static str),
+    NoSuchSong(u8),  // 0-based
+}
+impl fmt::Display for GbsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GbsError::Io(e) => write!(f, "{}", e),
+            GbsError::BadMagic => write!(f, "Not a GBS file"),
+            GbsError::Truncated => write!(f, "GBS file is truncated"),
+            GbsError::Invalid(reason) => write!(f, "Invalid GBS file: {}", reason),
+            GbsError::NoSuchSong(song) => write!(f, "No song {} in this GBS file", *song as u16 + 1),
+        }
+    }
+}
+impl From<io::Error> for GbsError {
+    fn from(e: io::Error) -> Self {
+        GbsError::Io(e)
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gbs {
+    pub version: u8,
+    pub song_count: u8,
+    pub first_song: u8,  // 1-based as stored
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+    pub data: Vec<u8>,
+}
+impl Gbs {
+    pub fn parse(file: &[u8]) -> Result<Self, GbsError> {
+        if !file.starts_with(MAGIC) {
+            return Err(GbsError::BadMagic);
+        }
+        let header = file.get(..HEADER_SIZE).ok_or(GbsError::Truncated)?;
+        let word = |offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]);
+        let text = |offset: usize| {
+            let field = &header[offset..offset + TEXT_SIZE];
+            let end = field.iter().position(|&c| c == 0).unwrap_or(TEXT_SIZE);
+            String::from_utf8_lossy(&field[..end]).trim().to_string()
+        };
+        let gbs = Gbs {
+            version: header[0x03],
+            song_count: header[0x04],
+            first_song: header[0x05],
+            load_address: word(0x06),
+            init_address: word(0x08),
+            play_address: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: header[0x0E],
+            timer_control: header[0x0F],
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50),
+            data: file[HEADER_SIZE..].to_vec(),
+        };
+        if gbs.version != 1 {
+            return Err(GbsError::Invalid("unsupported version"));
+        }
+        if gbs.song_count == 0 {
+            return Err(GbsError::Invalid("no songs"));
+        }
+        if !(MIN_LOAD_ADDRESS..0x8000).contains(&gbs.load_address) {
+            return Err(GbsError::Invalid("load address outside 0x0400-0x7FFF"));
+        }
+        Ok(gbs)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, GbsError> {
+        Gbs::parse(&std::fs::read(path)?)
+    }
+
+    /// Whether PLAY runs on the timer rather than at VBlank.
+    pub fn uses_timer(&self) -> bool {
+        self.timer_control & TAC_USE_TIMER != 0
+    }
+    pub fn double_speed(&self) -> bool {
+        self.timer_control & TAC_DOUBLE_SPEED != 0
+    }
+
+    /// CPU cycles between PLAY calls.
+    pub fn play_period(&self) -> u64 {
+        if !self.uses_timer() {
+            return CYCLES_PER_FRAME as u64;
+        }
+        let frequency = TIMER_FREQUENCIES[(self.timer_control & 0x03) as usize] as u64;
+        let period = CPU_CLOCK_HZ as u64 * (256 - self.timer_modulo as u64) / frequency;
+        // The timer runs twice as fast in double speed
+        if self.double_speed() { period / 2 } else { period }
+    }
+
+    // Data at the load address behind the stubs and a header picking MBC5
+    fn rom(&self) -> Vec<u8> {
+        let size = (self.load_address as usize + self.data.len()).max(0x8000).next_multiple_of(0x4000);
+        let mut rom = vec![0xFF; size];
+        let load = self.load_address as usize;
+        rom[load..load + self.data.len()].copy_from_slice(&self.data);
+
+        for vector in RST_VECTORS {
+            let target = self.load_address + vector;
+            rom[vector as usize] = OPCODE_JP;
+            rom[vector as usize + 1..vector as usize + 3].copy_from_slice(&target.to_le_bytes());
+        }
+        for vector in INTERRUPT_VECTORS {
+            rom[vector as usize] = OPCODE_RETI;
+        }
+        rom[IDLE_ADDRESS as usize..IDLE_ADDRESS as usize + 2].copy_from_slice(&IDLE_LOOP);
+        rom[CARTRIDGE_CGB_FLAG] = if self.double_speed() { 0x80 } else { 0x00 };
+        rom[CARTRIDGE_TYPE] = MBC5_RAM_BATTERY;
+        rom[CARTRIDGE_RAM_SIZE] = RAM_8KB;
+        rom
+    }
+}
+
+
+/// Plays one song of a GBS file on an emulated Game Boy.
+pub struct GbsPlayer {
+    gb: GameBoy,
+    play_address: u16,
+    period: u64,
+    next_play: u64,  // Machine cycle count the next PLAY call is due at
+    pending: Vec<i16>,  // Samples produced past what `render` returned
+}
+impl GbsPlayer {
+    /// Runs INIT for `song` (0-based), producing stereo samples at `sample_rate`.
+    pub fn new(gbs: &Gbs, song: u8, sample_rate: u32) -> Result<Self, GbsError> {
+        if song >= gbs.song_count {
+            return Err(GbsError::NoSuchSong(song));
+        }
+        let cartridge = Cartridge::new(gbs.rom()).ok_or(GbsError::Invalid("data doesn't fit a ROM"))?;
+        let model = if gbs.double_speed() { Model::Cgb } else { Model::Dmg };
+        let mut gb = GameBoy::with_model(cartridge, model);
+        if gbs.double_speed() {
+            gb.mmu.switch_speed();
+        }
+        gb.write(MBC_RAM_ENABLE, RAM_ENABLE);
+        gb.write(IE, 0x00);
+        gb.write(IF, 0x00);
+        gb.write(TIMER_TMA, gbs.timer_modulo);
+        gb.write(TIMER_TAC, gbs.timer_control & 0x07);
+        gb.write(NR50, 0x77);
+        gb.write(NR51, 0xFF);
+        gb.mmu.apu.set_sample_rate(Some(sample_rate));
+
+        let mut player = GbsPlayer { gb, play_address: gbs.play_address, period: gbs.play_period(), next_play: 0, pending: Vec::new() };
+        player.gb.cpu.reg.sp = gbs.stack_pointer;
+        player.gb.cpu.reg.a = song;
+        player.call(gbs.init_address);
+        let start = player.gb.cycles();
+        while !player.idle() && player.gb.cycles() - start < INIT_TIMEOUT_CYCLES {
+            player.gb.step();
+        }
+        // Whatever INIT played is the start of the song
+        player.next_play = player.gb.cycles();
+        player.pending = player.gb.mmu.apu.take_samples();
+        Ok(player)
+    }
+
+    pub fn gameboy(&self) -> &GameBoy {
+        &self.gb
+    }
+    pub fn gameboy_mut(&mut self) -> &mut GameBoy {
+        &mut self.gb
+    }
+
+    /// Plays on until `frames` more stereo samples are out, returned interleaved.
+    pub fn render(&mut self, frames: usize) -> Vec<i16> {
+        let mut samples = std::mem::take(&mut self.pending);
+        while samples.len() < frames * 2 {
+            if self.idle() && self.gb.cycles() >= self.next_play {
+                self.call(self.play_address);
+                // A PLAY running late pushes the next one back rather than
+                // calling it twice in a row
+                self.next_play = (self.next_play + self.period).max(self.gb.cycles());
+            }
+            self.gb.step();
+            samples.extend(self.gb.mmu.apu.take_samples());
+        }
+        self.pending = samples.split_off(frames * 2);
+        samples
+    }
+
+    fn idle(&self) -> bool {
+        self.gb.cpu.reg.pc == IDLE_ADDRESS
+    }
+
+    // Pushes the idle loop as the return address and jumps to `address`
+    fn call(&mut self, address: u16) {
+        let sp = self.gb.cpu.reg.sp.wrapping_sub(2);
+        let [low, high] = IDLE_ADDRESS.to_le_bytes();
+        self.gb.write(sp, low);
+        self.gb.write(sp.wrapping_add(1), high);
+        self.gb.cpu.reg.sp = sp;
+        self.gb.cpu.reg.pc = address;
+        self.gb.cpu.halted = false;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOAD: u16 = 0x0400;
+    const PLAY: u16 = 0x0418;
+
+    // INIT stores 0x42 in cartridge RAM, copies it back to 0xC000 and
+    // starts channel 2, PLAY counts its calls at 0xC001
+    fn rip() -> Vec<u8> {
+        let mut file = vec![0; HEADER_SIZE];
+        file[..4].copy_from_slice(b"GBS\x01");
+        file[0x04] = 2;
+        file[0x05] = 1;
+        for (offset, word) in [(0x06, LOAD), (0x08, LOAD), (0x0A, PLAY), (0x0C, 0xFFFE)] {
+            file[offset..offset + 2].copy_from_slice(&word.to_le_bytes());
+        }
+        file[0x10..0x14].copy_from_slice(b"Test");
+        let mut code = vec![
+            0x3E, 0x42,        // ld a, $42
+            0xEA, 0x00, 0xA0,  // ld [$A000], a
+            0xFA, 0x00, 0xA0,  // ld a, [$A000]
+            0xEA, 0x00, 0xC0,  // ld [$C000], a
+            0x3E, 0xF0,        // ld a, $F0
+            0xE0, 0x17,        // ldh [NR22], a
+            0x3E, 0x87,        // ld a, $87
+            0xE0, 0x19,        // ldh [NR24], a
+            0xC9,              // ret
+        ];
+        code.resize((PLAY - LOAD) as usize, 0x00);
+        code.extend([
+            0x21, 0x01, 0xC0,  // ld hl, $C001
+            0x34,              // inc [hl]
+            0xC9,              // ret
+        ]);
+        file.extend(code);
+        file
+    }
+
+    #[test]
+    fn header_is_parsed_and_checked() {
+        let gbs = Gbs::parse(&rip()).unwrap();
+        assert_eq!((gbs.song_count, gbs.load_address, gbs.play_address), (2, LOAD, PLAY));
+        assert_eq!(gbs.title, "Test");
+        assert_eq!(gbs.play_period(), CYCLES_PER_FRAME as u64);
+        assert!(matches!(GbsPlayer::new(&gbs, 2, 44_100), Err(GbsError::NoSuchSong(2))));
+
+        assert!(matches!(Gbs::parse(b"NES"), Err(GbsError::BadMagic)));
+        assert!(matches!(Gbs::parse(&rip()[..0x20]), Err(GbsError::Truncated)));
+        let mut file = rip();
+        file[0x06..0x08].copy_from_slice(&0x0100u16.to_le_bytes());
+        assert!(matches!(Gbs::parse(&file), Err(GbsError::Invalid(_))));
+    }
+
+    #[test]
+    fn player_runs_init_then_play_every_frame() {
+        let gbs = Gbs::parse(&rip()).unwrap();
+        let mut player = GbsPlayer::new(&gbs, 1, 44_100).unwrap();
+        assert_eq!(player.gameboy().mmu.peek(0xC000), Some(0x42), "cartridge RAM works in INIT");
+        assert_eq!(player.gameboy().cpu.reg.pc, IDLE_ADDRESS);
+
+        let samples = player.render(44_100);
+        assert_eq!(samples.len(), 2 * 44_100);
+        assert!(samples.iter().any(|&s| s != 0));
+        // About 59.7 VBlanks a second
+        let calls = player.gameboy().mmu.peek(0xC001).unwrap();
+        assert!((59..=60).contains(&calls), "{} PLAY calls", calls);
+    }
+}
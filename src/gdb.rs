@@ -0,0 +1,671 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use crate::common::CYCLES_PER_FRAME;
+use crate::debugger::{Debugger, StopReason, WatchKind};
+
+// GDB remote serial protocol stub, see
+// https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+//
+// GDB has no SM83 target, so the register layout is described with
+// target.xml: A, F, B, C, D, E, H, L as 8-bit registers followed by
+// SP and PC as 16-bit little endian.
+
+const REGISTER_BYTES: usize = 12;
+
+// Signals used in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+const INTERRUPT_BYTE: u8 = 0x03;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.sm83.core">
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="f" bitsize="8" type="uint8"/>
+    <reg name="b" bitsize="8" type="uint8"/>
+    <reg name="c" bitsize="8" type="uint8"/>
+    <reg name="d" bitsize="8" type="uint8"/>
+    <reg name="e" bitsize="8" type="uint8"/>
+    <reg name="h" bitsize="8" type="uint8"/>
+    <reg name="l" bitsize="8" type="uint8"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+
+// Z/z packet types
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum PointKind {
+    Software,
+    Hardware,
+    Write,
+    Read,
+    Access,
+}
+impl PointKind {
+    fn parse(kind: &str) -> Option<Self> {
+        let kind = match kind {
+            "0" => PointKind::Software,
+            "1" => PointKind::Hardware,
+            "2" => PointKind::Write,
+            "3" => PointKind::Read,
+            "4" => PointKind::Access,
+            _ => return None,
+        };
+        Some(kind)
+    }
+}
+
+// What the packet handler wants done next
+enum Action {
+    Reply(String),
+    Resume { step: bool },
+    Detach,
+    Kill,
+}
+
+
+/// Serves one GDB client, reading from a background thread so a running
+/// target can still notice Ctrl-C.
+pub struct GdbStub<W: Write> {
+    pub debugger: Debugger,
+    input: Receiver<u8>,
+    output: W,
+    no_ack: bool,
+    last_packet: String,
+    points: HashMap<(PointKind, u16, u16), usize>,  // (kind, addr, length) -> debugger id
+}
+impl GdbStub<TcpStream> {
+    /// Waits for a single client on `addr`, e.g. "127.0.0.1:2345".
+    pub fn listen<A: ToSocketAddrs>(debugger: Debugger, addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(GdbStub::new(debugger, stream.try_clone()?, stream))
+    }
+}
+impl GdbStub<io::Stdout> {
+    /// Talks over stdin/stdout, for `target remote | gdbstub game.gb --pipe`.
+    pub fn stdio(debugger: Debugger) -> Self {
+        GdbStub::new(debugger, io::stdin(), io::stdout())
+    }
+}
+impl<W: Write> GdbStub<W> {
+    pub fn new<R: Read + Send + 'static>(debugger: Debugger, mut reader: R, output: W) -> Self {
+        let (sender, input) = mpsc::channel();
+        thread::spawn(move || {
+            let mut byte = [0u8];
+            while let Ok(1) = reader.read(&mut byte) {
+                if sender.send(byte[0]).is_err() {
+                    break;
+                }
+            }
+        });
+
+        GdbStub {
+            debugger,
+            input,
+            output,
+            no_ack: false,
+            last_packet: String::new(),
+            points: HashMap::new(),
+        }
+    }
+
+    /// Handles packets until the client detaches, kills the target or disconnects.
+    pub fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.receive_packet()? {
+            match self.handle(&packet) {
+                Action::Reply(reply) => self.send_packet(&reply)?,
+                Action::Resume { step } => match self.resume(step) {
+                    Some(reply) => self.send_packet(&reply)?,
+                    None => break,
+                },
+                Action::Detach => {
+                    self.send_packet("OK")?;
+                    break;
+                }
+                Action::Kill => break,
+            }
+        }
+        Ok(())
+    }
+
+    // Blocks for the next well formed packet, None once the client is gone
+    fn receive_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let Ok(byte) = self.input.recv() else {
+                return Ok(None);
+            };
+            match byte {
+                b'+' => {}
+                b'-' => {
+                    let packet = std::mem::take(&mut self.last_packet);
+                    self.send_packet(&packet)?;
+                }
+                // Ctrl-C while stopped, report where we are
+                INTERRUPT_BYTE => self.send_packet(&format!("S{:02x}", SIGINT))?,
+                b'$' => {
+                    let mut data = Vec::new();
+                    loop {
+                        match self.input.recv() {
+                            Ok(b'#') => break,
+                            Ok(byte) => data.push(byte),
+                            Err(_) => return Ok(None),
+                        }
+                    }
+                    let mut checksum = [0u8; 2];
+                    for digit in checksum.iter_mut() {
+                        match self.input.recv() {
+                            Ok(byte) => *digit = byte,
+                            Err(_) => return Ok(None),
+                        }
+                    }
+                    let expected = std::str::from_utf8(&checksum).ok()
+                        .and_then(|text| u8::from_str_radix(text, 16).ok());
+
+                    if !self.no_ack {
+                        let valid = expected == Some(checksum_of(&data));
+                        self.output.write_all(if valid { b"+" } else { b"-" })?;
+                        self.output.flush()?;
+                        if !valid {
+                            continue;
+                        }
+                    }
+                    // Only X packets carry binary, keep it byte for byte
+                    return Ok(Some(data.iter().map(|&b| b as char).collect()));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let checksum = checksum_of(data.as_bytes());
+        write!(self.output, "${}#{:02x}", data, checksum)?;
+        self.output.flush()?;
+        self.last_packet = data.to_string();
+        Ok(())
+    }
+
+    fn handle(&mut self, packet: &str) -> Action {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "X" => self.write_memory_binary(args),
+            "Z" => self.insert_point(args),
+            "z" => self.remove_point(args),
+            "c" | "s" => {
+                if let Some(addr) = parse_hex(args) {
+                    self.debugger.gb.cpu.reg.pc = addr;
+                }
+                return Action::Resume { step: command == "s" };
+            }
+            "v" => return self.handle_v(args),
+            "q" | "Q" => self.handle_query(packet),
+            "H" | "T" => "OK".to_string(),
+            "D" => return Action::Detach,
+            "k" => return Action::Kill,
+            _ => String::new(),
+        };
+        Action::Reply(reply)
+    }
+
+    fn handle_v(&mut self, args: &str) -> Action {
+        if args == "Cont?" {
+            return Action::Reply("vCont;c;C;s;S".to_string());
+        }
+        // Single threaded, so only the first action matters
+        if let Some(actions) = args.strip_prefix("Cont;") {
+            return match actions.chars().next() {
+                Some('c' | 'C') => Action::Resume { step: false },
+                Some('s' | 'S') => Action::Resume { step: true },
+                _ => Action::Reply("E01".to_string()),
+            };
+        }
+        if args.starts_with("Kill") {
+            return Action::Kill;
+        }
+        Action::Reply(String::new())
+    }
+
+    fn handle_query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=4000;qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+".to_string();
+        }
+        if packet == "QStartNoAckMode" {
+            self.no_ack = true;
+            return "OK".to_string();
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return read_chunk(TARGET_XML, range);
+        }
+        match packet {
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            "qOffsets" => "Text=0;Data=0;Bss=0".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn register_bytes(&self) -> [u8; REGISTER_BYTES] {
+        let reg = &self.debugger.gb.cpu.reg;
+        let [sp_lo, sp_hi] = reg.sp.to_le_bytes();
+        let [pc_lo, pc_hi] = reg.pc.to_le_bytes();
+        [reg.a, reg.f, reg.b, reg.c, reg.d, reg.e, reg.h, reg.l, sp_lo, sp_hi, pc_lo, pc_hi]
+    }
+
+    fn read_registers(&self) -> String {
+        to_hex(&self.register_bytes())
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        match from_hex(args) {
+            Some(bytes) if bytes.len() >= REGISTER_BYTES => {
+                let reg = &mut self.debugger.gb.cpu.reg;
+                reg.a = bytes[0];
+                reg.f = bytes[1] & 0xF0;
+                reg.b = bytes[2];
+                reg.c = bytes[3];
+                reg.d = bytes[4];
+                reg.e = bytes[5];
+                reg.h = bytes[6];
+                reg.l = bytes[7];
+                reg.sp = u16::from_le_bytes([bytes[8], bytes[9]]);
+                reg.pc = u16::from_le_bytes([bytes[10], bytes[11]]);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        let bytes = self.register_bytes();
+        match register_span(args) {
+            Some(span) => to_hex(&bytes[span]),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let Some((number, value)) = args.split_once('=') else {
+            return "E01".to_string();
+        };
+        let (Some(span), Some(value)) = (register_span(number), from_hex(value)) else {
+            return "E01".to_string();
+        };
+        if value.len() != span.len() {
+            return "E01".to_string();
+        }
+
+        let reg = &mut self.debugger.gb.cpu.reg;
+        let word = u16::from_le_bytes([value[0], *value.get(1).unwrap_or(&0)]);
+        match span.start {
+            0 => reg.a = value[0],
+            1 => reg.f = value[0] & 0xF0,
+            2 => reg.b = value[0],
+            3 => reg.c = value[0],
+            4 => reg.d = value[0],
+            5 => reg.e = value[0],
+            6 => reg.h = value[0],
+            7 => reg.l = value[0],
+            8 => reg.sp = word,
+            _ => reg.pc = word,
+        }
+        "OK".to_string()
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let Some((addr, length)) = parse_range(args) else {
+            return "E01".to_string();
+        };
+        let bytes: Vec<u8> = (0..length).map(|i| self.debugger.gb.read(addr.wrapping_add(i))).collect();
+        to_hex(&bytes)
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let Some((range, data)) = args.split_once(':') else {
+            return "E01".to_string();
+        };
+        match (parse_range(range), from_hex(data)) {
+            (Some((addr, length)), Some(bytes)) if bytes.len() == length as usize => {
+                self.write_bytes(addr, &bytes);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_memory_binary(&mut self, args: &str) -> String {
+        let Some((range, data)) = args.split_once(':') else {
+            return "E01".to_string();
+        };
+        // '}' escapes the next byte XOR 0x20
+        let mut bytes = Vec::new();
+        let mut escaped = false;
+        for c in data.chars() {
+            let byte = c as u8;
+            match (escaped, byte) {
+                (false, b'}') => escaped = true,
+                (true, _) => {
+                    bytes.push(byte ^ 0x20);
+                    escaped = false;
+                }
+                (false, _) => bytes.push(byte),
+            }
+        }
+        match parse_range(range) {
+            Some((addr, length)) if bytes.len() == length as usize => {
+                self.write_bytes(addr, &bytes);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    // Goes through the bus, so writes to ROM reach the mapper registers
+    fn write_bytes(&mut self, addr: u16, bytes: &[u8]) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.debugger.gb.write(addr.wrapping_add(i as u16), byte);
+        }
+    }
+
+    fn parse_point(args: &str) -> Option<(PointKind, u16, u16)> {
+        let mut fields = args.split(';').next()?.split(',');
+        let kind = PointKind::parse(fields.next()?)?;
+        let addr = parse_hex(fields.next()?)?;
+        let length = parse_hex(fields.next()?)?;
+        Some((kind, addr, length))
+    }
+
+    fn insert_point(&mut self, args: &str) -> String {
+        let Some((kind, addr, length)) = Self::parse_point(args) else {
+            return String::new();
+        };
+        if self.points.contains_key(&(kind, addr, length)) {
+            return "OK".to_string();
+        }
+        let end = addr.saturating_add(length.max(1) - 1);
+        let id = match kind {
+            PointKind::Software | PointKind::Hardware => self.debugger.add_breakpoint(None, addr, None),
+            PointKind::Write => self.debugger.add_watchpoint(addr..=end, WatchKind::Write),
+            PointKind::Read => self.debugger.add_watchpoint(addr..=end, WatchKind::Read),
+            PointKind::Access => self.debugger.add_watchpoint(addr..=end, WatchKind::ReadWrite),
+        };
+        self.points.insert((kind, addr, length), id);
+        "OK".to_string()
+    }
+
+    fn remove_point(&mut self, args: &str) -> String {
+        let Some(key) = Self::parse_point(args) else {
+            return String::new();
+        };
+        if let Some(id) = self.points.remove(&key) {
+            self.debugger.remove(id);
+        }
+        "OK".to_string()
+    }
+
+    // Runs in frame sized slices so Ctrl-C gets noticed, None if the client left
+    fn resume(&mut self, step: bool) -> Option<String> {
+        let reason = if step {
+            self.debugger.step_in()
+        } else {
+            loop {
+                let reason = self.debugger.resume_for(CYCLES_PER_FRAME as u64);
+                if reason != StopReason::CycleLimit {
+                    break reason;
+                }
+                match self.input.try_recv() {
+                    Ok(INTERRUPT_BYTE) => return Some(format!("S{:02x}", SIGINT)),
+                    Ok(_) | Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => return None,
+                }
+            }
+        };
+        Some(self.stop_reply(reason))
+    }
+
+    fn stop_reply(&self, reason: StopReason) -> String {
+        let kind_of = |id: usize| {
+            self.points.iter().find(|(_, &point)| point == id).map(|(&(kind, _, _), _)| kind)
+        };
+        match reason {
+            StopReason::Breakpoint(id) => match kind_of(id) {
+                Some(PointKind::Hardware) => format!("T{:02x}hwbreak:;", SIGTRAP),
+                _ => format!("T{:02x}swbreak:;", SIGTRAP),
+            },
+            StopReason::SoftwareBreakpoint => format!("T{:02x}swbreak:;", SIGTRAP),
+            StopReason::Watchpoint { id, addr, .. } => {
+                let name = match kind_of(id) {
+                    Some(PointKind::Write) => "watch",
+                    Some(PointKind::Read) => "rwatch",
+                    _ => "awatch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, name, addr)
+            }
+            StopReason::Locked => format!("S{:02x}", SIGILL),
+            StopReason::Step | StopReason::FrameEnd | StopReason::CycleLimit => format!("S{:02x}", SIGTRAP),
+        }
+    }
+}
+
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    u16::from_str_radix(text, 16).ok()
+}
+
+// "addr,length"
+fn parse_range(text: &str) -> Option<(u16, u16)> {
+    let (addr, length) = text.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(length)?))
+}
+
+// Byte range of register `number` within the `g` packet
+fn register_span(number: &str) -> Option<std::ops::Range<usize>> {
+    let number = usize::from_str_radix(number, 16).ok()?;
+    match number {
+        0..=7 => Some(number..number + 1),
+        8 | 9 => {
+            let start = 8 + (number - 8) * 2;
+            Some(start..start + 2)
+        }
+        _ => None,
+    }
+}
+
+// Answers a qXfer "offset,length" request, 'l' marks the last chunk
+fn read_chunk(document: &str, range: &str) -> String {
+    let Some((offset, length)) = range.split_once(',') else {
+        return "E01".to_string();
+    };
+    let (Ok(offset), Ok(length)) = (usize::from_str_radix(offset, 16), usize::from_str_radix(length, 16)) else {
+        return "E01".to_string();
+    };
+    let bytes = document.as_bytes();
+    if offset >= bytes.len() {
+        return "l".to_string();
+    }
+    let end = (offset + length).min(bytes.len());
+    let marker = if end == bytes.len() { 'l' } else { 'm' };
+    format!("{}{}", marker, &document[offset..end])
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::cartridge::{self, Cartridge};
+    use crate::common::Model;
+    use crate::gameboy::GameBoy;
+    use std::io::Cursor;
+
+    const PROGRAM: &str = r#"
+        SECTION "code", ROM0[$0150]
+        start:
+            ld a, 3
+            ld [$c000], a
+            ld a, [$c000]
+            call inner
+        spin:
+            jr spin
+        inner:
+            ret
+    "#;
+
+    fn stub<R: Read + Send + 'static>(input: R) -> (GdbStub<Vec<u8>>, HashMap<String, u16>) {
+        let program = asm::assemble_program(PROGRAM).unwrap();
+        let cartridge = Cartridge::new(cartridge::test_rom(&program)).unwrap();
+        let debugger = Debugger::new(GameBoy::with_model(cartridge, Model::Dmg));
+        (GdbStub::new(debugger, input, Vec::new()), program.symbols)
+    }
+
+    fn framed(data: &str) -> String {
+        format!("${}#{:02x}", data, checksum_of(data.as_bytes()))
+    }
+
+    fn reply<W: Write>(stub: &mut GdbStub<W>, packet: &str) -> String {
+        match stub.handle(packet) {
+            Action::Reply(reply) => reply,
+            _ => panic!("{} did not reply", packet),
+        }
+    }
+
+    // Runs a whole session over `input` and returns what the stub sent
+    fn session(input: &str) -> String {
+        let (mut stub, _) = stub(Cursor::new(input.as_bytes().to_vec()));
+        stub.run().unwrap();
+        String::from_utf8(stub.output).unwrap()
+    }
+
+    #[test]
+    fn packets_are_checked_and_acknowledged() {
+        assert_eq!(session(&framed("?")), format!("+{}", framed("S05")));
+        assert_eq!(session("$?#00"), "-");
+
+        // A nak asks for the last packet again
+        let output = session(&format!("{}-", framed("qAttached")));
+        assert_eq!(output, format!("+{}{}", framed("1"), framed("1")));
+
+        // Without acks nothing is checked or acknowledged
+        let output = session(&format!("{}$qC#00", framed("QStartNoAckMode")));
+        assert_eq!(output, format!("+{}{}", framed("OK"), framed("QC1")));
+    }
+
+    #[test]
+    fn registers_use_the_target_xml_layout() {
+        let (mut stub, _) = stub(io::empty());
+        assert_eq!(reply(&mut stub, "G0aff0b0c0d0e0f10feff5001"), "OK");
+        let reg = &stub.debugger.gb.cpu.reg;
+        assert_eq!((reg.a, reg.f, reg.b, reg.l), (0x0A, 0xF0, 0x0B, 0x10));
+        assert_eq!((reg.sp, reg.pc), (0xFFFE, 0x0150));
+        assert_eq!(reply(&mut stub, "g"), "0af00b0c0d0e0f10feff5001");
+        assert_eq!(reply(&mut stub, "G0a"), "E01");
+
+        assert_eq!(reply(&mut stub, "p0"), "0a");
+        assert_eq!(reply(&mut stub, "p8"), "feff");
+        assert_eq!(reply(&mut stub, "pa"), "E01");
+        assert_eq!(reply(&mut stub, "P9=3412"), "OK");
+        assert_eq!(stub.debugger.gb.cpu.reg.pc, 0x1234);
+        assert_eq!(reply(&mut stub, "P1=ff"), "OK");
+        assert_eq!(stub.debugger.gb.cpu.reg.f, 0xF0);
+        assert_eq!(reply(&mut stub, "P8=12"), "E01");
+    }
+
+    #[test]
+    fn memory_is_read_and_written() {
+        let (mut stub, _) = stub(io::empty());
+        assert_eq!(reply(&mut stub, "Mc000,2:abcd"), "OK");
+        assert_eq!(reply(&mut stub, "mc000,2"), "abcd");
+        assert_eq!(reply(&mut stub, "Mc000,3:abcd"), "E01");
+
+        // '}', '#' and '$' are escaped in binary data
+        assert_eq!(reply(&mut stub, "Xc010,4:}]}\x03}\x04a"), "OK");
+        assert_eq!(reply(&mut stub, "mc010,4"), "7d232461");
+        assert_eq!(reply(&mut stub, "Xc010,2:a"), "E01");
+    }
+
+    #[test]
+    fn points_map_to_breakpoints_and_watchpoints() {
+        let (mut stub, symbols) = stub(io::empty());
+        let inner = format!("{:x}", symbols["inner"]);
+        assert_eq!(reply(&mut stub, &format!("Z0,{},1", inner)), "OK");
+        assert_eq!(reply(&mut stub, &format!("Z0,{},1", inner)), "OK");
+        assert_eq!(stub.debugger.breakpoints().len(), 1);
+        assert_eq!(stub.resume(false).unwrap(), "T05swbreak:;");
+        assert_eq!(stub.debugger.gb.cpu.reg.pc, symbols["inner"]);
+        assert_eq!(reply(&mut stub, &format!("z0,{},1", inner)), "OK");
+        assert!(stub.debugger.breakpoints().is_empty());
+
+        let (mut stub, _) = self::stub(io::empty());
+        assert_eq!(reply(&mut stub, "Z2,c000,1"), "OK");
+        let watch = &stub.debugger.watchpoints()[0];
+        assert_eq!((watch.range.clone(), watch.kind), (0xC000..=0xC000, WatchKind::Write));
+        assert_eq!(stub.resume(false).unwrap(), "T05watch:c000;");
+        assert_eq!(reply(&mut stub, "z2,c000,1"), "OK");
+        assert_eq!(reply(&mut stub, "Z3,bfff,2"), "OK");
+        assert_eq!(stub.debugger.watchpoints()[0].range, 0xBFFF..=0xC000);
+        assert_eq!(stub.resume(false).unwrap(), "T05rwatch:c000;");
+
+        let (mut stub, symbols) = self::stub(io::empty());
+        assert_eq!(reply(&mut stub, &format!("Z1,{:x},1", symbols["inner"])), "OK");
+        assert_eq!(stub.resume(false).unwrap(), "T05hwbreak:;");
+        assert_eq!(reply(&mut stub, "Z4,c000,1"), "OK");
+        assert_eq!(stub.debugger.watchpoints()[0].kind, WatchKind::ReadWrite);
+        assert_eq!(reply(&mut stub, "Z9,c000,1"), "");
+    }
+
+    #[test]
+    fn target_xml_is_sent_in_chunks() {
+        assert_eq!(read_chunk("abcdef", "0,4"), "mabcd");
+        assert_eq!(read_chunk("abcdef", "4,4"), "lef");
+        assert_eq!(read_chunk("abcdef", "6,4"), "l");
+        assert_eq!(read_chunk("abcdef", "0"), "E01");
+
+        let (mut stub, _) = stub(io::empty());
+        let mut document = String::new();
+        loop {
+            let chunk = reply(&mut stub, &format!("qXfer:features:read:target.xml:{:x},40", document.len()));
+            document.push_str(&chunk[1..]);
+            if chunk.starts_with('l') {
+                break;
+            }
+            assert!(chunk.starts_with('m'));
+        }
+        assert_eq!(document, TARGET_XML);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::common::crc32;
+use crate::inflate::inflate_with_length;
+
+// Minimal gzip reader, see https://www.rfc-editor.org/rfc/rfc1952
+// Members are a 10 byte header, optional extra field, name, comment and
+// header CRC, a DEFLATE stream, then CRC32 and size of the data. Multiple
+// members are concatenated.
+
+pub const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+const METHOD_DEFLATE: u8 = 8;
+const HEADER_SIZE: usize = 10;
+const TRAILER_SIZE: usize = 8;
+
+// Header flags
+const FLAG_HCRC: u8 = 0x02;
+const FLAG_EXTRA: u8 = 0x04;
+const FLAG_NAME: u8 = 0x08;
+const FLAG_COMMENT: u8 = 0x10;
+
+
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&GZIP_MAGIC)
+}
+
+/// Decompresses every member, `None` if the data is corrupt or truncated.
+pub fn gunzip(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        offset += member(&data[offset..], &mut out)?;
+    }
+    Some(out)
+}
+
+// Appends one member's data to `out`, returns the bytes it took up
+fn member(data: &[u8], out: &mut Vec<u8>) -> Option<usize> {
+    if !is_gzip(data) || *data.get(2)? != METHOD_DEFLATE {
+        return None;
+    }
+    let flags = *data.get(3)?;
+    let mut offset = HEADER_SIZE;
+    if flags & FLAG_EXTRA != 0 {
+        let length = u16::from_le_bytes([*data.get(offset)?, *data.get(offset + 1)?]) as usize;
+        offset += 2 + length;
+    }
+    // Zero terminated strings
+    for flag in [FLAG_NAME, FLAG_COMMENT] {
+        if flags & flag != 0 {
+            offset += data.get(offset..)?.iter().position(|&c| c == 0)? + 1;
+        }
+    }
+    if flags & FLAG_HCRC != 0 {
+        offset += 2;
+    }
+
+    let (contents, used) = inflate_with_length(data.get(offset..)?)?;
+    offset += used;
+    let trailer = data.get(offset..offset + TRAILER_SIZE)?;
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if crc32(&contents) != crc || contents.len() as u32 != size {
+        return None;
+    }
+    out.extend_from_slice(&contents);
+    Some(offset + TRAILER_SIZE)
+}
+
+/// One gzip member holding `data`, named `name` if given, for tests.
+#[cfg(test)]
+pub(crate) fn test_member(name: Option<&str>, data: &[u8]) -> Vec<u8> {
+    let flags = if name.is_some() { FLAG_NAME } else { 0 };
+    let mut member = vec![GZIP_MAGIC[0], GZIP_MAGIC[1], METHOD_DEFLATE, flags, 0, 0, 0, 0, 0, 0xFF];
+    if let Some(name) = name {
+        member.extend_from_slice(name.as_bytes());
+        member.push(0);
+    }
+    member.extend_from_slice(&crate::deflate::deflate(data));
+    member.extend_from_slice(&crc32(data).to_le_bytes());
+    member.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    member
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &[u8] = b"Thank you Mario! But our princess is in another castle!";
+
+    #[test]
+    fn gunzips_members() {
+        let member = test_member(Some("castle.txt"), TEXT);
+        assert!(is_gzip(&member));
+        assert_eq!(gunzip(&member).unwrap(), TEXT);
+
+        let both = [test_member(None, b"first "), test_member(None, TEXT)].concat();
+        assert_eq!(gunzip(&both).unwrap(), [&b"first "[..], TEXT].concat());
+    }
+
+    #[test]
+    fn skips_optional_header_fields() {
+        let plain = test_member(None, TEXT);
+        let mut member = plain[..HEADER_SIZE].to_vec();
+        member[3] = FLAG_EXTRA | FLAG_NAME | FLAG_COMMENT | FLAG_HCRC;
+        member.extend_from_slice(&[3, 0, b'x', b'y', b'z']);
+        member.extend_from_slice(b"name\0a comment\0");
+        member.extend_from_slice(&[0x12, 0x34]);
+        member.extend_from_slice(&plain[HEADER_SIZE..]);
+        assert_eq!(gunzip(&member).unwrap(), TEXT);
+    }
+
+    #[test]
+    fn rejects_damaged_members() {
+        let member = test_member(None, TEXT);
+        assert_eq!(gunzip(&member[..member.len() - 1]), None);
+
+        let mut wrong_crc = member.clone();
+        let crc = member.len() - TRAILER_SIZE;
+        wrong_crc[crc] ^= 0x01;
+        assert_eq!(gunzip(&wrong_crc), None);
+
+        let mut wrong_method = member.clone();
+        wrong_method[2] = 0;
+        assert_eq!(gunzip(&wrong_method), None);
+        assert!(!is_gzip(b"PK\x03\x04"));
+    }
+}
@@ -0,0 +1,311 @@
+// Raw DEFLATE decoder (RFC 1951), used for zip archives and .bk2 movies.
+// Follows the structure of zlib's `puff`: canonical Huffman codes decoded
+// one bit at a time, which is plenty fast for ROM sized inputs.
+
+const MAX_BITS: usize = 15;
+const MAX_LITERAL_CODES: usize = 288;
+const MAX_DISTANCE_CODES: usize = 30;
+
+// Base values and extra bits for length codes 257-285
+pub(crate) const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+pub(crate) const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+// Base values and extra bits for distance codes 0-29
+pub(crate) const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+pub(crate) const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+// Order code length code lengths are sent in dynamic blocks
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u32,
+    count: u32,
+}
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, pos: 0, buffer: 0, count: 0 }
+    }
+
+    // Least significant bit first, as DEFLATE packs them
+    fn bits(&mut self, n: u32) -> Option<u32> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos)?;
+            self.pos += 1;
+            self.buffer |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1u64 << n) - 1) as u32;
+        self.buffer >>= n;
+        self.count -= n;
+        Some(value)
+    }
+
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let slice = self.data.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(slice)
+    }
+
+    /// Bytes consumed so far, whole bytes only.
+    fn position(&self) -> usize {
+        self.pos - (self.count / 8) as usize
+    }
+}
+
+// Canonical Huffman code: number of codes per length and the symbols in code order
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+impl Huffman {
+    fn new(lengths: &[u8]) -> Option<Self> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+
+        // Reject over-subscribed codes, incomplete ones are allowed
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return None;
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for length in 1..MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Some(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Option<u16> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for &count in &self.counts[1..] {
+            code |= reader.bits(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return self.symbols.get((index + code - first) as usize).copied();
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        None
+    }
+}
+
+
+/// Decompresses a raw DEFLATE stream, `None` if it is corrupt or truncated.
+pub fn inflate(data: &[u8]) -> Option<Vec<u8>> {
+    inflate_with_length(data).map(|(out, _)| out)
+}
+
+/// Like `inflate`, also returning how many input bytes the stream used so
+/// container formats can find what follows it.
+pub fn inflate_with_length(data: &[u8]) -> Option<(Vec<u8>, usize)> {
+    let mut reader = BitReader::new(data);
+    let mut out = Vec::with_capacity(data.len() * 3);
+
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => stored_block(&mut reader, &mut out)?,
+            1 => {
+                let (literals, distances) = fixed_codes();
+                codes_block(&mut reader, &mut out, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut reader)?;
+                codes_block(&mut reader, &mut out, &literals, &distances)?;
+            }
+            _ => return None,
+        }
+        if last {
+            return Some((out, reader.position()));
+        }
+    }
+}
+
+fn stored_block(reader: &mut BitReader, out: &mut Vec<u8>) -> Option<()> {
+    reader.align();
+    let header = reader.bytes(4)?;
+    let length = u16::from_le_bytes([header[0], header[1]]);
+    let complement = u16::from_le_bytes([header[2], header[3]]);
+    if length != !complement {
+        return None;
+    }
+    out.extend_from_slice(reader.bytes(length as usize)?);
+    Some(())
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; MAX_LITERAL_CODES];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    let literals = Huffman::new(&lengths).expect("fixed code is valid");
+    let distances = Huffman::new(&[5; MAX_DISTANCE_CODES]).expect("fixed code is valid");
+    (literals, distances)
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Option<(Huffman, Huffman)> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_count = reader.bits(4)? as usize + 4;
+    if literal_count > MAX_LITERAL_CODES || distance_count > MAX_DISTANCE_CODES {
+        return None;
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &index in &CODE_LENGTH_ORDER[..code_count] {
+        code_lengths[index] = reader.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths)?;
+
+    // Literal and distance lengths share one run length coded sequence
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let symbol = code_lengths.decode(reader)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last()?, 3 + reader.bits(2)?),
+            17 => (0, 3 + reader.bits(3)?),
+            18 => (0, 11 + reader.bits(7)?),
+            _ => return None,
+        };
+        lengths.extend(std::iter::repeat_n(value, repeat as usize));
+    }
+    if lengths.len() != literal_count + distance_count || lengths[256] == 0 {
+        return None;
+    }
+
+    let literals = Huffman::new(&lengths[..literal_count])?;
+    let distances = Huffman::new(&lengths[literal_count..])?;
+    Some((literals, distances))
+}
+
+fn codes_block(reader: &mut BitReader, out: &mut Vec<u8>, literals: &Huffman, distances: &Huffman) -> Option<()> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Some(()),
+            _ => {
+                let index = symbol - 257;
+                let length = *LENGTH_BASE.get(index)? as usize + reader.bits(*LENGTH_EXTRA.get(index)? as u32)? as usize;
+                let index = distances.decode(reader)? as usize;
+                let distance = *DISTANCE_BASE.get(index)? as usize + reader.bits(*DISTANCE_EXTRA.get(index)? as u32)? as usize;
+                if distance > out.len() {
+                    return None;
+                }
+                // Copies may overlap their own output
+                let start = out.len() - distance;
+                for i in 0..length {
+                    out.push(out[start + i]);
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FOX: &[u8] = b"The quick brown fox jumps over the lazy dog. ";
+    const RAIN: &[u8] = b"abracadabra abracadabra abracadabra AAAAAAAAAAAAAAAAAAAAAAAA the rain in spain stays mainly in the plain; the rain in spain";
+
+    // Made with zlib at level 9: FOX four times, and RAIN with the default
+    // strategy (fixed codes) and Huffman only (dynamic codes)
+    const FOX_FIXED: &[u8] = &[
+        0x0B, 0xC9, 0x48, 0x55, 0x28, 0x2C, 0xCD, 0x4C, 0xCE, 0x56, 0x48, 0x2A, 0xCA, 0x2F, 0xCF, 0x53,
+        0x48, 0xCB, 0xAF, 0x50, 0xC8, 0x2A, 0xCD, 0x2D, 0x28, 0x56, 0xC8, 0x2F, 0x4B, 0x2D, 0x52, 0x28,
+        0x01, 0x4A, 0xE7, 0x24, 0x56, 0x55, 0x2A, 0xA4, 0xE4, 0xA7, 0xEB, 0x29, 0x84, 0x0C, 0x0E, 0xC5,
+        0x00,
+    ];
+    const RAIN_FIXED: &[u8] = &[
+        0x4B, 0x4C, 0x2A, 0x4A, 0x4C, 0x4E, 0x4C, 0x49, 0x04, 0x52, 0x0A, 0x89, 0x38, 0xD8, 0x8E, 0x38,
+        0x80, 0x42, 0x49, 0x46, 0xAA, 0x42, 0x51, 0x62, 0x66, 0x9E, 0x02, 0x10, 0x15, 0x17, 0x80, 0x18,
+        0xC5, 0x25, 0x89, 0x95, 0xC5, 0x0A, 0xB9, 0x40, 0x66, 0x4E, 0x25, 0x48, 0x14, 0xA4, 0xA2, 0x20,
+        0x07, 0xC8, 0xB5, 0xC6, 0x54, 0x0C, 0x00,
+    ];
+    const RAIN_DYNAMIC: &[u8] = &[
+        0x05, 0xC1, 0xCB, 0x09, 0x04, 0x31, 0x0C, 0x44, 0xC1, 0x54, 0x3A, 0x8F, 0x3D, 0xBD, 0x50, 0xDA,
+        0x1F, 0xB0, 0x60, 0x56, 0x18, 0xD9, 0x97, 0xC9, 0x7E, 0xAA, 0xDC, 0xCA, 0xDD, 0xC3, 0xAD, 0x2C,
+        0xB7, 0x72, 0xF7, 0x70, 0x2B, 0xCB, 0xAD, 0xDC, 0x3D, 0xDC, 0xCA, 0x02, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0xD0, 0x5D, 0x53, 0xE5, 0x48, 0x45, 0xEA, 0x6C, 0x47, 0xEA, 0x5C, 0xBF, 0x47, 0x7F, 0x47,
+        0x3E, 0xAF, 0x22, 0x75, 0xD7, 0xD4, 0x7E, 0x1C, 0xF9, 0xD3, 0x5D, 0x53, 0xE5, 0x48, 0x45, 0xEA,
+        0x6C, 0x47, 0x7E,
+    ];
+    // BFINAL clear, stored, length 7
+    const STORED: &[u8] = &[0x00, 0x07, 0x00, 0xF8, 0xFF, b's', b't', b'o', b'r', b'e', b'd', b'!'];
+
+    #[test]
+    fn inflates_every_block_type() {
+        assert_eq!(inflate(FOX_FIXED).unwrap(), FOX.repeat(4));
+        assert_eq!(inflate(RAIN_FIXED).unwrap(), RAIN);
+        assert_eq!(inflate(RAIN_DYNAMIC).unwrap(), RAIN);
+        // A literal, then length 3 at distance 1 copying its own output
+        assert_eq!(inflate(&[0x4B, 0x04, 0x02, 0x00]).unwrap(), b"aaaa");
+
+        let mut last = STORED.to_vec();
+        last[0] = 0x01;
+        assert_eq!(inflate(&last).unwrap(), b"stored!");
+    }
+
+    #[test]
+    fn blocks_follow_each_other() {
+        let stream = [STORED, RAIN_DYNAMIC].concat();
+        assert_eq!(inflate(&stream).unwrap(), [b"stored!", RAIN].concat());
+    }
+
+    #[test]
+    fn reports_the_bytes_used() {
+        let stream = [FOX_FIXED, b"trailer"].concat();
+        let (out, used) = inflate_with_length(&stream).unwrap();
+        assert_eq!(out, FOX.repeat(4));
+        assert_eq!(used, FOX_FIXED.len());
+    }
+
+    #[test]
+    fn rejects_corrupt_streams() {
+        assert_eq!(inflate(&[]), None);
+        assert_eq!(inflate(&FOX_FIXED[..FOX_FIXED.len() / 2]), None, "truncated");
+        assert_eq!(inflate(STORED), None, "no final block");
+        assert_eq!(inflate(&[0x07]), None, "block type 3");
+        assert_eq!(inflate(&[0x01, 0x07, 0x00, 0x00, 0x00]), None, "bad stored length complement");
+        // Fixed block with a match before any output: length 3, distance 1
+        assert_eq!(inflate(&[0x03, 0x02, 0x00]), None);
+    }
+}
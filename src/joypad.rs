@@ -0,0 +1,85 @@
+use crate::common::INTERRUPT_JOYPAD;
+use crate::savestate::{Savable, StateError, StateReader, StateWriter};
+
+// Joypad register at 0xFF00, see https://gbdev.io/pandocs/Joypad_Input.html
+
+// Bit flags for `Joypad::set_pressed`, one per button
+pub const BUTTON_RIGHT: u8 = 0x01;
+pub const BUTTON_LEFT: u8 = 0x02;
+pub const BUTTON_UP: u8 = 0x04;
+pub const BUTTON_DOWN: u8 = 0x08;
+pub const BUTTON_A: u8 = 0x10;
+pub const BUTTON_B: u8 = 0x20;
+pub const BUTTON_SELECT: u8 = 0x40;
+pub const BUTTON_START: u8 = 0x80;
+
+
+#[derive(Debug, Clone)]
+pub struct Joypad {
+    select: u8,   // Bits 4-5 as last written, active low
+    pressed: u8,  // BUTTON_* flags, active high
+    pub interrupts: u8,
+}
+impl Default for Joypad {
+    fn default() -> Self {
+        Joypad { select: 0x30, pressed: 0, interrupts: 0 }
+    }
+}
+impl Joypad {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&self) -> u8 {
+        self.read_buttons(self.pressed)
+    }
+
+    /// P1 as it reads with `buttons` held instead, for extra SGB controllers.
+    pub fn read_buttons(&self, buttons: u8) -> u8 {
+        0xC0 | self.select | lines(self.select, buttons)
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.select = value & 0x30;
+    }
+
+    pub fn pressed(&self) -> u8 {
+        self.pressed
+    }
+
+    /// Replaces the set of held buttons, raising the joypad interrupt on new presses.
+    pub fn set_pressed(&mut self, buttons: u8) {
+        let before = lines(self.select, self.pressed);
+        self.pressed = buttons;
+        // Any input line going from high to low requests the interrupt
+        if before & !lines(self.select, self.pressed) != 0 {
+            self.interrupts |= INTERRUPT_JOYPAD;
+        }
+    }
+}
+
+// Lower nibble of 0xFF00 for a selection, active low
+fn lines(select: u8, pressed: u8) -> u8 {
+    let mut low = 0;
+    if select & 0x10 == 0 {
+        low |= pressed & 0x0F;
+    }
+    if select & 0x20 == 0 {
+        low |= pressed >> 4;
+    }
+    !low & 0x0F
+}
+impl Savable for Joypad {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.select);
+        w.u8(self.pressed);
+        w.u8(self.interrupts);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.select = r.u8()? & 0x30;
+        self.pressed = r.u8()?;
+        self.interrupts = r.u8()?;
+        Ok(())
+    }
+}
@@ -1,6 +1,42 @@
-mod cpu;
+pub mod cpu;
 mod bus;
-mod mmu;
+pub mod mmu;
 pub mod cartridge;
 pub mod common;
 pub mod opcode;
+pub mod step;
+pub mod mbc;
+pub mod camera;
+pub mod timer;
+pub mod dma;
+pub mod ppu;
+pub mod apu;
+pub mod resample;
+pub mod joypad;
+pub mod sgb;
+pub mod serial;
+pub mod link;
+pub mod printer;
+pub mod gameboy;
+pub mod boot;
+pub mod cheats;
+pub mod sst;
+pub mod disasm;
+pub mod asm;
+pub mod debugger;
+pub mod gdb;
+pub mod trace;
+pub mod savestate;
+pub mod rewind;
+pub mod inflate;
+pub mod deflate;
+pub mod zip;
+pub mod gzip;
+pub mod patch;
+pub mod png;
+pub mod video;
+pub mod wav;
+pub mod vgm;
+pub mod capture;
+pub mod gbs;
+pub mod movie;
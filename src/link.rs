@@ -0,0 +1,354 @@
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::common::CYCLES_PER_FRAME;
+use crate::gameboy::GameBoy;
+
+// Link cables for the serial port. A transfer is one byte each way: the
+// side driving the clock calls `exchange`, the other side finds the byte
+// through `poll` and answers with what it had in SB. The serial port goes
+// through `start` instead, so ports that can't answer straight away hand
+// the reply over later through `reply` and the clock holds until then.
+//
+// The socket transport sends two byte messages, a tag and the data:
+//   TRANSFER  the sender drives the clock, answer with REPLY
+//   REPLY     the answer to a TRANSFER
+
+const TAG_TRANSFER: u8 = 0x01;
+const TAG_REPLY: u8 = 0x02;
+
+// How long to wait for the other emulator before treating it as unplugged
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+
+/// One end of a link cable.
+pub trait LinkPort {
+    /// Sends a byte as the clock master and returns the byte the other side
+    /// shifted back, 0xFF when nobody answers.
+    fn exchange(&mut self, outgoing: u8) -> u8;
+    /// Like `exchange` but without waiting, `None` when the answer is still
+    /// on its way. Only ports with a slow other side need to override it.
+    fn start(&mut self, outgoing: u8) -> Option<u8> {
+        Some(self.exchange(outgoing))
+    }
+    /// Answer to a `start` that returned `None`, `None` while it is still
+    /// pending. Never blocks.
+    fn reply(&mut self) -> Option<u8> {
+        Some(0xFF)
+    }
+    /// Checks for a byte clocked in by the other side. `reply` is what gets
+    /// shifted back, `None` when no externally clocked transfer is armed,
+    /// in which case nothing is received.
+    fn poll(&mut self, reply: Option<u8>) -> Option<u8>;
+}
+
+/// No cable, every bit reads back as 1.
+pub struct Disconnected;
+impl LinkPort for Disconnected {
+    fn exchange(&mut self, _outgoing: u8) -> u8 {
+        0xFF
+    }
+    fn poll(&mut self, _reply: Option<u8>) -> Option<u8> {
+        None
+    }
+}
+
+
+// Shared between the two ends of a `LocalLink`, indexed by side
+#[derive(Default)]
+struct Wire {
+    waiting: [Option<u8>; 2],  // SB of a side with an armed external clock transfer
+    inbox: [Option<u8>; 2],    // Byte clocked in by the other side
+}
+
+/// Cable between two machines in the same process, see `LinkedPair`.
+pub struct LocalLink {
+    wire: Rc<RefCell<Wire>>,
+    side: usize,
+}
+impl LocalLink {
+    pub fn pair() -> (LocalLink, LocalLink) {
+        let wire = Rc::new(RefCell::new(Wire::default()));
+        (LocalLink { wire: wire.clone(), side: 0 }, LocalLink { wire, side: 1 })
+    }
+}
+impl LinkPort for LocalLink {
+    fn exchange(&mut self, outgoing: u8) -> u8 {
+        let mut wire = self.wire.borrow_mut();
+        let other = 1 - self.side;
+        match wire.waiting[other].take() {
+            Some(reply) => {
+                wire.inbox[other] = Some(outgoing);
+                reply
+            }
+            None => 0xFF,
+        }
+    }
+
+    fn poll(&mut self, reply: Option<u8>) -> Option<u8> {
+        let mut wire = self.wire.borrow_mut();
+        let incoming = wire.inbox[self.side].take();
+        wire.waiting[self.side] = if incoming.is_some() { None } else { reply };
+        incoming
+    }
+}
+
+/// Two machines joined by a `LocalLink`, stepped so neither gets more than
+/// an instruction ahead of the other.
+pub struct LinkedPair {
+    pub a: GameBoy,
+    pub b: GameBoy,
+}
+impl LinkedPair {
+    pub fn new(mut a: GameBoy, mut b: GameBoy) -> Self {
+        let (port_a, port_b) = LocalLink::pair();
+        a.mmu.serial.connect(Box::new(port_a));
+        b.mmu.serial.connect(Box::new(port_b));
+        LinkedPair { a, b }
+    }
+
+    /// Steps whichever machine is behind.
+    pub fn step(&mut self) {
+        if self.a.cycles() <= self.b.cycles() {
+            self.a.step();
+        } else {
+            self.b.step();
+        }
+    }
+
+    /// Runs until `a` finishes a frame, keeping `b` alongside.
+    pub fn run_frame(&mut self) {
+        let frame = self.a.frame_count();
+        let start = self.a.cycles();
+        while self.a.frame_count() == frame && self.a.cycles() - start < CYCLES_PER_FRAME as u64 {
+            self.step();
+        }
+    }
+}
+
+
+/// Cable to another emulator process over TCP or a Unix domain socket.
+pub struct SocketLink {
+    output: Box<dyn Write + Send>,
+    input: Receiver<[u8; 2]>,
+    sent: Option<Instant>,  // When the transfer `start` sent without a reply yet went out
+}
+impl SocketLink {
+    /// Waits for the other emulator to connect.
+    pub fn tcp_listen<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        SocketLink::tcp(stream)
+    }
+    pub fn tcp_connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        SocketLink::tcp(TcpStream::connect(addr)?)
+    }
+    fn tcp(stream: TcpStream) -> io::Result<Self> {
+        // Every transfer is a tiny round trip
+        stream.set_nodelay(true)?;
+        Ok(SocketLink::new(stream.try_clone()?, stream))
+    }
+
+    #[cfg(unix)]
+    pub fn unix_listen<P: AsRef<std::path::Path>>(path: P) -> io::Result<Self> {
+        let (stream, _) = std::os::unix::net::UnixListener::bind(path)?.accept()?;
+        Ok(SocketLink::new(stream.try_clone()?, stream))
+    }
+    #[cfg(unix)]
+    pub fn unix_connect<P: AsRef<std::path::Path>>(path: P) -> io::Result<Self> {
+        let stream = std::os::unix::net::UnixStream::connect(path)?;
+        Ok(SocketLink::new(stream.try_clone()?, stream))
+    }
+
+    /// Runs the protocol over any byte stream, messages are read on a
+    /// background thread so polling never blocks.
+    pub fn new<R, W>(mut reader: R, output: W) -> Self
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let (sender, input) = mpsc::channel();
+        thread::spawn(move || {
+            let mut message = [0u8; 2];
+            while reader.read_exact(&mut message).is_ok() {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        SocketLink { output: Box::new(output), input, sent: None }
+    }
+
+    fn send(&mut self, tag: u8, byte: u8) {
+        // A dead connection behaves like an unplugged cable
+        let _ = self.output.write_all(&[tag, byte]).and_then(|_| self.output.flush());
+    }
+
+    // The reply byte while waiting on a transfer of our own
+    fn answer(&mut self, message: [u8; 2]) -> Option<u8> {
+        match message {
+            [TAG_REPLY, byte] => Some(byte),
+            // Both sides drove the clock at once, the other one gets nothing back
+            [TAG_TRANSFER, _] => {
+                self.send(TAG_REPLY, 0xFF);
+                None
+            }
+            _ => None,
+        }
+    }
+}
+impl LinkPort for SocketLink {
+    fn exchange(&mut self, outgoing: u8) -> u8 {
+        self.send(TAG_TRANSFER, outgoing);
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        loop {
+            match self.input.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(message) => if let Some(byte) = self.answer(message) {
+                    return byte;
+                },
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => return 0xFF,
+            }
+        }
+    }
+
+    fn start(&mut self, outgoing: u8) -> Option<u8> {
+        self.send(TAG_TRANSFER, outgoing);
+        self.sent = Some(Instant::now());
+        self.reply()
+    }
+
+    fn reply(&mut self) -> Option<u8> {
+        let sent = self.sent?;
+        let reply = loop {
+            match self.input.try_recv() {
+                Ok(message) => if let Some(byte) = self.answer(message) {
+                    break Some(byte);
+                },
+                Err(TryRecvError::Empty) if sent.elapsed() < REPLY_TIMEOUT => break None,
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => break Some(0xFF),
+            }
+        };
+        if reply.is_some() {
+            self.sent = None;
+        }
+        reply
+    }
+
+    fn poll(&mut self, reply: Option<u8>) -> Option<u8> {
+        while let Ok(message) = self.input.try_recv() {
+            // Late replies to a transfer that already timed out are dropped
+            if let [TAG_TRANSFER, byte] = message {
+                self.send(TAG_REPLY, reply.unwrap_or(0xFF));
+                return reply.map(|_| byte);
+            }
+        }
+        None
+    }
+}
+
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use crate::common::INTERRUPT_SERIAL;
+    use crate::serial::{Serial, SERIAL_SB, SERIAL_SC};
+    use crate::step::Step;
+
+    const DEADLINE: Duration = Duration::from_secs(5);
+
+    // A socket link plus the raw other end of the connection
+    fn socket_link() -> (SocketLink, UnixStream) {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        (SocketLink::new(ours.try_clone().unwrap(), ours), theirs)
+    }
+
+    fn receive(stream: &mut UnixStream) -> [u8; 2] {
+        let mut message = [0; 2];
+        stream.read_exact(&mut message).unwrap();
+        message
+    }
+
+    #[test]
+    fn start_does_not_wait_for_the_reply() {
+        let (mut link, mut other) = socket_link();
+        let start = Instant::now();
+        assert_eq!(link.start(0x42), None);
+        assert_eq!(link.reply(), None);
+        assert!(start.elapsed() < REPLY_TIMEOUT / 2);
+        assert_eq!(receive(&mut other), [TAG_TRANSFER, 0x42]);
+
+        other.write_all(&[TAG_REPLY, 0x99]).unwrap();
+        let start = Instant::now();
+        let reply = loop {
+            if let Some(byte) = link.reply() {
+                break byte;
+            }
+            assert!(start.elapsed() < DEADLINE);
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(reply, 0x99);
+    }
+
+    #[test]
+    fn unanswered_start_times_out() {
+        let (mut link, _other) = socket_link();
+        assert_eq!(link.start(0x42), None);
+        thread::sleep(REPLY_TIMEOUT);
+        assert_eq!(link.reply(), Some(0xFF));
+        // Nothing is pending any more
+        assert_eq!(link.reply(), None);
+    }
+
+    #[test]
+    fn exchange_waits_for_the_reply() {
+        let (mut link, mut other) = socket_link();
+        let answer = thread::spawn(move || {
+            let message = receive(&mut other);
+            other.write_all(&[TAG_REPLY, 0x99]).unwrap();
+            message
+        });
+        assert_eq!(link.exchange(0x42), 0x99);
+        assert_eq!(answer.join().unwrap(), [TAG_TRANSFER, 0x42]);
+        // With nobody answering it gives up
+        assert_eq!(link.exchange(0x42), 0xFF);
+    }
+
+    #[test]
+    fn serial_clock_holds_until_the_reply_arrives() {
+        let (link, mut other) = socket_link();
+        let mut serial = Serial::new();
+        serial.connect(Box::new(link));
+        serial.write(SERIAL_SB, 0x42);
+        serial.write(SERIAL_SC, 0x81);
+
+        // Far longer than eight bits take
+        for _ in 0..1000 {
+            serial.step(100);
+        }
+        assert_eq!(serial.interrupts, 0);
+        assert_eq!(serial.read(SERIAL_SC) & 0x80, 0x80);
+        assert_eq!(receive(&mut other), [TAG_TRANSFER, 0x42]);
+
+        other.write_all(&[TAG_REPLY, 0x99]).unwrap();
+        let start = Instant::now();
+        while serial.interrupts & INTERRUPT_SERIAL == 0 {
+            assert!(start.elapsed() < DEADLINE);
+            serial.step(100);
+        }
+        assert_eq!(serial.read(SERIAL_SB), 0x99);
+        assert_eq!(serial.read(SERIAL_SC) & 0x80, 0);
+    }
+
+    #[test]
+    fn local_link_swaps_bytes() {
+        let (mut master, mut slave) = LocalLink::pair();
+        assert_eq!(master.exchange(0x42), 0xFF, "nobody armed a transfer");
+        assert_eq!(slave.poll(Some(0x99)), None);
+        assert_eq!(master.start(0x42), Some(0x99));
+        assert_eq!(slave.poll(Some(0x99)), Some(0x42));
+    }
+}
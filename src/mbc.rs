@@ -0,0 +1,2129 @@
+use crate::camera::{ImageSource, PocketCamera};
+use crate::common::CPU_CLOCK_HZ;
+use crate::savestate::{Savable, StateError, StateReader, StateWriter};
+use crate::step::Step;
+
+// Memory bank controllers, see https://gbdev.io/pandocs/MBCs.html
+// The cartridge owns ROM and RAM, mappers only hold their registers and
+// translate CPU addresses into offsets. Save states cover those registers,
+// the RAM itself is saved by the cartridge.
+
+pub const ROM_BANK_SIZE: usize = 0x4000; // 16 KB
+pub const RAM_BANK_SIZE: usize = 0x2000; // 8 KB
+
+
+pub trait Mapper: Step + Savable {
+    /// Reads from 0x0000-0x7FFF. `ram` is there for mappers that can map
+    /// saved memory into the ROM area.
+    fn read_rom(&self, rom: &[u8], ram: &[u8], addr: u16) -> u8;
+    /// Register writes to 0x0000-0x7FFF.
+    fn write_rom(&mut self, ram: &mut [u8], addr: u16, value: u8);
+    /// Reads from 0xA000-0xBFFF, `None` while RAM is disabled.
+    fn read_ram(&self, ram: &[u8], addr: u16) -> Option<u8>;
+    /// Writes to 0xA000-0xBFFF.
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8);
+    /// ROM bank currently visible at `addr` (0x0000-0x7FFF).
+    fn rom_bank(&self, addr: u16) -> usize;
+    /// Connects the tilt sensor of MBC7 cartridges, false for other mappers.
+    fn set_accelerometer(&mut self, _source: Box<dyn AccelerometerSource>) -> bool {
+        false
+    }
+    /// Advances the mapper, for mappers that write to RAM on their own.
+    fn step_with_ram(&mut self, _ram: &mut [u8], cycles: u8) {
+        self.step(cycles);
+    }
+    /// Connects the sensor of Pocket Camera cartridges, false for other mappers.
+    fn set_image_source(&mut self, _source: Box<dyn ImageSource>) -> bool {
+        false
+    }
+}
+
+// Reads `addr` from the given 16 KB bank, wrapping the bank number to the ROM size
+pub(crate) fn rom_byte(rom: &[u8], bank: usize, addr: u16) -> u8 {
+    let banks = (rom.len() / ROM_BANK_SIZE).max(1);
+    let offset = (bank % banks) * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1));
+    rom.get(offset).copied().unwrap_or(0xFF)
+}
+
+pub(crate) fn ram_offset(ram: &[u8], bank: usize, addr: u16) -> Option<usize> {
+    if ram.is_empty() {
+        return None;
+    }
+    let offset = bank * RAM_BANK_SIZE + (addr as usize & (RAM_BANK_SIZE - 1));
+    Some(offset % ram.len())
+}
+
+
+/// ROM only carts, optionally with unbanked RAM.
+#[derive(Debug, Default)]
+pub struct NoMbc;
+
+impl Mapper for NoMbc {
+    fn read_rom(&self, rom: &[u8], _ram: &[u8], addr: u16) -> u8 {
+        rom.get(addr as usize).copied().unwrap_or(0xFF)
+    }
+    fn write_rom(&mut self, _ram: &mut [u8], _addr: u16, _value: u8) {}
+    fn read_ram(&self, ram: &[u8], addr: u16) -> Option<u8> {
+        ram_offset(ram, 0, addr).map(|o| ram[o])
+    }
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8) {
+        if let Some(o) = ram_offset(ram, 0, addr) {
+            ram[o] = value;
+        }
+    }
+    fn rom_bank(&self, addr: u16) -> usize {
+        (addr as usize) / ROM_BANK_SIZE
+    }
+}
+impl Step for NoMbc {
+    fn step(&mut self, _cycles: u8) {}
+}
+impl Savable for NoMbc {
+    fn save_state(&self, _w: &mut StateWriter) {}
+    fn load_state(&mut self, _r: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
+}
+
+
+#[derive(Debug)]
+pub struct Mbc1 {
+    ram_enabled: bool,
+    bank1: u8, // 5 bits, 0x2000-0x3FFF
+    bank2: u8, // 2 bits, 0x4000-0x5FFF
+    mode: bool,
+}
+impl Mbc1 {
+    pub fn new() -> Self {
+        Mbc1 { ram_enabled: false, bank1: 1, bank2: 0, mode: false }
+    }
+}
+impl Default for Mbc1 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Mapper for Mbc1 {
+    fn read_rom(&self, rom: &[u8], _ram: &[u8], addr: u16) -> u8 {
+        rom_byte(rom, self.rom_bank(addr), addr)
+    }
+    fn write_rom(&mut self, _ram: &mut [u8], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.bank1 = (value & 0x1F).max(1),
+            0x4000..=0x5FFF => self.bank2 = value & 0x03,
+            _ => self.mode = value & 0x01 != 0,
+        }
+    }
+    fn read_ram(&self, ram: &[u8], addr: u16) -> Option<u8> {
+        let bank = if self.mode { self.bank2 as usize } else { 0 };
+        match self.ram_enabled {
+            true => ram_offset(ram, bank, addr).map(|o| ram[o]),
+            false => None,
+        }
+    }
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8) {
+        let bank = if self.mode { self.bank2 as usize } else { 0 };
+        if let Some(o) = ram_offset(ram, bank, addr).filter(|_| self.ram_enabled) {
+            ram[o] = value;
+        }
+    }
+    fn rom_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x3FFF if self.mode => (self.bank2 as usize) << 5,
+            0x0000..=0x3FFF => 0,
+            _ => ((self.bank2 as usize) << 5) | self.bank1 as usize,
+        }
+    }
+}
+impl Step for Mbc1 {
+    fn step(&mut self, _cycles: u8) {}
+}
+impl Savable for Mbc1 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.ram_enabled);
+        w.u8(self.bank1);
+        w.u8(self.bank2);
+        w.bool(self.mode);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enabled = r.bool()?;
+        self.bank1 = r.u8()?;
+        self.bank2 = r.u8()?;
+        self.mode = r.bool()?;
+        Ok(())
+    }
+}
+
+
+/// MBC2 has 512 half-bytes of built in RAM, the cartridge allocates it.
+#[derive(Debug)]
+pub struct Mbc2 {
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+impl Mbc2 {
+    pub const RAM_SIZE: usize = 512;
+
+    pub fn new() -> Self {
+        Mbc2 { ram_enabled: false, rom_bank: 1 }
+    }
+}
+impl Default for Mbc2 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Mapper for Mbc2 {
+    fn read_rom(&self, rom: &[u8], _ram: &[u8], addr: u16) -> u8 {
+        rom_byte(rom, self.rom_bank(addr), addr)
+    }
+    fn write_rom(&mut self, _ram: &mut [u8], addr: u16, value: u8) {
+        // Address bit 8 selects between RAM enable and ROM bank
+        match addr {
+            0x0000..=0x3FFF if addr & 0x0100 == 0 => self.ram_enabled = value & 0x0F == 0x0A,
+            0x0000..=0x3FFF => self.rom_bank = (value & 0x0F).max(1),
+            _ => {}
+        }
+    }
+    fn read_ram(&self, ram: &[u8], addr: u16) -> Option<u8> {
+        match self.ram_enabled {
+            true => ram.get(addr as usize & (Self::RAM_SIZE - 1)).map(|v| v | 0xF0),
+            false => None,
+        }
+    }
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8) {
+        if self.ram_enabled {
+            if let Some(cell) = ram.get_mut(addr as usize & (Self::RAM_SIZE - 1)) {
+                *cell = value & 0x0F;
+            }
+        }
+    }
+    fn rom_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        }
+    }
+}
+impl Step for Mbc2 {
+    fn step(&mut self, _cycles: u8) {}
+}
+impl Savable for Mbc2 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.ram_enabled);
+        w.u8(self.rom_bank);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enabled = r.bool()?;
+        self.rom_bank = r.u8()?;
+        Ok(())
+    }
+}
+
+
+/// Real time clock of the MBC3, counted in emulated time so runs stay deterministic.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Rtc {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub days: u16,        // 9 bits
+    pub halted: bool,
+    pub day_carry: bool,
+    pub subsecond_cycles: u32,
+}
+impl Rtc {
+    // Register order as selected through 0x08-0x0C
+    pub fn read(&self, register: u8) -> u8 {
+        match register {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => self.days as u8,
+            _ => ((self.days >> 8) as u8 & 0x01) | ((self.halted as u8) << 6) | ((self.day_carry as u8) << 7),
+        }
+    }
+    pub fn write(&mut self, register: u8, value: u8) {
+        match register {
+            0x08 => {
+                self.seconds = value & 0x3F;
+                self.subsecond_cycles = 0;
+            }
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | value as u16,
+            _ => {
+                self.days = (self.days & 0xFF) | (((value & 0x01) as u16) << 8);
+                self.halted = value & 0x40 != 0;
+                self.day_carry = value & 0x80 != 0;
+            }
+        }
+    }
+    pub fn tick_second(&mut self) {
+        // Out of range values keep counting up to the 6/5 bit limit like hardware
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days > 0x1FF {
+            self.days = 0;
+            self.day_carry = true;
+        }
+    }
+}
+impl Step for Rtc {
+    fn step(&mut self, cycles: u8) {
+        if self.halted {
+            return;
+        }
+        self.subsecond_cycles += cycles as u32;
+        if self.subsecond_cycles >= CPU_CLOCK_HZ {
+            self.subsecond_cycles -= CPU_CLOCK_HZ;
+            self.tick_second();
+        }
+    }
+}
+impl Savable for Rtc {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.seconds);
+        w.u8(self.minutes);
+        w.u8(self.hours);
+        w.u16(self.days);
+        w.bool(self.halted);
+        w.bool(self.day_carry);
+        w.u32(self.subsecond_cycles);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        // Masked like register writes so corrupt states can't overflow the counters
+        self.seconds = r.u8()? & 0x3F;
+        self.minutes = r.u8()? & 0x3F;
+        self.hours = r.u8()? & 0x1F;
+        self.days = r.u16()? & 0x1FF;
+        self.halted = r.bool()?;
+        self.day_carry = r.bool()?;
+        self.subsecond_cycles = r.u32()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Mbc3 {
+    ram_enabled: bool,
+    rom_bank: u8,
+    ram_select: u8,   // 0x00-0x03 RAM bank, 0x08-0x0C RTC register
+    latch_armed: bool,
+    has_rtc: bool,
+    pub rtc: Rtc,
+    latched: Rtc,
+}
+impl Mbc3 {
+    pub fn new(has_rtc: bool) -> Self {
+        Mbc3 {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_select: 0,
+            latch_armed: false,
+            has_rtc,
+            rtc: Rtc::default(),
+            latched: Rtc::default(),
+        }
+    }
+}
+impl Mapper for Mbc3 {
+    fn read_rom(&self, rom: &[u8], _ram: &[u8], addr: u16) -> u8 {
+        rom_byte(rom, self.rom_bank(addr), addr)
+    }
+    fn write_rom(&mut self, _ram: &mut [u8], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank = (value & 0x7F).max(1),
+            0x4000..=0x5FFF => self.ram_select = value & 0x0F,
+            _ => {
+                // Writing 0x00 then 0x01 latches the clock
+                if self.latch_armed && value == 0x01 {
+                    self.latched = self.rtc;
+                }
+                self.latch_armed = value == 0x00;
+            }
+        }
+    }
+    fn read_ram(&self, ram: &[u8], addr: u16) -> Option<u8> {
+        if !self.ram_enabled {
+            return None;
+        }
+        match self.ram_select {
+            0x00..=0x03 => ram_offset(ram, self.ram_select as usize, addr).map(|o| ram[o]),
+            0x08..=0x0C if self.has_rtc => Some(self.latched.read(self.ram_select)),
+            _ => None,
+        }
+    }
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        match self.ram_select {
+            0x00..=0x03 => {
+                if let Some(o) = ram_offset(ram, self.ram_select as usize, addr) {
+                    ram[o] = value;
+                }
+            }
+            0x08..=0x0C if self.has_rtc => {
+                self.rtc.write(self.ram_select, value);
+                self.latched.write(self.ram_select, value);
+            }
+            _ => {}
+        }
+    }
+    fn rom_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        }
+    }
+}
+impl Step for Mbc3 {
+    fn step(&mut self, cycles: u8) {
+        if self.has_rtc {
+            self.rtc.step(cycles);
+        }
+    }
+}
+impl Savable for Mbc3 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.ram_enabled);
+        w.u8(self.rom_bank);
+        w.u8(self.ram_select);
+        w.bool(self.latch_armed);
+        self.rtc.save_state(w);
+        self.latched.save_state(w);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enabled = r.bool()?;
+        self.rom_bank = r.u8()?;
+        self.ram_select = r.u8()?;
+        self.latch_armed = r.bool()?;
+        self.rtc.load_state(r)?;
+        self.latched.load_state(r)
+    }
+}
+
+
+#[derive(Debug)]
+pub struct Mbc5 {
+    ram_enabled: bool,
+    rom_bank: u16, // 9 bits
+    ram_bank: u8,
+    has_rumble: bool,
+    pub rumble: bool,
+}
+impl Mbc5 {
+    pub fn new(has_rumble: bool) -> Self {
+        Mbc5 { ram_enabled: false, rom_bank: 1, ram_bank: 0, has_rumble, rumble: false }
+    }
+}
+impl Mapper for Mbc5 {
+    fn read_rom(&self, rom: &[u8], _ram: &[u8], addr: u16) -> u8 {
+        rom_byte(rom, self.rom_bank(addr), addr)
+    }
+    fn write_rom(&mut self, _ram: &mut [u8], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+            0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | (((value & 0x01) as u16) << 8),
+            0x4000..=0x5FFF if self.has_rumble => {
+                // Bit 3 drives the rumble motor instead of selecting RAM
+                self.rumble = value & 0x08 != 0;
+                self.ram_bank = value & 0x07;
+            }
+            0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+            _ => {}
+        }
+    }
+    fn read_ram(&self, ram: &[u8], addr: u16) -> Option<u8> {
+        match self.ram_enabled {
+            true => ram_offset(ram, self.ram_bank as usize, addr).map(|o| ram[o]),
+            false => None,
+        }
+    }
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8) {
+        if let Some(o) = ram_offset(ram, self.ram_bank as usize, addr).filter(|_| self.ram_enabled) {
+            ram[o] = value;
+        }
+    }
+    fn rom_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        }
+    }
+}
+impl Step for Mbc5 {
+    fn step(&mut self, _cycles: u8) {}
+}
+impl Savable for Mbc5 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.ram_enabled);
+        w.u16(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.bool(self.rumble);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enabled = r.bool()?;
+        self.rom_bank = r.u16()? & 0x1FF;
+        self.ram_bank = r.u8()?;
+        self.rumble = r.bool()?;
+        Ok(())
+    }
+}
+
+
+/// MMM01 multicart. The menu lives in the last 32 KB of the ROM and is
+/// what shows at power on, it then picks a game's banks and locks the
+/// mapping, after which the game sees an MBC1 confined to its own banks.
+#[derive(Debug)]
+pub struct Mmm01 {
+    ram_enabled: bool,
+    locked: bool,
+    rom_bank_low: u8,   // 5 bits, the game's MBC1 bank number
+    rom_bank_mid: u8,   // 2 bits
+    rom_bank_high: u8,  // 2 bits
+    rom_bank_mask: u8,  // 4 bits, bank bits 1-4 the game can't change
+    ram_bank_low: u8,   // 2 bits
+    ram_bank_high: u8,  // 2 bits
+    ram_bank_mask: u8,  // 2 bits, RAM bank bits the game can't change
+    mbc1_mode: bool,
+    mode_locked: bool,  // The game can't switch MBC1 modes
+    multiplex: bool,    // MBC1 mode swaps the mid ROM bits with the RAM bank
+}
+impl Mmm01 {
+    pub fn new() -> Self {
+        Mmm01 {
+            ram_enabled: false,
+            locked: false,
+            rom_bank_low: 0,
+            rom_bank_mid: 0,
+            rom_bank_high: 0,
+            rom_bank_mask: 0,
+            ram_bank_low: 0,
+            ram_bank_high: 0,
+            ram_bank_mask: 0,
+            mbc1_mode: false,
+            mode_locked: false,
+            multiplex: false,
+        }
+    }
+
+    // 16 KB banks at 0x0000 and 0x4000
+    fn banks(&self) -> (usize, usize) {
+        if !self.locked {
+            // All bank bits set, `rom_byte` wraps this to the last 32 KB
+            return (0x1FE, 0x1FF);
+        }
+        let fixed = self.rom_bank_low & (self.rom_bank_mask << 1);
+        let (mid_rom0, mid) = match self.multiplex {
+            true => (if self.mbc1_mode { self.ram_bank_low } else { 0 }, self.ram_bank_low),
+            false => (self.rom_bank_mid, self.rom_bank_mid),
+        };
+        let high = (self.rom_bank_high as usize) << 7;
+        let rom0 = high | ((mid_rom0 as usize) << 5) | fixed as usize;
+        let mut rom_n = high | ((mid as usize) << 5) | self.rom_bank_low as usize;
+        if rom_n == rom0 {
+            rom_n += 1;
+        }
+        (rom0, rom_n)
+    }
+
+    fn ram_bank(&self) -> usize {
+        let low = match self.multiplex {
+            true if self.mbc1_mode => self.rom_bank_mid,
+            true => 0,
+            false => self.ram_bank_low,
+        };
+        ((self.ram_bank_high as usize) << 2) | low as usize
+    }
+}
+impl Default for Mmm01 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Mapper for Mmm01 {
+    fn read_rom(&self, rom: &[u8], _ram: &[u8], addr: u16) -> u8 {
+        rom_byte(rom, self.rom_bank(addr), addr)
+    }
+    fn write_rom(&mut self, _ram: &mut [u8], addr: u16, value: u8) {
+        // Until the mapping is locked the menu can set every bit
+        match addr {
+            0x0000..=0x1FFF => {
+                self.ram_enabled = value & 0x0F == 0x0A;
+                if !self.locked {
+                    self.ram_bank_mask = (value >> 4) & 0x03;
+                    self.locked = value & 0x40 != 0;
+                }
+            }
+            0x2000..=0x3FFF => {
+                if !self.locked {
+                    self.rom_bank_mid = (value >> 5) & 0x03;
+                }
+                let writable = !(self.rom_bank_mask << 1) & 0x1F;
+                self.rom_bank_low = (self.rom_bank_low & !writable) | (value & writable);
+            }
+            0x4000..=0x5FFF => {
+                let writable = !self.ram_bank_mask & 0x03;
+                self.ram_bank_low = (self.ram_bank_low & !writable) | (value & writable);
+                if !self.locked {
+                    self.ram_bank_high = (value >> 2) & 0x03;
+                    self.rom_bank_high = (value >> 4) & 0x03;
+                    self.mode_locked = value & 0x40 != 0;
+                }
+            }
+            _ => {
+                if !self.mode_locked {
+                    self.mbc1_mode = value & 0x01 != 0;
+                }
+                if !self.locked {
+                    self.rom_bank_mask = (value >> 2) & 0x0F;
+                    self.multiplex = value & 0x40 != 0;
+                }
+            }
+        }
+    }
+    fn read_ram(&self, ram: &[u8], addr: u16) -> Option<u8> {
+        match self.ram_enabled {
+            true => ram_offset(ram, self.ram_bank(), addr).map(|o| ram[o]),
+            false => None,
+        }
+    }
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8) {
+        if let Some(o) = ram_offset(ram, self.ram_bank(), addr).filter(|_| self.ram_enabled) {
+            ram[o] = value;
+        }
+    }
+    fn rom_bank(&self, addr: u16) -> usize {
+        let (rom0, rom_n) = self.banks();
+        match addr {
+            0x0000..=0x3FFF => rom0,
+            _ => rom_n,
+        }
+    }
+}
+impl Step for Mmm01 {
+    fn step(&mut self, _cycles: u8) {}
+}
+impl Savable for Mmm01 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.ram_enabled);
+        w.bool(self.locked);
+        w.u8(self.rom_bank_low);
+        w.u8(self.rom_bank_mid);
+        w.u8(self.rom_bank_high);
+        w.u8(self.rom_bank_mask);
+        w.u8(self.ram_bank_low);
+        w.u8(self.ram_bank_high);
+        w.u8(self.ram_bank_mask);
+        w.bool(self.mbc1_mode);
+        w.bool(self.mode_locked);
+        w.bool(self.multiplex);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enabled = r.bool()?;
+        self.locked = r.bool()?;
+        self.rom_bank_low = r.u8()? & 0x1F;
+        self.rom_bank_mid = r.u8()? & 0x03;
+        self.rom_bank_high = r.u8()? & 0x03;
+        self.rom_bank_mask = r.u8()? & 0x0F;
+        self.ram_bank_low = r.u8()? & 0x03;
+        self.ram_bank_high = r.u8()? & 0x03;
+        self.ram_bank_mask = r.u8()? & 0x03;
+        self.mbc1_mode = r.bool()?;
+        self.mode_locked = r.bool()?;
+        self.multiplex = r.bool()?;
+        Ok(())
+    }
+}
+
+
+// Flash chip command state, see `Mbc6::write_flash`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FlashMode {
+    Read,
+    Unlock1,       // Got 0xAA at 0x5555
+    Unlock2,       // Then 0x55 at 0x2AAA, the next write is the command
+    EraseUnlock1,  // Same sequence after an 0x80 erase setup
+    EraseUnlock2,
+    EraseReady,
+    Program,       // The next write programs a byte
+    Id,            // Reads return the chip ID
+}
+impl FlashMode {
+    fn from_u8(value: u8) -> FlashMode {
+        match value {
+            1 => FlashMode::Unlock1,
+            2 => FlashMode::Unlock2,
+            3 => FlashMode::EraseUnlock1,
+            4 => FlashMode::EraseUnlock2,
+            5 => FlashMode::EraseReady,
+            6 => FlashMode::Program,
+            7 => FlashMode::Id,
+            _ => FlashMode::Read,
+        }
+    }
+}
+
+/// MBC6, the Net de Get cartridge. ROM and flash are mapped in two 8 KB
+/// halves at 0x4000 and 0x6000, RAM in two 4 KB halves at 0xA000 and
+/// 0xB000. The cartridge RAM holds the 32 KB of RAM followed by the 1 MB
+/// of flash, so both end up in the save file.
+#[derive(Debug)]
+pub struct Mbc6 {
+    ram_enabled: bool,
+    ram_banks: [u8; 2],     // 4 KB banks at 0xA000 and 0xB000
+    rom_banks: [u8; 2],     // 8 KB ROM or flash banks at 0x4000 and 0x6000
+    flash_mapped: [bool; 2],
+    flash_enabled: bool,
+    flash_write_enabled: bool,
+    flash_mode: FlashMode,
+}
+impl Mbc6 {
+    pub const RAM_SIZE: usize = 0x8000;
+    pub const FLASH_SIZE: usize = 0x10_0000;
+
+    const HALF_BANK_SIZE: usize = 0x2000;
+    const RAM_HALF_BANK_SIZE: usize = 0x1000;
+    const FLASH_SECTOR_SIZE: usize = 0x2_0000;
+    // Macronix manufacturer and device codes reported in ID mode
+    const FLASH_ID: [u8; 2] = [0xC2, 0x81];
+
+    pub fn new() -> Self {
+        Mbc6 {
+            ram_enabled: false,
+            ram_banks: [0; 2],
+            rom_banks: [0; 2],
+            flash_mapped: [false; 2],
+            flash_enabled: false,
+            flash_write_enabled: false,
+            flash_mode: FlashMode::Read,
+        }
+    }
+
+    // Offset of `addr` (0x4000-0x7FFF) into ROM or flash
+    fn banked_offset(&self, addr: u16) -> (bool, usize) {
+        let half = (addr as usize >> 13) & 1;
+        let offset = (self.rom_banks[half] as usize) * Self::HALF_BANK_SIZE + (addr as usize & (Self::HALF_BANK_SIZE - 1));
+        (self.flash_mapped[half], offset)
+    }
+
+    // Commands follow the usual JEDEC unlock sequence on flash addresses
+    // 0x5555 and 0x2AAA, that is bank 2 at 0x5555 and bank 1 at 0x4AAA
+    fn write_flash(&mut self, flash: &mut [u8], offset: usize, value: u8) {
+        if value == 0xF0 {
+            self.flash_mode = FlashMode::Read;
+            return;
+        }
+        let writable = self.flash_write_enabled;
+        self.flash_mode = match (self.flash_mode, offset, value) {
+            (FlashMode::Read | FlashMode::Id, 0x5555, 0xAA) => FlashMode::Unlock1,
+            (FlashMode::Unlock1, 0x2AAA, 0x55) => FlashMode::Unlock2,
+            (FlashMode::Unlock2, 0x5555, 0x80) => FlashMode::EraseReady,
+            (FlashMode::Unlock2, 0x5555, 0x90) => FlashMode::Id,
+            (FlashMode::Unlock2, 0x5555, 0xA0) => FlashMode::Program,
+            (FlashMode::EraseReady, 0x5555, 0xAA) => FlashMode::EraseUnlock1,
+            (FlashMode::EraseUnlock1, 0x2AAA, 0x55) => FlashMode::EraseUnlock2,
+            (FlashMode::EraseUnlock2, 0x5555, 0x10) => {
+                if writable {
+                    flash.fill(0xFF);
+                }
+                FlashMode::Read
+            }
+            (FlashMode::EraseUnlock2, _, 0x30) => {
+                if writable {
+                    let start = offset & !(Self::FLASH_SECTOR_SIZE - 1);
+                    if let Some(sector) = flash.get_mut(start..start + Self::FLASH_SECTOR_SIZE) {
+                        sector.fill(0xFF);
+                    }
+                }
+                FlashMode::Read
+            }
+            (FlashMode::Program, _, _) => {
+                // Programming can only clear bits, erasing sets them
+                if let Some(cell) = flash.get_mut(offset).filter(|_| writable) {
+                    *cell &= value;
+                }
+                FlashMode::Read
+            }
+            (FlashMode::Id, _, _) => FlashMode::Id,
+            _ => FlashMode::Read,
+        };
+    }
+}
+impl Default for Mbc6 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Mapper for Mbc6 {
+    fn read_rom(&self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
+        if addr < 0x4000 {
+            return rom.get(addr as usize).copied().unwrap_or(0xFF);
+        }
+        let (flash, offset) = self.banked_offset(addr);
+        if !flash {
+            return rom.get(offset % rom.len().max(1)).copied().unwrap_or(0xFF);
+        }
+        if !self.flash_enabled {
+            return 0xFF;
+        }
+        match self.flash_mode {
+            FlashMode::Id => Self::FLASH_ID[offset & 1],
+            _ => ram.get(Self::RAM_SIZE + offset % Self::FLASH_SIZE).copied().unwrap_or(0xFF),
+        }
+    }
+    fn write_rom(&mut self, ram: &mut [u8], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x03FF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x0400..=0x07FF => self.ram_banks[0] = value & 0x07,
+            0x0800..=0x0BFF => self.ram_banks[1] = value & 0x07,
+            0x0C00..=0x0FFF => self.flash_enabled = value & 0x01 != 0,
+            0x1000..=0x1FFF => self.flash_write_enabled = value & 0x01 != 0,
+            0x2000..=0x27FF => self.rom_banks[0] = value & 0x7F,
+            0x2800..=0x2FFF => self.flash_mapped[0] = value == 0x08,
+            0x3000..=0x37FF => self.rom_banks[1] = value & 0x7F,
+            0x3800..=0x3FFF => self.flash_mapped[1] = value == 0x08,
+            _ => {
+                let (flash, offset) = self.banked_offset(addr);
+                if flash && self.flash_enabled && ram.len() >= Self::RAM_SIZE + Self::FLASH_SIZE {
+                    self.write_flash(&mut ram[Self::RAM_SIZE..], offset % Self::FLASH_SIZE, value);
+                }
+            }
+        }
+    }
+    fn read_ram(&self, ram: &[u8], addr: u16) -> Option<u8> {
+        let half = (addr as usize >> 12) & 1;
+        let offset = (self.ram_banks[half] as usize) * Self::RAM_HALF_BANK_SIZE + (addr as usize & (Self::RAM_HALF_BANK_SIZE - 1));
+        ram.get(offset).copied().filter(|_| self.ram_enabled)
+    }
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8) {
+        let half = (addr as usize >> 12) & 1;
+        let offset = (self.ram_banks[half] as usize) * Self::RAM_HALF_BANK_SIZE + (addr as usize & (Self::RAM_HALF_BANK_SIZE - 1));
+        if let Some(cell) = ram.get_mut(offset).filter(|_| self.ram_enabled) {
+            *cell = value;
+        }
+    }
+    // In 16 KB units like the other mappers, flash counts as bank 0
+    fn rom_bank(&self, addr: u16) -> usize {
+        match self.banked_offset(addr) {
+            _ if addr < 0x4000 => 0,
+            (true, _) => 0,
+            (false, offset) => offset / ROM_BANK_SIZE,
+        }
+    }
+}
+impl Step for Mbc6 {
+    fn step(&mut self, _cycles: u8) {}
+}
+impl Savable for Mbc6 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.ram_enabled);
+        w.u8(self.ram_banks[0]);
+        w.u8(self.ram_banks[1]);
+        w.u8(self.rom_banks[0]);
+        w.u8(self.rom_banks[1]);
+        w.bool(self.flash_mapped[0]);
+        w.bool(self.flash_mapped[1]);
+        w.bool(self.flash_enabled);
+        w.bool(self.flash_write_enabled);
+        w.u8(self.flash_mode as u8);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enabled = r.bool()?;
+        self.ram_banks = [r.u8()? & 0x07, r.u8()? & 0x07];
+        self.rom_banks = [r.u8()? & 0x7F, r.u8()? & 0x7F];
+        self.flash_mapped = [r.bool()?, r.bool()?];
+        self.flash_enabled = r.bool()?;
+        self.flash_write_enabled = r.bool()?;
+        self.flash_mode = FlashMode::from_u8(r.u8()?);
+        Ok(())
+    }
+}
+
+
+/// Tilt input for MBC7 cartridges, such as Kirby Tilt 'n' Tumble.
+pub trait AccelerometerSource {
+    /// Acceleration along X (right) and Y (down) in g, level is (0, 0).
+    fn acceleration(&mut self) -> (f32, f32);
+}
+
+// 93LC56 serial EEPROM, 128 words of 16 bits driven one bit at a time.
+// After a start bit each command is 2 opcode bits and 8 address bits:
+//   10 AAAAAAAA  READ, then 16 data bits out, continuing into the next word
+//   01 AAAAAAAA  WRITE, then 16 data bits in
+//   11 AAAAAAAA  ERASE
+//   00 11xxxxxx  EWEN, enable writes    00 00xxxxxx  EWDS, disable writes
+//   00 10xxxxxx  ERAL, erase all        00 01xxxxxx  WRAL, then 16 data bits in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EepromState {
+    Idle,
+    Command,
+    Read,
+    Write,
+    WriteAll,
+}
+impl EepromState {
+    fn from_u8(value: u8) -> EepromState {
+        match value {
+            1 => EepromState::Command,
+            2 => EepromState::Read,
+            3 => EepromState::Write,
+            4 => EepromState::WriteAll,
+            _ => EepromState::Idle,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Eeprom {
+    cs: bool,
+    clk: bool,
+    di: bool,
+    output: bool,        // DO, 1 when ready
+    write_enabled: bool,
+    state: EepromState,
+    shift: u16,          // Bits clocked in or still to clock out
+    bits: u8,
+    address: u8,
+}
+impl Eeprom {
+    const WORDS: usize = 128;
+
+    fn new() -> Self {
+        Eeprom {
+            cs: false,
+            clk: false,
+            di: false,
+            output: true,
+            write_enabled: false,
+            state: EepromState::Idle,
+            shift: 0,
+            bits: 0,
+            address: 0,
+        }
+    }
+
+    // Bit 7 CS, bit 6 CLK, bit 1 DI, bit 0 DO
+    fn read(&self) -> u8 {
+        ((self.cs as u8) << 7) | ((self.clk as u8) << 6) | ((self.di as u8) << 1) | self.output as u8
+    }
+
+    fn write(&mut self, memory: &mut [u8], value: u8) {
+        let (cs, clk, di) = (value & 0x80 != 0, value & 0x40 != 0, value & 0x02 != 0);
+        if !cs {
+            self.state = EepromState::Idle;
+        } else if clk && !self.clk {
+            self.clock(memory, di);
+        }
+        self.cs = cs;
+        self.clk = clk;
+        self.di = di;
+    }
+
+    fn word(memory: &[u8], address: u8) -> u16 {
+        let i = (address as usize % Self::WORDS) * 2;
+        u16::from_le_bytes([memory.get(i).copied().unwrap_or(0xFF), memory.get(i + 1).copied().unwrap_or(0xFF)])
+    }
+
+    fn set_word(&self, memory: &mut [u8], address: u8, value: u16) {
+        let i = (address as usize % Self::WORDS) * 2;
+        if self.write_enabled && i + 1 < memory.len() {
+            memory[i..i + 2].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    // Rising clock edge with CS high
+    fn clock(&mut self, memory: &mut [u8], di: bool) {
+        match self.state {
+            EepromState::Idle => {
+                if di {
+                    self.state = EepromState::Command;
+                    self.shift = 0;
+                    self.bits = 0;
+                }
+            }
+            EepromState::Command => {
+                self.shift = (self.shift << 1) | di as u16;
+                self.bits += 1;
+                if self.bits == 10 {
+                    self.command(memory);
+                }
+            }
+            EepromState::Read => {
+                self.output = self.shift & 0x8000 != 0;
+                self.shift <<= 1;
+                self.bits -= 1;
+                if self.bits == 0 {
+                    self.address = self.address.wrapping_add(1) % Self::WORDS as u8;
+                    self.shift = Self::word(memory, self.address);
+                    self.bits = 16;
+                }
+            }
+            EepromState::Write | EepromState::WriteAll => {
+                self.shift = (self.shift << 1) | di as u16;
+                self.bits += 1;
+                if self.bits == 16 {
+                    match self.state {
+                        EepromState::Write => self.set_word(memory, self.address, self.shift),
+                        _ => (0..Self::WORDS as u8).for_each(|a| self.set_word(memory, a, self.shift)),
+                    }
+                    self.output = true;
+                    self.state = EepromState::Idle;
+                }
+            }
+        }
+    }
+
+    fn command(&mut self, memory: &mut [u8]) {
+        self.address = (self.shift & 0x7F) as u8;
+        self.bits = 0;
+        self.state = EepromState::Idle;
+        match (self.shift >> 8) & 0x03 {
+            0b10 => {
+                // A dummy 0 comes out before the data
+                self.state = EepromState::Read;
+                self.output = false;
+                self.shift = Self::word(memory, self.address);
+                self.bits = 16;
+            }
+            0b01 => {
+                self.state = EepromState::Write;
+                self.shift = 0;
+            }
+            0b11 => self.set_word(memory, self.address, 0xFFFF),
+            _ => match (self.shift >> 6) & 0x03 {
+                0b11 => self.write_enabled = true,
+                0b00 => self.write_enabled = false,
+                0b10 => (0..Self::WORDS as u8).for_each(|a| self.set_word(memory, a, 0xFFFF)),
+                _ => {
+                    self.state = EepromState::WriteAll;
+                    self.shift = 0;
+                }
+            },
+        }
+    }
+}
+impl Savable for Eeprom {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.read());
+        w.bool(self.write_enabled);
+        w.u8(self.state as u8);
+        w.u16(self.shift);
+        w.u8(self.bits);
+        w.u8(self.address);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let pins = r.u8()?;
+        self.cs = pins & 0x80 != 0;
+        self.clk = pins & 0x40 != 0;
+        self.di = pins & 0x02 != 0;
+        self.output = pins & 0x01 != 0;
+        self.write_enabled = r.bool()?;
+        self.state = EepromState::from_u8(r.u8()?);
+        self.shift = r.u16()?;
+        self.bits = r.u8()?.min(16);
+        self.address = r.u8()? & 0x7F;
+        Ok(())
+    }
+}
+
+/// MBC7 with its two axis accelerometer and 93LC56 EEPROM, which is the
+/// cartridge RAM. Registers sit at 0xA000-0xAFFF, one per 16 bytes.
+pub struct Mbc7 {
+    ram_enabled: [bool; 2],  // 0x0A to 0x0000 and 0x40 to 0x4000, both needed
+    rom_bank: u8,
+    accelerometer: Option<Box<dyn AccelerometerSource>>,
+    latch_erased: bool,
+    x: u16,
+    y: u16,
+    eeprom: Eeprom,
+}
+impl Mbc7 {
+    pub const RAM_SIZE: usize = Eeprom::WORDS * 2;
+
+    // Sensor reading when level, and how much one g moves it
+    const CENTER: f32 = 0x81D0 as f32;
+    const ONE_G: f32 = 0x70 as f32;
+    const ERASED: u16 = 0x8000;
+
+    pub fn new() -> Self {
+        Mbc7 {
+            ram_enabled: [false; 2],
+            rom_bank: 1,
+            accelerometer: None,
+            latch_erased: false,
+            x: Self::ERASED,
+            y: Self::ERASED,
+            eeprom: Eeprom::new(),
+        }
+    }
+
+    fn latch(&mut self) {
+        let (x, y) = self.accelerometer.as_mut().map_or((0.0, 0.0), |source| source.acceleration());
+        let reading = |g: f32| (Self::CENTER + g * Self::ONE_G).clamp(0.0, u16::MAX as f32) as u16;
+        self.x = reading(x);
+        self.y = reading(y);
+    }
+}
+impl Default for Mbc7 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Mapper for Mbc7 {
+    fn read_rom(&self, rom: &[u8], _ram: &[u8], addr: u16) -> u8 {
+        rom_byte(rom, self.rom_bank(addr), addr)
+    }
+    fn write_rom(&mut self, _ram: &mut [u8], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled[0] = value == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank = value & 0x7F,
+            0x4000..=0x5FFF => self.ram_enabled[1] = value == 0x40,
+            _ => {}
+        }
+    }
+    fn read_ram(&self, _ram: &[u8], addr: u16) -> Option<u8> {
+        if self.ram_enabled != [true; 2] {
+            return None;
+        }
+        let value = match (addr, (addr >> 4) & 0x0F) {
+            (0xB000..=0xBFFF, _) => 0xFF,
+            (_, 0x2) => self.x as u8,
+            (_, 0x3) => (self.x >> 8) as u8,
+            (_, 0x4) => self.y as u8,
+            (_, 0x5) => (self.y >> 8) as u8,
+            (_, 0x6) => 0x00,
+            (_, 0x8) => self.eeprom.read(),
+            _ => 0xFF,
+        };
+        Some(value)
+    }
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8) {
+        if self.ram_enabled != [true; 2] || addr > 0xAFFF {
+            return;
+        }
+        match (addr >> 4) & 0x0F {
+            // Erase with 0x55, then latch a new reading with 0xAA
+            0x0 if value == 0x55 => {
+                self.latch_erased = true;
+                self.x = Self::ERASED;
+                self.y = Self::ERASED;
+            }
+            0x1 if value == 0xAA && self.latch_erased => {
+                self.latch_erased = false;
+                self.latch();
+            }
+            0x8 => self.eeprom.write(ram, value),
+            _ => {}
+        }
+    }
+    fn rom_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        }
+    }
+    fn set_accelerometer(&mut self, source: Box<dyn AccelerometerSource>) -> bool {
+        self.accelerometer = Some(source);
+        true
+    }
+}
+impl Step for Mbc7 {
+    fn step(&mut self, _cycles: u8) {}
+}
+// The accelerometer is input, like the joypad it isn't saved
+impl Savable for Mbc7 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.ram_enabled[0]);
+        w.bool(self.ram_enabled[1]);
+        w.u8(self.rom_bank);
+        w.bool(self.latch_erased);
+        w.u16(self.x);
+        w.u16(self.y);
+        self.eeprom.save_state(w);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enabled = [r.bool()?, r.bool()?];
+        self.rom_bank = r.u8()? & 0x7F;
+        self.latch_erased = r.bool()?;
+        self.x = r.u16()?;
+        self.y = r.u16()?;
+        self.eeprom.load_state(r)
+    }
+}
+
+
+// What HuC1 and HuC3 infrared ports read with nothing shining at them
+const IR_NO_LIGHT: u8 = 0xC0;
+
+/// Hudson HuC1, MBC1 like banking plus an infrared LED and sensor that
+/// replace RAM at 0xA000 while 0x0E is written to 0x0000.
+#[derive(Debug)]
+pub struct Huc1 {
+    ir_mode: bool,
+    rom_bank: u8,
+    ram_bank: u8,
+    pub ir_led: bool,
+}
+impl Huc1 {
+    pub fn new() -> Self {
+        Huc1 { ir_mode: false, rom_bank: 1, ram_bank: 0, ir_led: false }
+    }
+}
+impl Default for Huc1 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Mapper for Huc1 {
+    fn read_rom(&self, rom: &[u8], _ram: &[u8], addr: u16) -> u8 {
+        rom_byte(rom, self.rom_bank(addr), addr)
+    }
+    fn write_rom(&mut self, _ram: &mut [u8], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ir_mode = value & 0x0F == 0x0E,
+            0x2000..=0x3FFF => self.rom_bank = (value & 0x3F).max(1),
+            0x4000..=0x5FFF => self.ram_bank = value & 0x03,
+            _ => {}
+        }
+    }
+    // RAM can't be disabled, only swapped out for the IR port
+    fn read_ram(&self, ram: &[u8], addr: u16) -> Option<u8> {
+        match self.ir_mode {
+            true => Some(IR_NO_LIGHT),
+            false => ram_offset(ram, self.ram_bank as usize, addr).map(|o| ram[o]),
+        }
+    }
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8) {
+        if self.ir_mode {
+            self.ir_led = value & 0x01 != 0;
+        } else if let Some(o) = ram_offset(ram, self.ram_bank as usize, addr) {
+            ram[o] = value;
+        }
+    }
+    fn rom_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        }
+    }
+}
+impl Step for Huc1 {
+    fn step(&mut self, _cycles: u8) {}
+}
+impl Savable for Huc1 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.ir_mode);
+        w.u8(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.bool(self.ir_led);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.ir_mode = r.bool()?;
+        self.rom_bank = (r.u8()? & 0x3F).max(1);
+        self.ram_bank = r.u8()? & 0x03;
+        self.ir_led = r.bool()?;
+        Ok(())
+    }
+}
+
+
+/// Hudson HuC3. Writes to 0x0000 pick what 0xA000 is: RAM (0x0 read only,
+/// 0xA read/write), the command port (0xB), its reply (0xC), a ready flag
+/// (0xD) or infrared (0xE). Commands are a nibble each way with the
+/// argument in the low nibble:
+///   1  read the nibble at the address into the reply, then increment
+///   3  write the argument at the address, then increment
+///   4  set the address low nibble      5  set the address high nibble
+///   6  0 copies the clock to nibbles 0-6, 1 sets the clock from them, 2 replies 1
+/// The clock counts minutes of the day (3 nibbles) and days (4 nibbles).
+#[derive(Debug)]
+pub struct Huc3 {
+    mode: u8,
+    rom_bank: u8,
+    ram_bank: u8,
+    address: u8,
+    memory: [u8; 0x100],  // Nibbles the commands read and write
+    reply: u8,
+    pub minutes: u16,     // Minutes into the day, 0-1439
+    pub days: u16,
+    seconds: u8,
+    subsecond_cycles: u32,
+    pub ir_led: bool,
+}
+impl Huc3 {
+    const MINUTES_PER_DAY: u16 = 24 * 60;
+
+    pub fn new() -> Self {
+        Huc3 {
+            mode: 0,
+            rom_bank: 1,
+            ram_bank: 0,
+            address: 0,
+            memory: [0; 0x100],
+            reply: 0,
+            minutes: 0,
+            days: 0,
+            seconds: 0,
+            subsecond_cycles: 0,
+            ir_led: false,
+        }
+    }
+
+    fn command(&mut self, value: u8) {
+        let argument = value & 0x0F;
+        match value >> 4 {
+            0x1 => {
+                self.reply = (value & 0xF0) | self.memory[self.address as usize];
+                self.address = self.address.wrapping_add(1);
+            }
+            0x3 => {
+                self.memory[self.address as usize] = argument;
+                self.address = self.address.wrapping_add(1);
+            }
+            0x4 => self.address = (self.address & 0xF0) | argument,
+            0x5 => self.address = (self.address & 0x0F) | (argument << 4),
+            0x6 => match argument {
+                0x0 => {
+                    let clock = (self.minutes as u32) | ((self.days as u32) << 12);
+                    for i in 0..7 {
+                        self.memory[i] = ((clock >> (i * 4)) & 0x0F) as u8;
+                    }
+                }
+                0x1 => {
+                    let clock = (0..7).fold(0u32, |clock, i| clock | ((self.memory[i] as u32) << (i * 4)));
+                    self.minutes = ((clock & 0xFFF) as u16) % Self::MINUTES_PER_DAY;
+                    self.days = (clock >> 12) as u16;
+                    self.seconds = 0;
+                }
+                0x2 => self.reply = 0x61,
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+impl Default for Huc3 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Mapper for Huc3 {
+    fn read_rom(&self, rom: &[u8], _ram: &[u8], addr: u16) -> u8 {
+        rom_byte(rom, self.rom_bank(addr), addr)
+    }
+    fn write_rom(&mut self, _ram: &mut [u8], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.mode = value & 0x0F,
+            0x2000..=0x3FFF => self.rom_bank = value & 0x7F,
+            0x4000..=0x5FFF => self.ram_bank = value & 0x03,
+            _ => {}
+        }
+    }
+    fn read_ram(&self, ram: &[u8], addr: u16) -> Option<u8> {
+        match self.mode {
+            0x0 | 0xA => ram_offset(ram, self.ram_bank as usize, addr).map(|o| ram[o]),
+            0xB | 0xC => Some(self.reply),
+            0xD => Some(0x01),  // Always ready
+            0xE => Some(IR_NO_LIGHT),
+            _ => None,
+        }
+    }
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8) {
+        match self.mode {
+            0xA => {
+                if let Some(o) = ram_offset(ram, self.ram_bank as usize, addr) {
+                    ram[o] = value;
+                }
+            }
+            0xB => self.command(value),
+            0xE => self.ir_led = value & 0x01 != 0,
+            _ => {}
+        }
+    }
+    fn rom_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        }
+    }
+}
+impl Step for Huc3 {
+    fn step(&mut self, cycles: u8) {
+        self.subsecond_cycles += cycles as u32;
+        if self.subsecond_cycles < CPU_CLOCK_HZ {
+            return;
+        }
+        self.subsecond_cycles -= CPU_CLOCK_HZ;
+        self.seconds += 1;
+        if self.seconds < 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes += 1;
+        if self.minutes == Self::MINUTES_PER_DAY {
+            self.minutes = 0;
+            self.days = self.days.wrapping_add(1);
+        }
+    }
+}
+impl Savable for Huc3 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.mode);
+        w.u8(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.u8(self.address);
+        w.bytes(&self.memory);
+        w.u8(self.reply);
+        w.u16(self.minutes);
+        w.u16(self.days);
+        w.u8(self.seconds);
+        w.u32(self.subsecond_cycles);
+        w.bool(self.ir_led);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.mode = r.u8()? & 0x0F;
+        self.rom_bank = r.u8()? & 0x7F;
+        self.ram_bank = r.u8()? & 0x03;
+        self.address = r.u8()?;
+        r.bytes_into(&mut self.memory)?;
+        self.reply = r.u8()?;
+        self.minutes = r.u16()? % Self::MINUTES_PER_DAY;
+        self.days = r.u16()?;
+        self.seconds = r.u8()?.min(59);
+        self.subsecond_cycles = r.u32()?.min(CPU_CLOCK_HZ - 1);
+        self.ir_led = r.bool()?;
+        Ok(())
+    }
+}
+
+
+/// Bandai TAMA5. Everything goes through two ports: 0xA001 selects a
+/// register and 0xA000 reads or writes its nibble.
+///   0, 1  ROM bank low nibble and bit 4
+///   4, 5  data to write, low and high nibble
+///   6     bit 0 address bit 4, bits 1-3 the operation
+///   7     address low nibble, writing it runs the operation:
+///         0 write RAM, 1 read RAM, 2 write clock register, 3 read clock register
+///   A     reads 0xF1 once the chip is ready
+///   C, D  result of a read, low and high nibble
+/// The 32 bytes of RAM are the cartridge RAM. Clock registers are BCD:
+/// 0 seconds, 1 minutes, 2 hours, 3 day, 4 month, 5 year.
+#[derive(Debug)]
+pub struct Tama5 {
+    register: u8,
+    rom_bank: u8,
+    data: u8,
+    address: u8,
+    operation: u8,
+    result: u8,
+    pub clock: [u8; 6],   // Seconds, minutes, hours, day (1-31), month (1-12), year (0-99), in binary
+    subsecond_cycles: u32,
+}
+impl Tama5 {
+    pub const RAM_SIZE: usize = 32;
+
+    const READY: u8 = 0xF1;
+
+    pub fn new() -> Self {
+        Tama5 {
+            register: 0,
+            rom_bank: 0,
+            data: 0,
+            address: 0,
+            operation: 0,
+            result: 0,
+            clock: [0, 0, 0, 1, 1, 0],
+            subsecond_cycles: 0,
+        }
+    }
+
+    fn run(&mut self, ram: &mut [u8]) {
+        let address = self.address as usize;
+        match self.operation {
+            0 => {
+                if let Some(cell) = ram.get_mut(address) {
+                    *cell = self.data;
+                }
+            }
+            1 => self.result = ram.get(address).copied().unwrap_or(0xFF),
+            2 => {
+                if let Some(field) = self.clock.get_mut(address & 0x0F) {
+                    *field = from_bcd(self.data);
+                }
+            }
+            3 => self.result = self.clock.get(address & 0x0F).map_or(0xFF, |&field| to_bcd(field)),
+            _ => {}
+        }
+    }
+
+    fn days_in_month(&self) -> u8 {
+        match self.clock[4] {
+            2 if self.clock[5].is_multiple_of(4) => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        }
+    }
+
+    fn tick_second(&mut self) {
+        // Each field rolls over into the next one up
+        let limits = [60, 60, 24];
+        for (i, limit) in limits.into_iter().enumerate() {
+            self.clock[i] += 1;
+            if self.clock[i] < limit {
+                return;
+            }
+            self.clock[i] = 0;
+        }
+        self.clock[3] += 1;
+        if self.clock[3] <= self.days_in_month() {
+            return;
+        }
+        self.clock[3] = 1;
+        self.clock[4] += 1;
+        if self.clock[4] <= 12 {
+            return;
+        }
+        self.clock[4] = 1;
+        self.clock[5] = (self.clock[5] + 1) % 100;
+    }
+}
+impl Default for Tama5 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+impl Mapper for Tama5 {
+    fn read_rom(&self, rom: &[u8], _ram: &[u8], addr: u16) -> u8 {
+        rom_byte(rom, self.rom_bank(addr), addr)
+    }
+    fn write_rom(&mut self, _ram: &mut [u8], _addr: u16, _value: u8) {}
+    fn read_ram(&self, _ram: &[u8], addr: u16) -> Option<u8> {
+        if addr & 0x01 != 0 {
+            return Some(0xFF);
+        }
+        let value = match self.register {
+            0xA => Self::READY,
+            0xC => 0xF0 | (self.result & 0x0F),
+            0xD => 0xF0 | (self.result >> 4),
+            _ => 0xFF,
+        };
+        Some(value)
+    }
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8) {
+        let value = value & 0x0F;
+        if addr & 0x01 != 0 {
+            self.register = value;
+            return;
+        }
+        match self.register {
+            0x0 => self.rom_bank = (self.rom_bank & 0x10) | value,
+            0x1 => self.rom_bank = (self.rom_bank & 0x0F) | ((value & 0x01) << 4),
+            0x4 => self.data = (self.data & 0xF0) | value,
+            0x5 => self.data = (self.data & 0x0F) | (value << 4),
+            0x6 => {
+                self.address = (self.address & 0x0F) | ((value & 0x01) << 4);
+                self.operation = value >> 1;
+            }
+            0x7 => {
+                self.address = (self.address & 0x10) | value;
+                self.run(ram);
+            }
+            _ => {}
+        }
+    }
+    fn rom_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        }
+    }
+}
+impl Step for Tama5 {
+    fn step(&mut self, cycles: u8) {
+        self.subsecond_cycles += cycles as u32;
+        if self.subsecond_cycles >= CPU_CLOCK_HZ {
+            self.subsecond_cycles -= CPU_CLOCK_HZ;
+            self.tick_second();
+        }
+    }
+}
+impl Savable for Tama5 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.register);
+        w.u8(self.rom_bank);
+        w.u8(self.data);
+        w.u8(self.address);
+        w.u8(self.operation);
+        w.u8(self.result);
+        w.bytes(&self.clock);
+        w.u32(self.subsecond_cycles);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.register = r.u8()? & 0x0F;
+        self.rom_bank = r.u8()? & 0x1F;
+        self.data = r.u8()?;
+        self.address = r.u8()? & 0x1F;
+        self.operation = r.u8()? & 0x07;
+        self.result = r.u8()?;
+        r.bytes_into(&mut self.clock)?;
+        // Kept in range so corrupt states can't overflow the fields as they tick
+        let [seconds, minutes, hours, day, month, year] = self.clock;
+        self.clock = [seconds.min(59), minutes.min(59), hours.min(23), day.clamp(1, 31), month.clamp(1, 12), year % 100];
+        self.subsecond_cycles = r.u32()?.min(CPU_CLOCK_HZ - 1);
+        Ok(())
+    }
+}
+
+
+/// Picks the mapper for a cartridge type byte (0x147), `None` if unsupported.
+pub fn mapper_for(cartridge_type: u8) -> Option<Box<dyn Mapper>> {
+    let mapper: Box<dyn Mapper> = match cartridge_type {
+        0x00 | 0x08 | 0x09 => Box::new(NoMbc),
+        0x01..=0x03 => Box::new(Mbc1::new()),
+        0x05 | 0x06 => Box::new(Mbc2::new()),
+        0x0F | 0x10 => Box::new(Mbc3::new(true)),
+        0x11..=0x13 => Box::new(Mbc3::new(false)),
+        0x19..=0x1B => Box::new(Mbc5::new(false)),
+        0x1C..=0x1E => Box::new(Mbc5::new(true)),
+        0x0B..=0x0D => Box::new(Mmm01::new()),
+        0x20 => Box::new(Mbc6::new()),
+        0x22 => Box::new(Mbc7::new()),
+        0xFC => Box::new(PocketCamera::new()),
+        0xFD => Box::new(Tama5::new()),
+        0xFE => Box::new(Huc3::new()),
+        0xFF => Box::new(Huc1::new()),
+        _ => return None,
+    };
+    Some(mapper)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::savestate::STATE_VERSION;
+
+    // Every bank starts with its own number, low byte first
+    fn banked_rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+            rom[bank * ROM_BANK_SIZE + 1] = (bank >> 8) as u8;
+        }
+        rom
+    }
+
+    fn bank_at(mapper: &dyn Mapper, rom: &[u8], addr: u16) -> usize {
+        let low = mapper.read_rom(rom, &[], addr) as usize;
+        let high = mapper.read_rom(rom, &[], addr + 1) as usize;
+        low | high << 8
+    }
+
+    fn latch(mbc: &mut Mbc3) {
+        mbc.write_rom(&mut [], 0x6000, 0x00);
+        mbc.write_rom(&mut [], 0x6000, 0x01);
+    }
+
+    fn read_rtc(mbc: &mut Mbc3, register: u8) -> Option<u8> {
+        mbc.write_rom(&mut [], 0x4000, register);
+        mbc.read_ram(&[], 0xA000)
+    }
+
+    #[test]
+    fn mbc1_switches_banks() {
+        let rom = banked_rom(128);
+        let mut ram = vec![0; 4 * RAM_BANK_SIZE];
+        let mut mbc = Mbc1::new();
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 1);
+
+        mbc.write_rom(&mut ram, 0x2000, 0x00);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 1, "bank 0 maps to 1");
+        mbc.write_rom(&mut ram, 0x2000, 0x05);
+        mbc.write_rom(&mut ram, 0x4000, 0x01);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0x25);
+        assert_eq!(bank_at(&mbc, &rom, 0x0000), 0);
+
+        // Mode 1 moves the upper bits onto 0x0000 and banks RAM
+        mbc.write_rom(&mut ram, 0x6000, 0x01);
+        assert_eq!(bank_at(&mbc, &rom, 0x0000), 0x20);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), None);
+        mbc.write_rom(&mut ram, 0x0000, 0x0A);
+        mbc.write_ram(&mut ram, 0xA000, 0x42);
+        assert_eq!(ram[RAM_BANK_SIZE], 0x42);
+        mbc.write_rom(&mut ram, 0x4000, 0x00);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), Some(0x00));
+    }
+
+    #[test]
+    fn mbc2_uses_address_bit_8_and_nibble_ram() {
+        let rom = banked_rom(16);
+        let mut ram = vec![0; Mbc2::RAM_SIZE];
+        let mut mbc = Mbc2::new();
+
+        mbc.write_rom(&mut ram, 0x2100, 0x07);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 7);
+        // Bit 8 clear is the RAM enable, not a bank switch
+        mbc.write_rom(&mut ram, 0x2000, 0x0A);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 7);
+
+        mbc.write_ram(&mut ram, 0xA000, 0x5C);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), Some(0xFC));
+        assert_eq!(mbc.read_ram(&ram, 0xA200), Some(0xFC), "RAM echoes every 512 bytes");
+    }
+
+    #[test]
+    fn mbc3_switches_rom_and_ram_banks() {
+        let rom = banked_rom(128);
+        let mut ram = vec![0; 4 * RAM_BANK_SIZE];
+        let mut mbc = Mbc3::new(false);
+
+        mbc.write_rom(&mut ram, 0x2000, 0x7F);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0x7F);
+        mbc.write_rom(&mut ram, 0x2000, 0x00);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 1);
+
+        mbc.write_rom(&mut ram, 0x0000, 0x0A);
+        mbc.write_rom(&mut ram, 0x4000, 0x03);
+        mbc.write_ram(&mut ram, 0xA123, 0x42);
+        assert_eq!(ram[3 * RAM_BANK_SIZE + 0x123], 0x42);
+        // No clock on this cartridge
+        assert_eq!(read_rtc(&mut mbc, 0x08), None);
+    }
+
+    #[test]
+    fn mbc3_clock_reads_the_latched_time() {
+        let mut mbc = Mbc3::new(true);
+        mbc.write_rom(&mut [], 0x0000, 0x0A);
+        for _ in 0..CPU_CLOCK_HZ / 128 * 5 {
+            mbc.step(128);
+        }
+        assert_eq!(read_rtc(&mut mbc, 0x08), Some(0), "nothing latched yet");
+
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x08), Some(5));
+        mbc.rtc.tick_second();
+        assert_eq!(read_rtc(&mut mbc, 0x08), Some(5), "latched value holds");
+        // Writing 0x01 alone does not latch
+        mbc.write_rom(&mut [], 0x6000, 0x01);
+        assert_eq!(read_rtc(&mut mbc, 0x08), Some(5));
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x08), Some(6));
+    }
+
+    #[test]
+    fn mbc3_clock_carries_into_days() {
+        let mut mbc = Mbc3::new(true);
+        mbc.write_rom(&mut [], 0x0000, 0x0A);
+        for (register, value) in [(0x08, 59), (0x09, 59), (0x0A, 23), (0x0B, 0xFF), (0x0C, 0x01)] {
+            mbc.write_rom(&mut [], 0x4000, register);
+            mbc.write_ram(&mut [], 0xA000, value);
+        }
+        mbc.rtc.tick_second();
+        latch(&mut mbc);
+        for register in 0x08..=0x0B {
+            assert_eq!(read_rtc(&mut mbc, register), Some(0));
+        }
+        assert_eq!(read_rtc(&mut mbc, 0x0C), Some(0x80), "day counter overflowed");
+
+        // Halting stops the clock
+        mbc.write_ram(&mut [], 0xA000, 0x40);
+        for _ in 0..CPU_CLOCK_HZ / 128 * 2 {
+            mbc.step(128);
+        }
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x08), Some(0));
+    }
+
+    #[test]
+    fn rtc_state_is_masked_on_load() {
+        let mut w = StateWriter::new();
+        w.u8(0xFF);
+        w.u8(0xFF);
+        w.u8(0xFF);
+        w.u16(0xFFFF);
+        w.bool(false);
+        w.bool(false);
+        w.u32(0);
+        let data = w.into_inner();
+
+        let mut rtc = Rtc::default();
+        rtc.load_state(&mut StateReader::new(&data, STATE_VERSION)).unwrap();
+        assert_eq!((rtc.seconds, rtc.minutes, rtc.hours, rtc.days), (0x3F, 0x3F, 0x1F, 0x1FF));
+        for _ in 0..0x40 * 0x40 * 0x20 {
+            rtc.tick_second();
+        }
+    }
+
+    #[test]
+    fn mbc5_has_nine_bit_rom_banks() {
+        let rom = banked_rom(512);
+        let mut ram = vec![0; 16 * RAM_BANK_SIZE];
+        let mut mbc = Mbc5::new(false);
+
+        mbc.write_rom(&mut ram, 0x2000, 0x23);
+        mbc.write_rom(&mut ram, 0x3000, 0x01);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0x123);
+        // Unlike MBC1 and MBC3, bank 0 can be mapped at 0x4000
+        mbc.write_rom(&mut ram, 0x3000, 0x00);
+        mbc.write_rom(&mut ram, 0x2000, 0x00);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0);
+
+        mbc.write_rom(&mut ram, 0x0000, 0x0A);
+        mbc.write_rom(&mut ram, 0x4000, 0x0F);
+        mbc.write_ram(&mut ram, 0xA000, 0x42);
+        assert_eq!(ram[15 * RAM_BANK_SIZE], 0x42);
+    }
+
+    #[test]
+    fn mbc5_rumble_takes_ram_bank_bit_3() {
+        let mut ram = vec![0; 16 * RAM_BANK_SIZE];
+        let mut mbc = Mbc5::new(true);
+        mbc.write_rom(&mut ram, 0x0000, 0x0A);
+        mbc.write_rom(&mut ram, 0x4000, 0x0A);
+        assert!(mbc.rumble);
+        mbc.write_ram(&mut ram, 0xA000, 0x42);
+        assert_eq!(ram[2 * RAM_BANK_SIZE], 0x42);
+    }
+
+    // Flash mapped at both halves, bank 2 at 0x4000 and bank 1 at 0x6000, so
+    // the command addresses 0x5555 and 0x2AAA are 0x5555 and 0x6AAA
+    fn mbc6_flash_command(mbc: &mut Mbc6, ram: &mut [u8], command: u8) {
+        mbc.write_rom(ram, 0x5555, 0xAA);
+        mbc.write_rom(ram, 0x6AAA, 0x55);
+        mbc.write_rom(ram, 0x5555, command);
+    }
+
+    #[test]
+    fn mbc6_flash_unlocks_programs_and_erases() {
+        let mut ram = vec![0; Mbc6::RAM_SIZE + Mbc6::FLASH_SIZE];
+        let mut mbc = Mbc6::new();
+        for (addr, value) in [(0x2000, 2), (0x2800, 0x08), (0x3000, 1), (0x3800, 0x08)] {
+            mbc.write_rom(&mut ram, addr, value);
+        }
+        assert_eq!(mbc.read_rom(&[], &ram, 0x4000), 0xFF, "flash disabled");
+        mbc.write_rom(&mut ram, 0x0C00, 0x01);
+        assert_eq!(mbc.read_rom(&[], &ram, 0x4000), 0x00);
+
+        mbc6_flash_command(&mut mbc, &mut ram, 0x90);
+        assert_eq!([mbc.read_rom(&[], &ram, 0x4000), mbc.read_rom(&[], &ram, 0x4001)], Mbc6::FLASH_ID);
+        mbc.write_rom(&mut ram, 0x4000, 0xF0);
+        assert_eq!(mbc.read_rom(&[], &ram, 0x4000), 0x00);
+
+        // Nothing changes until writes are enabled
+        mbc6_flash_command(&mut mbc, &mut ram, 0x80);
+        mbc6_flash_command(&mut mbc, &mut ram, 0x10);
+        assert_eq!(mbc.read_rom(&[], &ram, 0x4000), 0x00);
+        mbc.write_rom(&mut ram, 0x1000, 0x01);
+
+        // Sector erase at flash 0x4000 only clears the first 128 KB
+        mbc6_flash_command(&mut mbc, &mut ram, 0x80);
+        mbc.write_rom(&mut ram, 0x5555, 0xAA);
+        mbc.write_rom(&mut ram, 0x6AAA, 0x55);
+        mbc.write_rom(&mut ram, 0x4000, 0x30);
+        assert!(ram[Mbc6::RAM_SIZE..][..Mbc6::FLASH_SECTOR_SIZE].iter().all(|&b| b == 0xFF));
+        assert_eq!(ram[Mbc6::RAM_SIZE + Mbc6::FLASH_SECTOR_SIZE], 0x00);
+
+        mbc6_flash_command(&mut mbc, &mut ram, 0xA0);
+        mbc.write_rom(&mut ram, 0x4000, 0x3C);
+        assert_eq!(mbc.read_rom(&[], &ram, 0x4000), 0x3C);
+        mbc6_flash_command(&mut mbc, &mut ram, 0xA0);
+        mbc.write_rom(&mut ram, 0x4000, 0xF5);
+        assert_eq!(mbc.read_rom(&[], &ram, 0x4000), 0x34, "programming only clears bits");
+        // Without the unlock sequence writes are ignored
+        mbc.write_rom(&mut ram, 0x4001, 0x00);
+        assert_eq!(mbc.read_rom(&[], &ram, 0x4001), 0xFF);
+
+        mbc6_flash_command(&mut mbc, &mut ram, 0x80);
+        mbc6_flash_command(&mut mbc, &mut ram, 0x10);
+        assert!(ram[Mbc6::RAM_SIZE..].iter().all(|&b| b == 0xFF), "chip erase");
+    }
+
+    fn eeprom_bits(mbc: &mut Mbc7, ram: &mut [u8], value: u32, count: u32) {
+        for i in (0..count).rev() {
+            let di = (((value >> i) & 1) as u8) << 1;
+            mbc.write_ram(ram, 0xA080, 0x80 | di);
+            mbc.write_ram(ram, 0xA080, 0xC0 | di);
+        }
+    }
+
+    // Start bit, two opcode bits and eight address bits, then CS low
+    fn eeprom_command(mbc: &mut Mbc7, ram: &mut [u8], command: u32, data: Option<u16>) {
+        eeprom_bits(mbc, ram, 1 << 10 | command, 11);
+        if let Some(data) = data {
+            eeprom_bits(mbc, ram, data as u32, 16);
+        }
+        mbc.write_ram(ram, 0xA080, 0x00);
+    }
+
+    fn eeprom_read(mbc: &mut Mbc7, ram: &mut [u8], address: u8) -> u16 {
+        eeprom_bits(mbc, ram, 1 << 10 | 0b10 << 8 | address as u32, 11);
+        assert_eq!(mbc.read_ram(ram, 0xA080).map(|pins| pins & 0x01), Some(0), "dummy bit");
+        let mut word = 0;
+        for _ in 0..16 {
+            eeprom_bits(mbc, ram, 0, 1);
+            word = word << 1 | (mbc.read_ram(ram, 0xA080).unwrap() & 0x01) as u16;
+        }
+        mbc.write_ram(ram, 0xA080, 0x00);
+        word
+    }
+
+    #[test]
+    fn mbc7_eeprom_runs_commands() {
+        let mut ram = vec![0; Mbc7::RAM_SIZE];
+        let mut mbc = Mbc7::new();
+        mbc.write_rom(&mut ram, 0x0000, 0x0A);
+        assert_eq!(mbc.read_ram(&ram, 0xA080), None, "needs both enables");
+        mbc.write_rom(&mut ram, 0x4000, 0x40);
+
+        const WRITE: u32 = 0b01 << 8;
+        const ERASE: u32 = 0b11 << 8;
+        const EWEN: u32 = 0b11 << 6;
+        const EWDS: u32 = 0;
+        const ERAL: u32 = 0b10 << 6;
+        const WRAL: u32 = 0b01 << 6;
+
+        eeprom_command(&mut mbc, &mut ram, WRITE | 0x05, Some(0x1234));
+        assert_eq!(ram[0x0A..0x0C], [0, 0], "writes start disabled");
+        eeprom_command(&mut mbc, &mut ram, EWEN, None);
+        eeprom_command(&mut mbc, &mut ram, WRITE | 0x05, Some(0x1234));
+        eeprom_command(&mut mbc, &mut ram, WRITE | 0x06, Some(0xBEEF));
+        assert_eq!(ram[0x0A..0x0E], [0x34, 0x12, 0xEF, 0xBE]);
+        assert_eq!(eeprom_read(&mut mbc, &mut ram, 0x05), 0x1234);
+        assert_eq!(eeprom_read(&mut mbc, &mut ram, 0x06), 0xBEEF);
+
+        eeprom_command(&mut mbc, &mut ram, ERASE | 0x05, None);
+        assert_eq!(eeprom_read(&mut mbc, &mut ram, 0x05), 0xFFFF);
+        eeprom_command(&mut mbc, &mut ram, WRAL, Some(0xA5A5));
+        assert!(ram.chunks(2).all(|word| word == [0xA5, 0xA5]));
+        eeprom_command(&mut mbc, &mut ram, EWDS, None);
+        eeprom_command(&mut mbc, &mut ram, ERAL, None);
+        assert!(ram.iter().all(|&b| b == 0xA5), "writes disabled again");
+        eeprom_command(&mut mbc, &mut ram, EWEN, None);
+        eeprom_command(&mut mbc, &mut ram, ERAL, None);
+        assert!(ram.iter().all(|&b| b == 0xFF));
+    }
+
+    fn huc3_command(mbc: &mut Huc3, command: u8) -> u8 {
+        mbc.write_rom(&mut [], 0x0000, 0x0B);
+        mbc.write_ram(&mut [], 0xA000, command);
+        mbc.write_rom(&mut [], 0x0000, 0x0C);
+        mbc.read_ram(&[], 0xA000).unwrap()
+    }
+
+    #[test]
+    fn huc3_clock_commands_set_and_read_the_time() {
+        let mut mbc = Huc3::new();
+        // 23:59 on day 0x123, minutes then days, low nibble first
+        huc3_command(&mut mbc, 0x40);
+        huc3_command(&mut mbc, 0x50);
+        for nibble in [0xF, 0x9, 0x5, 0x3, 0x2, 0x1, 0x0] {
+            huc3_command(&mut mbc, 0x30 | nibble);
+        }
+        huc3_command(&mut mbc, 0x61);
+        assert_eq!((mbc.minutes, mbc.days), (1439, 0x123));
+
+        for _ in 0..CPU_CLOCK_HZ / 128 * 60 {
+            mbc.step(128);
+        }
+        assert_eq!((mbc.minutes, mbc.days), (0, 0x124));
+
+        huc3_command(&mut mbc, 0x60);
+        huc3_command(&mut mbc, 0x40);
+        let nibbles: Vec<u8> = (0..7).map(|_| huc3_command(&mut mbc, 0x10)).collect();
+        assert_eq!(nibbles, [0x10, 0x10, 0x10, 0x14, 0x12, 0x11, 0x10]);
+        assert_eq!(huc3_command(&mut mbc, 0x62), 0x61);
+
+        mbc.write_rom(&mut [], 0x0000, 0x0D);
+        assert_eq!(mbc.read_ram(&[], 0xA000), Some(0x01));
+    }
+
+    fn tama5_write(mbc: &mut Tama5, ram: &mut [u8], register: u8, value: u8) {
+        mbc.write_ram(ram, 0xA001, register);
+        mbc.write_ram(ram, 0xA000, value);
+    }
+
+    fn tama5_read(mbc: &mut Tama5, ram: &mut [u8], register: u8) -> u8 {
+        mbc.write_ram(ram, 0xA001, register);
+        mbc.read_ram(ram, 0xA000).unwrap()
+    }
+
+    #[test]
+    fn tama5_registers_reach_rom_ram_and_clock() {
+        let rom = banked_rom(32);
+        let mut ram = vec![0; Tama5::RAM_SIZE];
+        let mut mbc = Tama5::new();
+        assert_eq!(tama5_read(&mut mbc, &mut ram, 0xA), Tama5::READY);
+
+        tama5_write(&mut mbc, &mut ram, 0x0, 0x5);
+        tama5_write(&mut mbc, &mut ram, 0x1, 0x1);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0x15);
+
+        // Write 0x42 to RAM 0x13, then read it back
+        tama5_write(&mut mbc, &mut ram, 0x4, 0x2);
+        tama5_write(&mut mbc, &mut ram, 0x5, 0x4);
+        tama5_write(&mut mbc, &mut ram, 0x6, 0x1);
+        tama5_write(&mut mbc, &mut ram, 0x7, 0x3);
+        assert_eq!(ram[0x13], 0x42);
+        tama5_write(&mut mbc, &mut ram, 0x6, 1 << 1 | 1);
+        tama5_write(&mut mbc, &mut ram, 0x7, 0x3);
+        assert_eq!(tama5_read(&mut mbc, &mut ram, 0xC), 0xF2);
+        assert_eq!(tama5_read(&mut mbc, &mut ram, 0xD), 0xF4);
+
+        // Minutes go in and come out as BCD
+        tama5_write(&mut mbc, &mut ram, 0x4, 0x9);
+        tama5_write(&mut mbc, &mut ram, 0x5, 0x5);
+        tama5_write(&mut mbc, &mut ram, 0x6, 2 << 1);
+        tama5_write(&mut mbc, &mut ram, 0x7, 0x1);
+        assert_eq!(mbc.clock[1], 59);
+        mbc.clock[0] = 59;
+        mbc.tick_second();
+        assert_eq!(mbc.clock[..3], [0, 0, 1]);
+        tama5_write(&mut mbc, &mut ram, 0x6, 3 << 1);
+        tama5_write(&mut mbc, &mut ram, 0x7, 0x2);
+        assert_eq!(tama5_read(&mut mbc, &mut ram, 0xC), 0xF1);
+        assert_eq!(tama5_read(&mut mbc, &mut ram, 0xD), 0xF0);
+    }
+
+    #[test]
+    fn tama5_clock_is_clamped_on_load() {
+        let mut w = StateWriter::new();
+        for _ in 0..6 {
+            w.u8(0);
+        }
+        w.bytes(&[0xFF; 6]);
+        w.u32(0);
+        let data = w.into_inner();
+
+        let mut mbc = Tama5::new();
+        mbc.load_state(&mut StateReader::new(&data, STATE_VERSION)).unwrap();
+        assert_eq!(mbc.clock, [59, 59, 23, 31, 12, 55]);
+        mbc.tick_second();
+        assert_eq!(mbc.clock, [0, 0, 0, 1, 1, 56]);
+    }
+
+    #[test]
+    fn mmm01_menu_picks_and_locks_a_game() {
+        let rom = banked_rom(256);
+        let mut ram = vec![0; 4 * RAM_BANK_SIZE];
+        let mut mbc = Mmm01::new();
+        // The menu in the last 32 KB shows first
+        assert_eq!((bank_at(&mbc, &rom, 0x0000), bank_at(&mbc, &rom, 0x4000)), (254, 255));
+
+        // Game at 0xC0, banks 1-2 fixed by the mask
+        mbc.write_rom(&mut ram, 0x2000, 0x44);
+        mbc.write_rom(&mut ram, 0x4000, 0x10);
+        mbc.write_rom(&mut ram, 0x6000, 0x03 << 2);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 255, "still unlocked");
+        mbc.write_rom(&mut ram, 0x0000, 0x40);
+        assert_eq!((bank_at(&mbc, &rom, 0x0000), bank_at(&mbc, &rom, 0x4000)), (0xC4, 0xC5));
+
+        // The game only reaches the unmasked low bits
+        mbc.write_rom(&mut ram, 0x2000, 0x7F);
+        mbc.write_rom(&mut ram, 0x4000, 0x30);
+        assert_eq!((bank_at(&mbc, &rom, 0x0000), bank_at(&mbc, &rom, 0x4000)), (0xC4, 0xDD));
+        mbc.write_rom(&mut ram, 0x2000, 0x00);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0xC5);
+
+        // Locking is for good, the low nibble still enables RAM
+        mbc.write_rom(&mut ram, 0x0000, 0x0A);
+        assert_eq!(bank_at(&mbc, &rom, 0x0000), 0xC4);
+        mbc.write_ram(&mut ram, 0xA000, 0x42);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), Some(0x42));
+    }
+
+    #[test]
+    fn mmm01_multiplex_swaps_rom_and_ram_bits() {
+        let rom = banked_rom(128);
+        let mut ram = vec![0; 4 * RAM_BANK_SIZE];
+        let mut mbc = Mmm01::new();
+        mbc.write_rom(&mut ram, 0x6000, 0x40);
+        mbc.write_rom(&mut ram, 0x4000, 0x02);
+        mbc.write_rom(&mut ram, 0x2000, 0x23);
+        mbc.write_rom(&mut ram, 0x0000, 0x4A);
+
+        // The RAM bank number stands in for the mid ROM bits
+        assert_eq!((bank_at(&mbc, &rom, 0x0000), bank_at(&mbc, &rom, 0x4000)), (0x00, 0x43));
+        mbc.write_ram(&mut ram, 0xA000, 0x11);
+        assert_eq!(ram[0], 0x11);
+
+        // In MBC1 mode they reach 0x0000 too, and RAM is banked by the mid bits
+        mbc.write_rom(&mut ram, 0x6000, 0x01);
+        assert_eq!((bank_at(&mbc, &rom, 0x0000), bank_at(&mbc, &rom, 0x4000)), (0x40, 0x43));
+        mbc.write_ram(&mut ram, 0xA000, 0x22);
+        assert_eq!(ram[RAM_BANK_SIZE], 0x22);
+
+        // A locked mode ignores the game's mode writes
+        let mut mbc = Mmm01::new();
+        mbc.write_rom(&mut ram, 0x6000, 0x40);
+        mbc.write_rom(&mut ram, 0x4000, 0x42);
+        mbc.write_rom(&mut ram, 0x0000, 0x40);
+        mbc.write_rom(&mut ram, 0x6000, 0x01);
+        assert_eq!(bank_at(&mbc, &rom, 0x0000), 0x00);
+    }
+
+    #[test]
+    fn huc1_banks_ram_and_swaps_in_the_ir_port() {
+        let rom = banked_rom(64);
+        let mut ram = vec![0; 4 * RAM_BANK_SIZE];
+        let mut mbc = Huc1::new();
+        mbc.write_rom(&mut ram, 0x2000, 0x00);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 1);
+        mbc.write_rom(&mut ram, 0x2000, 0x45);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 5);
+
+        // RAM needs no enable
+        mbc.write_rom(&mut ram, 0x4000, 0x06);
+        mbc.write_ram(&mut ram, 0xA010, 0x99);
+        assert_eq!(ram[2 * RAM_BANK_SIZE + 0x10], 0x99);
+        assert_eq!(mbc.read_ram(&ram, 0xA010), Some(0x99));
+
+        mbc.write_rom(&mut ram, 0x0000, 0x0E);
+        assert_eq!(mbc.read_ram(&ram, 0xA010), Some(IR_NO_LIGHT));
+        mbc.write_ram(&mut ram, 0xA010, 0x01);
+        assert!(mbc.ir_led);
+        assert_eq!(ram[2 * RAM_BANK_SIZE + 0x10], 0x99);
+        mbc.write_rom(&mut ram, 0x0000, 0x00);
+        assert_eq!(mbc.read_ram(&ram, 0xA010), Some(0x99));
+    }
+
+    #[test]
+    fn huc1_rom_bank_is_never_0_after_load() {
+        let mut w = StateWriter::new();
+        w.bool(false);
+        w.u8(0x40);
+        w.u8(0);
+        w.bool(false);
+        let data = w.into_inner();
+
+        let mut mbc = Huc1::new();
+        mbc.load_state(&mut StateReader::new(&data, STATE_VERSION)).unwrap();
+        assert_eq!(bank_at(&mbc, &banked_rom(64), 0x4000), 1);
+    }
+}
@@ -1,5 +1,15 @@
+use crate::apu::{Apu, APU_START, APU_END};
 use crate::common::IO;
 use crate::cartridge::Cartridge;
+use crate::cheats::Cheats;
+use crate::dma::{DmaBus, Hdma, OamDma, DMA_REGISTER, HDMA_BLOCK_CYCLES, HDMA_BLOCK_SIZE, HDMA_CONTROL, HDMA_SOURCE_HIGH};
+use crate::joypad::Joypad;
+use crate::ppu::{Mode, PPU, PPU_VBK};
+use crate::serial::{Serial, SERIAL_SB, SERIAL_SC};
+use crate::sgb::Sgb;
+use crate::savestate::{Savable, StateError, StateReader, StateWriter};
+use crate::step::Step;
+use crate::timer::Timer;
 
 // Complete memory map can be found here https://gbdev.gg8.se/wiki/articles/Memory_Map
 
@@ -51,14 +61,25 @@ const MMU_INTERRUPT_ENABLE_REGISTER: u16 = 0xFFFF;
 
 const KB: usize = 1024;
 
-const ROM_BANK_SIZE: usize = 16 * KB; // 16 KB
-const VRAM_SIZE: usize = 8 * KB;      // 8 KB
-const ERAM_SIZE: usize = 8 * KB;      // 8 KB
 const WRAM_BANK_SIZE: usize = 4 * KB; // 4 KB
-const OAM_SIZE: usize = 160;          // 160 B
+const WRAM_BANKS: usize = 8;          // 1 on DMG plus 7 switchable on CGB
 const HRAM_SIZE: usize = 127;         // 127 B
+const IO_SIZE: usize = 128;           // 128 B
 
+// I/O registers handled by the MMU itself
+const MMU_JOYPAD: u16 = 0xFF00;
+const MMU_TIMER_START: u16 = 0xFF04;
+const MMU_TIMER_END: u16 = 0xFF07;
+const MMU_INTERRUPT_FLAG: u16 = 0xFF0F;
+const MMU_LCD_START: u16 = 0xFF40;
+const MMU_LCD_END: u16 = 0xFF4B;
+const MMU_KEY0: u16 = 0xFF4C;  // CGB compatibility mode, boot ROM only
+const MMU_KEY1: u16 = 0xFF4D;  // CGB speed switch
+const MMU_BOOT: u16 = 0xFF50;  // Unmaps the boot ROM
+const MMU_SVBK: u16 = 0xFF70;  // CGB WRAM bank
 
+
+#[allow(unused)]
 enum MMUError {
     InvalidAddress,
     ReadOnlyAddress,
@@ -68,99 +89,382 @@ enum MMUError {
 
 
 
-#[allow(unused)]
-struct MMU {
+#[allow(clippy::upper_case_acronyms)]
+pub struct MMU {
     cartridge: Option<Cartridge>,
-    // ROM
-    rom_bank_0: [u8; ROM_BANK_SIZE], // 16 KB
-    rom_bank_n: [u8; ROM_BANK_SIZE], // 16 KB
-    // Video RAM
-    vram: [u8; VRAM_SIZE],           // 8 KB
-    // External RAM
-    eram: [u8; ERAM_SIZE],           // 8 KB
-    // Work RAM
-    wram_bank_0: [u8; WRAM_BANK_SIZE], // 4 KB
-    wram_bank_n: [u8; WRAM_BANK_SIZE], // 4 KB
-    // OAM
-    oam: [u8; OAM_SIZE],             // 160 B
+    // Boot ROM over 0x0000-0x00FF (and 0x0200-0x08FF on CGB) until FF50 is written
+    boot_rom: Option<Vec<u8>>,
+    boot_rom_mapped: bool,
+    dmg_compat: bool,                 // KEY0 asked for DMG mode once the boot ROM is done
+    // Work RAM, bank 0 is fixed at 0xC000, 0xD000 shows the bank selected by SVBK
+    wram: Box<[u8; WRAM_BANK_SIZE * WRAM_BANKS]>, // 32 KB
+    svbk: u8,                         // Bank 0 selects 1, always 1 outside CGB mode
+    cgb_mode: bool,
+    // KEY1
+    double_speed: bool,
+    speed_switch_armed: bool,
     // High RAM
     hram: [u8; HRAM_SIZE],           // 127 B
+    // I/O registers without a dedicated component, kept as written
+    io: [u8; IO_SIZE],               // 128 B
     // Interrupt Enable Register
     ie: u8,                          // 1 B
+    // Interrupt Flag Register
+    if_reg: u8,                      // 1 B
+    // OAM DMA, copies into the PPU's OAM and locks the CPU out of the bus meanwhile
+    dma: OamDma,
+    // CGB VRAM DMA, and the CPU T-cycles it has halted the CPU for
+    hdma: Hdma,
+    stall: u32,
+    // Peripherals, VRAM and OAM belong to the PPU
+    pub ppu: PPU,
+    pub apu: Apu,
+    pub timer: Timer,
+    pub joypad: Joypad,
+    pub serial: Serial,
+    // Packets sent through P1 when running as a Super Game Boy
+    pub sgb: Option<Sgb>,
+    // Game Genie codes patch ROM reads, GameShark codes write RAM every VBlank
+    pub cheats: Cheats,
+}
+impl Default for MMU {
+    fn default() -> Self {
+        Self::new()
+    }
 }
-#[allow(unused)]
 impl MMU {
+    pub fn new() -> Self {
+        MMU {
+            cartridge: None,
+            boot_rom: None,
+            boot_rom_mapped: false,
+            dmg_compat: false,
+            wram: Box::new([0; WRAM_BANK_SIZE * WRAM_BANKS]),
+            svbk: 0,
+            cgb_mode: false,
+            double_speed: false,
+            speed_switch_armed: false,
+            hram: [0; HRAM_SIZE],
+            io: [0xFF; IO_SIZE],
+            ie: 0,
+            if_reg: 0,
+            dma: OamDma::new(),
+            hdma: Hdma::new(),
+            stall: 0,
+            ppu: PPU::new(),
+            apu: Apu::new(),
+            timer: Timer::new(),
+            joypad: Joypad::new(),
+            serial: Serial::new(),
+            sgb: None,
+            cheats: Cheats::new(),
+        }
+    }
     pub fn reset(&mut self) {
-        // Reset memory to initial state
+        let cartridge = self.cartridge.take();
+        let boot_rom = self.boot_rom.take();
+        let mut serial = std::mem::take(&mut self.serial);
+        serial.reset();
+        let cheats = std::mem::take(&mut self.cheats);
+        let sgb = self.sgb.is_some();
+        let sample_rate = self.apu.sample_rate();
+        let high_pass = self.apu.high_pass();
+        let cgb_mode = self.cgb_mode;
+        *self = MMU::new();
+        self.apu.set_high_pass(high_pass);
+        self.apu.set_sample_rate(sample_rate);
+        self.cartridge = cartridge;
+        self.sgb = sgb.then(Sgb::new);
+        self.serial = serial;
+        self.cheats = cheats;
+        self.set_cgb_mode(cgb_mode);
+        if let Some(boot_rom) = boot_rom {
+            self.load_boot_rom(boot_rom);
+        }
+    }
+    /// Maps a boot ROM over the cartridge until the program writes FF50.
+    pub fn load_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.boot_rom = Some(boot_rom);
+        self.boot_rom_mapped = true;
+        self.dmg_compat = false;
+    }
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom_mapped
+    }
+    /// Enables the CGB only registers: VBK, SVBK, KEY1 and VRAM DMA.
+    pub fn set_cgb_mode(&mut self, enabled: bool) {
+        self.cgb_mode = enabled;
+        self.serial.set_cgb_mode(enabled);
+    }
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb_mode
     }
     pub fn load_cartridge(&mut self, cartridge: Cartridge) {
         self.cartridge = Some(cartridge);
     }
-}
-#[allow(unused)]
-impl MMU {
-    fn read(&self, addr: u16) -> Option<u8> {
+    pub fn cartridge(&self) -> Option<&Cartridge> {
+        self.cartridge.as_ref()
+    }
+    pub fn cartridge_mut(&mut self) -> Option<&mut Cartridge> {
+        self.cartridge.as_mut()
+    }
+    /// Work RAM banks in bank order.
+    pub fn wram_banks(&self) -> impl Iterator<Item = &[u8]> {
+        self.wram.chunks(WRAM_BANK_SIZE)
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+    /// KEY1 bit 0, a STOP now switches speed instead of stopping.
+    pub fn speed_switch_armed(&self) -> bool {
+        self.speed_switch_armed
+    }
+    pub fn switch_speed(&mut self) {
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+    }
+
+    /// True while an OAM DMA transfer holds the bus.
+    pub fn dma_active(&self) -> bool {
+        self.dma.active()
+    }
+
+    /// CPU T-cycles VRAM DMA has halted the CPU for since the last call.
+    pub fn take_stall(&mut self) -> u32 {
+        std::mem::take(&mut self.stall)
+    }
+
+    /// Converts CPU T-cycles into the fixed 4 MHz time base of the PPU and APU.
+    pub fn real_cycles(&self, cycles: u8) -> u8 {
+        if self.double_speed { cycles / 2 } else { cycles }
+    }
+
+    /// Advances everything but the CPU clocked timer, for the speed switch pause.
+    pub fn step_paused(&mut self, cycles: u8) {
+        self.ppu.step(cycles);
+        self.apu.step(cycles);
+        self.step_hdma();
+        if let Some(cart) = &mut self.cartridge {
+            cart.step(cycles);
+        }
+        self.apply_cheats();
+        self.update_sgb();
+        self.collect_interrupts();
+    }
+
+    fn collect_interrupts(&mut self) {
+        self.if_reg |= self.timer.interrupts | self.ppu.interrupts | self.joypad.interrupts | self.serial.interrupts;
+        self.timer.interrupts = 0;
+        self.serial.interrupts = 0;
+        self.ppu.interrupts = 0;
+        self.joypad.interrupts = 0;
+    }
+
+    fn update_sgb(&mut self) {
+        if let Some(sgb) = &mut self.sgb {
+            sgb.vblank(self.ppu.frame_count(), self.ppu.framebuffer());
+        }
+    }
+
+    // GameShark codes take effect once per frame, as VBlank starts
+    fn apply_cheats(&mut self) {
+        for write in self.cheats.vblank(self.ppu.frame_count()) {
+            match (write.bank, write.addr) {
+                (Some(bank), MMU_WORK_RAM_N_START..=MMU_WORK_RAM_N_END) => {
+                    let offset = (bank.max(1) as usize) * WRAM_BANK_SIZE + (write.addr - MMU_WORK_RAM_N_START) as usize;
+                    self.wram[offset] = write.value;
+                }
+                _ => self.poke(write.addr, write.value),
+            }
+        }
+    }
+
+    // OAM DMA runs off the CPU clock, so it takes CPU T-cycles like the timer
+    fn step_dma(&mut self, cycles: u8) {
+        for (source, offset) in self.dma.step(cycles) {
+            let value = self.peek(source).unwrap_or(0xFF);
+            self.dma.value = value;
+            self.ppu.write(MMU_OAM_START + offset, value);
+        }
+    }
+
+    // HBlank DMA copies a block each time the PPU enters mode 0
+    fn step_hdma(&mut self) {
+        if self.ppu.take_hblank_entered() && self.hdma.hblank_pending() {
+            self.hdma_block();
+        }
+    }
+
+    fn write_hdma(&mut self, addr: u16, value: u8) {
+        self.hdma.write(addr, value);
+        if addr != HDMA_CONTROL {
+            return;
+        }
+        // General purpose DMA runs to completion right away
+        while self.hdma.general_pending() {
+            self.hdma_block();
+        }
+        // Starting an HBlank transfer outside of drawing copies the first block at once
+        if self.hdma.hblank_pending() && self.ppu.mode() == Mode::HBlank {
+            self.hdma_block();
+        }
+    }
+
+    // Blocks land in the VRAM bank VBK selects
+    fn hdma_block(&mut self) {
+        let Some((source, dest)) = self.hdma.next_block() else { return };
+        for i in 0..HDMA_BLOCK_SIZE {
+            // VRAM can't be a source, the DMA reads open bus
+            let value = match DmaBus::of(source) {
+                Some(DmaBus::Video) => 0xFF,
+                _ => self.peek(source.wrapping_add(i)).unwrap_or(0xFF),
+            };
+            self.ppu.write(dest + i, value);
+        }
+        // Same real time in both speeds, so twice the CPU cycles in double speed
+        self.stall += HDMA_BLOCK_CYCLES << self.double_speed as u32;
+    }
+
+    // What the CPU gets instead of `addr` while DMA or the PPU holds it, see
+    // https://gbdev.io/pandocs/Rendering.html#accessing-vram-and-oam
+    fn conflict(&self, addr: u16) -> Option<u8> {
+        if let Some(bus) = self.dma.bus() {
+            // Only the I/O registers and HRAM, inside the CPU, stay reachable.
+            // Reads on the bus DMA is using see the byte it is moving
+            match addr {
+                MMU_IO_REGISTERS_START..=MMU_INTERRUPT_ENABLE_REGISTER => {}
+                _ if DmaBus::of(addr) == Some(bus) => return Some(self.dma.value),
+                _ => return Some(0xFF),
+            }
+        }
+        match (addr, self.ppu.mode()) {
+            (MMU_VRAM_START..=MMU_VRAM_END, Mode::Drawing) => Some(0xFF),
+            (MMU_OAM_START..=MMU_OAM_END, Mode::OamScan | Mode::Drawing) => Some(0xFF),
+            _ => None,
+        }
+    }
+
+    // CGB boot ROMs are 2304 bytes and leave a gap for the cartridge header
+    fn boot_rom_read(&self, addr: u16) -> Option<u8> {
+        let boot_rom = self.boot_rom.as_ref().filter(|_| self.boot_rom_mapped)?;
+        match addr {
+            0x0000..=0x00FF | 0x0200..=0x08FF => boot_rom.get(addr as usize).copied(),
+            _ => None,
+        }
+    }
+
+    // Offset into `wram` for 0xC000-0xDFFF
+    fn wram_offset(&self, addr: u16) -> usize {
+        let offset = (addr - MMU_WORK_RAM_0_START) as usize;
+        if offset < WRAM_BANK_SIZE {
+            return offset;
+        }
+        let bank = (self.svbk as usize).max(1);
+        bank * WRAM_BANK_SIZE + offset - WRAM_BANK_SIZE
+    }
+
+    fn read_io(&self, addr: u16) -> u8 {
+        match addr {
+            MMU_JOYPAD => match &self.sgb {
+                Some(sgb) => sgb.read_joypad(&self.joypad),
+                None => self.joypad.read(),
+            },
+            SERIAL_SB | SERIAL_SC => self.serial.read(addr),
+            MMU_TIMER_START..=MMU_TIMER_END => self.timer.read(addr),
+            MMU_INTERRUPT_FLAG => 0xE0 | self.if_reg,
+            DMA_REGISTER => self.dma.read(),
+            HDMA_SOURCE_HIGH..=HDMA_CONTROL if self.cgb_mode => self.hdma.read(addr),
+            HDMA_SOURCE_HIGH..=HDMA_CONTROL => 0xFF,
+            APU_START..=APU_END => self.apu.read(addr),
+            MMU_LCD_START..=MMU_LCD_END => self.ppu.read(addr),
+            MMU_KEY0 | MMU_BOOT => 0xFF,
+            MMU_KEY1 if self.cgb_mode => 0x7E | ((self.double_speed as u8) << 7) | self.speed_switch_armed as u8,
+            MMU_SVBK if self.cgb_mode => 0xF8 | self.svbk,
+            PPU_VBK if self.cgb_mode => self.ppu.read(addr),
+            MMU_KEY1 | MMU_SVBK | PPU_VBK => 0xFF,
+            _ => self.io[(addr - MMU_IO_REGISTERS_START) as usize],
+        }
+    }
+
+    fn write_io(&mut self, addr: u16, value: u8) {
+        match addr {
+            MMU_JOYPAD => {
+                self.joypad.write(value);
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.write_joypad(value);
+                }
+            }
+            SERIAL_SB | SERIAL_SC => self.serial.write(addr, value),
+            MMU_TIMER_START..=MMU_TIMER_END => self.timer.write(addr, value),
+            MMU_INTERRUPT_FLAG => self.if_reg = value & 0x1F,
+            DMA_REGISTER => self.dma.write(value),
+            HDMA_SOURCE_HIGH..=HDMA_CONTROL if self.cgb_mode => self.write_hdma(addr, value),
+            HDMA_SOURCE_HIGH..=HDMA_CONTROL => {}
+            APU_START..=APU_END => self.apu.write(addr, value),
+            MMU_LCD_START..=MMU_LCD_END => self.ppu.write(addr, value),
+            MMU_KEY0 if self.cgb_mode && self.boot_rom_mapped => self.dmg_compat = value & 0x04 != 0,
+            MMU_KEY0 => {}
+            MMU_BOOT if self.boot_rom_mapped && value != 0 => {
+                self.boot_rom_mapped = false;
+                if self.dmg_compat {
+                    self.set_cgb_mode(false);
+                }
+            }
+            MMU_BOOT => {}
+            MMU_KEY1 if self.cgb_mode => self.speed_switch_armed = value & 0x01 != 0,
+            MMU_SVBK if self.cgb_mode => self.svbk = value & 0x07,
+            PPU_VBK if self.cgb_mode => self.ppu.write(addr, value),
+            MMU_KEY1 | MMU_SVBK | PPU_VBK => {}
+            _ => self.io[(addr - MMU_IO_REGISTERS_START) as usize] = value,
+        }
+    }
+
+    /// Reads memory the way the hardware maps it, ignoring DMA and PPU
+    /// access restrictions. Meant for tools and DMA itself.
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+        if let Some(value) = self.boot_rom_read(addr) {
+            return Some(value);
+        }
         match addr {
             // ROM Bank 0
             MMU_ROM_BANK_0_START..=MMU_ROM_BANK_0_END => {
-                let offset = addr as usize - MMU_ROM_BANK_0_START as usize;
-                Some(self.rom_bank_0[offset])
+                self.cartridge.as_ref().and_then(|cart| cart.read(addr)).map(|value| self.cheats.patch_rom(addr, value))
             }
 
-            // Switchable ROM Bank N
+            // Switchable ROM Bank N, selected by the cartridge mapper
             MMU_ROM_BANK_N_START..=MMU_ROM_BANK_N_END => {
-                let offset= addr - MMU_ROM_BANK_N_START;
-                todo!()
+                self.cartridge.as_ref().and_then(|cart| cart.read(addr)).map(|value| self.cheats.patch_rom(addr, value))
             }
 
             // Video RAM
-            MMU_VRAM_START..=MMU_VRAM_END => {
-                let offset = addr as usize - MMU_VRAM_START as usize;
-                Some(self.vram[offset])
-            }
+            MMU_VRAM_START..=MMU_VRAM_END => Some(self.ppu.read(addr)),
 
             // External RAM
             MMU_EXTERNAL_RAM_START..=MMU_EXTERNAL_RAM_END => {
-                let offset = addr as usize - MMU_EXTERNAL_RAM_START as usize;
-                Some(self.eram[offset])
+                self.cartridge.as_ref().and_then(|cart| cart.read(addr))
             }
 
             // Work RAM Bank 0
-            MMU_WORK_RAM_0_START..=MMU_WORK_RAM_0_END => {
-                let offset = addr as usize - MMU_WORK_RAM_0_START as usize;
-                Some(self.wram_bank_0[offset])
-            }
+            MMU_WORK_RAM_0_START..=MMU_WORK_RAM_0_END => Some(self.wram[self.wram_offset(addr)]),
 
-            // Work RAM Bank N
-            MMU_WORK_RAM_N_START..=MMU_WORK_RAM_N_END => {
-                let offset = addr as usize - MMU_WORK_RAM_N_START as usize;
-                Some(self.wram_bank_n[offset])
-            }
+            // Work RAM Bank N, switchable on CGB
+            MMU_WORK_RAM_N_START..=MMU_WORK_RAM_N_END => Some(self.wram[self.wram_offset(addr)]),
 
             // Echo RAM (mirrors Work RAM 0 + N)
             MMU_ECHO_RAM_START..=MMU_ECHO_RAM_END => {
-                let mirrored_addr = addr - MMU_ECHO_RAM_START;
-                if mirrored_addr < WRAM_BANK_SIZE as u16 {
-                    Some(self.wram_bank_0[mirrored_addr as usize])
-                } else {
-                    let offset = mirrored_addr as usize - WRAM_BANK_SIZE;
-                    Some(self.wram_bank_n[offset])
-                }
+                let mirrored_addr = addr - MMU_ECHO_RAM_START + MMU_WORK_RAM_0_START;
+                Some(self.wram[self.wram_offset(mirrored_addr)])
             }
 
             // OAM (sprite attributes)
-            MMU_OAM_START..=MMU_OAM_END => {
-                None // can be restricted based on GPU mode
-            }
+            MMU_OAM_START..=MMU_OAM_END => Some(self.ppu.read(addr)),
 
             // Not usable
             MMU_NOT_USABLE_START..=MMU_NOT_USABLE_END => None,
 
             // I/O registers
-            MMU_IO_REGISTERS_START..=MMU_IO_REGISTERS_END => {
-                // For now, return dummy or implement proper I/O later
-                Some(0xFF)
-            }
+            MMU_IO_REGISTERS_START..=MMU_IO_REGISTERS_END => Some(self.read_io(addr)),
 
             // High RAM
             MMU_HIGH_RAM_START..=MMU_HIGH_RAM_END => {
@@ -170,75 +474,56 @@ impl MMU {
 
             // Interrupt Enable Register
             MMU_INTERRUPT_ENABLE_REGISTER => Some(self.ie),
-
-            _ => unreachable!("MMU read failed: address out of range {:x}", addr),
         }
     }
 
-    fn write(&mut self, addr: u16, value: u8) {
+    /// Writes memory ignoring DMA and PPU access restrictions.
+    pub fn poke(&mut self, addr: u16, value: u8) {
         match addr {
-            // ROM Bank 0 — read-only, ignore writes
-            MMU_ROM_BANK_0_START..=MMU_ROM_BANK_0_END => {
-                // ignore writes or log warning
-            }
-
-            // Switchable ROM Bank N — typically writes go to cartridge (MBC)
-            MMU_ROM_BANK_N_START..=MMU_ROM_BANK_N_END => {
+            // ROM — read-only, writes go to the cartridge mapper (MBC)
+            MMU_ROM_BANK_0_START..=MMU_ROM_BANK_0_END | MMU_ROM_BANK_N_START..=MMU_ROM_BANK_N_END => {
                 if let Some(cart) = &mut self.cartridge {
-                    let offset = (addr as usize - MMU_ROM_BANK_N_START as usize) as u16;
-                    cart.write(offset, value);
+                    cart.write(addr, value);
                 }
                 // else ignore write
             }
 
             // Video RAM
-            MMU_VRAM_START..=MMU_VRAM_END => {
-                let offset = addr as usize - MMU_VRAM_START as usize;
-                self.vram[offset] = value;
-            }
+            MMU_VRAM_START..=MMU_VRAM_END => self.ppu.write(addr, value),
 
             // External RAM
             MMU_EXTERNAL_RAM_START..=MMU_EXTERNAL_RAM_END => {
-                let offset = addr as usize - MMU_EXTERNAL_RAM_START as usize;
-                self.eram[offset] = value;
+                if let Some(cart) = &mut self.cartridge {
+                    cart.write(addr, value);
+                }
             }
 
             // Work RAM Bank 0
             MMU_WORK_RAM_0_START..=MMU_WORK_RAM_0_END => {
-                let offset = addr as usize - MMU_WORK_RAM_0_START as usize;
-                self.wram_bank_0[offset] = value;
+                let offset = self.wram_offset(addr);
+                self.wram[offset] = value;
             }
 
-            // Work RAM Bank N
+            // Work RAM Bank N, switchable on CGB
             MMU_WORK_RAM_N_START..=MMU_WORK_RAM_N_END => {
-                let offset = addr as usize - MMU_WORK_RAM_N_START as usize;
-                self.wram_bank_n[offset] = value;
+                let offset = self.wram_offset(addr);
+                self.wram[offset] = value;
             }
 
             // Echo RAM — mirrors Work RAM
             MMU_ECHO_RAM_START..=MMU_ECHO_RAM_END => {
-                let mirrored_addr = addr - MMU_ECHO_RAM_START;
-                if mirrored_addr < WRAM_BANK_SIZE as u16 {
-                    self.wram_bank_0[mirrored_addr as usize] = value;
-                } else {
-                    let offset = mirrored_addr as usize - WRAM_BANK_SIZE;
-                    self.wram_bank_n[offset] = value;
-                }
+                let offset = self.wram_offset(addr - MMU_ECHO_RAM_START + MMU_WORK_RAM_0_START);
+                self.wram[offset] = value;
             }
 
             // OAM (sprite attributes)
-            MMU_OAM_START..=MMU_OAM_END => {
-                // Can be written depending on GPU mode, for now allow
-                // Implement GPU restrictions later
-            }
+            MMU_OAM_START..=MMU_OAM_END => self.ppu.write(addr, value),
 
             // Not usable — ignore writes
             MMU_NOT_USABLE_START..=MMU_NOT_USABLE_END => {}
 
             // I/O registers
-            MMU_IO_REGISTERS_START..=MMU_IO_REGISTERS_END => {
-                // Implement proper I/O writes later
-            }
+            MMU_IO_REGISTERS_START..=MMU_IO_REGISTERS_END => self.write_io(addr, value),
 
             // High RAM
             MMU_HIGH_RAM_START..=MMU_HIGH_RAM_END => {
@@ -248,8 +533,318 @@ impl MMU {
 
             // Interrupt Enable Register
             MMU_INTERRUPT_ENABLE_REGISTER => self.ie = value,
+        }
+    }
+}
+// The CPU's view of memory
+impl IO for MMU {
+    fn read(&self, addr: u16) -> Option<u8> {
+        match self.conflict(addr) {
+            Some(value) => Some(value),
+            None => self.peek(addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) -> bool {
+        // Writes the CPU can't reach are dropped
+        if self.conflict(addr).is_none() {
+            self.poke(addr, value);
+        }
+        true
+    }
+}
+// Takes CPU T-cycles, in double speed only the timer keeps up with the CPU
+impl Step for MMU {
+    fn step(&mut self, cycles: u8) {
+        self.timer.step(cycles);
+        self.serial.step(cycles);
+        self.step_dma(cycles);
+        let real = self.real_cycles(cycles);
+        self.ppu.step(real);
+        self.apu.step(real);
+        self.step_hdma();
+        if let Some(cart) = &mut self.cartridge {
+            cart.step(real);
+        }
+        self.apply_cheats();
+        self.update_sgb();
+
+        // Collect interrupt requests raised by the peripherals
+        self.collect_interrupts();
+    }
+}
+// Memory owned by the MMU itself, the peripherals and cartridge are saved separately
+impl Savable for MMU {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.wram[..]);
+        w.bytes(&self.hram);
+        w.bytes(&self.io);
+        w.u8(self.ie);
+        w.u8(self.if_reg);
+        w.u8(self.svbk);
+        w.bool(self.double_speed);
+        w.bool(self.speed_switch_armed);
+        self.dma.save_state(w);
+        self.hdma.save_state(w);
+        w.bool(self.boot_rom_mapped);
+        w.bool(self.dmg_compat);
+    }
 
-            _ => unreachable!("MMU write failed: address out of range {:x}", addr),
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes_into(&mut self.wram[..])?;
+        r.bytes_into(&mut self.hram)?;
+        r.bytes_into(&mut self.io)?;
+        self.ie = r.u8()?;
+        self.if_reg = r.u8()? & 0x1F;
+        self.svbk = r.u8()? & 0x07;
+        self.double_speed = r.bool()?;
+        self.speed_switch_armed = r.bool()?;
+        self.dma.load_state(r)?;
+        self.hdma.load_state(r)?;
+        self.stall = 0;
+        self.boot_rom_mapped = r.bool()? && self.boot_rom.is_some();
+        self.dmg_compat = r.bool()?;
+        if !self.boot_rom_mapped && self.dmg_compat {
+            self.set_cgb_mode(false);
         }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start_dma(mmu: &mut MMU, page: u8) {
+        mmu.write(DMA_REGISTER, page);
+        mmu.step(4);  // The writing instruction
+        mmu.step(4);  // Setup
+    }
+
+    #[test]
+    fn oam_dma_copies_160_bytes_in_160_m_cycles() {
+        let mut mmu = MMU::new();
+        for i in 0..0xA0u16 {
+            mmu.poke(0xC100 + i, i as u8 ^ 0x5A);
+        }
+        start_dma(&mut mmu, 0xC1);
+        for _ in 0..159 {
+            mmu.step(4);
+        }
+        assert!(mmu.dma_active());
+        assert_eq!(mmu.peek(0xFE9F), Some(0x00));
+        mmu.step(4);
+        assert!(!mmu.dma_active());
+        for i in 0..0xA0u16 {
+            assert_eq!(mmu.read(MMU_OAM_START + i), Some(i as u8 ^ 0x5A));
+        }
+
+        // Echo RAM pages reach work RAM
+        mmu.poke(0xC200, 0x77);
+        start_dma(&mut mmu, 0xE2);
+        mmu.step(4);
+        assert_eq!(mmu.peek(MMU_OAM_START), Some(0x77));
+    }
+
+    #[test]
+    fn the_cpu_only_reaches_io_and_hram_during_oam_dma() {
+        let mut mmu = MMU::new();
+        mmu.poke(0xC100, 0x12);
+        mmu.poke(0xC101, 0x34);
+        mmu.poke(0xD000, 0x56);
+        mmu.poke(0x8000, 0x78);
+        mmu.poke(0xFF80, 0x9A);
+        start_dma(&mut mmu, 0xC1);
+        mmu.step(4);
+        mmu.step(4);
+
+        // Work RAM shares the bus and sees the byte being moved
+        assert_eq!(mmu.read(0xD000), Some(0x34));
+        assert_eq!(mmu.read(0xE000), Some(0x34));
+        assert_eq!(mmu.read(0x8000), Some(0xFF));
+        assert_eq!(mmu.read(MMU_OAM_START), Some(0xFF));
+        assert_eq!(mmu.read(0xFEA0), Some(0xFF));
+        assert_eq!(mmu.read(0xFF80), Some(0x9A));
+        assert_eq!(mmu.read(DMA_REGISTER), Some(0xC1));
+
+        // Writes outside HRAM and I/O are dropped
+        mmu.write(0xD000, 0x00);
+        mmu.write(0x8000, 0x00);
+        mmu.write(0xFF81, 0xBC);
+        assert_eq!((mmu.peek(0xD000), mmu.peek(0x8000)), (Some(0x56), Some(0x78)));
+        assert_eq!(mmu.peek(0xFF81), Some(0xBC));
+
+        // A transfer from VRAM conflicts there instead
+        let mut mmu = MMU::new();
+        mmu.poke(0x8000, 0x78);
+        mmu.poke(0xC000, 0x11);
+        start_dma(&mut mmu, 0x80);
+        mmu.step(4);
+        assert_eq!(mmu.read(0x9800), Some(0x78));
+        assert_eq!(mmu.read(0xC000), Some(0xFF));
+    }
+
+    #[test]
+    fn vram_and_oam_follow_the_ppu_mode() {
+        let mut mmu = MMU::new();
+        mmu.poke(0x8000, 0x11);
+        mmu.poke(MMU_OAM_START, 0x22);
+        assert_eq!(mmu.ppu.mode(), Mode::HBlank);
+        assert_eq!((mmu.read(0x8000), mmu.read(MMU_OAM_START)), (Some(0x11), Some(0x22)));
+
+        mmu.poke(0xFF40, 0x91);
+        assert_eq!(mmu.ppu.mode(), Mode::OamScan);
+        assert_eq!((mmu.read(0x8000), mmu.read(MMU_OAM_START)), (Some(0x11), Some(0xFF)));
+        mmu.write(MMU_OAM_START, 0x33);
+        assert_eq!(mmu.peek(MMU_OAM_START), Some(0x22));
+
+        while mmu.ppu.mode() != Mode::Drawing {
+            mmu.step(4);
+        }
+        assert_eq!((mmu.read(0x8000), mmu.read(MMU_OAM_START)), (Some(0xFF), Some(0xFF)));
+        mmu.write(0x8000, 0x44);
+        assert_eq!(mmu.peek(0x8000), Some(0x11));
+
+        while mmu.ppu.mode() != Mode::HBlank {
+            mmu.step(4);
+        }
+        mmu.write(0x8000, 0x44);
+        mmu.write(MMU_OAM_START, 0x33);
+        assert_eq!((mmu.read(0x8000), mmu.read(MMU_OAM_START)), (Some(0x44), Some(0x33)));
+    }
+
+    fn cgb_mmu() -> MMU {
+        let mut mmu = MMU::new();
+        mmu.set_cgb_mode(true);
+        mmu
+    }
+
+    fn start_hdma(mmu: &mut MMU, source: u16, dest: u16, control: u8) {
+        mmu.write(HDMA_SOURCE_HIGH, (source >> 8) as u8);
+        mmu.write(HDMA_SOURCE_HIGH + 1, source as u8);
+        mmu.write(HDMA_SOURCE_HIGH + 2, (dest >> 8) as u8);
+        mmu.write(HDMA_SOURCE_HIGH + 3, dest as u8);
+        mmu.write(HDMA_CONTROL, control);
+    }
+
+    #[test]
+    fn vbk_switches_vram_banks_in_cgb_mode() {
+        let mut mmu = cgb_mmu();
+        mmu.write(0x8000, 0x11);
+        mmu.write(PPU_VBK, 0xFF);
+        assert_eq!(mmu.read(PPU_VBK), Some(0xFF));
+        assert_eq!(mmu.read(0x8000), Some(0x00));
+        mmu.write(0x8000, 0x22);
+        mmu.write(PPU_VBK, 0x00);
+        assert_eq!(mmu.read(PPU_VBK), Some(0xFE));
+        assert_eq!(mmu.read(0x8000), Some(0x11));
+
+        let mut mmu = MMU::new();
+        mmu.write(PPU_VBK, 0x01);
+        assert_eq!(mmu.read(PPU_VBK), Some(0xFF));
+        mmu.write(0x8000, 0x33);
+        assert_eq!(mmu.ppu.read(PPU_VBK), 0xFE);
+    }
+
+    #[test]
+    fn general_dma_copies_at_once_and_stalls_the_cpu() {
+        let mut mmu = cgb_mmu();
+        for i in 0..0x20u16 {
+            mmu.poke(0xC000 + i, i as u8 + 1);
+        }
+        mmu.write(PPU_VBK, 0x01);
+        start_hdma(&mut mmu, 0xC000, 0x8100, 0x01);
+        assert_eq!(mmu.read(HDMA_CONTROL), Some(0xFF));
+        assert_eq!(mmu.take_stall(), 2 * HDMA_BLOCK_CYCLES);
+        assert_eq!(mmu.take_stall(), 0);
+        assert_eq!((mmu.read(0x8100), mmu.read(0x811F)), (Some(0x01), Some(0x20)));
+        mmu.write(PPU_VBK, 0x00);
+        assert_eq!(mmu.read(0x8100), Some(0x00), "bank 0 untouched");
+
+        // The same transfer takes twice the CPU cycles in double speed
+        mmu.switch_speed();
+        start_hdma(&mut mmu, 0xC000, 0x8100, 0x01);
+        assert_eq!(mmu.take_stall(), 4 * HDMA_BLOCK_CYCLES);
+
+        // VRAM sources read open bus
+        mmu.switch_speed();
+        start_hdma(&mut mmu, 0x8100, 0x8200, 0x00);
+        assert_eq!(mmu.read(0x8200), Some(0xFF));
+    }
+
+    #[test]
+    fn hblank_dma_copies_one_block_per_hblank() {
+        let mut mmu = cgb_mmu();
+        for i in 0..0x30u16 {
+            mmu.poke(0xC000 + i, 0xA0 + i as u8);
+        }
+        mmu.poke(0xFF40, 0x91);
+        start_hdma(&mut mmu, 0xC000, 0x8000, 0x82);
+        assert_eq!(mmu.read(HDMA_CONTROL), Some(0x02), "nothing copied before HBlank");
+        assert_eq!(mmu.take_stall(), 0);
+
+        let mut copied = Vec::new();
+        for _ in 0..3 {
+            while mmu.ppu.mode() != Mode::HBlank {
+                mmu.step(4);
+            }
+            copied.push((mmu.read(HDMA_CONTROL), mmu.take_stall()));
+            while mmu.ppu.mode() == Mode::HBlank {
+                mmu.step(4);
+            }
+        }
+        assert_eq!(copied, [(Some(0x01), HDMA_BLOCK_CYCLES), (Some(0x00), HDMA_BLOCK_CYCLES), (Some(0xFF), HDMA_BLOCK_CYCLES)]);
+        assert_eq!((mmu.peek(0x8000), mmu.peek(0x802F)), (Some(0xA0), Some(0xCF)));
+
+        // Cancelled transfers stop where they were
+        start_hdma(&mut mmu, 0xC000, 0x8100, 0x83);
+        while mmu.ppu.mode() != Mode::HBlank {
+            mmu.step(4);
+        }
+        mmu.write(HDMA_CONTROL, 0x00);
+        assert_eq!(mmu.read(HDMA_CONTROL), Some(0x82));
+        for _ in 0..456 {
+            mmu.step(4);
+        }
+        assert_eq!(mmu.peek(0x8110), Some(0x00));
+    }
+
+    fn run_to_frame(mmu: &mut MMU, frame: u64) {
+        while mmu.ppu.frame_count() < frame {
+            mmu.step(4);
+        }
+    }
+
+    #[test]
+    fn cheats_patch_rom_and_write_ram_every_vblank() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x4A17] = 0xC8;
+        rom[0x0A23] = 0x55;
+        let mut mmu = cgb_mmu();
+        mmu.load_cartridge(Cartridge::new(rom).unwrap());
+        mmu.cheats.add("00A-17B-C49", "Compared").unwrap();
+        mmu.cheats.add("01FF3CD1", "Mapped bank").unwrap();
+        mmu.cheats.add("9263A0D0", "Bank 2").unwrap();
+        assert_eq!(mmu.read(0x4A17), Some(0x00));
+        assert_eq!(mmu.read(0x0A23), Some(0x55));
+        mmu.cheats.add("01a-23f", "").unwrap();
+        assert_eq!(mmu.read(0x0A23), Some(0x01));
+
+        // RAM codes wait for VBlank
+        mmu.poke(0xFF40, 0x91);
+        mmu.step(4);
+        assert_eq!(mmu.peek(0xD13C), Some(0x00));
+        run_to_frame(&mut mmu, 1);
+        assert_eq!(mmu.peek(0xD13C), Some(0xFF));
+        assert_eq!(mmu.wram_banks().nth(2).unwrap()[0x0A0], 0x63);
+        assert_eq!(mmu.peek(0xD0A0), Some(0x00), "bank 1 is mapped");
+
+        // The game can change the value until the next frame
+        mmu.write(0xD13C, 0x03);
+        mmu.step(4);
+        assert_eq!(mmu.peek(0xD13C), Some(0x03));
+        run_to_frame(&mut mmu, 2);
+        assert_eq!(mmu.peek(0xD13C), Some(0xFF));
     }
 }
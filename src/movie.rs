@@ -0,0 +1,521 @@
+use std::fmt;
+use std::io;
+use crate::cartridge::Cartridge;
+use crate::common::Model;
+use crate::gameboy::GameBoy;
+use crate::joypad::*;
+use crate::savestate::{StateError, StateReader, StateWriter};
+use crate::zip::ZipArchive;
+
+// Input movies: the joypad state for every frame, starting at power on or
+// from an embedded save state. Recorded movies also store a hash of WRAM
+// and the framebuffer per frame so replays can detect desyncs. Little endian:
+//
+//   magic "GBMV", version u16, ROM title (u32 length + bytes), global checksum u16,
+//   ROM hash u64, model u8, start state (u32 length + bytes, empty for power on),
+//   frame count u32, hashed u8, then per frame buttons u8, on SGB models the
+//   buttons of players 2-4 as 3 x u8, and, if hashed, hash u64
+
+pub const MOVIE_MAGIC: [u8; 4] = *b"GBMV";
+pub const MOVIE_VERSION: u16 = 1;
+
+// 64-bit FNV-1a
+const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+// Files inside a BizHawk .bk2 archive
+const BK2_INPUT_LOG: &str = "Input Log.txt";
+const BK2_HEADER: &str = "Header.txt";
+
+// Model byte in the header
+const MODELS: [Model; 5] = [Model::Dmg, Model::Mgb, Model::Sgb, Model::Sgb2, Model::Cgb];
+
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    RomMismatch { title: String },  // ROM the movie was recorded with
+    State(StateError),
+    Invalid(String),
+    Desync { frame: usize, expected: u64, actual: u64 },
+}
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::Io(e) => write!(f, "{}", e),
+            MovieError::BadMagic => write!(f, "Not a movie file"),
+            MovieError::UnsupportedVersion(v) => write!(f, "Unsupported movie version {}", v),
+            MovieError::RomMismatch { title } => write!(f, "Movie was recorded with another ROM: \"{}\"", title),
+            MovieError::State(e) => write!(f, "Start state: {}", e),
+            MovieError::Invalid(what) => write!(f, "Invalid movie: {}", what),
+            MovieError::Desync { frame, expected, actual } => {
+                write!(f, "Desync at frame {}: expected hash {:016X}, got {:016X}", frame, expected, actual)
+            }
+        }
+    }
+}
+impl From<io::Error> for MovieError {
+    fn from(e: io::Error) -> Self {
+        MovieError::Io(e)
+    }
+}
+impl From<StateError> for MovieError {
+    fn from(e: StateError) -> Self {
+        MovieError::State(e)
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub title: String,
+    pub global_checksum: u16,
+    pub rom_hash: u64,
+    pub model: Model,
+    pub start_state: Option<Vec<u8>>,  // None starts from power on
+    pub inputs: Vec<u8>,               // BUTTON_* flags per frame
+    pub player_inputs: Vec<[u8; 3]>,   // Players 2-4 per frame on SGB models, empty otherwise
+    pub hashes: Vec<u64>,              // Per frame, empty for imported movies
+}
+impl Movie {
+    /// Empty movie starting from power on, on the model `GameBoy::new` picks.
+    pub fn for_rom(cartridge: &Cartridge) -> Self {
+        Movie {
+            title: cartridge.get_header().get_title(),
+            global_checksum: cartridge.get_header().get_global_checksum(),
+            rom_hash: fnv1a(FNV_OFFSET, cartridge.get_rom_data()),
+            model: GameBoy::default_model(cartridge),
+            start_state: None,
+            inputs: Vec::new(),
+            player_inputs: Vec::new(),
+            hashes: Vec::new(),
+        }
+    }
+
+    /// Empty movie starting from power on, on the model `gb` is.
+    pub fn for_machine(gb: &GameBoy) -> Option<Self> {
+        let mut movie = Movie::for_rom(gb.mmu.cartridge()?);
+        movie.model = gb.model();
+        Some(movie)
+    }
+
+    /// Empty movie starting from the current state of `gb`.
+    pub fn from_state(gb: &GameBoy) -> Option<Self> {
+        let mut movie = Movie::for_machine(gb)?;
+        movie.start_state = Some(gb.save_state());
+        Some(movie)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    pub fn matches_rom(&self, cartridge: &Cartridge) -> bool {
+        self.rom_hash == fnv1a(FNV_OFFSET, cartridge.get_rom_data())
+    }
+
+    /// Builds the machine the movie starts on, power on or the embedded state.
+    pub fn start(&self, cartridge: Cartridge) -> Result<GameBoy, MovieError> {
+        if !self.matches_rom(&cartridge) {
+            return Err(MovieError::RomMismatch { title: self.title.clone() });
+        }
+        let mut gb = GameBoy::with_model(cartridge, self.model);
+        if let Some(state) = &self.start_state {
+            gb.load_state(state)?;
+        }
+        Ok(gb)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        MOVIE_MAGIC.iter().for_each(|&b| w.u8(b));
+        w.u16(MOVIE_VERSION);
+        w.bytes(self.title.as_bytes());
+        w.u16(self.global_checksum);
+        w.u64(self.rom_hash);
+        w.u8(MODELS.iter().position(|&model| model == self.model).unwrap() as u8);
+        w.bytes(self.start_state.as_deref().unwrap_or(&[]));
+
+        let hashed = self.hashes.len() == self.inputs.len() && !self.inputs.is_empty();
+        w.u32(self.inputs.len() as u32);
+        w.bool(hashed);
+        for (i, &buttons) in self.inputs.iter().enumerate() {
+            w.u8(buttons);
+            if self.model.is_sgb() {
+                let players = self.player_inputs.get(i).copied().unwrap_or_default();
+                players.iter().for_each(|&b| w.u8(b));
+            }
+            if hashed {
+                w.u64(self.hashes[i]);
+            }
+        }
+        w.into_inner()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, MovieError> {
+        let truncated = |_| MovieError::Invalid("truncated".to_string());
+        let mut r = StateReader::new(data, MOVIE_VERSION);
+        let mut magic = [0u8; 4];
+        for byte in magic.iter_mut() {
+            *byte = r.u8().map_err(truncated)?;
+        }
+        if magic != MOVIE_MAGIC {
+            return Err(MovieError::BadMagic);
+        }
+        let version = r.u16().map_err(truncated)?;
+        if version != MOVIE_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+
+        let title = String::from_utf8_lossy(r.bytes().map_err(truncated)?).to_string();
+        let global_checksum = r.u16().map_err(truncated)?;
+        let rom_hash = r.u64().map_err(truncated)?;
+        let model = *MODELS.get(r.u8().map_err(truncated)? as usize)
+            .ok_or_else(|| MovieError::Invalid("unknown model".to_string()))?;
+        let start_state = Some(r.bytes().map_err(truncated)?.to_vec()).filter(|s| !s.is_empty());
+
+        let count = r.u32().map_err(truncated)? as usize;
+        let hashed = r.bool().map_err(truncated)?;
+        let mut inputs = Vec::with_capacity(count.min(data.len()));
+        let mut player_inputs = Vec::new();
+        let mut hashes = Vec::new();
+        for _ in 0..count {
+            inputs.push(r.u8().map_err(truncated)?);
+            if model.is_sgb() {
+                player_inputs.push([r.u8().map_err(truncated)?, r.u8().map_err(truncated)?, r.u8().map_err(truncated)?]);
+            }
+            if hashed {
+                hashes.push(r.u64().map_err(truncated)?);
+            }
+        }
+        Ok(Movie { title, global_checksum, rom_hash, model, start_state, inputs, player_inputs, hashes })
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), MovieError> {
+        std::fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Self, MovieError> {
+        Movie::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// Imports a BizHawk .bk2 archive recorded with `cartridge`. Only movies
+    /// starting from power on are supported, and BizHawk's frame boundaries
+    /// need not line up with ours, so imported movies carry no hashes.
+    pub fn import_bk2(data: &[u8], cartridge: &Cartridge) -> Result<Self, MovieError> {
+        let archive = ZipArchive::parse(data).ok_or_else(|| MovieError::Invalid("not a .bk2 archive".to_string()))?;
+        if let Some(header) = archive.read(BK2_HEADER) {
+            let header = String::from_utf8_lossy(&header);
+            let from_state = header.lines()
+                .any(|line| line.trim().eq_ignore_ascii_case("StartsFromSavestate True"));
+            if from_state {
+                return Err(MovieError::Invalid("movies starting from a BizHawk savestate are not supported".to_string()));
+            }
+        }
+        let log = archive.read(BK2_INPUT_LOG)
+            .ok_or_else(|| MovieError::Invalid(format!("missing {}", BK2_INPUT_LOG)))?;
+        Movie::import_bk2_log(&String::from_utf8_lossy(&log), cartridge)
+    }
+
+    /// Imports the "Input Log.txt" of a .bk2, e.g.
+    /// `LogKey:#Up|Down|Left|Right|Start|Select|B|A|Power|` then `|U.......|` per frame.
+    pub fn import_bk2_log(log: &str, cartridge: &Cartridge) -> Result<Self, MovieError> {
+        let mut movie = Movie::for_rom(cartridge);
+        let mut buttons: Option<Vec<u8>> = None;
+
+        for line in log.lines().map(str::trim) {
+            if let Some(key) = line.strip_prefix("LogKey:") {
+                buttons = Some(key.split(['#', '|']).filter(|n| !n.is_empty()).map(bk2_button).collect());
+                continue;
+            }
+            if !line.starts_with('|') {
+                continue;
+            }
+            let buttons = buttons.as_ref().ok_or_else(|| MovieError::Invalid("input before LogKey".to_string()))?;
+            let states: Vec<char> = line.chars().filter(|&c| c != '|').collect();
+            if states.len() != buttons.len() {
+                return Err(MovieError::Invalid(format!("frame {} does not match the LogKey", movie.len())));
+            }
+            let pressed = states.iter()
+                .zip(buttons)
+                .filter(|(&state, _)| state != '.' && state != ' ')
+                .fold(0, |pressed, (_, &button)| pressed | button);
+            movie.inputs.push(pressed);
+        }
+        Ok(movie)
+    }
+}
+
+// BizHawk button name to BUTTON_* flag, 0 for the ones we ignore like Power
+fn bk2_button(name: &str) -> u8 {
+    let name = name.strip_prefix("P1 ").unwrap_or(name);
+    match name.to_ascii_lowercase().as_str() {
+        "up" => BUTTON_UP,
+        "down" => BUTTON_DOWN,
+        "left" => BUTTON_LEFT,
+        "right" => BUTTON_RIGHT,
+        "start" => BUTTON_START,
+        "select" => BUTTON_SELECT,
+        "b" => BUTTON_B,
+        "a" => BUTTON_A,
+        _ => 0,
+    }
+}
+
+
+/// Determinism check for a frame: hash of WRAM and the framebuffer.
+pub fn frame_hash(gb: &GameBoy) -> u64 {
+    let hash = gb.mmu.wram_banks().fold(FNV_OFFSET, fnv1a);
+    fnv1a(hash, gb.framebuffer())
+}
+
+fn fnv1a(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |hash, &byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+
+/// Records the buttons held for each frame the machine runs.
+pub struct Recorder {
+    pub movie: Movie,
+}
+impl Recorder {
+    /// Starts from power on if `gb` has not run yet, otherwise from its current state.
+    pub fn start(gb: &GameBoy) -> Option<Self> {
+        let movie = match gb.cycles() {
+            0 => Movie::for_machine(gb)?,
+            _ => Movie::from_state(gb)?,
+        };
+        Some(Recorder { movie })
+    }
+
+    /// Runs one frame with `buttons` held and records it, along with what
+    /// players 2-4 hold on an SGB (see `GameBoy::set_player_buttons`).
+    pub fn frame(&mut self, gb: &mut GameBoy, buttons: u8) {
+        gb.set_buttons(buttons);
+        let players = gb.mmu.sgb.as_ref().map_or([0; 3], |sgb| sgb.buttons());
+        gb.run_frame();
+        self.movie.inputs.push(buttons);
+        if self.movie.model.is_sgb() {
+            self.movie.player_inputs.push(players);
+        }
+        self.movie.hashes.push(frame_hash(gb));
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+/// Feeds a movie's inputs into a machine frame by frame.
+pub struct Player<'a> {
+    movie: &'a Movie,
+    frame: usize,
+}
+impl<'a> Player<'a> {
+    pub fn new(movie: &'a Movie) -> Self {
+        Player { movie, frame: 0 }
+    }
+
+    /// Index of the next frame to play.
+    pub fn position(&self) -> usize {
+        self.frame
+    }
+    pub fn finished(&self) -> bool {
+        self.frame >= self.movie.len()
+    }
+
+    /// Plays the next frame, checking its hash when the movie has one.
+    /// Returns false once the movie has ended.
+    pub fn frame(&mut self, gb: &mut GameBoy) -> Result<bool, MovieError> {
+        let Some(&buttons) = self.movie.inputs.get(self.frame) else {
+            return Ok(false);
+        };
+        gb.set_buttons(buttons);
+        if let Some(players) = self.movie.player_inputs.get(self.frame) {
+            for (player, &held) in players.iter().enumerate() {
+                gb.set_player_buttons(player + 1, held);
+            }
+        }
+        gb.run_frame();
+
+        if let Some(&expected) = self.movie.hashes.get(self.frame) {
+            let actual = frame_hash(gb);
+            if actual != expected {
+                return Err(MovieError::Desync { frame: self.frame, expected, actual });
+            }
+        }
+        self.frame += 1;
+        Ok(true)
+    }
+}
+
+/// Plays a whole movie on `cartridge`, returning the machine at the end.
+pub fn replay(movie: &Movie, cartridge: Cartridge) -> Result<GameBoy, MovieError> {
+    let mut gb = movie.start(cartridge)?;
+    let mut player = Player::new(movie);
+    while player.frame(&mut gb)? {}
+    Ok(gb)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::cartridge;
+
+    // Copies the buttons into WRAM so every input shows in the frame hash
+    const PROGRAM: &str = r#"
+        SECTION "code", ROM0[$0150]
+        start:
+            ld hl, $c000
+        loop:
+            ld a, $10
+            ldh [$00], a
+            ldh a, [$00]
+            ldh a, [$00]
+            ld [hl+], a
+            ld a, $20
+            ldh [$00], a
+            ldh a, [$00]
+            ldh a, [$00]
+            ld [hl+], a
+            res 4, h
+            jr loop
+    "#;
+
+    const INPUTS: [u8; 6] = [0, BUTTON_A, BUTTON_A | BUTTON_RIGHT, 0, BUTTON_START, BUTTON_DOWN | BUTTON_B];
+
+    fn rom(title: &[u8]) -> Vec<u8> {
+        let mut rom = cartridge::test_rom(&asm::assemble_program(PROGRAM).unwrap());
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom
+    }
+
+    fn cartridge(title: &[u8]) -> Cartridge {
+        Cartridge::new(rom(title)).unwrap()
+    }
+
+    fn sgb_cartridge() -> Cartridge {
+        let mut rom = rom(b"SGB");
+        rom[0x146] = 0x03;
+        Cartridge::new(rom).unwrap()
+    }
+
+    fn record(gb: &mut GameBoy) -> Movie {
+        let mut recorder = Recorder::start(gb).unwrap();
+        for buttons in INPUTS {
+            recorder.frame(gb, buttons);
+        }
+        recorder.finish()
+    }
+
+    #[test]
+    fn recorded_movie_replays() {
+        let mut gb = GameBoy::new(cartridge(b"MOVIE"));
+        let movie = record(&mut gb);
+        assert_eq!(movie.start_state, None);
+        assert_eq!(movie.inputs, INPUTS);
+        assert_eq!(movie.hashes.len(), INPUTS.len());
+
+        let loaded = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        assert_eq!(loaded, movie);
+        let replayed = replay(&loaded, cartridge(b"MOVIE")).unwrap();
+        assert_eq!(frame_hash(&replayed), frame_hash(&gb));
+        assert_eq!(replayed.save_state(), gb.save_state());
+    }
+
+    #[test]
+    fn movie_can_start_from_a_state() {
+        let mut gb = GameBoy::new(cartridge(b"MOVIE"));
+        gb.set_buttons(BUTTON_SELECT);
+        for _ in 0..3 {
+            gb.run_frame();
+        }
+        let movie = record(&mut gb);
+        assert!(movie.start_state.is_some());
+        let replayed = replay(&Movie::from_bytes(&movie.to_bytes()).unwrap(), cartridge(b"MOVIE")).unwrap();
+        assert_eq!(replayed.save_state(), gb.save_state());
+    }
+
+    #[test]
+    fn movies_replay_on_the_recorded_model() {
+        let mut gb = GameBoy::with_model(cartridge(b"MOVIE"), Model::Mgb);
+        let movie = record(&mut gb);
+        assert_eq!(movie.model, Model::Mgb);
+        assert!(movie.player_inputs.is_empty());
+        let loaded = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        assert_eq!(loaded, movie);
+        let replayed = replay(&loaded, cartridge(b"MOVIE")).unwrap();
+        assert_eq!(replayed.model(), Model::Mgb);
+        assert_eq!(replayed.save_state(), gb.save_state());
+
+        let mut bytes = movie.to_bytes();
+        let model_offset = 4 + 2 + 4 + b"MOVIE".len() + 2 + 8;
+        bytes[model_offset] = 0xFF;
+        assert!(matches!(Movie::from_bytes(&bytes), Err(MovieError::Invalid(_))));
+    }
+
+    #[test]
+    fn sgb_players_are_recorded() {
+        let mut gb = GameBoy::with_model(sgb_cartridge(), Model::Sgb);
+        let mut recorder = Recorder::start(&gb).unwrap();
+        for (frame, buttons) in INPUTS.into_iter().enumerate() {
+            gb.set_player_buttons(1 + frame % 3, buttons);
+            recorder.frame(&mut gb, buttons);
+        }
+        let movie = recorder.finish();
+        assert_eq!(movie.model, Model::Sgb);
+        assert_eq!(movie.player_inputs.len(), INPUTS.len());
+        assert_eq!(movie.player_inputs[2], [0, BUTTON_A, BUTTON_A | BUTTON_RIGHT]);
+        assert_eq!(movie.player_inputs[5], [0, BUTTON_START, BUTTON_DOWN | BUTTON_B]);
+
+        let loaded = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        assert_eq!(loaded, movie);
+        let replayed = replay(&loaded, sgb_cartridge()).unwrap();
+        assert_eq!(replayed.model(), Model::Sgb);
+        assert_eq!(replayed.mmu.sgb.as_ref().unwrap().buttons(), gb.mmu.sgb.as_ref().unwrap().buttons());
+        assert_eq!(replayed.save_state(), gb.save_state());
+    }
+
+    #[test]
+    fn changed_inputs_desync() {
+        let mut movie = record(&mut GameBoy::new(cartridge(b"MOVIE")));
+        movie.inputs[4] = BUTTON_SELECT;
+        match replay(&movie, cartridge(b"MOVIE")) {
+            Err(MovieError::Desync { frame, .. }) => assert_eq!(frame, 4),
+            other => panic!("expected a desync, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn other_roms_and_files_are_rejected() {
+        let movie = record(&mut GameBoy::new(cartridge(b"MOVIE")));
+        assert!(matches!(movie.start(cartridge(b"OTHER")), Err(MovieError::RomMismatch { .. })));
+
+        let bytes = movie.to_bytes();
+        assert!(matches!(Movie::from_bytes(b"GBSS"), Err(MovieError::BadMagic)));
+        assert!(matches!(Movie::from_bytes(&bytes[..bytes.len() - 1]), Err(MovieError::Invalid(_))));
+        let mut future = bytes.clone();
+        future[4] = 0xFF;
+        assert!(matches!(Movie::from_bytes(&future), Err(MovieError::UnsupportedVersion(_))));
+    }
+
+    #[test]
+    fn imports_bk2_input_logs() {
+        let log = "[Input]\nLogKey:#P1 Up|P1 Down|P1 Left|P1 Right|P1 Start|P1 Select|P1 B|P1 A|P1 Power|\n\
+                   |.........|\n|U......A.|\n|...R..B..|\n[/Input]\n";
+        let movie = Movie::import_bk2_log(log, &cartridge(b"MOVIE")).unwrap();
+        assert_eq!(movie.inputs, [0, BUTTON_UP | BUTTON_A, BUTTON_RIGHT | BUTTON_B]);
+        assert!(movie.hashes.is_empty());
+
+        assert!(Movie::import_bk2_log("|U.|\n", &cartridge(b"MOVIE")).is_err(), "input before LogKey");
+        assert!(Movie::import_bk2_log("LogKey:#Up|A|\n|U|\n", &cartridge(b"MOVIE")).is_err(), "wrong width");
+    }
+}
@@ -1,9 +1,13 @@
 use std::collections::HashMap;
+use std::sync::OnceLock;
 use serde::Deserialize;
 use serde::de::{self, Deserializer};
 
 type OpcodeTable = HashMap<u8, Opcode>;
 
+// Opcode metadata shipped with the crate, see data/Opcodes.json
+const OPCODES_JSON: &str = include_str!("../data/Opcodes.json");
+
 
 fn de_hex_key<'de, D, T>(deserializer: D) -> Result<HashMap<u8, T>, D::Error>
 where
@@ -28,6 +32,33 @@ pub struct OpcodeTables {
     #[serde(deserialize_with = "de_hex_key")]
     pub cbprefixed: OpcodeTable,
 }
+impl OpcodeTables {
+    /// Parses opcode tables from JSON in the Opcodes.json layout.
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        let mut tables: OpcodeTables = serde_json::from_str(json)?;
+        for (code, opcode) in tables.unprefixed.iter_mut().chain(tables.cbprefixed.iter_mut()) {
+            opcode.code = *code;
+        }
+        Ok(tables)
+    }
+    /// Loads the tables embedded in the crate.
+    pub fn load() -> Self {
+        Self::from_json(OPCODES_JSON)
+            .unwrap_or_else(|e| panic!("Failed to deserialize embedded opcode JSON: {}", e))
+    }
+    /// Shared copy of the embedded tables, parsed on first use.
+    pub fn global() -> &'static Self {
+        static TABLES: OnceLock<OpcodeTables> = OnceLock::new();
+        TABLES.get_or_init(Self::load)
+    }
+    /// Looks up an opcode, `prefixed` selects the CB table.
+    pub fn get(&self, prefixed: bool, code: u8) -> Option<&Opcode> {
+        match prefixed {
+            true => self.cbprefixed.get(&code),
+            false => self.unprefixed.get(&code),
+        }
+    }
+}
 
 
 #[allow(unused)]
@@ -49,26 +80,29 @@ pub struct Opcode {
 #[allow(unused)]
 #[derive(Debug, Deserialize)]
 pub struct Operand {
-    name: String,                   // Operand name (e.g., "A", "B", "(HL)", "d8", "a16")  
+    pub name: String,               // Operand name (e.g., "A", "B", "(HL)", "d8", "a16")  
 
     #[serde(default)]
-    bytes: Option<u8>,              // Number of bytes the operand occupies (if applicable)
+    pub bytes: Option<u8>,          // Number of bytes the operand occupies (if applicable)
 
-    #[serde(default)]
-    is_increment: bool,             // Is the operand an increment (e.g., "HL+")     
+    #[serde(default, rename = "increment")]
+    pub is_increment: bool,         // Is the operand an increment (e.g., "HL+")     
+
+    #[serde(default, rename = "decrement")]
+    pub is_decrement: bool,         // Is the operand a decrement (e.g., "HL-")
 
     #[serde(rename = "immediate")]
-    is_immediate: bool,             // Is the operand an immediate value  
+    pub is_immediate: bool,         // Is the operand an immediate value  
 }
 
 #[allow(unused)]
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "UPPERCASE")]
 pub struct Flags {
-    z: char,                        // Zero Flag
-    n: char,                        // Subtract Flag
-    h: char,                        // Half Carry Flag
-    c: char,                        // Carry Flag
+    pub z: char,                    // Zero Flag
+    pub n: char,                    // Subtract Flag
+    pub h: char,                    // Half Carry Flag
+    pub c: char,                    // Carry Flag
 }
 
 
@@ -0,0 +1,578 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use crate::common::crc32;
+
+// ROM patches applied in memory at load time:
+//   IPS  https://zerosoft.zophar.net/ips.php
+//        "PATCH", records of offset u24 BE + size u16 BE + data (size 0 is
+//        an RLE record: count u16 BE + byte), "EOF", optional truncation u24
+//   UPS  "UPS1", source and target size, then runs of a skip length and
+//        bytes XORed into the source up to a 0, CRC32 of source, target, patch
+//   BPS  https://www.romhacking.net/documents/746/
+//        "BPS1", source, target and metadata size, metadata, copy actions,
+//        CRC32 of source, target, patch
+// Numbers in UPS and BPS are the byuu variable length encoding, see `read_number`.
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: u32 = 0x454F46;  // "EOF"
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+// Three CRC32s close both UPS and BPS patches
+const FOOTER_SIZE: usize = 12;
+
+// BPS actions, packed with the length into the low bits of a number
+const BPS_SOURCE_READ: u64 = 0;
+const BPS_TARGET_READ: u64 = 1;
+const BPS_SOURCE_COPY: u64 = 2;
+const BPS_TARGET_COPY: u64 = 3;
+
+// Shortest match `create_bps` copies rather than storing literally
+const MIN_MATCH: usize = 4;
+
+// Sibling patch extensions, in the order they are looked for
+const PATCH_EXTENSIONS: [&str; 3] = ["bps", "ups", "ips"];
+// Containers the ROM may be in, stripped before looking for a patch
+const ARCHIVE_EXTENSIONS: [&str; 2] = ["gz", "zip"];
+
+
+#[derive(Debug)]
+pub enum PatchError {
+    UnknownFormat,
+    Truncated,
+    Invalid(&'static str),
+    SourceChecksum { expected: u32, actual: u32 },  // Patch made for another ROM
+    TargetChecksum { expected: u32, actual: u32 },
+    PatchChecksum { expected: u32, actual: u32 },   // Patch file damaged
+}
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "Not an IPS, UPS or BPS patch"),
+            PatchError::Truncated => write!(f, "Patch is truncated"),
+            PatchError::Invalid(what) => write!(f, "Invalid patch: {}", what),
+            PatchError::SourceChecksum { expected, actual } => {
+                write!(f, "Patch is for another ROM: expected CRC32 {:08X}, got {:08X}", expected, actual)
+            }
+            PatchError::TargetChecksum { expected, actual } => {
+                write!(f, "Patched ROM is wrong: expected CRC32 {:08X}, got {:08X}", expected, actual)
+            }
+            PatchError::PatchChecksum { expected, actual } => {
+                write!(f, "Patch is damaged: expected CRC32 {:08X}, got {:08X}", expected, actual)
+            }
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    Ips,
+    Ups,
+    Bps,
+}
+impl PatchFormat {
+    /// Identifies a patch by its magic bytes.
+    pub fn detect(patch: &[u8]) -> Option<PatchFormat> {
+        if patch.starts_with(IPS_MAGIC) {
+            Some(PatchFormat::Ips)
+        } else if patch.starts_with(UPS_MAGIC) {
+            Some(PatchFormat::Ups)
+        } else if patch.starts_with(BPS_MAGIC) {
+            Some(PatchFormat::Bps)
+        } else {
+            None
+        }
+    }
+}
+
+/// Patches `rom`, whatever format `patch` is in.
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    match PatchFormat::detect(patch).ok_or(PatchError::UnknownFormat)? {
+        PatchFormat::Ips => apply_ips(rom, patch),
+        PatchFormat::Ups => apply_ups(rom, patch),
+        PatchFormat::Bps => apply_bps(rom, patch),
+    }
+}
+
+/// `game.bps`, `game.ups` or `game.ips` next to `game.gb`, `game.gb.gz` or
+/// `game.zip`, the first one that exists.
+pub fn find_sibling<P: AsRef<Path>>(rom_path: P) -> Option<PathBuf> {
+    let rom_path = rom_path.as_ref();
+    let archived = rom_path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| ARCHIVE_EXTENSIONS.iter().any(|archive| extension.eq_ignore_ascii_case(archive)));
+    let stem = if archived { rom_path.with_extension("") } else { rom_path.to_path_buf() };
+    PATCH_EXTENSIONS.iter()
+        .map(|extension| stem.with_extension(extension))
+        .find(|path| path.is_file())
+}
+
+
+// Reads through a patch, running out of data is `Truncated`
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], offset: usize) -> Self {
+        Reader { data, offset }
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], PatchError> {
+        let end = self.offset.checked_add(count).ok_or(PatchError::Truncated)?;
+        let bytes = self.data.get(self.offset..end).ok_or(PatchError::Truncated)?;
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, PatchError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16_be(&mut self) -> Result<u16, PatchError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24_be(&mut self) -> Result<u32, PatchError> {
+        let bytes = self.bytes(3)?;
+        Ok(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]))
+    }
+
+    fn u32_le(&mut self) -> Result<u32, PatchError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // 7 bits per byte, least significant first, the last byte has bit 7 set.
+    // Each continuation also adds one so every number has a single encoding.
+    fn read_number(&mut self) -> Result<u64, PatchError> {
+        let mut number = 0u64;
+        let mut shift = 1u64;
+        loop {
+            let byte = self.u8()?;
+            number = (byte as u64 & 0x7F).checked_mul(shift)
+                .and_then(|value| number.checked_add(value))
+                .ok_or(PatchError::Invalid("number too large"))?;
+            if byte & 0x80 != 0 {
+                return Ok(number);
+            }
+            shift = shift.checked_shl(7).filter(|&shift| shift < 1 << 56).ok_or(PatchError::Invalid("number too large"))?;
+            number += shift;
+        }
+    }
+
+    fn size(&mut self) -> Result<usize, PatchError> {
+        let size = self.read_number()?;
+        usize::try_from(size).ok().filter(|&size| size <= u32::MAX as usize).ok_or(PatchError::Invalid("size too large"))
+    }
+}
+
+fn write_number(out: &mut Vec<u8>, mut number: u64) {
+    loop {
+        let byte = (number & 0x7F) as u8;
+        number >>= 7;
+        if number == 0 {
+            out.push(byte | 0x80);
+            return;
+        }
+        out.push(byte);
+        number -= 1;
+    }
+}
+
+// CRC32s closing a UPS or BPS patch: source, target, then the patch up to the last one
+fn read_footer(patch: &[u8]) -> Result<[u32; 3], PatchError> {
+    let start = patch.len().checked_sub(FOOTER_SIZE).ok_or(PatchError::Truncated)?;
+    let mut footer = Reader::new(patch, start);
+    let checksums = [footer.u32_le()?, footer.u32_le()?, footer.u32_le()?];
+    let actual = crc32(&patch[..patch.len() - 4]);
+    if actual != checksums[2] {
+        return Err(PatchError::PatchChecksum { expected: checksums[2], actual });
+    }
+    Ok(checksums)
+}
+
+fn check_target(target: &[u8], expected: u32) -> Result<(), PatchError> {
+    let actual = crc32(target);
+    match actual == expected {
+        true => Ok(()),
+        false => Err(PatchError::TargetChecksum { expected, actual }),
+    }
+}
+
+
+pub fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut r = Reader::new(patch, IPS_MAGIC.len());
+    if !patch.starts_with(IPS_MAGIC) {
+        return Err(PatchError::UnknownFormat);
+    }
+    let mut out = rom.to_vec();
+    loop {
+        let offset = r.u24_be()?;
+        if offset == IPS_EOF {
+            break;
+        }
+        let offset = offset as usize;
+        let (size, data) = match r.u16_be()? {
+            0 => {
+                let count = r.u16_be()? as usize;
+                (count, None)
+            }
+            size => (size as usize, Some(r.bytes(size as usize)?)),
+        };
+        if out.len() < offset + size {
+            out.resize(offset + size, 0);
+        }
+        match data {
+            Some(data) => out[offset..offset + size].copy_from_slice(data),
+            None => out[offset..offset + size].fill(r.u8()?),
+        }
+    }
+    // Lunar IPS extension, a size to cut the ROM down to
+    if let Ok(length) = r.u24_be() {
+        out.truncate(length as usize);
+    }
+    Ok(out)
+}
+
+/// Applies a UPS patch, which also works backwards: patching the target
+/// ROM gives back the source.
+pub fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if !patch.starts_with(UPS_MAGIC) {
+        return Err(PatchError::UnknownFormat);
+    }
+    let [source_crc, target_crc, _] = read_footer(patch)?;
+    let end = patch.len() - FOOTER_SIZE;
+    let mut r = Reader::new(&patch[..end], UPS_MAGIC.len());
+    let source_size = r.size()?;
+    let target_size = r.size()?;
+
+    let actual = crc32(rom);
+    let (size, expected) = if rom.len() == source_size && actual == source_crc {
+        (target_size, target_crc)
+    } else if rom.len() == target_size && actual == target_crc {
+        (source_size, source_crc)
+    } else {
+        return Err(PatchError::SourceChecksum { expected: source_crc, actual });
+    };
+
+    let mut out = rom.to_vec();
+    out.resize(size, 0);
+    let mut position = 0usize;
+    while r.offset < end {
+        position = position.checked_add(r.size()?).ok_or(PatchError::Invalid("offset too large"))?;
+        loop {
+            let byte = r.u8()?;
+            if position < out.len() {
+                out[position] ^= byte;
+            }
+            position += 1;
+            if byte == 0 {
+                break;
+            }
+        }
+    }
+    check_target(&out, expected)?;
+    Ok(out)
+}
+
+pub fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if !patch.starts_with(BPS_MAGIC) {
+        return Err(PatchError::UnknownFormat);
+    }
+    let [source_crc, target_crc, _] = read_footer(patch)?;
+    let end = patch.len() - FOOTER_SIZE;
+    let mut r = Reader::new(&patch[..end], BPS_MAGIC.len());
+    let source_size = r.size()?;
+    let target_size = r.size()?;
+    let metadata_size = r.size()?;
+    r.bytes(metadata_size)?;
+
+    let actual = crc32(rom);
+    if rom.len() != source_size || actual != source_crc {
+        return Err(PatchError::SourceChecksum { expected: source_crc, actual });
+    }
+
+    let mut out = Vec::with_capacity(target_size);
+    let mut source_offset = 0i64;
+    let mut target_offset = 0i64;
+    while r.offset < end {
+        let command = r.read_number()?;
+        let length = ((command >> 2) + 1) as usize;
+        if out.len() + length > target_size {
+            return Err(PatchError::Invalid("writes past the target size"));
+        }
+        match command & 3 {
+            BPS_SOURCE_READ => {
+                let start = out.len();
+                out.extend_from_slice(rom.get(start..start + length).ok_or(PatchError::Invalid("reads past the source"))?);
+            }
+            BPS_TARGET_READ => out.extend_from_slice(r.bytes(length)?),
+            BPS_SOURCE_COPY => {
+                source_offset += relative_offset(r.read_number()?);
+                let start = usize::try_from(source_offset).map_err(|_| PatchError::Invalid("reads before the source"))?;
+                out.extend_from_slice(rom.get(start..start + length).ok_or(PatchError::Invalid("reads past the source"))?);
+                source_offset += length as i64;
+            }
+            _ => {
+                target_offset += relative_offset(r.read_number()?);
+                let start = usize::try_from(target_offset).map_err(|_| PatchError::Invalid("reads before the target"))?;
+                if start >= out.len() {
+                    return Err(PatchError::Invalid("copies unwritten target data"));
+                }
+                // Byte by byte, the copy may overlap what it is writing
+                for i in start..start + length {
+                    out.push(out[i]);
+                }
+                target_offset += length as i64;
+            }
+        }
+    }
+    if out.len() != target_size {
+        return Err(PatchError::Invalid("target size mismatch"));
+    }
+    check_target(&out, target_crc)?;
+    Ok(out)
+}
+
+// Bit 0 is the sign, the rest the distance
+fn relative_offset(number: u64) -> i64 {
+    let distance = (number >> 1) as i64;
+    if number & 1 != 0 { -distance } else { distance }
+}
+
+fn encode_offset(delta: i64) -> u64 {
+    ((delta.unsigned_abs()) << 1) | (delta < 0) as u64
+}
+
+
+/// Makes a BPS patch turning `source` into `target`. Unchanged stretches
+/// are read from the source, moved ones copied from wherever they are in
+/// the source or the target written so far, the rest stored as is.
+pub fn create_bps(source: &[u8], target: &[u8], metadata: &str) -> Vec<u8> {
+    let mut patch = BPS_MAGIC.to_vec();
+    write_number(&mut patch, source.len() as u64);
+    write_number(&mut patch, target.len() as u64);
+    write_number(&mut patch, metadata.len() as u64);
+    patch.extend_from_slice(metadata.as_bytes());
+
+    // Where each run of MIN_MATCH bytes last showed up
+    let mut source_index: HashMap<&[u8], usize> = HashMap::new();
+    for (i, window) in source.windows(MIN_MATCH).enumerate() {
+        source_index.insert(window, i);
+    }
+    let mut target_index: HashMap<&[u8], usize> = HashMap::new();
+
+    let mut source_offset = 0i64;
+    let mut target_offset = 0i64;
+    let mut literal_start = 0;
+    let mut position = 0;
+    while position < target.len() {
+        let matching = |from: &[u8], start: usize| {
+            from[start..].iter().zip(&target[position..]).take_while(|(a, b)| a == b).count()
+        };
+        let read = if position < source.len() { matching(source, position) } else { 0 };
+        let window = target.get(position..position + MIN_MATCH);
+        let source_copy = window.and_then(|w| source_index.get(w)).map(|&at| (at, matching(source, at)));
+        // Only from data already written, though the match may run into the copy itself
+        let target_copy = window.and_then(|w| target_index.get(w)).map(|&at| {
+            (at, target[at..].iter().zip(&target[position..]).take_while(|(a, b)| a == b).count())
+        });
+
+        let best = [
+            (read, BPS_SOURCE_READ, 0),
+            source_copy.map_or((0, BPS_SOURCE_COPY, 0), |(at, length)| (length, BPS_SOURCE_COPY, at)),
+            target_copy.map_or((0, BPS_TARGET_COPY, 0), |(at, length)| (length, BPS_TARGET_COPY, at)),
+        ].into_iter().max_by_key(|&(length, action, _)| (length, action == BPS_SOURCE_READ)).unwrap();
+
+        let (length, action, at) = best;
+        if length < MIN_MATCH {
+            if let Some(window) = window {
+                target_index.insert(window, position);
+            }
+            position += 1;
+            continue;
+        }
+
+        write_target_read(&mut patch, &target[literal_start..position]);
+        write_number(&mut patch, (((length - 1) as u64) << 2) | action);
+        match action {
+            BPS_SOURCE_COPY => {
+                write_number(&mut patch, encode_offset(at as i64 - source_offset));
+                source_offset = (at + length) as i64;
+            }
+            BPS_TARGET_COPY => {
+                write_number(&mut patch, encode_offset(at as i64 - target_offset));
+                target_offset = (at + length) as i64;
+            }
+            _ => {}
+        }
+        for i in position..(position + length).min(target.len().saturating_sub(MIN_MATCH - 1)) {
+            target_index.insert(&target[i..i + MIN_MATCH], i);
+        }
+        position += length;
+        literal_start = position;
+    }
+    write_target_read(&mut patch, &target[literal_start..]);
+
+    patch.extend_from_slice(&crc32(source).to_le_bytes());
+    patch.extend_from_slice(&crc32(target).to_le_bytes());
+    let checksum = crc32(&patch);
+    patch.extend_from_slice(&checksum.to_le_bytes());
+    patch
+}
+
+fn write_target_read(patch: &mut Vec<u8>, literal: &[u8]) {
+    if literal.is_empty() {
+        return;
+    }
+    write_number(patch, (((literal.len() - 1) as u64) << 2) | BPS_TARGET_READ);
+    patch.extend_from_slice(literal);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..len).map(|_| {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (x >> 24) as u8
+        }).collect()
+    }
+
+    // Minimal UPS writer: XOR runs wherever the files differ
+    fn create_ups(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = UPS_MAGIC.to_vec();
+        write_number(&mut patch, source.len() as u64);
+        write_number(&mut patch, target.len() as u64);
+        let size = source.len().max(target.len());
+        let xor = |i: usize| source.get(i).copied().unwrap_or(0) ^ target.get(i).copied().unwrap_or(0);
+        let mut position = 0;
+        let mut i = 0;
+        while i < size {
+            if xor(i) == 0 {
+                i += 1;
+                continue;
+            }
+            write_number(&mut patch, (i - position) as u64);
+            while i < size && xor(i) != 0 {
+                patch.push(xor(i));
+                i += 1;
+            }
+            patch.push(0);
+            i += 1;
+            position = i;
+        }
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let checksum = crc32(&patch);
+        patch.extend_from_slice(&checksum.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn numbers_round_trip() {
+        for number in [0, 1, 0x7F, 0x80, 0x407F, 0x4080, 0xFFFF_FFFF, 1 << 48] {
+            let mut encoded = Vec::new();
+            write_number(&mut encoded, number);
+            assert_eq!(Reader::new(&encoded, 0).read_number().unwrap(), number);
+        }
+        assert!(Reader::new(&[0x00; 12], 0).read_number().is_err());
+    }
+
+    #[test]
+    fn ips_records() {
+        let source = rom(0x40, 1);
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x10, 0x00, 0x03, 0xAA, 0xBB, 0xCC]);
+        // RLE record of five 0x77 running past the end
+        patch.extend_from_slice(&[0x00, 0x00, 0x3E, 0x00, 0x00, 0x00, 0x05, 0x77]);
+        patch.extend_from_slice(b"EOF");
+
+        let out = apply(&source, &patch).unwrap();
+        assert_eq!(out.len(), 0x43);
+        assert_eq!(out[0x10..0x13], [0xAA, 0xBB, 0xCC]);
+        assert_eq!(out[0x3E..], [0x77; 5]);
+        assert_eq!(out[..0x10], source[..0x10]);
+
+        // Truncation extension
+        patch.extend_from_slice(&[0x00, 0x00, 0x20]);
+        assert_eq!(apply(&source, &patch).unwrap().len(), 0x20);
+        assert!(matches!(apply(&source, &patch[..patch.len() - 6]), Err(PatchError::Truncated)));
+    }
+
+    #[test]
+    fn ups_applies_both_ways() {
+        let source = rom(0x1000, 2);
+        let mut target = source.clone();
+        target[0x123] ^= 0x55;
+        target[0x800..0x810].fill(0);
+        target.extend_from_slice(&rom(0x100, 3));
+
+        let patch = create_ups(&source, &target);
+        assert_eq!(PatchFormat::detect(&patch), Some(PatchFormat::Ups));
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+        assert_eq!(apply(&target, &patch).unwrap(), source);
+
+        assert!(matches!(apply(&rom(0x1000, 4), &patch), Err(PatchError::SourceChecksum { .. })));
+        let mut damaged = patch.clone();
+        damaged[8] ^= 1;
+        assert!(matches!(apply(&source, &damaged), Err(PatchError::PatchChecksum { .. })));
+    }
+
+    #[test]
+    fn created_bps_patches_apply() {
+        let source = rom(0x4000, 5);
+        let mut edited = source.clone();
+        edited[0x100..0x104].copy_from_slice(b"EDIT");
+        // A block moved elsewhere, a repeated pattern and new data at the end
+        let mut moved = source[0x2000..].to_vec();
+        moved.extend_from_slice(&source[..0x2000]);
+        let repeated = [b"ABCD".repeat(100), source.clone()].concat();
+        let grown = [source.clone(), rom(0x1000, 6)].concat();
+
+        for target in [source.clone(), edited, moved, repeated, grown, source[..0x100].to_vec(), Vec::new()] {
+            let patch = create_bps(&source, &target, "<test/>");
+            assert_eq!(apply(&source, &patch).unwrap(), target, "{} bytes", target.len());
+        }
+        // Nothing new to store, so the patches stay small
+        assert!(create_bps(&source, &source, "").len() < 32);
+        let mut moved = source[0x2000..].to_vec();
+        moved.extend_from_slice(&source[..0x2000]);
+        assert!(create_bps(&source, &moved, "").len() < 48);
+
+        let from_nothing = create_bps(&[], &source, "");
+        assert_eq!(apply_bps(&[], &from_nothing).unwrap(), source);
+    }
+
+    #[test]
+    fn bps_checks_the_source() {
+        let source = rom(0x400, 7);
+        let patch = create_bps(&source, &rom(0x400, 8), "");
+        assert!(matches!(apply(&rom(0x400, 9), &patch), Err(PatchError::SourceChecksum { .. })));
+        assert!(matches!(apply(&source, &patch[..patch.len() - 1]), Err(PatchError::PatchChecksum { .. })));
+        assert!(matches!(apply(&source, b"NOPE"), Err(PatchError::UnknownFormat)));
+    }
+
+    #[test]
+    fn siblings_are_found_next_to_archives() {
+        let dir = std::env::temp_dir().join(format!("gbc_sibling_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(find_sibling(dir.join("game.gb")), None);
+
+        std::fs::write(dir.join("game.ips"), IPS_MAGIC).unwrap();
+        assert_eq!(find_sibling(dir.join("game.gb")), Some(dir.join("game.ips")));
+        std::fs::write(dir.join("game.bps"), BPS_MAGIC).unwrap();
+        for rom in ["game.gb", "game.gb.gz", "game.GBC.GZ", "game.zip", "game.gz"] {
+            assert_eq!(find_sibling(dir.join(rom)), Some(dir.join("game.bps")), "{}", rom);
+        }
+        assert_eq!(find_sibling(dir.join("other.gb.gz")), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+use crate::common::crc32;
+use crate::deflate::deflate;
+
+// Minimal PNG encoder, see https://www.w3.org/TR/png/
+// 8 bits per channel, no filtering, image data compressed with DEFLATE or
+// kept in stored blocks when that comes out smaller.
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorType {
+    Gray,
+    Rgb,
+}
+impl ColorType {
+    fn channels(&self) -> usize {
+        match self {
+            ColorType::Gray => 1,
+            ColorType::Rgb => 3,
+        }
+    }
+    fn code(&self) -> u8 {
+        match self {
+            ColorType::Gray => 0,
+            ColorType::Rgb => 2,
+        }
+    }
+}
+
+/// Encodes `pixels`, row after row with `color.channels()` bytes per pixel.
+pub fn encode(width: usize, height: usize, color: ColorType, pixels: &[u8]) -> Vec<u8> {
+    let stride = width * color.channels();
+    assert_eq!(pixels.len(), stride * height, "pixel data doesn't match the image size");
+
+    // Each row starts with its filter type, 0 for none
+    let mut raw = Vec::with_capacity((stride + 1) * height);
+    for row in pixels.chunks(stride.max(1)).take(height) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, color.code(), 0, 0, 0]);  // Depth, color, compression, filter, interlace
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// zlib stream, compressed unless the data doesn't shrink
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let compressed = deflate(data);
+    if compressed.len() < data.len() {
+        out.extend_from_slice(&compressed);
+    } else {
+        stored_blocks(&mut out, data);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+// Uncompressed DEFLATE blocks
+fn stored_blocks(out: &mut Vec<u8>, data: &[u8]) {
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(blocks.peek().is_none() as u8);  // BFINAL, BTYPE 00
+        let length = block.len() as u16;
+        out.extend_from_slice(&length.to_le_bytes());
+        out.extend_from_slice(&(!length).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inflate::inflate;
+
+    // Chunk types and data, checking each CRC
+    fn chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(png[..8], SIGNATURE);
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let length = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let (body, tail) = rest[4..].split_at(4 + length);
+            assert_eq!(crc32(body).to_be_bytes(), tail[..4]);
+            chunks.push((body[..4].try_into().unwrap(), body[4..].to_vec()));
+            rest = &tail[4..];
+        }
+        chunks
+    }
+
+    // The filtered rows the image data holds
+    fn image_data(png: &[u8]) -> Vec<u8> {
+        let chunks = chunks(png);
+        let kinds: Vec<&[u8; 4]> = chunks.iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        let zlib = &chunks[1].1;
+        assert_eq!(u16::from_be_bytes([zlib[0], zlib[1]]) % 31, 0, "zlib header check");
+        let (stream, checksum) = zlib[2..].split_at(zlib.len() - 6);
+        let raw = inflate(stream).unwrap();
+        assert_eq!(adler32(&raw).to_be_bytes(), checksum);
+        raw
+    }
+
+    #[test]
+    fn adler32_matches_known_values() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        // Long enough that the sums wrap
+        assert_eq!(adler32(&[0xFF; 100_000]), 0x149A_302C);
+    }
+
+    #[test]
+    fn header_and_rows_are_written() {
+        let pixels: Vec<u8> = (0..3 * 2 * 3).collect();
+        let png = encode(3, 2, ColorType::Rgb, &pixels);
+        let header = &chunks(&png)[0].1;
+        assert_eq!(header[..], [0, 0, 0, 3, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        let rows = [&[0][..], &pixels[..9], &[0], &pixels[9..]].concat();
+        assert_eq!(image_data(&png), rows);
+
+        let png = encode(2, 1, ColorType::Gray, &[0x00, 0xFF]);
+        assert_eq!(chunks(&png)[0].1[9], 0);
+        assert_eq!(image_data(&png), [0x00, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn data_that_doesnt_shrink_is_stored() {
+        let mut x = 1u32;
+        let noise: Vec<u8> = (0..3 * 200 * 200).map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            (x >> 24) as u8
+        }).collect();
+        // Over 64 KB, so it takes two stored blocks
+        let png = encode(200, 200, ColorType::Rgb, &noise);
+        let zlib = &chunks(&png)[1].1;
+        assert_eq!(zlib[2], 0x00, "stored, not final");
+        assert_eq!(image_data(&png).len(), 200 * 601);
+
+        let flat = encode(200, 200, ColorType::Rgb, &[0x55; 3 * 200 * 200]);
+        assert!(flat.len() < 200 * 601 / 40);
+        assert_eq!(image_data(&flat).len(), 200 * 601);
+
+        assert!(image_data(&encode(0, 0, ColorType::Gray, &[])).is_empty());
+    }
+}
@@ -0,0 +1,413 @@
+use crate::common::{INTERRUPT_LCD_STAT, INTERRUPT_VBLANK};
+use crate::savestate::{Savable, StateError, StateReader, StateWriter};
+use crate::step::Step;
+
+// Picture processing unit, see https://gbdev.io/pandocs/Rendering.html
+// Lines are rendered in one go when mode 3 ends, which is enough for
+// everything short of mid-scanline register effects. CGB VRAM bank 1 can be
+// written and read through VBK, but lines are drawn from bank 0 only.
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+// VRAM and OAM
+const VRAM_START: u16 = 0x8000;
+const VRAM_END: u16   = 0x9FFF;
+const OAM_START: u16  = 0xFE00;
+const OAM_END: u16    = 0xFE9F;
+
+const VRAM_SIZE: usize = 8 * 1024; // 8 KB
+const VRAM_BANKS: usize = 2;       // Bank 1 is CGB only
+const OAM_SIZE: usize = 160;       // 160 B
+
+// LCD registers
+pub const PPU_LCDC: u16 = 0xFF40;
+pub const PPU_STAT: u16 = 0xFF41;
+pub const PPU_SCY: u16  = 0xFF42;
+pub const PPU_SCX: u16  = 0xFF43;
+pub const PPU_LY: u16   = 0xFF44;
+pub const PPU_LYC: u16  = 0xFF45;
+pub const PPU_BGP: u16  = 0xFF47;
+pub const PPU_OBP0: u16 = 0xFF48;
+pub const PPU_OBP1: u16 = 0xFF49;
+pub const PPU_WY: u16   = 0xFF4A;
+pub const PPU_WX: u16   = 0xFF4B;
+pub const PPU_VBK: u16  = 0xFF4F;  // CGB VRAM bank
+
+// Timing in T-cycles
+const CYCLES_PER_LINE: u16 = 456;
+const OAM_SCAN_CYCLES: u16 = 80;
+const DRAWING_CYCLES: u16  = 172;
+const LINES_PER_FRAME: u8  = 154;
+
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone)]
+pub struct PPU {
+    vram: [u8; VRAM_SIZE * VRAM_BANKS],
+    vbk: u8,          // Bank the CPU sees at 0x8000, the MMU only lets CGB mode change it
+    oam: [u8; OAM_SIZE],
+
+    lcdc: u8,
+    stat: u8,   // Only the interrupt select bits 3-6 are stored
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+
+    mode: Mode,
+    dot: u16,         // Position within the current line
+    window_line: u8,  // Internal window line counter
+    stat_line: bool,  // STAT interrupt fires on the rising edge
+    pub interrupts: u8,
+    hblank_entered: bool,     // Set on entering mode 0 of a visible line, cleared by the MMU for HDMA
+    ly_stub: Option<u8>,      // Fixed LY value for tests like Gameboy Doctor
+
+    // Shade (0-3) per pixel after palette lookup
+    framebuffer: Box<[u8; SCREEN_WIDTH * SCREEN_HEIGHT]>,
+    frame_count: u64,
+}
+impl Default for PPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl PPU {
+    pub fn new() -> Self {
+        PPU {
+            vram: [0; VRAM_SIZE * VRAM_BANKS],
+            vbk: 0,
+            oam: [0; OAM_SIZE],
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+            mode: Mode::HBlank,
+            dot: 0,
+            window_line: 0,
+            stat_line: false,
+            interrupts: 0,
+            hblank_entered: false,
+            ly_stub: None,
+            framebuffer: Box::new([0; SCREEN_WIDTH * SCREEN_HEIGHT]),
+            frame_count: 0,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+    pub fn ly(&self) -> u8 {
+        self.ly
+    }
+    /// Number of frames completed, incremented on entering VBlank.
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer[..]
+    }
+    /// Whether a visible line entered HBlank since the last call.
+    pub fn take_hblank_entered(&mut self) -> bool {
+        std::mem::take(&mut self.hblank_entered)
+    }
+    /// Makes LY always read as `value`, or as the real line again with `None`.
+    pub fn set_ly_override(&mut self, value: Option<u8>) {
+        self.ly_stub = value;
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            VRAM_START..=VRAM_END => self.vram[self.vram_offset(addr)],
+            OAM_START..=OAM_END => self.oam[(addr - OAM_START) as usize],
+            PPU_LCDC => self.lcdc,
+            PPU_STAT => {
+                let coincidence = (self.ly == self.lyc) as u8;
+                0x80 | self.stat | (coincidence << 2) | self.mode as u8
+            }
+            PPU_SCY => self.scy,
+            PPU_SCX => self.scx,
+            PPU_LY => self.ly_stub.unwrap_or(self.ly),
+            PPU_LYC => self.lyc,
+            PPU_BGP => self.bgp,
+            PPU_OBP0 => self.obp0,
+            PPU_OBP1 => self.obp1,
+            PPU_WY => self.wy,
+            PPU_WX => self.wx,
+            PPU_VBK => 0xFE | self.vbk,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            VRAM_START..=VRAM_END => self.vram[self.vram_offset(addr)] = value,
+            OAM_START..=OAM_END => self.oam[(addr - OAM_START) as usize] = value,
+            PPU_LCDC => {
+                let was_on = self.lcd_enabled();
+                self.lcdc = value;
+                if was_on && !self.lcd_enabled() {
+                    // Turning the LCD off resets the line and drops into mode 0
+                    self.ly = 0;
+                    self.dot = 0;
+                    self.window_line = 0;
+                    self.mode = Mode::HBlank;
+                } else if !was_on && self.lcd_enabled() {
+                    self.mode = Mode::OamScan;
+                }
+                self.update_stat_line();
+            }
+            PPU_STAT => {
+                self.stat = value & 0x78;
+                self.update_stat_line();
+            }
+            PPU_SCY => self.scy = value,
+            PPU_SCX => self.scx = value,
+            PPU_LY => {} // Read only
+            PPU_LYC => {
+                self.lyc = value;
+                self.update_stat_line();
+            }
+            PPU_BGP => self.bgp = value,
+            PPU_OBP0 => self.obp0 = value,
+            PPU_OBP1 => self.obp1 = value,
+            PPU_WY => self.wy = value,
+            PPU_WX => self.wx = value,
+            PPU_VBK => self.vbk = value & 0x01,
+            _ => {}
+        }
+    }
+
+    fn vram_offset(&self, addr: u16) -> usize {
+        self.vbk as usize * VRAM_SIZE + (addr - VRAM_START) as usize
+    }
+
+    fn lcd_enabled(&self) -> bool {
+        self.lcdc & 0x80 != 0
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.update_stat_line();
+    }
+
+    fn update_stat_line(&mut self) {
+        let line = self.lcd_enabled() && (
+            (self.stat & 0x40 != 0 && self.ly == self.lyc)
+            || (self.stat & 0x08 != 0 && self.mode == Mode::HBlank)
+            || (self.stat & 0x10 != 0 && self.mode == Mode::VBlank)
+            || (self.stat & 0x20 != 0 && self.mode == Mode::OamScan));
+        if line && !self.stat_line {
+            self.interrupts |= INTERRUPT_LCD_STAT;
+        }
+        self.stat_line = line;
+    }
+
+    fn tick(&mut self) {
+        self.dot += 1;
+
+        if self.ly < SCREEN_HEIGHT as u8 {
+            if self.dot == OAM_SCAN_CYCLES {
+                self.set_mode(Mode::Drawing);
+            } else if self.dot == OAM_SCAN_CYCLES + DRAWING_CYCLES {
+                self.render_line();
+                self.set_mode(Mode::HBlank);
+                self.hblank_entered = true;
+            }
+        }
+
+        if self.dot < CYCLES_PER_LINE {
+            return;
+        }
+        self.dot = 0;
+        self.ly += 1;
+
+        if self.ly == SCREEN_HEIGHT as u8 {
+            self.interrupts |= INTERRUPT_VBLANK;
+            self.frame_count += 1;
+            self.set_mode(Mode::VBlank);
+        } else if self.ly == LINES_PER_FRAME {
+            self.ly = 0;
+            self.window_line = 0;
+            self.set_mode(Mode::OamScan);
+        } else if self.ly < SCREEN_HEIGHT as u8 {
+            self.set_mode(Mode::OamScan);
+        } else {
+            self.update_stat_line();
+        }
+    }
+
+    // Two bits per pixel, low bit plane first
+    fn tile_pixel(&self, tile_addr: u16, x: u8, y: u8) -> u8 {
+        let row = tile_addr as usize - VRAM_START as usize + y as usize * 2;
+        let bit = 7 - x;
+        let lo = (self.vram[row] >> bit) & 1;
+        let hi = (self.vram[row + 1] >> bit) & 1;
+        (hi << 1) | lo
+    }
+
+    // Tile data address for a background or window tile index
+    fn bg_tile_addr(&self, index: u8) -> u16 {
+        match self.lcdc & 0x10 != 0 {
+            true => 0x8000 + index as u16 * 16,
+            false => (0x9000i32 + (index as i8 as i32) * 16) as u16,
+        }
+    }
+
+    fn render_line(&mut self) {
+        let ly = self.ly;
+        let mut bg_indices = [0u8; SCREEN_WIDTH];
+        let line_start = ly as usize * SCREEN_WIDTH;
+
+        // Background and window
+        let window_visible = self.lcdc & 0x20 != 0 && self.wy <= ly && self.wx <= 166;
+        if self.lcdc & 0x01 != 0 {
+            let bg_map: u16 = if self.lcdc & 0x08 != 0 { 0x9C00 } else { 0x9800 };
+            let window_map: u16 = if self.lcdc & 0x40 != 0 { 0x9C00 } else { 0x9800 };
+
+            for (x, index) in bg_indices.iter_mut().enumerate() {
+                let in_window = window_visible && x as i16 >= self.wx as i16 - 7;
+                let (map, px, py) = match in_window {
+                    true => (window_map, (x as i16 - (self.wx as i16 - 7)) as u8, self.window_line),
+                    false => (bg_map, self.scx.wrapping_add(x as u8), self.scy.wrapping_add(ly)),
+                };
+                let map_addr = map + (py as u16 / 8) * 32 + px as u16 / 8;
+                let tile = self.vram[(map_addr - VRAM_START) as usize];
+                *index = self.tile_pixel(self.bg_tile_addr(tile), px % 8, py % 8);
+            }
+        }
+        if window_visible && self.lcdc & 0x01 != 0 {
+            self.window_line += 1;
+        }
+        for (x, &index) in bg_indices.iter().enumerate() {
+            self.framebuffer[line_start + x] = (self.bgp >> (index * 2)) & 0x03;
+        }
+
+        // Sprites
+        if self.lcdc & 0x02 == 0 {
+            return;
+        }
+        let height: i16 = if self.lcdc & 0x04 != 0 { 16 } else { 8 };
+        let mut sprites: Vec<(usize, &[u8])> = self.oam.chunks(4)
+            .enumerate()
+            .filter(|(_, s)| {
+                let top = s[0] as i16 - 16;
+                (top..top + height).contains(&(ly as i16))
+            })
+            .take(MAX_SPRITES_PER_LINE)
+            .collect();
+        // Lower X wins, ties go to the earlier OAM entry
+        sprites.sort_by_key(|&(i, s)| (s[1], i));
+
+        let mut drawn = [false; SCREEN_WIDTH];
+        let mut pixels = Vec::new();
+        for (_, sprite) in &sprites {
+            let (y, x, mut tile, attributes) = (sprite[0], sprite[1], sprite[2], sprite[3]);
+            let mut row = ly as i16 - (y as i16 - 16);
+            if attributes & 0x40 != 0 {
+                row = height - 1 - row;
+            }
+            if height == 16 {
+                tile &= 0xFE;
+            }
+            let tile_addr = 0x8000 + tile as u16 * 16;
+            for col in 0..8u8 {
+                let screen_x = x as i16 - 8 + col as i16;
+                if !(0..SCREEN_WIDTH as i16).contains(&screen_x) || drawn[screen_x as usize] {
+                    continue;
+                }
+                let px = if attributes & 0x20 != 0 { 7 - col } else { col };
+                let index = self.tile_pixel(tile_addr, px, row as u8);
+                if index == 0 {
+                    continue;
+                }
+                drawn[screen_x as usize] = true;
+                let behind_bg = attributes & 0x80 != 0 && bg_indices[screen_x as usize] != 0;
+                if !behind_bg {
+                    let palette = if attributes & 0x10 != 0 { self.obp1 } else { self.obp0 };
+                    pixels.push((screen_x as usize, (palette >> (index * 2)) & 0x03));
+                }
+            }
+        }
+        for (x, shade) in pixels {
+            self.framebuffer[line_start + x] = shade;
+        }
+    }
+}
+impl Step for PPU {
+    fn step(&mut self, cycles: u8) {
+        if !self.lcd_enabled() {
+            return;
+        }
+        for _ in 0..cycles {
+            self.tick();
+        }
+    }
+}
+impl Savable for PPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.vram);
+        w.bytes(&self.oam);
+        let registers = [
+            self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc,
+            self.bgp, self.obp0, self.obp1, self.wy, self.wx, self.vbk,
+        ];
+        registers.iter().for_each(|&value| w.u8(value));
+        w.u8(self.mode as u8);
+        w.u16(self.dot);
+        w.u8(self.window_line);
+        w.bool(self.stat_line);
+        w.u8(self.interrupts);
+        w.bytes(&self.framebuffer[..]);
+        w.u64(self.frame_count);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes_into(&mut self.vram)?;
+        r.bytes_into(&mut self.oam)?;
+        let registers = [
+            &mut self.lcdc, &mut self.stat, &mut self.scy, &mut self.scx, &mut self.ly, &mut self.lyc,
+            &mut self.bgp, &mut self.obp0, &mut self.obp1, &mut self.wy, &mut self.wx, &mut self.vbk,
+        ];
+        for register in registers {
+            *register = r.u8()?;
+        }
+        self.vbk &= 0x01;
+        self.mode = match r.u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OamScan,
+            3 => Mode::Drawing,
+            _ => return Err(StateError::Invalid("PPU mode")),
+        };
+        self.dot = r.u16()?;
+        self.window_line = r.u8()?;
+        self.stat_line = r.bool()?;
+        self.interrupts = r.u8()?;
+        r.bytes_into(&mut self.framebuffer[..])?;
+        self.frame_count = r.u64()?;
+        Ok(())
+    }
+}
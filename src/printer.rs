@@ -0,0 +1,458 @@
+use std::cell::RefCell;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+use crate::link::LinkPort;
+use crate::png::{self, ColorType};
+
+// Game Boy Printer, see https://gbdev.io/pandocs/Gameboy_Printer.html
+// Packets look like:
+//
+//   0x88 0x33, command, compression flag, data length (u16 LE), data,
+//   checksum (u16 LE, sum of command through data), then two bytes during
+//   which the printer answers 0x81 and its status
+//
+// Tile data piles up in printer memory until a PRINT command turns it into
+// paper. Prints with no margin after them continue on the same strip, so a
+// picture sent in several prints comes out as a single image.
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+const DEVICE_ID: u8 = 0x81;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_BREAK: u8 = 0x08;
+const COMMAND_STATUS: u8 = 0x0F;
+
+// Status bits
+pub const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+pub const STATUS_PRINTING: u8 = 0x02;
+pub const STATUS_MEMORY_FULL: u8 = 0x04;
+pub const STATUS_UNPROCESSED: u8 = 0x08;
+
+pub const PAPER_WIDTH: usize = 160;
+const TILES_PER_ROW: usize = PAPER_WIDTH / 8;
+const TILE_BYTES: usize = 16;
+const MEMORY_SIZE: usize = 0x2000;
+// Each margin unit feeds one tile row of blank paper
+const MARGIN_ROWS: usize = 8;
+// STATUS replies that still report printing after a PRINT
+const BUSY_POLLS: u8 = 4;
+
+// Shades 0-3 as gray levels
+const GRAY_LEVELS: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+
+/// A printout, one shade 0-3 per pixel with 0 as blank paper.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrintedImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+impl PrintedImage {
+    fn gray(&self) -> Vec<u8> {
+        self.pixels.iter().map(|&shade| GRAY_LEVELS[shade as usize & 3]).collect()
+    }
+
+    pub fn to_png(&self) -> Vec<u8> {
+        png::encode(self.width, self.height, ColorType::Gray, &self.gray())
+    }
+
+    /// Binary PGM, the simplest format anything can open.
+    pub fn to_pgm(&self) -> Vec<u8> {
+        let mut pgm = format!("P5\n{} {}\n255\n", self.width, self.height).into_bytes();
+        pgm.extend(self.gray());
+        pgm
+    }
+
+    /// Writes a PGM for `.pgm` paths, a PNG otherwise.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let pgm = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("pgm"));
+        std::fs::write(path, if pgm { self.to_pgm() } else { self.to_png() })
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Magic(usize),
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    DeviceId,
+    Status,
+}
+
+struct Device {
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    sum: u16,        // Running checksum of the packet
+    checksum: u16,   // Checksum sent by the Game Boy
+
+    memory: Vec<u8>, // Tile data waiting to be printed
+    status: u8,
+    busy: u8,
+    strip: Vec<u8>,  // Paper printed since the last cut
+    images: Vec<PrintedImage>,
+}
+impl Device {
+    fn new() -> Self {
+        Device {
+            state: State::Magic(0),
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            sum: 0,
+            checksum: 0,
+            memory: Vec::new(),
+            status: 0,
+            busy: 0,
+            strip: Vec::new(),
+            images: Vec::new(),
+        }
+    }
+
+    // Takes one byte of a packet, returns the byte shifted back
+    fn receive(&mut self, byte: u8) -> u8 {
+        match self.state {
+            State::Magic(i) => {
+                self.state = match (i, byte == MAGIC[i]) {
+                    (0, true) => State::Magic(1),
+                    (_, true) => State::Command,
+                    // A stray 0x88 may start the real packet
+                    _ if byte == MAGIC[0] => State::Magic(1),
+                    _ => State::Magic(0),
+                };
+            }
+            State::Command => {
+                self.command = byte;
+                self.sum = byte as u16;
+                self.state = State::Compression;
+            }
+            State::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                self.add(byte);
+                self.state = State::LengthLow;
+            }
+            State::LengthLow => {
+                self.length = byte as u16;
+                self.add(byte);
+                self.state = State::LengthHigh;
+            }
+            State::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.add(byte);
+                self.data.clear();
+                self.state = if self.length == 0 { State::ChecksumLow } else { State::Data };
+            }
+            State::Data => {
+                self.data.push(byte);
+                self.add(byte);
+                if self.data.len() == self.length as usize {
+                    self.state = State::ChecksumLow;
+                }
+            }
+            State::ChecksumLow => {
+                self.checksum = byte as u16;
+                self.state = State::ChecksumHigh;
+            }
+            State::ChecksumHigh => {
+                self.checksum |= (byte as u16) << 8;
+                self.state = State::DeviceId;
+                if self.checksum == self.sum {
+                    self.status &= !STATUS_CHECKSUM_ERROR;
+                    self.execute();
+                } else {
+                    self.status |= STATUS_CHECKSUM_ERROR;
+                }
+            }
+            State::DeviceId => {
+                self.state = State::Status;
+                return DEVICE_ID;
+            }
+            State::Status => {
+                self.state = State::Magic(0);
+                let status = self.status;
+                if self.busy > 0 {
+                    self.busy -= 1;
+                    if self.busy == 0 {
+                        self.status &= !STATUS_PRINTING;
+                    }
+                }
+                return status;
+            }
+        }
+        0x00
+    }
+
+    fn add(&mut self, byte: u8) {
+        self.sum = self.sum.wrapping_add(byte as u16);
+    }
+
+    fn execute(&mut self) {
+        match self.command {
+            COMMAND_INIT => {
+                self.memory.clear();
+                self.status = 0;
+                self.busy = 0;
+            }
+            COMMAND_DATA => {
+                let data = std::mem::take(&mut self.data);
+                let tiles = if self.compressed { decompress(&data) } else { data };
+                self.memory.extend_from_slice(&tiles);
+                self.memory.truncate(MEMORY_SIZE);
+                if !self.memory.is_empty() {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+                if self.memory.len() >= MEMORY_SIZE {
+                    self.status |= STATUS_MEMORY_FULL;
+                }
+            }
+            COMMAND_PRINT if self.data.len() >= 4 => {
+                let (sheets, margins, palette) = (self.data[0], self.data[1], self.data[2]);
+                self.print(sheets, margins, palette);
+                self.status = (self.status & !(STATUS_UNPROCESSED | STATUS_MEMORY_FULL)) | STATUS_PRINTING;
+                self.busy = BUSY_POLLS;
+            }
+            COMMAND_BREAK => {
+                self.memory.clear();
+                self.status &= !(STATUS_UNPROCESSED | STATUS_MEMORY_FULL | STATUS_PRINTING);
+                self.busy = 0;
+            }
+            COMMAND_STATUS => {}
+            _ => {}
+        }
+    }
+
+    fn print(&mut self, sheets: u8, margins: u8, palette: u8) {
+        // Palette 0 prints like the usual 0xE4
+        let palette = if palette == 0 { 0xE4 } else { palette };
+        let page = render(&self.memory, palette);
+        self.memory.clear();
+
+        self.feed((margins >> 4) as usize);
+        for _ in 0..sheets {
+            self.strip.extend_from_slice(&page);
+        }
+        let after = (margins & 0x0F) as usize;
+        if after > 0 {
+            self.feed(after);
+            self.cut();
+        }
+    }
+
+    fn feed(&mut self, units: usize) {
+        self.strip.resize(self.strip.len() + units * MARGIN_ROWS * PAPER_WIDTH, 0);
+    }
+
+    fn cut(&mut self) {
+        if self.strip.is_empty() {
+            return;
+        }
+        let pixels = std::mem::take(&mut self.strip);
+        self.images.push(PrintedImage { width: PAPER_WIDTH, height: pixels.len() / PAPER_WIDTH, pixels });
+    }
+}
+
+// Run length coding: a control byte with bit 7 set repeats the next byte
+// (n & 0x7F) + 2 times, otherwise n + 1 literal bytes follow
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() * 2);
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 != 0 {
+            let Some(&byte) = data.get(i) else { break };
+            out.extend(std::iter::repeat_n(byte, (control & 0x7F) as usize + 2));
+            i += 1;
+        } else {
+            let end = (i + control as usize + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    out
+}
+
+// 2bpp tiles, 20 to a row, into shades through `palette`. A last row
+// short of 20 tiles is filled out with blank paper.
+fn render(tiles: &[u8], palette: u8) -> Vec<u8> {
+    let tile_rows = tiles.len().div_ceil(TILES_PER_ROW * TILE_BYTES);
+    let height = tile_rows * 8;
+    let mut pixels = vec![0u8; PAPER_WIDTH * height];
+    for y in 0..height {
+        for x in 0..PAPER_WIDTH {
+            let tile = (y / 8) * TILES_PER_ROW + x / 8;
+            if (tile + 1) * TILE_BYTES > tiles.len() {
+                continue;
+            }
+            let row = tile * TILE_BYTES + (y % 8) * 2;
+            let bit = 7 - (x % 8);
+            let color = (((tiles[row + 1] >> bit) & 1) << 1) | ((tiles[row] >> bit) & 1);
+            pixels[y * PAPER_WIDTH + x] = (palette >> (color * 2)) & 0x03;
+        }
+    }
+    pixels
+}
+
+
+/// Game Boy Printer on the end of a link cable. Clones share the same
+/// printer: connect one to the serial port and keep the other to collect
+/// the printouts with `take_images`.
+#[derive(Clone)]
+pub struct Printer {
+    device: Rc<RefCell<Device>>,
+}
+impl Default for Printer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Printer {
+    pub fn new() -> Self {
+        Printer { device: Rc::new(RefCell::new(Device::new())) }
+    }
+
+    pub fn status(&self) -> u8 {
+        self.device.borrow().status
+    }
+
+    /// Finished printouts, including paper still in the printer.
+    pub fn take_images(&self) -> Vec<PrintedImage> {
+        let mut device = self.device.borrow_mut();
+        device.cut();
+        std::mem::take(&mut device.images)
+    }
+
+    /// Saves the printouts as `<prefix>-<n>.<extension>`, returns how many there were.
+    pub fn save_images<P: AsRef<Path>>(&self, directory: P, prefix: &str, extension: &str) -> io::Result<usize> {
+        let images = self.take_images();
+        for (i, image) in images.iter().enumerate() {
+            image.save(directory.as_ref().join(format!("{}-{}.{}", prefix, i, extension)))?;
+        }
+        Ok(images.len())
+    }
+}
+// The Game Boy always drives the clock, the printer only answers
+impl LinkPort for Printer {
+    fn exchange(&mut self, outgoing: u8) -> u8 {
+        self.device.borrow_mut().receive(outgoing)
+    }
+    fn poll(&mut self, _reply: Option<u8>) -> Option<u8> {
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(command: u8, compressed: bool, data: &[u8]) -> Vec<u8> {
+        let mut body = vec![command, compressed as u8];
+        body.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        body.extend_from_slice(data);
+        let sum = body.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+        [&MAGIC[..], &body, &sum.to_le_bytes(), &[0, 0]].concat()
+    }
+
+    // Sends a packet, returns the device ID and status bytes
+    fn send(printer: &mut Printer, packet: &[u8]) -> [u8; 2] {
+        let replies: Vec<u8> = packet.iter().map(|&b| printer.exchange(b)).collect();
+        [replies[replies.len() - 2], replies[replies.len() - 1]]
+    }
+
+    // Every tile solid colour 3
+    fn black_tiles(count: usize) -> Vec<u8> {
+        vec![0xFF; count * TILE_BYTES]
+    }
+
+    fn print(printer: &mut Printer, tiles: &[u8], margins: u8) {
+        send(printer, &packet(COMMAND_INIT, false, &[]));
+        send(printer, &packet(COMMAND_DATA, false, tiles));
+        send(printer, &packet(COMMAND_PRINT, false, &[1, margins, 0xE4, 0x40]));
+    }
+
+    #[test]
+    fn prints_whole_tile_rows() {
+        let mut printer = Printer::new();
+        assert_eq!(send(&mut printer, &packet(COMMAND_INIT, false, &[])), [DEVICE_ID, 0]);
+        // The status comes after the command has run
+        assert_eq!(send(&mut printer, &packet(COMMAND_DATA, false, &black_tiles(40))), [DEVICE_ID, STATUS_UNPROCESSED]);
+        send(&mut printer, &packet(COMMAND_PRINT, false, &[1, 0x00, 0xE4, 0x40]));
+        assert_eq!(send(&mut printer, &packet(COMMAND_STATUS, false, &[]))[1], STATUS_PRINTING);
+
+        let images = printer.take_images();
+        assert_eq!(images.len(), 1);
+        assert_eq!((images[0].width, images[0].height), (PAPER_WIDTH, 16));
+        assert!(images[0].pixels.iter().all(|&shade| shade == 3));
+    }
+
+    #[test]
+    fn partial_last_row_is_padded_with_blank_paper() {
+        let mut printer = Printer::new();
+        print(&mut printer, &black_tiles(TILES_PER_ROW + 5), 0x00);
+
+        let image = &printer.take_images()[0];
+        assert_eq!(image.height, 16);
+        let row = |y: usize| &image.pixels[y * PAPER_WIDTH..(y + 1) * PAPER_WIDTH];
+        assert!(row(7).iter().all(|&shade| shade == 3));
+        assert!(row(15)[..40].iter().all(|&shade| shade == 3));
+        assert!(row(15)[40..].iter().all(|&shade| shade == 0));
+
+        // Less than one row still prints
+        print(&mut printer, &black_tiles(1)[..TILE_BYTES - 1], 0x00);
+        print(&mut printer, &black_tiles(1), 0x00);
+        let image = &printer.take_images()[0];
+        assert_eq!(image.height, 16);
+        assert!(image.pixels[..8 * PAPER_WIDTH].iter().all(|&shade| shade == 0), "incomplete tile");
+        assert_eq!(image.pixels[8 * PAPER_WIDTH..8 * PAPER_WIDTH + 8], [3; 8]);
+    }
+
+    #[test]
+    fn margins_feed_paper_and_cut() {
+        let mut printer = Printer::new();
+        print(&mut printer, &black_tiles(TILES_PER_ROW), 0x00);
+        print(&mut printer, &black_tiles(TILES_PER_ROW), 0x12);
+        print(&mut printer, &black_tiles(TILES_PER_ROW), 0x00);
+
+        let images = printer.take_images();
+        assert_eq!(images.iter().map(|i| i.height).collect::<Vec<_>>(), [8 + 8 + 8 + 16, 8]);
+    }
+
+    #[test]
+    fn compressed_data_and_checksums() {
+        let mut printer = Printer::new();
+        send(&mut printer, &packet(COMMAND_INIT, false, &[]));
+        // 160 bytes each of 0xFF and 0x00, ten black tiles then ten blank ones
+        let mut data = Vec::new();
+        for byte in [0xFF, 0x00] {
+            data.extend_from_slice(&[0x80 | 127, byte, 0x80 | 29, byte]);
+        }
+        send(&mut printer, &packet(COMMAND_DATA, true, &data));
+        send(&mut printer, &packet(COMMAND_PRINT, false, &[1, 0x01, 0xE4, 0x40]));
+        let image = &printer.take_images()[0];
+        assert_eq!(image.height, 16);
+        for row in image.pixels[..8 * PAPER_WIDTH].chunks(PAPER_WIDTH) {
+            assert!(row[..80].iter().all(|&shade| shade == 3));
+            assert!(row[80..].iter().all(|&shade| shade == 0));
+        }
+
+        let mut bad = packet(COMMAND_DATA, false, &black_tiles(1));
+        let checksum = bad.len() - 4;
+        bad[checksum] ^= 0x01;
+        assert_eq!(send(&mut printer, &bad)[1] & STATUS_CHECKSUM_ERROR, STATUS_CHECKSUM_ERROR);
+    }
+}
@@ -0,0 +1,250 @@
+use std::f64::consts::PI;
+
+// Band-limited resampling of the APU output, see
+// http://www.slack.net/~ant/bl-synth/ for the idea. Channel outputs only
+// ever jump between levels, so each jump is added to the output as a step
+// with its high frequencies cut off: the derivative of that step, a
+// windowed sinc, goes into a buffer of deltas at the jump's exact fractional
+// sample position, and summing the deltas gives the band-limited signal.
+// That costs a few multiplies per jump rather than filtering every cycle,
+// and nothing above the host Nyquist frequency aliases back down.
+//
+// The output then goes through the high-pass filter formed by the
+// capacitor on the console's audio output, which takes out the DC offset
+// the DACs leave, see https://gbdev.io/pandocs/Audio_details.html#obscure-behavior
+
+// Kernel size in output samples, steps come out delayed by half of it
+const TAPS: usize = 16;
+// Sub-sample positions a step can start at
+const PHASE_BITS: u32 = 6;
+const PHASES: usize = 1 << PHASE_BITS;
+// Passband as a fraction of the output Nyquist frequency
+const CUTOFF: f64 = 0.9;
+
+// 32.32 fixed point output sample positions
+const FRACTION_BITS: u32 = 32;
+
+// How much of the capacitor's charge is left after each 4 MHz cycle
+const CHARGE_DMG: f64 = 0.999958;
+const CHARGE_CGB: f64 = 0.998943;
+
+
+/// The DC-blocking filter on the audio output, which differs between models.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HighPass {
+    Off,
+    #[default]
+    Dmg,
+    Cgb,  // Also the Game Boy Pocket
+}
+impl HighPass {
+    fn charge_per_cycle(self) -> f64 {
+        match self {
+            HighPass::Off => 1.0,
+            HighPass::Dmg => CHARGE_DMG,
+            HighPass::Cgb => CHARGE_CGB,
+        }
+    }
+}
+
+
+/// Turns a signal changing at `clock_rate` into band-limited samples at
+/// `sample_rate`. Mono, run one per output channel.
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    clock_rate: u32,
+    sample_rate: u32,
+    kernel: Vec<[f32; TAPS]>,  // One row per phase
+    step: u64,    // Output samples per input cycle, fixed point
+    time: u64,    // Output position, fixed point, from the start of `deltas`
+    deltas: Vec<f32>,
+    level: f32,   // Input level, as last set
+    sum: f64,     // Running sum of the deltas already read
+    charge: f64,  // Capacitor charge left after each output sample
+    capacitor: f64,
+}
+impl Resampler {
+    pub fn new(clock_rate: u32, sample_rate: u32) -> Self {
+        let mut resampler = Resampler {
+            clock_rate,
+            sample_rate,
+            kernel: kernel(),
+            step: 0,
+            time: 0,
+            deltas: vec![0.0; TAPS],
+            level: 0.0,
+            sum: 0.0,
+            charge: 1.0,
+            capacitor: 0.0,
+        };
+        resampler.set_ratio(1.0);
+        resampler
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Produces `ratio` times as many samples per input second, for dynamic
+    /// rate control. Takes effect from the next cycle.
+    pub fn set_ratio(&mut self, ratio: f64) {
+        let step = self.sample_rate as f64 * ratio / self.clock_rate as f64;
+        self.step = (step * (1u64 << FRACTION_BITS) as f64).round() as u64;
+    }
+
+    pub fn set_high_pass(&mut self, high_pass: HighPass) {
+        let cycles_per_sample = self.clock_rate as f64 / self.sample_rate as f64;
+        self.charge = high_pass.charge_per_cycle().powf(cycles_per_sample);
+    }
+
+    /// Moves on `cycles` input cycles.
+    pub fn advance(&mut self, cycles: u32) {
+        self.time += self.step * cycles as u64;
+        let needed = (self.time >> FRACTION_BITS) as usize + TAPS + 1;
+        if self.deltas.len() < needed {
+            self.deltas.resize(needed, 0.0);
+        }
+    }
+
+    /// Changes the input to `level` at the current time.
+    pub fn set_level(&mut self, level: f32) {
+        let delta = level - self.level;
+        if delta == 0.0 {
+            return;
+        }
+        self.level = level;
+        let start = (self.time >> FRACTION_BITS) as usize;
+        let phase = (self.time >> (FRACTION_BITS - PHASE_BITS)) as usize & (PHASES - 1);
+        for (out, tap) in self.deltas[start..start + TAPS].iter_mut().zip(&self.kernel[phase]) {
+            *out += delta * tap;
+        }
+    }
+
+    /// Samples finished so far.
+    pub fn available(&self) -> usize {
+        (self.time >> FRACTION_BITS) as usize
+    }
+
+    /// Takes every finished sample, high-pass filtered.
+    pub fn read(&mut self) -> Vec<f32> {
+        let count = self.available();
+        let mut samples = Vec::with_capacity(count);
+        for delta in self.deltas.drain(..count) {
+            self.sum += delta as f64;
+            let out = self.sum - self.capacitor;
+            self.capacitor = self.sum - out * self.charge;
+            samples.push(out as f32);
+        }
+        self.time -= (count as u64) << FRACTION_BITS;
+        samples
+    }
+}
+
+
+/// Rate ratio for a frontend keeping its audio buffer half full, from how
+/// full it is now (0.0 to 1.0). Feed the result to `Apu::set_rate_ratio`
+/// every frame. `max_deviation` around 0.005 keeps the pitch change
+/// inaudible, see https://docs.libretro.com/development/cores/dynamic-rate-control/
+pub fn dynamic_rate_ratio(buffer_fill: f64, max_deviation: f64) -> f64 {
+    1.0 + (1.0 - 2.0 * buffer_fill.clamp(0.0, 1.0)) * max_deviation
+}
+
+// Blackman windowed sinc for each phase, each row summing to 1 so steps
+// settle at exactly their height
+fn kernel() -> Vec<[f32; TAPS]> {
+    let half = (TAPS / 2) as f64;
+    (0..PHASES).map(|phase| {
+        let offset = phase as f64 / PHASES as f64;
+        let mut row = [0.0f64; TAPS];
+        for (i, tap) in row.iter_mut().enumerate() {
+            let x = i as f64 - half - offset + 1.0;
+            let sinc = if x == 0.0 { 1.0 } else { (PI * CUTOFF * x).sin() / (PI * CUTOFF * x) };
+            let w = (x + half) / TAPS as f64;
+            let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+            *tap = sinc * window;
+        }
+        let total: f64 = row.iter().sum();
+        row.map(|tap| (tap / total) as f32)
+    }).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLOCK: u32 = 4_194_304;
+
+    // Runs `cycles` at most 255 at a time, as the APU does
+    fn run(resampler: &mut Resampler, mut cycles: u32) -> Vec<f32> {
+        let mut samples = Vec::new();
+        while cycles > 0 {
+            let chunk = cycles.min(255);
+            resampler.advance(chunk);
+            samples.extend(resampler.read());
+            cycles -= chunk;
+        }
+        samples
+    }
+
+    fn step_response(high_pass: HighPass, cycles: u32) -> Vec<f32> {
+        let mut resampler = Resampler::new(CLOCK, 48_000);
+        resampler.set_high_pass(high_pass);
+        run(&mut resampler, 1000);
+        resampler.set_level(1.0);
+        run(&mut resampler, cycles)
+    }
+
+    #[test]
+    fn steps_settle_at_their_level() {
+        for row in kernel() {
+            assert!((row.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        }
+        // At every sub-sample position
+        for offset in 0..PHASES as u32 {
+            let mut resampler = Resampler::new(CLOCK, 48_000);
+            resampler.set_high_pass(HighPass::Off);
+            run(&mut resampler, 1000 + offset);
+            for level in [0.25, -0.5] {
+                resampler.set_level(level);
+                let samples = run(&mut resampler, 4000);
+                assert!(samples.iter().rev().take(10).all(|&sample| (sample - level).abs() < 1e-5));
+            }
+        }
+    }
+
+    #[test]
+    fn ratio_scales_the_sample_count() {
+        for (rate, ratio) in [(48_000, 1.0), (44_100, 1.005), (32_768, 0.995)] {
+            let mut resampler = Resampler::new(CLOCK, rate);
+            resampler.set_ratio(ratio);
+            let samples = run(&mut resampler, CLOCK).len() as f64;
+            assert!((samples - rate as f64 * ratio).abs() <= 1.0, "{} Hz at {}: {}", rate, ratio, samples);
+        }
+    }
+
+    #[test]
+    fn high_pass_takes_out_dc() {
+        let second = |high_pass| step_response(high_pass, CLOCK);
+        assert!(second(HighPass::Off).iter().rev().take(100).all(|&sample| (sample - 1.0).abs() < 1e-5));
+        assert!(second(HighPass::Dmg).last().unwrap().abs() < 1e-3);
+        assert!(second(HighPass::Cgb).last().unwrap().abs() < 1e-3);
+
+        // The CGB capacitor drains faster
+        let early = |high_pass| *step_response(high_pass, 20_000).last().unwrap();
+        assert!(early(HighPass::Cgb) < 0.01);
+        assert!(early(HighPass::Dmg) > 0.3);
+        assert!(early(HighPass::Dmg) < early(HighPass::Off));
+    }
+
+    #[test]
+    fn dynamic_rate_stays_within_the_deviation() {
+        assert_eq!(dynamic_rate_ratio(0.5, 0.005), 1.0);
+        assert_eq!(dynamic_rate_ratio(0.0, 0.005), 1.005);
+        assert_eq!(dynamic_rate_ratio(1.0, 0.005), 0.995);
+        // Fill levels out of range are clamped
+        assert_eq!(dynamic_rate_ratio(-3.0, 0.005), 1.005);
+        assert_eq!(dynamic_rate_ratio(7.0, 0.005), 0.995);
+        assert!(dynamic_rate_ratio(0.25, 0.005) > 1.0);
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::VecDeque;
+use crate::gameboy::GameBoy;
+use crate::savestate::StateError;
+
+// Rewind buffer built on save states. Every `interval` frames a snapshot is
+// taken; most are stored as the XOR against the last keyframe, which is
+// almost all zeros and shrinks well with RLE. The oldest keyframe and its
+// deltas are dropped once the memory budget is exceeded.
+
+// RLE control bytes: below 0x80 a run of (n + 1) copies of the next byte,
+// 0x80 and above (n - 0x80 + 1) literal bytes follow
+const RLE_MAX_LENGTH: usize = 128;
+const RLE_MIN_RUN: usize = 3;
+
+
+#[derive(Debug, Clone, Copy)]
+pub struct RewindConfig {
+    pub interval: u32,            // Frames between snapshots
+    pub keyframe_interval: usize, // Snapshots between keyframes
+    pub memory_budget: usize,     // Bytes
+}
+impl Default for RewindConfig {
+    fn default() -> Self {
+        RewindConfig { interval: 1, keyframe_interval: 60, memory_budget: 32 * 1024 * 1024 }
+    }
+}
+
+struct Snapshot {
+    frame: u64,
+    keyframe: bool,
+    data: Vec<u8>, // RLE of the state, or of its XOR with the keyframe
+}
+
+pub struct Rewind {
+    pub config: RewindConfig,
+    snapshots: VecDeque<Snapshot>,
+    keyframe: Vec<u8>,        // Uncompressed copy of the newest keyframe
+    since_keyframe: usize,
+    frame: u64,               // Frames seen by `capture`
+    memory_used: usize,
+}
+impl Rewind {
+    pub fn new(config: RewindConfig) -> Self {
+        Rewind {
+            config,
+            snapshots: VecDeque::new(),
+            keyframe: Vec::new(),
+            since_keyframe: 0,
+            frame: 0,
+            memory_used: 0,
+        }
+    }
+
+    /// Call once per emulated frame, takes a snapshot every `interval` frames.
+    pub fn capture(&mut self, gb: &GameBoy) {
+        self.frame += 1;
+        if !self.frame.is_multiple_of(self.config.interval.max(1) as u64) {
+            return;
+        }
+        let state = gb.save_state();
+
+        let mut keyframe = self.keyframe.len() != state.len()
+            || self.since_keyframe >= self.config.keyframe_interval;
+        let data = if keyframe {
+            rle_encode(&state)
+        } else {
+            let delta: Vec<u8> = state.iter().zip(&self.keyframe).map(|(a, b)| a ^ b).collect();
+            rle_encode(&delta)
+        };
+        // A delta bigger than a fresh keyframe is not worth keeping
+        let data = if !keyframe && data.len() > state.len() / 2 {
+            keyframe = true;
+            rle_encode(&state)
+        } else {
+            data
+        };
+
+        if keyframe {
+            self.keyframe = state;
+            self.since_keyframe = 0;
+        }
+        self.since_keyframe += 1;
+        self.memory_used += data.len();
+        self.snapshots.push_back(Snapshot { frame: self.frame, keyframe, data });
+        self.enforce_budget();
+    }
+
+    /// Restores the newest snapshot at least `frames` frames back, or the
+    /// oldest one available. Later snapshots are discarded so repeated calls
+    /// keep going backwards. Returns the number of frames actually rewound.
+    pub fn rewind(&mut self, gb: &mut GameBoy, frames: u64) -> Result<u64, StateError> {
+        if self.snapshots.is_empty() {
+            return Ok(0);
+        }
+        let target = self.frame.saturating_sub(frames);
+        let index = self.snapshots.iter().rposition(|s| s.frame <= target).unwrap_or(0);
+
+        let state = self.reconstruct(index);
+        gb.load_state(&state)?;
+
+        while self.snapshots.len() > index + 1 {
+            let snapshot = self.snapshots.pop_back().unwrap();
+            self.memory_used -= snapshot.data.len();
+        }
+        // Later deltas must be taken against the keyframe of the restored snapshot
+        let key_index = self.keyframe_index(index);
+        self.keyframe = rle_decode(&self.snapshots[key_index].data);
+        self.since_keyframe = index - key_index + 1;
+
+        let rewound = self.frame - self.snapshots[index].frame;
+        self.frame = self.snapshots[index].frame;
+        Ok(rewound)
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+    /// Frames that can currently be rewound.
+    pub fn available_frames(&self) -> u64 {
+        self.snapshots.front().map_or(0, |s| self.frame - s.frame)
+    }
+    /// Compressed bytes held, plus the cached keyframe.
+    pub fn memory_used(&self) -> usize {
+        self.memory_used + self.keyframe.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.keyframe.clear();
+        self.since_keyframe = 0;
+        self.memory_used = 0;
+    }
+
+    fn keyframe_index(&self, index: usize) -> usize {
+        (0..=index).rev().find(|&i| self.snapshots[i].keyframe).expect("buffer starts with a keyframe")
+    }
+
+    fn reconstruct(&self, index: usize) -> Vec<u8> {
+        let snapshot = &self.snapshots[index];
+        if snapshot.keyframe {
+            return rle_decode(&snapshot.data);
+        }
+        let keyframe = rle_decode(&self.snapshots[self.keyframe_index(index)].data);
+        rle_decode(&snapshot.data).iter().zip(&keyframe).map(|(a, b)| a ^ b).collect()
+    }
+
+    // Drops whole keyframe groups from the front, always keeping the newest one
+    fn enforce_budget(&mut self) {
+        while self.memory_used() > self.config.memory_budget {
+            let Some(next_key) = self.snapshots.iter().skip(1).position(|s| s.keyframe) else {
+                break;
+            };
+            for snapshot in self.snapshots.drain(..=next_key) {
+                self.memory_used -= snapshot.data.len();
+            }
+        }
+    }
+}
+
+
+pub fn rle_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 4);
+    let mut literal_start = 0;
+    let mut i = 0;
+
+    let flush_literals = |out: &mut Vec<u8>, literals: &[u8]| {
+        for chunk in literals.chunks(RLE_MAX_LENGTH) {
+            out.push(0x80 | (chunk.len() - 1) as u8);
+            out.extend_from_slice(chunk);
+        }
+    };
+
+    while i < data.len() {
+        let value = data[i];
+        let run = data[i..].iter().take(RLE_MAX_LENGTH).take_while(|&&b| b == value).count();
+        if run >= RLE_MIN_RUN {
+            flush_literals(&mut out, &data[literal_start..i]);
+            out.push((run - 1) as u8);
+            out.push(value);
+            i += run;
+            literal_start = i;
+        } else {
+            i += run;
+        }
+    }
+    flush_literals(&mut out, &data[literal_start..]);
+    out
+}
+
+pub fn rle_decode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i] as usize;
+        i += 1;
+        if control < 0x80 {
+            let value = data.get(i).copied().unwrap_or(0);
+            out.extend(std::iter::repeat_n(value, control + 1));
+            i += 1;
+        } else {
+            let end = (i + control - 0x80 + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::cartridge::{self, Cartridge};
+    use crate::common::Model;
+
+    const PROGRAM: &str = r#"
+        SECTION "code", ROM0[$0150]
+        start:
+            ld hl, $c000
+        loop:
+            ldh a, [$04]
+            ld [hl+], a
+            res 4, h
+            jr loop
+    "#;
+
+    fn machine() -> GameBoy {
+        let rom = cartridge::test_rom(&asm::assemble_program(PROGRAM).unwrap());
+        GameBoy::with_model(Cartridge::new(rom).unwrap(), Model::Dmg)
+    }
+
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..len).map(|_| {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (x >> 24) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn rle_round_trips() {
+        let mut cases = vec![Vec::new(), vec![7], vec![7, 7], vec![7, 7, 7], noise(1000, 1)];
+        for run in [2, 3, 127, 128, 129, 256, 300] {
+            cases.push([vec![1, 2], vec![0; run], vec![3]].concat());
+        }
+        for literals in [127, 128, 129, 300] {
+            cases.push([noise(literals, literals as u32), vec![9; 10]].concat());
+        }
+        for data in cases {
+            assert_eq!(rle_decode(&rle_encode(&data)), data, "{} bytes", data.len());
+        }
+    }
+
+    #[test]
+    fn rle_shrinks_mostly_zero_deltas() {
+        let mut delta = vec![0; 20_000];
+        delta[100] = 1;
+        delta[15_000..15_010].copy_from_slice(&noise(10, 5));
+        let encoded = rle_encode(&delta);
+        assert!(encoded.len() < 400, "{} bytes", encoded.len());
+        assert_eq!(rle_decode(&encoded), delta);
+        // Random data costs at most one control byte per 128
+        assert!(rle_encode(&noise(1280, 3)).len() <= 1290);
+    }
+
+    #[test]
+    fn rewind_restores_earlier_states() {
+        let mut gb = machine();
+        let mut rewind = Rewind::new(RewindConfig { interval: 1, keyframe_interval: 4, ..RewindConfig::default() });
+        let mut states = Vec::new();
+        for _ in 0..10 {
+            gb.run_frame();
+            rewind.capture(&gb);
+            states.push(gb.save_state());
+        }
+        assert_eq!(rewind.len(), 10);
+        assert_eq!(rewind.available_frames(), 9);
+
+        // Frame 7 is a delta against the keyframe taken at frame 5
+        assert_eq!(rewind.rewind(&mut gb, 3).unwrap(), 3);
+        assert_eq!(gb.save_state(), states[6]);
+        assert_eq!(rewind.rewind(&mut gb, 2).unwrap(), 2);
+        assert_eq!(gb.save_state(), states[4]);
+
+        // Captures carry on from the restored point
+        gb.run_frame();
+        rewind.capture(&gb);
+        let state = gb.save_state();
+        gb.run_frame();
+        assert_eq!(rewind.rewind(&mut gb, 0).unwrap(), 0, "only captured frames count");
+        assert_eq!(gb.save_state(), state);
+        assert_eq!(rewind.rewind(&mut gb, 100).unwrap(), 5);
+        assert_eq!(gb.save_state(), states[0]);
+    }
+
+    #[test]
+    fn budget_drops_the_oldest_keyframe_groups() {
+        let mut gb = machine();
+        let mut rewind = Rewind::new(RewindConfig { interval: 1, keyframe_interval: 2, memory_budget: 0 });
+        for _ in 0..6 {
+            gb.run_frame();
+            rewind.capture(&gb);
+        }
+        // Only the newest keyframe and its delta are left
+        assert!(rewind.len() <= 2);
+        let state = gb.save_state();
+        assert_eq!(rewind.rewind(&mut gb, 0).unwrap(), 0);
+        assert_eq!(gb.save_state(), state);
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Save state container. Little endian throughout:
+//
+//   magic "GBSS", version u16, ROM title (u32 length + bytes), global checksum u16,
+//   section count u16, then per section: tag [u8; 4], length u32, payload
+//
+// Each component writes its own section through `Savable`, so unknown
+// sections can be skipped and a component can grow without breaking the
+// others. Layout changes bump STATE_VERSION.
+
+pub const STATE_MAGIC: [u8; 4] = *b"GBSS";
+pub const STATE_VERSION: u16 = 1;
+
+pub type SectionTag = [u8; 4];
+pub type Sections = BTreeMap<SectionTag, Vec<u8>>;
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    RomMismatch { title: String, global_checksum: u16 },  // ROM the state was made with
+    MissingSection(SectionTag),
+    Truncated,
+    Invalid(&'static str),
+}
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(v) => write!(f, "Unsupported save state version {}", v),
+            StateError::RomMismatch { title, global_checksum } => {
+                write!(f, "Save state belongs to another ROM: \"{}\" (checksum 0x{:04X})", title, global_checksum)
+            }
+            StateError::MissingSection(tag) => write!(f, "Save state is missing section {}", String::from_utf8_lossy(tag)),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::Invalid(what) => write!(f, "Invalid save state: {}", what),
+        }
+    }
+}
+
+
+/// Components that can be written to and restored from a save state.
+pub trait Savable {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>;
+}
+
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    /// Length prefixed byte string.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    version: u16,
+}
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8], version: u16) -> Self {
+        StateReader { data, pos: 0, version }
+    }
+    /// Version the state was written with.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        let end = self.pos.checked_add(length).ok_or(StateError::Truncated)?;
+        let slice = self.data.get(self.pos..end).ok_or(StateError::Truncated)?;
+        self.pos = end;
+        Ok(slice)
+    }
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+    pub fn bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+    /// Reads a byte string that must fill `buffer` exactly.
+    pub fn bytes_into(&mut self, buffer: &mut [u8]) -> Result<(), StateError> {
+        let bytes = self.bytes()?;
+        if bytes.len() != buffer.len() {
+            return Err(StateError::Invalid("memory size mismatch"));
+        }
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+
+/// Identifies the ROM a state was made with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateHeader {
+    pub version: u16,
+    pub title: String,
+    pub global_checksum: u16,
+}
+
+/// Serializes one component into a section.
+pub fn save_section<S: Savable + ?Sized>(sections: &mut Sections, tag: SectionTag, component: &S) {
+    let mut w = StateWriter::new();
+    component.save_state(&mut w);
+    sections.insert(tag, w.into_inner());
+}
+
+/// Restores one component from its section.
+pub fn load_section<S: Savable + ?Sized>(sections: &Sections, tag: SectionTag, version: u16, component: &mut S) -> Result<(), StateError> {
+    let data = sections.get(&tag).ok_or(StateError::MissingSection(tag))?;
+    component.load_state(&mut StateReader::new(data, version))
+}
+
+pub fn write_state(header: &StateHeader, sections: &Sections) -> Vec<u8> {
+    let mut w = StateWriter::new();
+    STATE_MAGIC.iter().for_each(|&b| w.u8(b));
+    w.u16(STATE_VERSION);
+    w.bytes(header.title.as_bytes());
+    w.u16(header.global_checksum);
+    w.u16(sections.len() as u16);
+    for (tag, data) in sections {
+        tag.iter().for_each(|&b| w.u8(b));
+        w.bytes(data);
+    }
+    w.into_inner()
+}
+
+/// Parses the container into its header and sections.
+pub fn read_state(data: &[u8]) -> Result<(StateHeader, Sections), StateError> {
+    let mut r = StateReader::new(data, 0);
+    if r.take(4)? != STATE_MAGIC {
+        return Err(StateError::BadMagic);
+    }
+    let version = r.u16()?;
+    if version != STATE_VERSION {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+    let title = String::from_utf8_lossy(r.bytes()?).to_string();
+    let global_checksum = r.u16()?;
+
+    let mut sections = Sections::new();
+    for _ in 0..r.u16()? {
+        let tag: SectionTag = r.take(4)?.try_into().unwrap();
+        sections.insert(tag, r.bytes()?.to_vec());
+    }
+    let header = StateHeader { version: STATE_VERSION, title, global_checksum };
+    Ok((header, sections))
+}
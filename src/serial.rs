@@ -0,0 +1,178 @@
+use crate::common::INTERRUPT_SERIAL;
+use crate::link::{Disconnected, LinkPort};
+use crate::savestate::{Savable, StateError, StateReader, StateWriter};
+use crate::step::Step;
+
+// Serial port, see https://gbdev.io/pandocs/Serial_Data_Transfer_(Link_Cable).html
+// Bytes go over the `LinkPort` whole: the side with the internal clock
+// exchanges its byte when the transfer starts and shifts the answer in a
+// bit at a time, the externally clocked side completes as soon as the
+// other side's byte arrives.
+
+pub const SERIAL_SB: u16 = 0xFF01;
+pub const SERIAL_SC: u16 = 0xFF02;
+
+// T-cycles per bit, the CGB fast clock is 32 times faster
+const BIT_CYCLES: u16 = 512;
+const FAST_BIT_CYCLES: u16 = 16;
+
+
+pub struct Serial {
+    sb: u8,
+    sc: u8,                // Bit 7 transfer running, bit 1 fast clock (CGB), bit 0 internal clock
+    bits_left: u8,         // Bits still to shift while driving the clock
+    bit_cycles: u16,       // T-cycles until the next bit
+    incoming: u8,          // Byte the other side sent, shifted in MSB first
+    awaiting_reply: bool,  // The cable hasn't delivered `incoming` yet, the clock holds
+    cgb_mode: bool,
+    port: Box<dyn LinkPort>,
+    pub interrupts: u8,
+}
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Serial {
+    pub fn new() -> Self {
+        Serial {
+            sb: 0,
+            sc: 0,
+            bits_left: 0,
+            bit_cycles: 0,
+            incoming: 0xFF,
+            awaiting_reply: false,
+            cgb_mode: false,
+            port: Box::new(Disconnected),
+            interrupts: 0,
+        }
+    }
+
+    /// Plugs in a link cable, replacing the current one.
+    pub fn connect(&mut self, port: Box<dyn LinkPort>) {
+        self.port = port;
+    }
+    pub fn disconnect(&mut self) {
+        self.port = Box::new(Disconnected);
+    }
+    /// Back to power on state, the cable stays plugged in.
+    pub fn reset(&mut self) {
+        let port = std::mem::replace(&mut self.port, Box::new(Disconnected));
+        *self = Serial::new();
+        self.port = port;
+    }
+    /// Enables the fast clock bit of SC.
+    pub fn set_cgb_mode(&mut self, enabled: bool) {
+        self.cgb_mode = enabled;
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            SERIAL_SB => self.sb,
+            SERIAL_SC if self.cgb_mode => 0x7C | self.sc,
+            SERIAL_SC => 0x7E | self.sc,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            SERIAL_SB => self.sb = value,
+            SERIAL_SC => {
+                let mask = if self.cgb_mode { 0x83 } else { 0x81 };
+                self.sc = value & mask;
+                if self.transferring() && self.internal_clock() {
+                    self.start();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn transferring(&self) -> bool {
+        self.sc & 0x80 != 0
+    }
+    fn internal_clock(&self) -> bool {
+        self.sc & 0x01 != 0
+    }
+
+    fn bit_length(&self) -> u16 {
+        if self.sc & 0x02 != 0 { FAST_BIT_CYCLES } else { BIT_CYCLES }
+    }
+
+    fn start(&mut self) {
+        let reply = self.port.start(self.sb);
+        self.incoming = reply.unwrap_or(0xFF);
+        self.awaiting_reply = reply.is_none();
+        self.bits_left = 8;
+        self.bit_cycles = self.bit_length();
+    }
+
+    fn finish(&mut self) {
+        self.sc &= 0x7F;
+        self.interrupts |= INTERRUPT_SERIAL;
+    }
+}
+// Takes CPU T-cycles, the clock doubles along with the CPU in double speed
+impl Step for Serial {
+    fn step(&mut self, cycles: u8) {
+        if !self.internal_clock() || !self.transferring() {
+            // The other side may clock a byte in whenever, as long as a transfer is armed
+            let waiting = (self.transferring() && !self.internal_clock()).then_some(self.sb);
+            if let Some(byte) = self.port.poll(waiting) {
+                self.sb = byte;
+                self.finish();
+            }
+            return;
+        }
+
+        if self.awaiting_reply {
+            match self.port.reply() {
+                Some(byte) => {
+                    self.incoming = byte;
+                    self.awaiting_reply = false;
+                }
+                None => return,
+            }
+        }
+
+        let mut cycles = cycles as u16;
+        while self.bits_left > 0 && cycles > 0 {
+            let elapsed = cycles.min(self.bit_cycles);
+            cycles -= elapsed;
+            self.bit_cycles -= elapsed;
+            if self.bit_cycles > 0 {
+                break;
+            }
+            self.sb = (self.sb << 1) | (self.incoming >> 7);
+            self.incoming <<= 1;
+            self.bits_left -= 1;
+            self.bit_cycles = self.bit_length();
+        }
+        if self.bits_left == 0 {
+            self.finish();
+        }
+    }
+}
+// The cable isn't part of the state, a transfer in flight finishes with the byte already exchanged
+impl Savable for Serial {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.sb);
+        w.u8(self.sc);
+        w.u8(self.bits_left);
+        w.u16(self.bit_cycles);
+        w.u8(self.incoming);
+        w.u8(self.interrupts);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.sb = r.u8()?;
+        self.sc = r.u8()? & 0x83;
+        self.bits_left = r.u8()?.min(8);
+        self.bit_cycles = r.u16()?.min(BIT_CYCLES);
+        self.incoming = r.u8()?;
+        self.awaiting_reply = false;
+        self.interrupts = r.u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,796 @@
+use crate::joypad::Joypad;
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::savestate::{Savable, StateError, StateReader, StateWriter};
+
+// Super Game Boy, see https://gbdev.io/pandocs/SGB_Functions.html
+// Games talk to the SNES side through the joypad register. Writing 0x00 to
+// P1 starts a 16 byte packet, then each bit is a write of 0x20 (0) or 0x10
+// (1) followed by 0x30, least significant bit first, and a 0 stop bit ends
+// the packet. The first byte is the command times 8 plus how many packets
+// the command takes.
+//
+// Bulk data (borders, palettes, attribute files) goes through the screen:
+// after a *_TRN command the SGB reads 4 KB from the next frame, tile by tile
+// as 20 tiles per row in the order the game would fill VRAM.
+//
+// Colours are RGB555. The output is the 256x224 SNES picture with the game
+// in the middle of the border.
+
+pub const SGB_SCREEN_WIDTH: usize = 256;
+pub const SGB_SCREEN_HEIGHT: usize = 224;
+// Where the Game Boy screen sits inside the border
+pub(crate) const GAME_X: usize = 48;
+pub(crate) const GAME_Y: usize = 40;
+
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+
+// Commands
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+const TRANSFER_SIZE: usize = 0x1000;
+// Frames between a *_TRN command and the frame that gets read, so the
+// screen is completely drawn after the command
+const TRANSFER_DELAY: u8 = 2;
+
+// 20x18 attribute cells, one per tile
+const ATTR_WIDTH: usize = SCREEN_WIDTH / 8;
+const ATTR_HEIGHT: usize = SCREEN_HEIGHT / 8;
+const ATTR_FILES: usize = 45;
+const ATTR_FILE_SIZE: usize = ATTR_WIDTH * ATTR_HEIGHT / 4;
+
+const SYSTEM_PALETTES: usize = 512;
+const BORDER_TILES: usize = 256;
+const BORDER_TILE_SIZE: usize = 32;  // 8x8 at 4 bits per pixel, SNES layout
+const BORDER_MAP_WIDTH: usize = 32;
+const BORDER_MAP_HEIGHT: usize = 28;
+const BORDER_PALETTES_OFFSET: usize = 0x800;
+const BORDER_FIRST_PALETTE: usize = 4;  // The map uses palettes 4-7
+
+// Palette 0 before the game sets one, gray shades from white to black
+const DEFAULT_PALETTE: [u16; 4] = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+
+
+/// What MASK_EN does to the game screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mask {
+    None,
+    Freeze,  // Keep showing the last frame
+    Black,
+    Color0,  // Fill with colour 0
+}
+impl Mask {
+    fn from_u8(value: u8) -> Mask {
+        match value & 0x03 {
+            1 => Mask::Freeze,
+            2 => Mask::Black,
+            3 => Mask::Color0,
+            _ => Mask::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transfer {
+    Palettes,
+    Tiles(usize),  // First tile
+    Picture,
+    Attributes,
+}
+impl Transfer {
+    fn to_u8(self) -> u8 {
+        match self {
+            Transfer::Palettes => 1,
+            Transfer::Tiles(0) => 2,
+            Transfer::Tiles(_) => 3,
+            Transfer::Picture => 4,
+            Transfer::Attributes => 5,
+        }
+    }
+    fn from_u8(value: u8) -> Option<Transfer> {
+        match value {
+            1 => Some(Transfer::Palettes),
+            2 => Some(Transfer::Tiles(0)),
+            3 => Some(Transfer::Tiles(BORDER_TILES / 2)),
+            4 => Some(Transfer::Picture),
+            5 => Some(Transfer::Attributes),
+            _ => None,
+        }
+    }
+}
+
+
+pub struct Sgb {
+    // Packet reception
+    lines: u8,            // P14/P15 as last written
+    receiving: bool,
+    bits: usize,          // Bits of the current packet so far
+    packet: [u8; PACKET_SIZE],
+    command: Vec<u8>,     // Packets of the command being received
+
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<u16>,  // From PAL_TRN, 4 colours each
+    attributes: [u8; ATTR_WIDTH * ATTR_HEIGHT],
+    attribute_files: Vec<u8>,
+    border_tiles: Vec<u8>,
+    border_picture: Vec<u8>,   // Map and palettes as sent by PCT_TRN
+    mask: Mask,
+
+    transfer: Option<Transfer>,
+    transfer_delay: u8,
+    frame: u64,           // Last frame seen by `vblank`
+
+    player_count: u8,
+    player: u8,
+    buttons: [u8; 3],     // Held by players 2-4
+
+    game: Vec<u16>,       // Game screen as last coloured
+    screen: Vec<u16>,
+}
+impl Default for Sgb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Sgb {
+    pub fn new() -> Self {
+        Sgb {
+            lines: 0x30,
+            receiving: false,
+            bits: 0,
+            packet: [0; PACKET_SIZE],
+            command: Vec::new(),
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![0; SYSTEM_PALETTES * 4],
+            attributes: [0; ATTR_WIDTH * ATTR_HEIGHT],
+            attribute_files: vec![0; ATTR_FILES * ATTR_FILE_SIZE],
+            border_tiles: vec![0; BORDER_TILES * BORDER_TILE_SIZE],
+            border_picture: vec![0; TRANSFER_SIZE],
+            mask: Mask::None,
+            transfer: None,
+            transfer_delay: 0,
+            frame: 0,
+            player_count: 1,
+            player: 0,
+            buttons: [0; 3],
+            game: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            screen: vec![0; SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT],
+        }
+    }
+
+    /// The 256x224 picture, RGB555 row by row.
+    pub fn screen(&self) -> &[u16] {
+        &self.screen
+    }
+    pub fn palettes(&self) -> &[[u16; 4]; 4] {
+        &self.palettes
+    }
+    pub fn mask(&self) -> Mask {
+        self.mask
+    }
+    /// Controllers the game asked for with MLT_REQ: 1, 2 or 4.
+    pub fn player_count(&self) -> u8 {
+        self.player_count
+    }
+    /// Buttons held by players 2-4.
+    pub fn buttons(&self) -> [u8; 3] {
+        self.buttons
+    }
+    /// Sets the buttons held by player 2-4 (`player` 1-3), see `Joypad::set_pressed`.
+    pub fn set_buttons(&mut self, player: usize, buttons: u8) {
+        if let Some(held) = player.checked_sub(1).and_then(|i| self.buttons.get_mut(i)) {
+            *held = buttons;
+        }
+    }
+
+    /// P1 as the game reads it. With several controllers, deselecting both
+    /// button groups reads the current controller, 0xF for player 1 down to 0xC.
+    pub fn read_joypad(&self, joypad: &Joypad) -> u8 {
+        if self.player_count == 1 {
+            return joypad.read();
+        }
+        match (self.lines, self.player) {
+            (0x30, player) => 0xF0 | (0x0F - player),
+            (_, 0) => joypad.read(),
+            (_, player) => joypad.read_buttons(self.buttons[player as usize - 1]),
+        }
+    }
+
+    /// Watches P1 writes for packets.
+    pub fn write_joypad(&mut self, value: u8) {
+        let lines = value & 0x30;
+        let previous = std::mem::replace(&mut self.lines, lines);
+        match lines {
+            0x00 => {
+                self.receiving = true;
+                self.bits = 0;
+                self.packet = [0; PACKET_SIZE];
+            }
+            // A pulse only counts after returning to 0x30
+            0x10 | 0x20 if self.receiving && previous == 0x30 => self.receive_bit(lines == 0x10),
+            // Outside packets P15 going high moves on to the next controller
+            0x30 if previous == 0x10 && !self.receiving && self.player_count > 1 => {
+                self.player = (self.player + 1) % self.player_count;
+            }
+            _ => {}
+        }
+    }
+
+    fn receive_bit(&mut self, bit: bool) {
+        if self.bits == PACKET_BITS {
+            // Stop bit, has to be 0
+            self.receiving = false;
+            if !bit {
+                self.receive_packet();
+            }
+            return;
+        }
+        self.packet[self.bits / 8] |= (bit as u8) << (self.bits % 8);
+        self.bits += 1;
+    }
+
+    fn receive_packet(&mut self) {
+        self.command.extend_from_slice(&self.packet);
+        let packets = (self.command[0] & 0x07).max(1) as usize;
+        if self.command.len() >= packets * PACKET_SIZE {
+            let command = std::mem::take(&mut self.command);
+            self.execute(&command);
+        }
+    }
+
+    fn execute(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            PAL01 => self.set_palettes(0, 1, data),
+            PAL23 => self.set_palettes(2, 3, data),
+            PAL03 => self.set_palettes(0, 3, data),
+            PAL12 => self.set_palettes(1, 2, data),
+            ATTR_BLK => self.attr_blk(data),
+            ATTR_LIN => self.attr_lin(data),
+            ATTR_DIV => self.attr_div(data),
+            ATTR_CHR => self.attr_chr(data),
+            PAL_SET => self.pal_set(data),
+            PAL_TRN => self.start_transfer(Transfer::Palettes),
+            MLT_REQ => {
+                self.player_count = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            CHR_TRN => self.start_transfer(Transfer::Tiles(if data[1] & 0x01 != 0 { BORDER_TILES / 2 } else { 0 })),
+            PCT_TRN => self.start_transfer(Transfer::Picture),
+            ATTR_TRN => self.start_transfer(Transfer::Attributes),
+            ATTR_SET => self.attr_set(data[1]),
+            MASK_EN => self.mask = Mask::from_u8(data[1]),
+            // Sound, SNES code uploads and the like
+            _ => {}
+        }
+    }
+
+    // Colour 0 is shared by every palette, the second palette only sets 1-3
+    fn set_palettes(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color = |i: usize| u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]) & 0x7FFF;
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    fn pal_set(&mut self, data: &[u8]) {
+        for (i, palette) in self.palettes.iter_mut().enumerate() {
+            let index = (u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]) as usize) % SYSTEM_PALETTES;
+            palette.copy_from_slice(&self.system_palettes[index * 4..index * 4 + 4]);
+        }
+        // Colour 0 comes from the first palette
+        let shared = self.palettes[0][0];
+        for palette in self.palettes.iter_mut() {
+            palette[0] = shared;
+        }
+        if data[9] & 0x80 != 0 {
+            self.attr_set(data[9] & 0x7F);
+        }
+    }
+
+    // Bit 6 also cancels the mask
+    fn attr_set(&mut self, value: u8) {
+        let file = (value & 0x3F) as usize;
+        if file < ATTR_FILES {
+            let bytes = &self.attribute_files[file * ATTR_FILE_SIZE..(file + 1) * ATTR_FILE_SIZE];
+            for (cell, attribute) in self.attributes.iter_mut().enumerate() {
+                *attribute = (bytes[cell / 4] >> (6 - (cell % 4) * 2)) & 0x03;
+            }
+        }
+        if value & 0x40 != 0 {
+            self.mask = Mask::None;
+        }
+    }
+
+    // Rectangles, each with palettes for inside, the border and outside
+    fn attr_blk(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for block in data[2..].chunks_exact(6).take(count) {
+            let control = block[0] & 0x07;
+            let inside = block[1] & 0x03;
+            let border = (block[1] >> 2) & 0x03;
+            let outside = (block[1] >> 4) & 0x03;
+            let (x1, y1) = (block[2] as usize & 0x1F, block[3] as usize & 0x1F);
+            let (x2, y2) = (block[4] as usize & 0x1F, block[5] as usize & 0x1F);
+            // With only inside or only outside picked, the border follows it
+            let border = match control {
+                0x01 => Some(inside),
+                0x04 => Some(outside),
+                _ if control & 0x02 != 0 => Some(border),
+                _ => None,
+            };
+            for y in 0..ATTR_HEIGHT {
+                for x in 0..ATTR_WIDTH {
+                    let within = (x1..=x2).contains(&x) && (y1..=y2).contains(&y);
+                    let on_border = within && (x == x1 || x == x2 || y == y1 || y == y2);
+                    let palette = match (on_border, within) {
+                        (true, _) => border,
+                        (false, true) => (control & 0x01 != 0).then_some(inside),
+                        (false, false) => (control & 0x04 != 0).then_some(outside),
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[y * ATTR_WIDTH + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    // Whole rows or columns
+    fn attr_lin(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for &line in data[2..].iter().take(count) {
+            let number = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+            if line & 0x80 != 0 {
+                if number < ATTR_HEIGHT {
+                    self.attributes[number * ATTR_WIDTH..(number + 1) * ATTR_WIDTH].fill(palette);
+                }
+            } else if number < ATTR_WIDTH {
+                for y in 0..ATTR_HEIGHT {
+                    self.attributes[y * ATTR_WIDTH + number] = palette;
+                }
+            }
+        }
+    }
+
+    // Splits the screen at one row or column
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 != 0;
+        let at = (data[2] & 0x1F) as usize;
+        for y in 0..ATTR_HEIGHT {
+            for x in 0..ATTR_WIDTH {
+                let position = if horizontal { y } else { x };
+                self.attributes[y * ATTR_WIDTH + x] = match position.cmp(&at) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    // Individual cells from a starting point, 4 per byte
+    fn attr_chr(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+        let count = (u16::from_le_bytes([data[3], data[4]]) as usize).min(ATTR_WIDTH * ATTR_HEIGHT);
+        let vertical = data[5] & 0x01 != 0;
+        for i in 0..count {
+            let Some(&byte) = data.get(6 + i / 4) else { break };
+            if x >= ATTR_WIDTH || y >= ATTR_HEIGHT {
+                break;
+            }
+            self.attributes[y * ATTR_WIDTH + x] = (byte >> (6 - (i % 4) * 2)) & 0x03;
+            if vertical {
+                y += 1;
+                if y == ATTR_HEIGHT {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == ATTR_WIDTH {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    fn start_transfer(&mut self, transfer: Transfer) {
+        self.transfer = Some(transfer);
+        self.transfer_delay = TRANSFER_DELAY;
+    }
+
+    /// Runs once per frame with the finished Game Boy screen (shades 0-3):
+    /// completes transfers and redraws the output.
+    pub fn vblank(&mut self, frame_count: u64, framebuffer: &[u8]) {
+        if frame_count == self.frame {
+            return;
+        }
+        self.frame = frame_count;
+        if let Some(transfer) = self.transfer {
+            self.transfer_delay -= 1;
+            if self.transfer_delay == 0 {
+                self.transfer = None;
+                self.finish_transfer(transfer, &screen_data(framebuffer));
+            }
+        }
+        self.render(framebuffer);
+    }
+
+    fn finish_transfer(&mut self, transfer: Transfer, data: &[u8]) {
+        match transfer {
+            Transfer::Palettes => {
+                for (color, bytes) in self.system_palettes.iter_mut().zip(data.chunks_exact(2)) {
+                    *color = u16::from_le_bytes([bytes[0], bytes[1]]) & 0x7FFF;
+                }
+            }
+            Transfer::Tiles(first) => {
+                let start = first * BORDER_TILE_SIZE;
+                self.border_tiles[start..start + TRANSFER_SIZE].copy_from_slice(data);
+            }
+            Transfer::Picture => self.border_picture.copy_from_slice(data),
+            Transfer::Attributes => {
+                let size = self.attribute_files.len();
+                self.attribute_files.copy_from_slice(&data[..size]);
+            }
+        }
+    }
+
+    fn render(&mut self, framebuffer: &[u8]) {
+        let backdrop = self.palettes[0][0];
+        match self.mask {
+            Mask::None => {
+                for (i, &shade) in framebuffer.iter().enumerate() {
+                    let (x, y) = (i % SCREEN_WIDTH, i / SCREEN_WIDTH);
+                    let palette = self.attributes[(y / 8) * ATTR_WIDTH + x / 8] as usize;
+                    self.game[i] = self.palettes[palette][shade as usize & 0x03];
+                }
+            }
+            Mask::Freeze => {}
+            Mask::Black => self.game.fill(0x0000),
+            Mask::Color0 => self.game.fill(backdrop),
+        }
+
+        self.screen.fill(backdrop);
+        for y in 0..SCREEN_HEIGHT {
+            let row = (GAME_Y + y) * SGB_SCREEN_WIDTH + GAME_X;
+            self.screen[row..row + SCREEN_WIDTH].copy_from_slice(&self.game[y * SCREEN_WIDTH..(y + 1) * SCREEN_WIDTH]);
+        }
+        self.draw_border();
+    }
+
+    // The border sits on top of the game, colour 0 is see-through
+    fn draw_border(&mut self) {
+        let palette_color = |palette: usize, color: usize| {
+            let i = BORDER_PALETTES_OFFSET + ((palette - BORDER_FIRST_PALETTE) * 16 + color) * 2;
+            u16::from_le_bytes([self.border_picture[i], self.border_picture[i + 1]]) & 0x7FFF
+        };
+        for map_y in 0..BORDER_MAP_HEIGHT {
+            for map_x in 0..BORDER_MAP_WIDTH {
+                let i = (map_y * BORDER_MAP_WIDTH + map_x) * 2;
+                let entry = u16::from_le_bytes([self.border_picture[i], self.border_picture[i + 1]]);
+                let tile = &self.border_tiles[(entry & 0xFF) as usize * BORDER_TILE_SIZE..][..BORDER_TILE_SIZE];
+                let palette = (((entry >> 10) & 0x07) as usize).clamp(BORDER_FIRST_PALETTE, 7);
+                let (flip_x, flip_y) = (entry & 0x4000 != 0, entry & 0x8000 != 0);
+                for row in 0..8 {
+                    let ty = if flip_y { 7 - row } else { row };
+                    // SNES 4bpp: planes 0-1 interleaved in the first 16 bytes, 2-3 in the rest
+                    let planes = [tile[ty * 2], tile[ty * 2 + 1], tile[16 + ty * 2], tile[16 + ty * 2 + 1]];
+                    for column in 0..8 {
+                        let bit = if flip_x { column } else { 7 - column };
+                        let color = planes.iter().enumerate()
+                            .fold(0, |color, (plane, &byte)| color | (((byte >> bit) & 1) as usize) << plane);
+                        if color != 0 {
+                            let (x, y) = (map_x * 8 + column, map_y * 8 + row);
+                            self.screen[y * SGB_SCREEN_WIDTH + x] = palette_color(palette, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The first 4 KB of tile data on screen, 20 tiles per row, back as 2bpp
+fn screen_data(framebuffer: &[u8]) -> Vec<u8> {
+    let mut data = vec![0; TRANSFER_SIZE];
+    for (tile, bytes) in data.chunks_exact_mut(16).enumerate() {
+        let (tile_x, tile_y) = ((tile % ATTR_WIDTH) * 8, (tile / ATTR_WIDTH) * 8);
+        for row in 0..8 {
+            for column in 0..8 {
+                let shade = framebuffer[(tile_y + row) * SCREEN_WIDTH + tile_x + column];
+                bytes[row * 2] |= (shade & 0x01) << (7 - column);
+                bytes[row * 2 + 1] |= ((shade >> 1) & 0x01) << (7 - column);
+            }
+        }
+    }
+    data
+}
+
+impl Savable for Sgb {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.lines);
+        w.bool(self.receiving);
+        w.u8(self.bits as u8);
+        w.bytes(&self.packet);
+        w.bytes(&self.command);
+        for &color in self.palettes.iter().flatten().chain(&self.system_palettes) {
+            w.u16(color);
+        }
+        w.bytes(&self.attributes);
+        w.bytes(&self.attribute_files);
+        w.bytes(&self.border_tiles);
+        w.bytes(&self.border_picture);
+        w.u8(self.mask as u8);
+        w.u8(self.transfer.map_or(0, Transfer::to_u8));
+        w.u8(self.transfer_delay);
+        w.u8(self.player_count);
+        w.u8(self.player);
+        for &color in &self.game {
+            w.u16(color);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.lines = r.u8()? & 0x30;
+        self.receiving = r.bool()?;
+        self.bits = (r.u8()? as usize).min(PACKET_BITS);
+        r.bytes_into(&mut self.packet)?;
+        self.command = r.bytes()?.to_vec();
+        if self.command.len() >= 8 * PACKET_SIZE {
+            return Err(StateError::Invalid("SGB command too long"));
+        }
+        for color in self.palettes.iter_mut().flatten() {
+            *color = r.u16()? & 0x7FFF;
+        }
+        for color in self.system_palettes.iter_mut() {
+            *color = r.u16()? & 0x7FFF;
+        }
+        r.bytes_into(&mut self.attributes)?;
+        for attribute in self.attributes.iter_mut() {
+            *attribute &= 0x03;
+        }
+        r.bytes_into(&mut self.attribute_files)?;
+        r.bytes_into(&mut self.border_tiles)?;
+        r.bytes_into(&mut self.border_picture)?;
+        self.mask = Mask::from_u8(r.u8()?);
+        self.transfer = Transfer::from_u8(r.u8()?);
+        self.transfer_delay = r.u8()?.clamp(1, TRANSFER_DELAY);
+        self.player_count = match r.u8()? {
+            count @ (1 | 2 | 4) => count,
+            _ => return Err(StateError::Invalid("SGB player count")),
+        };
+        self.player = r.u8()? % self.player_count;
+        for color in self.game.iter_mut() {
+            *color = r.u16()? & 0x7FFF;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::joypad::BUTTON_A;
+    use crate::savestate::STATE_VERSION;
+
+    // Pulses a packet into P1 the way a game does, with the given stop bit
+    fn send_packet(sgb: &mut Sgb, packet: &[u8], stop: bool) {
+        sgb.write_joypad(0x00);
+        sgb.write_joypad(0x30);
+        let bits = (0..PACKET_BITS).map(|i| packet.get(i / 8).is_some_and(|byte| byte >> (i % 8) & 1 != 0));
+        for bit in bits.chain([stop]) {
+            sgb.write_joypad(if bit { 0x10 } else { 0x20 });
+            sgb.write_joypad(0x30);
+        }
+    }
+
+    fn send(sgb: &mut Sgb, command: &[u8]) {
+        for packet in command.chunks(PACKET_SIZE) {
+            send_packet(sgb, packet, false);
+        }
+    }
+
+    fn attributes(sgb: &Sgb, y: usize) -> &[u8] {
+        &sgb.attributes[y * ATTR_WIDTH..(y + 1) * ATTR_WIDTH]
+    }
+
+    #[test]
+    fn packets_set_palettes() {
+        let mut sgb = Sgb::new();
+        let mut packet = vec![PAL01 << 3 | 1];
+        for color in [0x0001u16, 0x0002, 0x0003, 0x0004, 0x0005, 0x0006, 0xFFFF] {
+            packet.extend_from_slice(&color.to_le_bytes());
+        }
+        send(&mut sgb, &packet);
+        assert_eq!(sgb.palettes()[0], [0x0001, 0x0002, 0x0003, 0x0004]);
+        assert_eq!(sgb.palettes()[1], [0x0001, 0x0005, 0x0006, 0x7FFF]);
+        // Colour 0 is shared, the rest of palettes 2-3 stays
+        assert_eq!(sgb.palettes()[3], [0x0001, DEFAULT_PALETTE[1], DEFAULT_PALETTE[2], DEFAULT_PALETTE[3]]);
+
+        // A packet with a 1 as stop bit is dropped
+        let mut sgb = Sgb::new();
+        send_packet(&mut sgb, &packet, true);
+        assert_eq!(sgb.palettes()[0], DEFAULT_PALETTE);
+        // So are pulses that don't return to 0x30 in between
+        sgb.write_joypad(0x00);
+        sgb.write_joypad(0x30);
+        sgb.write_joypad(0x10);
+        sgb.write_joypad(0x20);
+        assert_eq!(sgb.bits, 1);
+    }
+
+    #[test]
+    fn attr_blk_border_follows_the_picked_side() {
+        let block = |control: u8| {
+            // Inside palette 1, border 2, outside 3, from (2, 2) to (5, 5)
+            let mut sgb = Sgb::new();
+            send(&mut sgb, &[ATTR_BLK << 3 | 1, 1, control, 0x39, 2, 2, 5, 5]);
+            sgb
+        };
+
+        let sgb = block(0x01);
+        assert_eq!(attributes(&sgb, 1)[..8], [0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(attributes(&sgb, 2)[..8], [0, 0, 1, 1, 1, 1, 0, 0]);
+        assert_eq!(attributes(&sgb, 3)[..8], [0, 0, 1, 1, 1, 1, 0, 0]);
+
+        let sgb = block(0x04);
+        assert_eq!(attributes(&sgb, 1)[..8], [3, 3, 3, 3, 3, 3, 3, 3]);
+        assert_eq!(attributes(&sgb, 2)[..8], [3, 3, 3, 3, 3, 3, 3, 3]);
+        assert_eq!(attributes(&sgb, 3)[..8], [3, 3, 3, 0, 0, 3, 3, 3]);
+
+        let sgb = block(0x02);
+        assert_eq!(attributes(&sgb, 1)[..8], [0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(attributes(&sgb, 3)[..8], [0, 0, 2, 0, 0, 2, 0, 0]);
+        assert_eq!(attributes(&sgb, 5)[..8], [0, 0, 2, 2, 2, 2, 0, 0]);
+
+        let sgb = block(0x07);
+        assert_eq!(attributes(&sgb, 3)[..8], [3, 3, 2, 1, 1, 2, 3, 3]);
+    }
+
+    #[test]
+    fn attr_chr_wraps_to_the_next_line() {
+        let mut sgb = Sgb::new();
+        // Left to right from (18, 0), then top to bottom from (0, 16)
+        send(&mut sgb, &[ATTR_CHR << 3 | 1, 18, 0, 4, 0, 0, 0b01_10_11_01]);
+        send(&mut sgb, &[ATTR_CHR << 3 | 1, 0, 16, 3, 0, 1, 0b10_11_01_00]);
+        assert_eq!(attributes(&sgb, 0)[18..], [1, 2]);
+        assert_eq!(attributes(&sgb, 1)[..2], [3, 1]);
+        assert_eq!(attributes(&sgb, 16)[0], 2);
+        assert_eq!(attributes(&sgb, 17)[0], 3);
+        assert_eq!(attributes(&sgb, 0)[1], 1);
+
+        // Nothing is written past the last cell
+        let mut sgb = Sgb::new();
+        send(&mut sgb, &[ATTR_CHR << 3 | 1, 19, 17, 8, 0, 0, 0xFF, 0xFF]);
+        assert_eq!(attributes(&sgb, 17)[19], 3);
+        assert_eq!(sgb.attributes.iter().filter(|&&a| a != 0).count(), 1);
+    }
+
+    #[test]
+    fn mlt_req_cycles_controller_ids() {
+        let mut sgb = Sgb::new();
+        let mut joypad = Joypad::new();
+        let mut write = |sgb: &mut Sgb, value: u8| {
+            joypad.write(value);
+            sgb.write_joypad(value);
+            sgb.read_joypad(&joypad)
+        };
+
+        send(&mut sgb, &[MLT_REQ << 3 | 1, 0x01]);
+        assert_eq!(sgb.player_count(), 2);
+        assert_eq!(write(&mut sgb, 0x30), 0xFF);
+        write(&mut sgb, 0x10);
+        assert_eq!(write(&mut sgb, 0x30), 0xFE);
+        // Player 2 reads their own buttons
+        sgb.set_buttons(1, BUTTON_A);
+        assert_eq!(write(&mut sgb, 0x10), 0xDE);
+        assert_eq!(write(&mut sgb, 0x30), 0xFF);
+
+        send(&mut sgb, &[MLT_REQ << 3 | 1, 0x03]);
+        assert_eq!(sgb.player_count(), 4);
+        let ids: Vec<u8> = (0..5).map(|_| {
+            write(&mut sgb, 0x10);
+            write(&mut sgb, 0x30)
+        }).collect();
+        assert_eq!(ids, [0xFE, 0xFD, 0xFC, 0xFF, 0xFE]);
+
+        send(&mut sgb, &[MLT_REQ << 3 | 1, 0x00]);
+        assert_eq!(sgb.player_count(), 1);
+        assert_eq!(write(&mut sgb, 0x30), 0xFF);
+    }
+
+    #[test]
+    fn transfers_read_the_screen_after_a_delay() {
+        let mut sgb = Sgb::new();
+        let mut framebuffer = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        // Top left pixel of the first and second tile
+        framebuffer[0] = 3;
+        framebuffer[8] = 1;
+
+        send(&mut sgb, &[CHR_TRN << 3 | 1, 0x01]);
+        for frame in 1..TRANSFER_DELAY as u64 {
+            sgb.vblank(frame, &framebuffer);
+            // The same frame again doesn't count
+            sgb.vblank(frame, &framebuffer);
+        }
+        let upper = (BORDER_TILES / 2) * BORDER_TILE_SIZE;
+        assert!(sgb.border_tiles.iter().all(|&b| b == 0));
+        sgb.vblank(TRANSFER_DELAY as u64, &framebuffer);
+        assert_eq!(sgb.border_tiles[upper..upper + 2], [0x80, 0x80]);
+        assert_eq!(sgb.border_tiles[upper + 16..upper + 18], [0x80, 0x00]);
+        assert!(sgb.border_tiles[..upper].iter().all(|&b| b == 0));
+        assert_eq!(sgb.transfer, None);
+
+        send(&mut sgb, &[PCT_TRN << 3 | 1]);
+        for frame in 0..TRANSFER_DELAY as u64 {
+            sgb.vblank(TRANSFER_DELAY as u64 + 1 + frame, &framebuffer);
+        }
+        assert_eq!(sgb.border_picture[..2], [0x80, 0x80]);
+        assert_eq!(sgb.border_picture[16..18], [0x80, 0x00]);
+    }
+
+    #[test]
+    fn state_round_trips() {
+        let mut sgb = Sgb::new();
+        send(&mut sgb, &[PAL12 << 3 | 1, 0x11, 0x11, 0x22, 0x22, 0x33, 0x33]);
+        send(&mut sgb, &[ATTR_DIV << 3 | 1, 0x1B, 9]);
+        send(&mut sgb, &[MLT_REQ << 3 | 1, 0x03]);
+        send(&mut sgb, &[MASK_EN << 3 | 1, 0x02]);
+        send(&mut sgb, &[PCT_TRN << 3 | 1]);
+        // The first packet of a two packet command and part of the next
+        send_packet(&mut sgb, &[ATTR_BLK << 3 | 2, 3], false);
+        sgb.write_joypad(0x00);
+        sgb.write_joypad(0x30);
+        sgb.write_joypad(0x10);
+        sgb.write_joypad(0x30);
+        sgb.write_joypad(0x10);
+
+        let mut w = StateWriter::new();
+        sgb.save_state(&mut w);
+        let data = w.into_inner();
+        let mut loaded = Sgb::new();
+        loaded.load_state(&mut StateReader::new(&data, STATE_VERSION)).unwrap();
+        let mut w = StateWriter::new();
+        loaded.save_state(&mut w);
+        assert_eq!(w.into_inner(), data);
+        assert_eq!(loaded.palettes(), sgb.palettes());
+        assert_eq!(loaded.attributes, sgb.attributes);
+        assert_eq!(loaded.player_count(), 4);
+        assert_eq!(loaded.mask(), Mask::Black);
+        assert_eq!(loaded.transfer, Some(Transfer::Picture));
+        assert_eq!((loaded.bits, loaded.packet[0], loaded.command.len()), (2, 0x03, PACKET_SIZE));
+
+        // A truncated state is an error
+        let mut loaded = Sgb::new();
+        assert!(loaded.load_state(&mut StateReader::new(&data[..data.len() - 1], STATE_VERSION)).is_err());
+    }
+}
@@ -0,0 +1,472 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use serde::Deserialize;
+use crate::common::IO;
+use crate::cpu::{CPU, Registers};
+use crate::opcode::{Mnemonic, OpcodeTables};
+
+// Runner for the community SM83 single-step tests ("sst"), see
+// https://github.com/SingleStepTests/sm83 for the format. Each JSON file holds
+// an array of cases with the machine state before and after one instruction.
+
+
+/// Register and memory state of a single-step test case.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CpuState {
+    pub pc: u16,
+    pub sp: u16,
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub f: u8,
+    pub h: u8,
+    pub l: u8,
+    #[serde(default)]
+    pub ime: u8,
+    #[serde(default)]
+    pub ie: Option<u8>,
+    #[serde(default)]
+    pub ram: Vec<(u16, u8)>,
+}
+impl CpuState {
+    fn registers(&self) -> Registers {
+        Registers {
+            a: self.a,
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            e: self.e,
+            f: self.f,
+            h: self.h,
+            l: self.l,
+            sp: self.sp,
+            pc: self.pc,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TestCase {
+    pub name: String,
+    pub initial: CpuState,
+    #[serde(rename = "final")]
+    pub expected: CpuState,
+    // One entry per M-cycle: [addr, value, pins] where pins is like "r-m"
+    // for a read, "-wm" for a write and "---" for an idle cycle
+    #[serde(default)]
+    pub cycles: Vec<serde_json::Value>,
+}
+impl TestCase {
+    /// Reads and writes the CPU should make, with the M-cycle each falls in.
+    pub fn expected_accesses(&self) -> Vec<(usize, BusAccess)> {
+        self.cycles.iter().enumerate().filter_map(|(cycle, entry)| {
+            let entry = entry.as_array()?;
+            let pins = entry.get(2)?.as_str()?;
+            let write = pins.contains('w');
+            if !write && !pins.contains('r') {
+                return None;
+            }
+            let addr = entry.first()?.as_u64()? as u16;
+            let value = entry.get(1)?.as_u64()? as u8;
+            Some((cycle, BusAccess { addr, value, write }))
+        }).collect()
+    }
+}
+
+/// One read or write on the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusAccess {
+    pub addr: u16,
+    pub value: u8,
+    pub write: bool,
+}
+impl fmt::Display for BusAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = if self.write { "write" } else { "read" };
+        write!(f, "{} {:#04X} at {:#06X}", kind, self.value, self.addr)
+    }
+}
+
+#[derive(Debug)]
+pub enum SstError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+}
+impl fmt::Display for SstError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SstError::Io(e) => write!(f, "I/O error: {}", e),
+            SstError::Json(e) => write!(f, "JSON error: {}", e),
+        }
+    }
+}
+
+
+/// Flat 64 KiB memory without any mapping, as assumed by the test vectors.
+/// Keeps a log of the accesses made through `IO` and the M-cycle each came
+/// in, counting idle cycles.
+pub struct FlatRam {
+    memory: Box<[u8; 0x10000]>,
+    accesses: RefCell<Vec<(usize, BusAccess)>>,  // Reads go through &self
+    cycle: Cell<usize>,
+}
+impl FlatRam {
+    pub fn new() -> Self {
+        FlatRam { memory: Box::new([0; 0x10000]), accesses: RefCell::new(Vec::new()), cycle: Cell::new(0) }
+    }
+
+    /// Accesses logged since the last call, in order, with the M-cycle
+    /// each was made in counted from the call before.
+    pub fn take_accesses(&mut self) -> Vec<(usize, BusAccess)> {
+        self.cycle.set(0);
+        std::mem::take(self.accesses.get_mut())
+    }
+
+    /// M-cycles since the last `take_accesses`, idle ones included.
+    pub fn cycles(&self) -> usize {
+        self.cycle.get()
+    }
+
+    fn log(&self, access: BusAccess) {
+        self.accesses.borrow_mut().push((self.cycle.get(), access));
+        self.cycle.set(self.cycle.get() + 1);
+    }
+}
+impl Default for FlatRam {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl IO for FlatRam {
+    fn read(&self, addr: u16) -> Option<u8> {
+        let value = self.memory[addr as usize];
+        self.log(BusAccess { addr, value, write: false });
+        Some(value)
+    }
+
+    fn write(&mut self, addr: u16, value: u8) -> bool {
+        self.memory[addr as usize] = value;
+        self.log(BusAccess { addr, value, write: true });
+        true
+    }
+
+    fn idle(&mut self) {
+        self.cycle.set(self.cycle.get() + 1);
+    }
+}
+
+
+/// Failures of a single opcode across all cases that were run.
+#[derive(Debug)]
+pub struct OpcodeFailures {
+    pub mnemonic: Option<Mnemonic>,
+    pub count: usize,
+    pub first_case: String,
+    pub first_diff: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,  // Cases that would take an interrupt instead
+    // Keyed by opcode, CB-prefixed opcodes are stored as 0xCBxx
+    pub failures: BTreeMap<u16, OpcodeFailures>,
+}
+impl Report {
+    pub fn merge(&mut self, other: Report) {
+        self.passed += other.passed;
+        self.failed += other.failed;
+        self.skipped += other.skipped;
+        for (opcode, failures) in other.failures {
+            match self.failures.get_mut(&opcode) {
+                Some(existing) => existing.count += failures.count,
+                None => { self.failures.insert(opcode, failures); }
+            }
+        }
+    }
+}
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Passed: {}, Failed: {}, Skipped: {}", self.passed, self.failed, self.skipped)?;
+        for (opcode, failures) in &self.failures {
+            let mnemonic = match failures.mnemonic {
+                Some(m) => format!("{:?}", m),
+                None => "Unknown".to_string(),
+            };
+            writeln!(f, "  {:#06X} {:<8} {} failures, first: \"{}\"",
+                opcode, mnemonic, failures.count, failures.first_case)?;
+            for diff in &failures.first_diff {
+                writeln!(f, "      {}", diff)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// Executes one test case and returns the list of mismatches (empty on success).
+pub fn run_case(case: &TestCase) -> Vec<String> {
+    let mut ram = FlatRam::new();
+    for &(addr, value) in &case.initial.ram {
+        ram.write(addr, value);
+    }
+    if let Some(ie) = case.initial.ie {
+        ram.write(0xFFFF, ie);
+    }
+    ram.take_accesses();
+
+    let mut cpu = CPU::new();
+    cpu.reg = case.initial.registers();
+    cpu.ime = case.initial.ime != 0;
+
+    let cycles = cpu.step(&mut ram);
+
+    let mut diffs = Vec::new();
+    let expected = case.expected.registers();
+    let actual = cpu.reg;
+    let pairs: [(&str, u16, u16); 10] = [
+        ("A", expected.a as u16, actual.a as u16),
+        ("F", expected.f as u16, actual.f as u16),
+        ("B", expected.b as u16, actual.b as u16),
+        ("C", expected.c as u16, actual.c as u16),
+        ("D", expected.d as u16, actual.d as u16),
+        ("E", expected.e as u16, actual.e as u16),
+        ("H", expected.h as u16, actual.h as u16),
+        ("L", expected.l as u16, actual.l as u16),
+        ("SP", expected.sp, actual.sp),
+        ("PC", expected.pc, actual.pc),
+    ];
+    for (name, expected, actual) in pairs {
+        if expected != actual {
+            diffs.push(format!("{}: expected {:#06X}, got {:#06X}", name, expected, actual));
+        }
+    }
+
+    // A pending EI counts as enabled, the vectors sample IME after the instruction
+    let ime = cpu.ime || cpu.ime_scheduled;
+    if (case.expected.ime != 0) != ime {
+        diffs.push(format!("IME: expected {}, got {}", case.expected.ime, ime as u8));
+    }
+
+    // The interrupt check reads IE and IF first, which on hardware doesn't
+    // take a bus cycle
+    let mut accesses = ram.take_accesses();
+    if accesses.len() >= 2 && !accesses[0].1.write && accesses[0].1.addr == 0xFFFF && accesses[1].1.addr == 0xFF0F {
+        accesses.drain(..2);
+        for (cycle, _) in accesses.iter_mut() {
+            *cycle -= 2;
+        }
+    }
+    if let Some(ie) = case.expected.ie {
+        let actual = ram.read(0xFFFF).unwrap_or(0xFF);
+        if actual != ie {
+            diffs.push(format!("IE: expected {:#04X}, got {:#04X}", ie, actual));
+        }
+    }
+    for &(addr, value) in &case.expected.ram {
+        let actual = ram.read(addr).unwrap_or(0xFF);
+        if actual != value {
+            diffs.push(format!("[{:#06X}]: expected {:#04X}, got {:#04X}", addr, value, actual));
+        }
+    }
+
+    if !case.cycles.is_empty() && case.cycles.len() * 4 != cycles as usize {
+        diffs.push(format!("cycles: expected {}, got {}", case.cycles.len() * 4, cycles));
+    }
+    if !case.cycles.is_empty() {
+        diffs.extend(diff_accesses(&case.expected_accesses(), &accesses));
+    }
+
+    diffs
+}
+
+// Compares the accesses made in each M-cycle, so an access or idle cycle
+// in the wrong place shows up even when the order is right
+fn diff_accesses(expected: &[(usize, BusAccess)], actual: &[(usize, BusAccess)]) -> Vec<String> {
+    let in_cycle = |accesses: &[(usize, BusAccess)], cycle: usize| {
+        accesses.iter().find(|&&(c, _)| c == cycle).map(|&(_, access)| access)
+    };
+    let last = expected.iter().chain(actual).map(|&(cycle, _)| cycle + 1).max().unwrap_or(0);
+    let mut diffs = Vec::new();
+    for cycle in 0..last {
+        match (in_cycle(expected, cycle), in_cycle(actual, cycle)) {
+            (Some(access), Some(got)) if got == access => {}
+            (Some(access), Some(got)) => diffs.push(format!("cycle {}: expected {}, got {}", cycle, access, got)),
+            (Some(access), None) => diffs.push(format!("cycle {}: expected {}, got nothing", cycle, access)),
+            (None, Some(got)) => diffs.push(format!("cycle {}: expected nothing, got {}", cycle, got)),
+            (None, None) => {}
+        }
+    }
+
+    diffs
+}
+
+/// Whether the case starts with an interrupt pending and enabled, which the
+/// CPU would service instead of running the opcode the vector is about.
+pub fn takes_interrupt(case: &TestCase) -> bool {
+    let byte_at = |addr: u16| case.initial.ram.iter().find(|&&(a, _)| a == addr).map_or(0, |&(_, v)| v);
+    let ie = case.initial.ie.unwrap_or_else(|| byte_at(0xFFFF));
+    case.initial.ime != 0 && ie & byte_at(0xFF0F) & 0x1F != 0
+}
+
+/// Returns the opcode key (0xCBxx for prefixed ones) of the instruction at the initial PC.
+pub fn case_opcode(case: &TestCase) -> u16 {
+    let byte_at = |addr: u16| {
+        case.initial.ram.iter().find(|&&(a, _)| a == addr).map(|&(_, v)| v).unwrap_or(0)
+    };
+    let opcode = byte_at(case.initial.pc);
+    match opcode {
+        0xCB => 0xCB00 | byte_at(case.initial.pc.wrapping_add(1)) as u16,
+        _ => opcode as u16,
+    }
+}
+
+/// Runs all cases and groups failures by opcode.
+pub fn run_cases(cases: &[TestCase], tables: &OpcodeTables) -> Report {
+    let mut report = Report::default();
+
+    for case in cases {
+        if takes_interrupt(case) {
+            report.skipped += 1;
+            continue;
+        }
+        let diffs = run_case(case);
+        if diffs.is_empty() {
+            report.passed += 1;
+            continue;
+        }
+        report.failed += 1;
+
+        let opcode = case_opcode(case);
+        report.failures
+            .entry(opcode)
+            .and_modify(|f| f.count += 1)
+            .or_insert_with(|| OpcodeFailures {
+                mnemonic: tables.get(opcode > 0xFF, opcode as u8).map(|op| op.mnemonic),
+                count: 1,
+                first_case: case.name.clone(),
+                first_diff: diffs,
+            });
+    }
+
+    report
+}
+
+pub fn load_cases(path: &Path) -> Result<Vec<TestCase>, SstError> {
+    let json = std::fs::read_to_string(path).map_err(SstError::Io)?;
+    serde_json::from_str(&json).map_err(SstError::Json)
+}
+
+/// Runs a single JSON file, or every JSON file in a directory.
+pub fn run_path(path: &Path, tables: &OpcodeTables) -> Result<Report, SstError> {
+    let mut report = Report::default();
+
+    if path.is_dir() {
+        let mut entries: Vec<_> = std::fs::read_dir(path)
+            .map_err(SstError::Io)?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        entries.sort();
+        for entry in entries {
+            report.merge(run_cases(&load_cases(&entry)?, tables));
+        }
+    } else {
+        report = run_cases(&load_cases(path)?, tables);
+    }
+
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LD (HL),A then the byte after it, with HL = 0xC000
+    fn case(cycles: &str) -> TestCase {
+        let json = format!(r#"{{
+            "name": "77 0000",
+            "initial": {{ "pc": 256, "sp": 65534, "a": 66, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 192, "l": 0,
+                          "ime": 0, "ram": [[256, 119], [49152, 0]] }},
+            "final": {{ "pc": 257, "sp": 65534, "a": 66, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 192, "l": 0,
+                        "ime": 0, "ram": [[256, 119], [49152, 66]] }},
+            "cycles": {}
+        }}"#, cycles);
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn matching_bus_activity_passes() {
+        let diffs = run_case(&case(r#"[[256, 119, "r-m"], [49152, 66, "-wm"]]"#));
+        assert!(diffs.is_empty(), "{:?}", diffs);
+    }
+
+    #[test]
+    fn bus_activity_is_diffed_per_cycle() {
+        let diffs = run_case(&case(r#"[[256, 119, "r-m"], [49153, 66, "-wm"]]"#));
+        assert_eq!(diffs, vec!["cycle 1: expected write 0x42 at 0xC001, got write 0x42 at 0xC000".to_string()]);
+    }
+
+    // PUSH BC with BC = 0x1234, an idle cycle then the two writes
+    fn push_case(cycles: &str, ie: u8) -> TestCase {
+        let json = format!(r#"{{
+            "name": "C5 0000",
+            "initial": {{ "pc": 256, "sp": 65534, "a": 0, "b": 18, "c": 52, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+                          "ime": 0, "ie": 0, "ram": [[256, 197]] }},
+            "final": {{ "pc": 257, "sp": 65532, "a": 0, "b": 18, "c": 52, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+                        "ime": 0, "ie": {}, "ram": [[65533, 18], [65532, 52]] }},
+            "cycles": {}
+        }}"#, ie, cycles);
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn idle_cycles_must_be_in_place() {
+        let diffs = run_case(&push_case(r#"[[256, 197, "r-m"], [null, null, "---"], [65533, 18, "-wm"], [65532, 52, "-wm"]]"#, 0));
+        assert!(diffs.is_empty(), "{:?}", diffs);
+
+        // Same accesses in the same order, with the idle cycle last
+        let diffs = run_case(&push_case(r#"[[256, 197, "r-m"], [65533, 18, "-wm"], [65532, 52, "-wm"], [null, null, "---"]]"#, 0));
+        assert_eq!(diffs, vec![
+            "cycle 1: expected write 0x12 at 0xFFFD, got nothing".to_string(),
+            "cycle 2: expected write 0x34 at 0xFFFC, got write 0x12 at 0xFFFD".to_string(),
+            "cycle 3: expected nothing, got write 0x34 at 0xFFFC".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn final_ie_is_checked() {
+        let diffs = run_case(&push_case(r#"[[256, 197, "r-m"], null, [65533, 18, "-wm"], [65532, 52, "-wm"]]"#, 1));
+        assert_eq!(diffs, vec!["IE: expected 0x01, got 0x00".to_string()]);
+    }
+
+    #[test]
+    fn cases_that_take_an_interrupt_are_skipped() {
+        let mut case = case(r#"[[256, 119, "r-m"], [49152, 66, "-wm"]]"#);
+        assert!(!takes_interrupt(&case));
+        case.initial.ime = 1;
+        case.initial.ie = Some(0x04);
+        case.initial.ram.push((0xFF0F, 0x04));
+        assert!(takes_interrupt(&case));
+        case.initial.ie = Some(0x01);
+        assert!(!takes_interrupt(&case), "requested but not enabled");
+        case.initial.ie = Some(0x04);
+
+        let report = run_cases(&[case], &OpcodeTables::load());
+        assert_eq!((report.passed, report.failed, report.skipped), (0, 0, 1));
+    }
+
+    #[test]
+    fn idle_cycles_are_skipped() {
+        let case = case(r#"[[256, 119, "r-m"], null, [49152, 66, "-wm"]]"#);
+        let accesses = case.expected_accesses();
+        assert_eq!(accesses.len(), 2);
+        assert_eq!(accesses[1].0, 2);
+    }
+}
@@ -0,0 +1,108 @@
+use crate::common::INTERRUPT_TIMER;
+use crate::savestate::{Savable, StateError, StateReader, StateWriter};
+use crate::step::Step;
+
+// Timer and divider, see https://gbdev.io/pandocs/Timer_and_Divider_Registers.html
+
+pub const TIMER_DIV: u16 = 0xFF04;
+pub const TIMER_TIMA: u16 = 0xFF05;
+pub const TIMER_TMA: u16 = 0xFF06;
+pub const TIMER_TAC: u16 = 0xFF07;
+
+
+#[derive(Debug, Default, Clone)]
+pub struct Timer {
+    counter: u16,   // Internal 16-bit counter, DIV is its upper byte
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    pub interrupts: u8,
+}
+impl Timer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            TIMER_DIV => (self.counter >> 8) as u8,
+            TIMER_TIMA => self.tima,
+            TIMER_TMA => self.tma,
+            TIMER_TAC => self.tac | 0xF8,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        let before = self.input();
+        match addr {
+            TIMER_DIV => self.counter = 0,
+            TIMER_TIMA => self.tima = value,
+            TIMER_TMA => self.tma = value,
+            TIMER_TAC => self.tac = value & 0x07,
+            _ => {}
+        }
+        // Resetting DIV or changing TAC can produce a falling edge too
+        if before && !self.input() {
+            self.increment();
+        }
+    }
+
+    pub fn counter(&self) -> u16 {
+        self.counter
+    }
+    /// Sets the internal counter without the side effects of a DIV write.
+    pub fn set_counter(&mut self, counter: u16) {
+        self.counter = counter;
+    }
+
+    // Counter bit selected by TAC, ANDed with the enable bit
+    fn input(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            _ => 7,
+        };
+        self.tac & 0x04 != 0 && self.counter & (1 << bit) != 0
+    }
+
+    fn increment(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        if overflow {
+            self.tima = self.tma;
+            self.interrupts |= INTERRUPT_TIMER;
+        } else {
+            self.tima = tima;
+        }
+    }
+}
+impl Step for Timer {
+    fn step(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            let before = self.input();
+            self.counter = self.counter.wrapping_add(1);
+            if before && !self.input() {
+                self.increment();
+            }
+        }
+    }
+}
+impl Savable for Timer {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.counter);
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac);
+        w.u8(self.interrupts);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.counter = r.u16()?;
+        self.tima = r.u8()?;
+        self.tma = r.u8()?;
+        self.tac = r.u8()? & 0x07;
+        self.interrupts = r.u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::ops::RangeInclusive;
+use crate::disasm::DecodedInstruction;
+use crate::gameboy::GameBoy;
+
+// Instruction traces in the Gameboy Doctor format, one line per instruction:
+// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+// See https://github.com/robert/gameboy-doctor. Doctor's reference logs
+// assume LY always reads 0x90, use `ppu.set_ly_override` to match them.
+
+/// Value Gameboy Doctor expects LY to read as.
+pub const DOCTOR_LY: u8 = 0x90;
+
+// Register names in log order, used to point at what diverged
+const FIELDS: [&str; 11] = ["A", "F", "B", "C", "D", "E", "H", "L", "SP", "PC", "PCMEM"];
+
+
+/// Formats the state before the next instruction as a Doctor log line.
+pub fn format_line(gb: &GameBoy) -> String {
+    let reg = &gb.cpu.reg;
+    let pc = reg.pc;
+    let pcmem: Vec<String> = (0..4).map(|i| format!("{:02X}", gb.read(pc.wrapping_add(i)))).collect();
+    format!(
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+        reg.a, reg.f, reg.b, reg.c, reg.d, reg.e, reg.h, reg.l, reg.sp, pc, pcmem.join(","),
+    )
+}
+
+/// Limits tracing to a PC range and/or ROM bank, an empty filter traces everything.
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    pub pc_range: Option<RangeInclusive<u16>>,
+    pub bank: Option<usize>,
+}
+impl TraceFilter {
+    pub fn matches(&self, gb: &GameBoy) -> bool {
+        let pc = gb.cpu.reg.pc;
+        let in_range = self.pc_range.as_ref().is_none_or(|range| range.contains(&pc));
+        // Bank filters only apply to code running from ROM
+        let in_bank = self.bank.is_none_or(|bank| pc >= 0x8000 || gb.rom_bank(pc) == bank);
+        in_range && in_bank
+    }
+}
+
+// Instructions are logged right before they run, so HALT idles and steps
+// that only dispatch an interrupt are not logged
+fn should_log(gb: &GameBoy, filter: &TraceFilter) -> bool {
+    gb.cpu.will_execute(&gb.mmu) && filter.matches(gb)
+}
+
+
+/// Writes a Doctor line to `output` before every executed instruction.
+pub struct Tracer<W: Write> {
+    output: W,
+    pub filter: TraceFilter,
+    lines: u64,
+}
+impl<W: Write> Tracer<W> {
+    pub fn new(output: W, filter: TraceFilter) -> Self {
+        Tracer { output, filter, lines: 0 }
+    }
+
+    /// Logs the current state if it passes the filter, then steps the machine.
+    pub fn step(&mut self, gb: &mut GameBoy) -> io::Result<u8> {
+        if should_log(gb, &self.filter) {
+            writeln!(self.output, "{}", format_line(gb))?;
+            self.lines += 1;
+        }
+        Ok(gb.step())
+    }
+
+    /// Number of lines written so far.
+    pub fn lines(&self) -> u64 {
+        self.lines
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+
+/// First point where the emulator and the reference log disagree.
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    pub line: usize,           // 1-based line in the reference log
+    pub expected: String,
+    pub actual: String,
+    pub previous: Option<(String, DecodedInstruction)>,  // Last matching line and what it executed
+    pub current: Option<DecodedInstruction>,
+}
+impl Divergence {
+    /// Names of the fields that differ, e.g. ["F", "PC"].
+    pub fn fields(&self) -> Vec<&'static str> {
+        let expected: Vec<&str> = self.expected.split_whitespace().collect();
+        let actual: Vec<&str> = self.actual.split_whitespace().collect();
+        FIELDS.iter()
+            .enumerate()
+            .filter(|&(i, _)| expected.get(i) != actual.get(i))
+            .map(|(_, &name)| name)
+            .collect()
+    }
+}
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Divergence at reference line {} ({})", self.line, self.fields().join(", "))?;
+        if let Some((line, instruction)) = &self.previous {
+            writeln!(f, "  after    {}", line)?;
+            writeln!(f, "  ran      ${:04X}: {}", instruction.address, instruction)?;
+        }
+        writeln!(f, "  expected {}", self.expected)?;
+        writeln!(f, "  actual   {}", self.actual)?;
+        if let Some(instruction) = &self.current {
+            write!(f, "  next     ${:04X}: {}", instruction.address, instruction)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum CompareError {
+    Io(io::Error),
+    Diverged(Box<Divergence>),
+}
+impl fmt::Display for CompareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompareError::Io(e) => write!(f, "Failed to read reference log: {}", e),
+            CompareError::Diverged(divergence) => write!(f, "{}", divergence),
+        }
+    }
+}
+
+/// Runs `gb` against a reference log until the log ends, `limit` instructions
+/// have been compared, or the two disagree. Returns the number of matching lines.
+pub fn compare<R: BufRead>(gb: &mut GameBoy, reference: R, filter: &TraceFilter, limit: Option<usize>) -> Result<usize, CompareError> {
+    let mut previous: Option<(String, DecodedInstruction)> = None;
+    let mut lines = reference.lines();
+    let mut matched = 0;
+    let mut line_number = 0;
+
+    while limit.is_none_or(|limit| matched < limit) {
+        if gb.cpu.locked {
+            break;
+        }
+        if !should_log(gb, filter) {
+            gb.step();
+            continue;
+        }
+
+        let Some(expected) = lines.next() else {
+            break;
+        };
+        let expected = expected.map_err(CompareError::Io)?;
+        let expected = expected.trim();
+        line_number += 1;
+        if expected.is_empty() {
+            continue;
+        }
+
+        let actual = format_line(gb);
+        let current = decode_at(gb);
+        if !expected.eq_ignore_ascii_case(&actual) {
+            return Err(CompareError::Diverged(Box::new(Divergence {
+                line: line_number,
+                expected: expected.to_string(),
+                actual,
+                previous,
+                current,
+            })));
+        }
+
+        matched += 1;
+        previous = current.map(|instruction| (actual, instruction));
+        gb.step();
+    }
+    Ok(matched)
+}
+
+fn decode_at(gb: &GameBoy) -> Option<DecodedInstruction> {
+    let pc = gb.cpu.reg.pc;
+    let bytes: Vec<u8> = (0..3).map(|i| gb.read(pc.wrapping_add(i))).collect();
+    crate::disasm::decode(&bytes, pc)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::cartridge::{self, Cartridge};
+    use crate::common::Model;
+
+    // The timer fires while the CPU is halted, with interrupts enabled or not
+    const PROGRAM: &str = r#"
+        SECTION "timer", ROM0[$0050]
+            inc c
+            reti
+        SECTION "code", ROM0[$0150]
+        start:
+            {ime}
+            ld a, $04
+            ld [$ffff], a
+            ld a, $F0
+            ld [$ff05], a
+            ld a, $05
+            ld [$ff07], a
+        wait:
+            halt
+        woken:
+            inc b
+        spin:
+            jr spin
+    "#;
+
+    fn setup(ime: &str) -> (GameBoy, std::collections::HashMap<String, u16>) {
+        let program = asm::assemble_program(&PROGRAM.replace("{ime}", ime)).unwrap();
+        let mut rom = cartridge::test_rom(&program);
+        rom[0x14D] = 0x01; // A nonzero header checksum leaves H and C set, as in Doctor's logs
+        let cartridge = Cartridge::new(rom).unwrap();
+        (GameBoy::with_model(cartridge, Model::Dmg), program.symbols)
+    }
+
+    fn trace(gb: &mut GameBoy, steps: usize) -> Vec<String> {
+        let mut tracer = Tracer::new(Vec::new(), TraceFilter::default());
+        for _ in 0..steps {
+            tracer.step(gb).unwrap();
+        }
+        String::from_utf8(tracer.into_inner()).unwrap().lines().map(str::to_string).collect()
+    }
+
+    fn pc_of(line: &str) -> u16 {
+        let pc = line.split_whitespace().find_map(|field| field.strip_prefix("PC:")).unwrap();
+        u16::from_str_radix(pc, 16).unwrap()
+    }
+
+    #[test]
+    fn lines_use_the_doctor_format() {
+        let (gb, symbols) = setup("di");
+        let [low, high] = symbols["start"].to_le_bytes();
+        assert_eq!(
+            format_line(&gb),
+            format!("A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,{:02X},{:02X}", low, high),
+        );
+    }
+
+    #[test]
+    fn filters_match_pc_ranges_and_banks() {
+        let (mut gb, _) = setup("di");
+        assert!(TraceFilter::default().matches(&gb));
+        assert!(TraceFilter { pc_range: Some(0x0100..=0x0100), bank: None }.matches(&gb));
+        assert!(!TraceFilter { pc_range: Some(0x0150..=0x01FF), bank: None }.matches(&gb));
+        assert!(TraceFilter { pc_range: None, bank: Some(0) }.matches(&gb));
+        assert!(!TraceFilter { pc_range: None, bank: Some(1) }.matches(&gb));
+
+        gb.cpu.reg.pc = 0x4000;
+        assert!(TraceFilter { pc_range: None, bank: Some(1) }.matches(&gb));
+        gb.cpu.reg.pc = 0xC000;
+        assert!(TraceFilter { pc_range: None, bank: Some(1) }.matches(&gb));
+    }
+
+    #[test]
+    fn a_halt_woken_without_ime_logs_the_next_instruction() {
+        let (mut gb, symbols) = setup("di");
+        let lines = trace(&mut gb, 200);
+        let pcs: Vec<u16> = lines.iter().map(|line| pc_of(line)).collect();
+        let halt = pcs.iter().position(|&pc| pc == symbols["wait"]).unwrap();
+        assert_eq!(pcs[halt + 1], symbols["woken"]);
+        assert_eq!(pcs.iter().filter(|&&pc| pc == symbols["wait"]).count(), 1);
+        assert_eq!(gb.cpu.reg.b, 1);
+
+        let (mut fresh, _) = setup("di");
+        assert_eq!(compare(&mut fresh, lines.join("\n").as_bytes(), &TraceFilter::default(), None).unwrap(), lines.len());
+    }
+
+    #[test]
+    fn interrupt_dispatch_is_not_logged() {
+        let (mut gb, symbols) = setup("ei");
+        let lines = trace(&mut gb, 200);
+        let pcs: Vec<u16> = lines.iter().map(|line| pc_of(line)).collect();
+        let halt = pcs.iter().position(|&pc| pc == symbols["wait"]).unwrap();
+        assert_eq!(pcs[halt + 1..halt + 4], [0x0050, 0x0051, symbols["woken"]]);
+        assert_eq!(gb.cpu.reg.c, 0x14);
+
+        // Nor is one taken between two instructions
+        gb.cpu.ime = true;
+        gb.write(0xFF0F, 0x04);
+        assert!(trace(&mut gb, 1).is_empty());
+        assert_eq!(gb.cpu.reg.pc, 0x0050);
+
+        let (mut fresh, _) = setup("ei");
+        assert_eq!(compare(&mut fresh, lines.join("\n").as_bytes(), &TraceFilter::default(), None).unwrap(), lines.len());
+    }
+
+    #[test]
+    fn divergence_names_the_fields_that_differ() {
+        let (mut gb, _) = setup("di");
+        let lines = trace(&mut gb, 20);
+        let mut reference = lines.clone();
+        reference[3] = format!("A:12 F:50{}", &lines[3][9..]);
+        let (mut fresh, _) = setup("di");
+        let error = compare(&mut fresh, reference.join("\n").as_bytes(), &TraceFilter::default(), None).unwrap_err();
+        let CompareError::Diverged(divergence) = error else {
+            panic!("expected a divergence");
+        };
+        assert_eq!(divergence.line, 4);
+        assert_eq!(divergence.fields(), ["A", "F"]);
+        assert!(divergence.previous.is_some());
+
+        // A limit stops the comparison before the bad line
+        let (mut fresh, _) = setup("di");
+        assert_eq!(compare(&mut fresh, reference.join("\n").as_bytes(), &TraceFilter::default(), Some(3)).unwrap(), 3);
+    }
+}
@@ -0,0 +1,140 @@
+use std::io;
+use std::path::Path;
+use crate::apu::{RegisterWrite, APU_START};
+use crate::common::CPU_CLOCK_HZ;
+
+// VGM 1.61 register logs, see https://vgmrips.net/wiki/VGM_Specification
+// A 0x100 byte header giving the chip clock, then a command stream of chip
+// writes and waits counted in 44100 Hz samples, ending with 0x66. Game Boy
+// writes are 0xB3 followed by the register (0 for 0xFF10) and value.
+
+const MAGIC: &[u8; 4] = b"Vgm ";
+const VERSION: u32 = 0x0000_0161;
+const HEADER_SIZE: usize = 0x100;
+const SAMPLE_RATE: u64 = 44_100;
+
+// Header offsets
+const EOF_OFFSET: usize = 0x04;
+const VERSION_OFFSET: usize = 0x08;
+const TOTAL_SAMPLES: usize = 0x18;
+const DATA_OFFSET: usize = 0x34;
+const DMG_CLOCK: usize = 0x80;
+
+// Commands
+const WRITE_DMG: u8 = 0xB3;
+const WAIT: u8 = 0x61;
+const WAIT_NTSC_FRAME: u8 = 0x62;
+const WAIT_PAL_FRAME: u8 = 0x63;
+const WAIT_SHORT: u8 = 0x70;  // Plus samples - 1, up to 16
+const END: u8 = 0x66;
+
+
+/// A complete file replaying `writes`, lasting `total_cycles`.
+pub fn encode(writes: &[RegisterWrite], total_cycles: u64) -> Vec<u8> {
+    let mut vgm = vec![0; HEADER_SIZE];
+    let mut position = 0;  // Samples written so far
+    for write in writes {
+        let time = to_samples(write.cycle);
+        wait(&mut vgm, time.saturating_sub(position));
+        position = position.max(time);
+        vgm.extend_from_slice(&[WRITE_DMG, (write.addr - APU_START) as u8, write.value]);
+    }
+    let total = to_samples(total_cycles).max(position);
+    wait(&mut vgm, total - position);
+    vgm.push(END);
+
+    let size = vgm.len();
+    let mut put = |offset: usize, value: u32| vgm[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    put(EOF_OFFSET, (size - EOF_OFFSET) as u32);
+    put(VERSION_OFFSET, VERSION);
+    put(TOTAL_SAMPLES, total as u32);
+    put(DATA_OFFSET, (HEADER_SIZE - DATA_OFFSET) as u32);
+    put(DMG_CLOCK, CPU_CLOCK_HZ);
+    vgm[..4].copy_from_slice(MAGIC);
+    vgm
+}
+
+pub fn save<P: AsRef<Path>>(path: P, writes: &[RegisterWrite], total_cycles: u64) -> io::Result<()> {
+    std::fs::write(path, encode(writes, total_cycles))
+}
+
+// Rounded down, so waits never drift from the cycle count
+fn to_samples(cycles: u64) -> u64 {
+    cycles * SAMPLE_RATE / CPU_CLOCK_HZ as u64
+}
+
+fn wait(vgm: &mut Vec<u8>, mut samples: u64) {
+    while samples > 0 {
+        let chunk = samples.min(u16::MAX as u64);
+        match chunk {
+            735 => vgm.push(WAIT_NTSC_FRAME),
+            882 => vgm.push(WAIT_PAL_FRAME),
+            1..=16 => vgm.push(WAIT_SHORT + chunk as u8 - 1),
+            _ => {
+                vgm.push(WAIT);
+                vgm.extend_from_slice(&(chunk as u16).to_le_bytes());
+            }
+        }
+        samples -= chunk;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apu::{NR52, WAVE_RAM_START};
+
+    // First cycle that rounds down to `samples`
+    fn at(samples: u64) -> u64 {
+        (samples * CPU_CLOCK_HZ as u64).div_ceil(SAMPLE_RATE)
+    }
+
+    fn write(cycle: u64, addr: u16, value: u8) -> RegisterWrite {
+        RegisterWrite { cycle, addr, value }
+    }
+
+    fn u32_at(vgm: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(vgm[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn waits_use_the_shortest_command() {
+        let writes = [
+            write(0, NR52, 0x80),
+            write(at(0), WAVE_RAM_START, 0x12),
+            write(at(735), NR52, 0x80),
+            write(at(735 + 882), NR52, 0x80),
+            write(at(735 + 882 + 16) - 1, NR52, 0x80),
+            write(at(735 + 882 + 16), NR52, 0x80),
+            write(at(735 + 882 + 16 + 1000), NR52, 0x80),
+        ];
+        let total = 735 + 882 + 16 + 1000 + 70_000;
+        let vgm = encode(&writes, at(total));
+        assert_eq!(vgm[HEADER_SIZE..], [
+            WRITE_DMG, 0x16, 0x80,
+            WRITE_DMG, 0x20, 0x12,
+            WAIT_NTSC_FRAME, WRITE_DMG, 0x16, 0x80,
+            WAIT_PAL_FRAME, WRITE_DMG, 0x16, 0x80,
+            WAIT_SHORT + 14, WRITE_DMG, 0x16, 0x80,
+            WAIT_SHORT, WRITE_DMG, 0x16, 0x80,
+            WAIT, 0xE8, 0x03, WRITE_DMG, 0x16, 0x80,
+            WAIT, 0xFF, 0xFF, WAIT, 0x71, 0x11,
+            END,
+        ]);
+        assert_eq!(u32_at(&vgm, TOTAL_SAMPLES), total as u32);
+    }
+
+    #[test]
+    fn header_points_at_the_data() {
+        let vgm = encode(&[write(100, NR52, 0x80)], 0);
+        assert_eq!(&vgm[..4], MAGIC);
+        assert_eq!(u32_at(&vgm, EOF_OFFSET) as usize, vgm.len() - EOF_OFFSET);
+        assert_eq!(u32_at(&vgm, VERSION_OFFSET), 0x161);
+        assert_eq!(DATA_OFFSET + u32_at(&vgm, DATA_OFFSET) as usize, HEADER_SIZE);
+        assert_eq!(u32_at(&vgm, DMG_CLOCK), CPU_CLOCK_HZ);
+        // The log is never shorter than its last write
+        assert_eq!(u32_at(&vgm, TOTAL_SAMPLES), 1);
+        assert_eq!(vgm[HEADER_SIZE..], [WAIT_SHORT, WRITE_DMG, 0x16, 0x80, END]);
+    }
+}
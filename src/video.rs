@@ -0,0 +1,287 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use crate::common::{CPU_CLOCK_HZ, CYCLES_PER_FRAME};
+use crate::gameboy::GameBoy;
+use crate::png::{self, ColorType};
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::sgb::{GAME_X, GAME_Y, SGB_SCREEN_HEIGHT, SGB_SCREEN_WIDTH};
+
+// Turning the framebuffer into pictures: DMG shades through a palette, or
+// the compatibility palette a CGB picks for DMG games, SGB screens from
+// RGB555, saved as PNG or appended to a frame sequence.
+//
+// Sequences are either YUV4MPEG2 (https://wiki.multimedia.cx/index.php/YUV4MPEG2),
+// which carries its size and frame rate, or bare RGB24 frames that need
+// them spelled out: ffmpeg -f rawvideo -pixel_format rgb24 -video_size
+// 160x144 -framerate 59.7275 -i frames.rgb -i audio.wav out.mp4
+
+/// Frames per second, 4194304 / 70224.
+pub const FRAME_RATE: f64 = CPU_CLOCK_HZ as f64 / CYCLES_PER_FRAME as f64;
+
+pub type Rgb = [u8; 3];
+
+
+/// Colours for the four DMG shades, lightest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DmgPalette {
+    #[default]
+    Greyscale,
+    ClassicGreen,
+    Custom([Rgb; 4]),
+}
+impl DmgPalette {
+    pub fn colors(&self) -> [Rgb; 4] {
+        match self {
+            DmgPalette::Greyscale => [[0xFF, 0xFF, 0xFF], [0xAA, 0xAA, 0xAA], [0x55, 0x55, 0x55], [0x00, 0x00, 0x00]],
+            DmgPalette::ClassicGreen => [[0x9B, 0xBC, 0x0F], [0x8B, 0xAC, 0x0F], [0x30, 0x62, 0x30], [0x0F, 0x38, 0x0F]],
+            DmgPalette::Custom(colors) => *colors,
+        }
+    }
+
+    /// From four RGB555 colours, such as the background of a CGB
+    /// compatibility palette.
+    pub fn from_rgb555(colors: [u16; 4], correction: ColorCorrection) -> Self {
+        DmgPalette::Custom(colors.map(|color| correction.to_rgb(color)))
+    }
+}
+
+
+/// How RGB555 colours become RGB888.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorCorrection {
+    /// Straight scaling, as the colours are stored.
+    #[default]
+    None,
+    /// The CGB LCD's washed out look, where each colour bleeds into the
+    /// others and nothing gets fully bright.
+    Cgb,
+}
+impl ColorCorrection {
+    pub fn to_rgb(&self, color: u16) -> Rgb {
+        let (r, g, b) = ((color & 0x1F) as u32, (color >> 5 & 0x1F) as u32, (color >> 10 & 0x1F) as u32);
+        match self {
+            ColorCorrection::None => [r, g, b].map(|c| (c << 3 | c >> 2) as u8),
+            ColorCorrection::Cgb => {
+                let mixed = [r * 26 + g * 4 + b * 2, g * 24 + b * 8, r * 6 + g * 4 + b * 22];
+                mixed.map(|c| (c.min(960) * 255 / 960) as u8)
+            }
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VideoOptions {
+    pub palette: DmgPalette,
+    /// Keep the SGB border around the game, only used for SGB games.
+    pub sgb_border: bool,
+}
+impl VideoOptions {
+    /// Size of the pictures `gb` gives with these options.
+    pub fn size(&self, gb: &GameBoy) -> (usize, usize) {
+        match gb.sgb_screen().is_some() && self.sgb_border {
+            true => (SGB_SCREEN_WIDTH, SGB_SCREEN_HEIGHT),
+            false => (SCREEN_WIDTH, SCREEN_HEIGHT),
+        }
+    }
+}
+
+
+/// An RGB888 picture, row by row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Picture {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+impl Picture {
+    /// What the screen shows now. SGB games come in their SGB colours, DMG
+    /// games on a CGB in the background colours of their compatibility
+    /// palette with CGB colour correction, everything else through the
+    /// palette. The PPU only puts out shades, so sprites share the
+    /// background colours and CGB games come out in shades as well.
+    pub fn capture(gb: &GameBoy, options: &VideoOptions) -> Self {
+        let (width, height) = options.size(gb);
+        let pixels = match gb.sgb_screen() {
+            Some(screen) => {
+                let (x, y) = if options.sgb_border { (0, 0) } else { (GAME_X, GAME_Y) };
+                screen.chunks(SGB_SCREEN_WIDTH).skip(y).take(height)
+                    .flat_map(|row| &row[x..x + width])
+                    .flat_map(|&color| ColorCorrection::None.to_rgb(color))
+                    .collect()
+            }
+            None => {
+                let colors = match gb.compat_palette() {
+                    Some(palette) => DmgPalette::from_rgb555(palette.bg, ColorCorrection::Cgb).colors(),
+                    None => options.palette.colors(),
+                };
+                gb.framebuffer().iter().flat_map(|&shade| colors[shade as usize & 3]).collect()
+            }
+        };
+        Picture { width, height, pixels }
+    }
+
+    pub fn to_png(&self) -> Vec<u8> {
+        png::encode(self.width, self.height, ColorType::Rgb, &self.pixels)
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        std::fs::write(path, self.to_png())
+    }
+
+    // Limited range BT.601 planes, as Y4M's C444 expects
+    fn to_yuv444(&self) -> Vec<u8> {
+        let count = self.width * self.height;
+        let mut yuv = vec![0; count * 3];
+        for (i, rgb) in self.pixels.chunks_exact(3).enumerate() {
+            let [r, g, b] = [rgb[0], rgb[1], rgb[2]].map(|c| c as i32);
+            yuv[i] = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+            yuv[count + i] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+            yuv[count * 2 + i] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+        }
+        yuv
+    }
+}
+
+/// Saves what the screen shows now as a PNG.
+pub fn screenshot<P: AsRef<Path>>(gb: &GameBoy, path: P, options: &VideoOptions) -> io::Result<()> {
+    Picture::capture(gb, options).save_png(path)
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameFormat {
+    Y4m,
+    RawRgb,
+}
+impl FrameFormat {
+    /// Y4M for `.y4m` paths, raw RGB otherwise.
+    pub fn for_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().is_some_and(|ext| ext.eq_ignore_ascii_case("y4m")) {
+            true => FrameFormat::Y4m,
+            false => FrameFormat::RawRgb,
+        }
+    }
+}
+
+
+/// Appends same-sized pictures to an uncompressed frame sequence.
+pub struct FrameWriter<W: Write> {
+    output: W,
+    format: FrameFormat,
+    width: usize,
+    height: usize,
+    frames: u64,
+}
+impl FrameWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, format: FrameFormat, width: usize, height: usize) -> io::Result<Self> {
+        FrameWriter::new(BufWriter::new(File::create(path)?), format, width, height)
+    }
+}
+impl<W: Write> FrameWriter<W> {
+    pub fn new(mut output: W, format: FrameFormat, width: usize, height: usize) -> io::Result<Self> {
+        if format == FrameFormat::Y4m {
+            writeln!(output, "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444", width, height, CPU_CLOCK_HZ, CYCLES_PER_FRAME)?;
+        }
+        Ok(FrameWriter { output, format, width, height, frames: 0 })
+    }
+
+    pub fn write(&mut self, picture: &Picture) -> io::Result<()> {
+        if (picture.width, picture.height) != (self.width, self.height) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "picture size changed mid sequence"));
+        }
+        match self.format {
+            FrameFormat::Y4m => {
+                self.output.write_all(b"FRAME\n")?;
+                self.output.write_all(&picture.to_yuv444())?;
+            }
+            FrameFormat::RawRgb => self.output.write_all(&picture.pixels)?,
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Frames written so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.output.flush()?;
+        Ok(self.output)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot::CompatPalette;
+    use crate::cartridge::Cartridge;
+    use crate::common::Model;
+
+    // Every tile row shows all four shades
+    fn striped(model: Model) -> GameBoy {
+        let mut gb = GameBoy::with_model(Cartridge::new(vec![0; 0x8000]).unwrap(), model);
+        gb.write(0xFF40, 0x00);
+        for row in 0..8 {
+            gb.write(0x8000 + row * 2, 0x55);
+            gb.write(0x8001 + row * 2, 0x33);
+        }
+        gb.write(0xFF47, 0xE4);
+        gb.write(0xFF40, 0x91);
+        gb.run_frame();
+        gb.run_frame();
+        gb
+    }
+
+    #[test]
+    fn capture_uses_the_palette_or_the_compat_palette() {
+        let options = VideoOptions { palette: DmgPalette::ClassicGreen, sgb_border: false };
+        let gb = striped(Model::Dmg);
+        assert_eq!(gb.compat_palette(), None);
+        let picture = Picture::capture(&gb, &options);
+        assert_eq!((picture.width, picture.height), (SCREEN_WIDTH, SCREEN_HEIGHT));
+        let colors = DmgPalette::ClassicGreen.colors();
+        assert_eq!(picture.pixels[..12], colors.concat());
+
+        let gb = striped(Model::Cgb);
+        let CompatPalette { bg, .. } = gb.compat_palette().unwrap();
+        let picture = Picture::capture(&gb, &options);
+        let colors = bg.map(|color| ColorCorrection::Cgb.to_rgb(color));
+        assert_eq!(picture.pixels[..12], colors.concat());
+        assert_ne!(colors, DmgPalette::ClassicGreen.colors());
+    }
+
+    #[test]
+    fn cgb_correction_mixes_the_channels() {
+        assert_eq!(ColorCorrection::None.to_rgb(0x7FFF), [0xFF; 3]);
+        assert_eq!(ColorCorrection::None.to_rgb(0x001F), [0xFF, 0x00, 0x00]);
+        assert_eq!(ColorCorrection::None.to_rgb(0x0200), [0x00, 0x84, 0x00]);
+        assert_eq!(ColorCorrection::Cgb.to_rgb(0x7FFF), [0xFF; 3]);
+        // Pure red comes out dimmer, with some blue
+        assert_eq!(ColorCorrection::Cgb.to_rgb(0x001F), [214, 0, 49]);
+    }
+
+    #[test]
+    fn frames_are_written_as_y4m_or_raw_rgb() {
+        let picture = Picture { width: 2, height: 1, pixels: vec![0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00] };
+        let mut writer = FrameWriter::new(Vec::new(), FrameFormat::Y4m, 2, 1).unwrap();
+        writer.write(&picture).unwrap();
+        let y4m = writer.finish().unwrap();
+        let header = format!("YUV4MPEG2 W2 H1 F{}:{} Ip A1:1 C444\nFRAME\n", CPU_CLOCK_HZ, CYCLES_PER_FRAME);
+        assert_eq!(y4m[..header.len()], *header.as_bytes());
+        // White and black luma, then neutral chroma
+        assert_eq!(y4m[header.len()..], [235, 16, 128, 128, 128, 128]);
+
+        let mut writer = FrameWriter::new(Vec::new(), FrameFormat::RawRgb, 2, 1).unwrap();
+        writer.write(&picture).unwrap();
+        let small = Picture { width: 1, height: 1, pixels: vec![0; 3] };
+        assert_eq!(writer.write(&small).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(writer.frames(), 1);
+        assert_eq!(writer.finish().unwrap(), picture.pixels);
+        assert_eq!(FrameFormat::for_path("out.Y4M"), FrameFormat::Y4m);
+        assert_eq!(FrameFormat::for_path("out.rgb"), FrameFormat::RawRgb);
+    }
+}
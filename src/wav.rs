@@ -0,0 +1,129 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// 16-bit PCM WAV files, see http://soundfile.sapp.org/doc/WaveFormat/
+// A RIFF header, a "fmt " chunk describing the samples and a "data" chunk
+// with the samples interleaved by channel, little endian throughout.
+
+const HEADER_SIZE: usize = 44;
+const FORMAT_PCM: u16 = 1;
+const BITS_PER_SAMPLE: u16 = 16;
+
+
+/// A complete file holding `samples`, interleaved when there are several channels.
+pub fn encode(sample_rate: u32, channels: u16, samples: &[i16]) -> Vec<u8> {
+    let mut wav = header(sample_rate, channels, samples.len() * 2);
+    for sample in samples {
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    wav
+}
+
+pub fn save<P: AsRef<Path>>(path: P, sample_rate: u32, channels: u16, samples: &[i16]) -> io::Result<()> {
+    std::fs::write(path, encode(sample_rate, channels, samples))
+}
+
+
+/// Streams samples out as they come, filling in the sizes on `finish`.
+pub struct WavWriter<W: Write + Seek> {
+    output: W,
+    sample_rate: u32,
+    channels: u16,
+    data_size: usize,
+}
+impl WavWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate, channels)
+    }
+}
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut output: W, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        output.write_all(&header(sample_rate, channels, 0))?;
+        Ok(WavWriter { output, sample_rate, channels, data_size: 0 })
+    }
+
+    pub fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        self.output.write_all(&bytes)?;
+        self.data_size += bytes.len();
+        Ok(())
+    }
+
+    /// Samples written so far, counting each channel.
+    pub fn samples(&self) -> usize {
+        self.data_size / 2
+    }
+
+    /// Rewrites the header for the samples written and flushes.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.output.seek(SeekFrom::Start(0))?;
+        self.output.write_all(&header(self.sample_rate, self.channels, self.data_size))?;
+        self.output.seek(SeekFrom::End(0))?;
+        self.output.flush()?;
+        Ok(self.output)
+    }
+}
+
+// Header for `data_size` bytes of samples
+fn header(sample_rate: u32, channels: u16, data_size: usize) -> Vec<u8> {
+    let block_align = channels * BITS_PER_SAMPLE / 8;
+    let mut header = Vec::with_capacity(HEADER_SIZE + data_size);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&((HEADER_SIZE - 8 + data_size) as u32).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&FORMAT_PCM.to_le_bytes());
+    header.extend_from_slice(&channels.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&(data_size as u32).to_le_bytes());
+    header
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn u32_at(wav: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(wav[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn header_describes_the_samples() {
+        let wav = encode(32_768, 2, &[1, -1, 0x1234, -2]);
+        assert_eq!(wav.len(), HEADER_SIZE + 8);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(u32_at(&wav, 4), 36 + 8);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(wav[20..24], [0x01, 0x00, 0x02, 0x00]);  // PCM, stereo
+        assert_eq!(u32_at(&wav, 24), 32_768);
+        assert_eq!(u32_at(&wav, 28), 32_768 * 4);           // Bytes per second
+        assert_eq!(wav[32..36], [0x04, 0x00, 0x10, 0x00]);  // Block align, bits
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32_at(&wav, 40), 8);
+        assert_eq!(wav[44..], [0x01, 0x00, 0xFF, 0xFF, 0x34, 0x12, 0xFE, 0xFF]);
+    }
+
+    #[test]
+    fn writer_fills_in_sizes_on_finish() {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), 22_050, 1).unwrap();
+        writer.write(&[1, 2, 3]).unwrap();
+        writer.write(&[]).unwrap();
+        writer.write(&[4, 5]).unwrap();
+        assert_eq!(writer.samples(), 5);
+        let wav = writer.finish().unwrap().into_inner();
+        assert_eq!(u32_at(&wav, 4), 36 + 10);
+        assert_eq!(u32_at(&wav, 40), 10);
+        assert_eq!(wav, encode(22_050, 1, &[1, 2, 3, 4, 5]));
+
+        // Nothing written is still a valid, empty file
+        let wav = WavWriter::new(Cursor::new(Vec::new()), 22_050, 1).unwrap().finish().unwrap().into_inner();
+        assert_eq!(wav, encode(22_050, 1, &[]));
+    }
+}
@@ -0,0 +1,202 @@
+use crate::common::crc32;
+use crate::inflate::inflate;
+
+// Minimal zip reader, see https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+// Only what ROM archives and .bk2 movies need: the central directory and
+// stored or deflated entries. No zip64, encryption or multi-disk archives.
+
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4B50;
+const CENTRAL_DIRECTORY_ENTRY: u32 = 0x0201_4B50;
+const LOCAL_FILE_HEADER: u32 = 0x0403_4B50;
+
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+const LOCAL_FILE_HEADER_SIZE: usize = 30;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+
+#[derive(Debug, Clone)]
+pub struct ZipEntry {
+    pub name: String,
+    pub method: u16,
+    pub crc32: u32,
+    pub compressed_size: usize,
+    pub size: usize,
+    header_offset: usize,
+}
+impl ZipEntry {
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+}
+
+pub struct ZipArchive<'a> {
+    data: &'a [u8],
+    entries: Vec<ZipEntry>,
+}
+impl<'a> ZipArchive<'a> {
+    /// Reads the central directory, `None` if `data` is not a zip archive.
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        // The end record sits at the end, possibly followed by a comment
+        let search_start = data.len().checked_sub(END_OF_CENTRAL_DIRECTORY_SIZE)?;
+        let end = (0..=search_start).rev()
+            .take(0xFFFF + 1)
+            .find(|&i| u32_at(data, i) == Some(END_OF_CENTRAL_DIRECTORY))?;
+
+        let count = u16_at(data, end + 10)? as usize;
+        let mut offset = u32_at(data, end + 16)? as usize;
+
+        let mut entries = Vec::with_capacity(count);
+        for _ in 0..count {
+            if u32_at(data, offset)? != CENTRAL_DIRECTORY_ENTRY {
+                return None;
+            }
+            let name_length = u16_at(data, offset + 28)? as usize;
+            let extra_length = u16_at(data, offset + 30)? as usize;
+            let comment_length = u16_at(data, offset + 32)? as usize;
+            let name = data.get(offset + 46..offset + 46 + name_length)?;
+
+            entries.push(ZipEntry {
+                name: String::from_utf8_lossy(name).to_string(),
+                method: u16_at(data, offset + 10)?,
+                crc32: u32_at(data, offset + 16)?,
+                compressed_size: u32_at(data, offset + 20)? as usize,
+                size: u32_at(data, offset + 24)? as usize,
+                header_offset: u32_at(data, offset + 42)? as usize,
+            });
+            offset += 46 + name_length + extra_length + comment_length;
+        }
+        Some(ZipArchive { data, entries })
+    }
+
+    pub fn entries(&self) -> &[ZipEntry] {
+        &self.entries
+    }
+
+    /// Entry with exactly this name.
+    pub fn find(&self, name: &str) -> Option<&ZipEntry> {
+        self.entries.iter().find(|e| e.name == name)
+    }
+
+    /// Decompresses an entry, `None` for unsupported methods or a CRC mismatch.
+    pub fn extract(&self, entry: &ZipEntry) -> Option<Vec<u8>> {
+        let header = entry.header_offset;
+        if u32_at(self.data, header)? != LOCAL_FILE_HEADER {
+            return None;
+        }
+        // The local header has its own name and extra field lengths
+        let name_length = u16_at(self.data, header + 26)? as usize;
+        let extra_length = u16_at(self.data, header + 28)? as usize;
+        let start = header + LOCAL_FILE_HEADER_SIZE + name_length + extra_length;
+        let compressed = self.data.get(start..start + entry.compressed_size)?;
+
+        let contents = match entry.method {
+            METHOD_STORED => compressed.to_vec(),
+            METHOD_DEFLATE => inflate(compressed)?,
+            _ => return None,
+        };
+        (contents.len() == entry.size && crc32(&contents) == entry.crc32).then_some(contents)
+    }
+
+    pub fn read(&self, name: &str) -> Option<Vec<u8>> {
+        self.extract(self.find(name)?)
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Archive holding `entries` as name, contents and whether to deflate them,
+/// for tests.
+#[cfg(test)]
+pub(crate) fn test_archive(entries: &[(&str, &[u8], bool)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut directory = Vec::new();
+    for &(name, contents, deflated) in entries {
+        let (method, stored) = match deflated {
+            true => (METHOD_DEFLATE, crate::deflate::deflate(contents)),
+            false => (METHOD_STORED, contents.to_vec()),
+        };
+        // Version, flags, method, time, date, CRC32, sizes, name and extra length
+        let mut fields = vec![20, 0, 0, 0];
+        fields.extend_from_slice(&method.to_le_bytes());
+        fields.extend_from_slice(&[0; 4]);
+        fields.extend_from_slice(&crc32(contents).to_le_bytes());
+        fields.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+        fields.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        fields.extend_from_slice(&[0, 0]);
+
+        directory.extend_from_slice(&CENTRAL_DIRECTORY_ENTRY.to_le_bytes());
+        directory.extend_from_slice(&[20, 0]);
+        directory.extend_from_slice(&fields);
+        // Comment length, disk, attributes, then the local header offset
+        directory.extend_from_slice(&[0; 10]);
+        directory.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        directory.extend_from_slice(name.as_bytes());
+
+        data.extend_from_slice(&LOCAL_FILE_HEADER.to_le_bytes());
+        data.extend_from_slice(&fields);
+        data.extend_from_slice(name.as_bytes());
+        data.extend_from_slice(&stored);
+    }
+    let directory_offset = data.len() as u32;
+    data.extend_from_slice(&directory);
+    data.extend_from_slice(&END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    data.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    data.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    data.extend_from_slice(&directory_offset.to_le_bytes());
+    data.extend_from_slice(&[7, 0]);
+    data.extend_from_slice(b"comment");
+    data
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &[u8] = b"It's dangerous to go alone! Take this. It's dangerous to go alone!";
+
+    #[test]
+    fn reads_stored_and_deflated_entries() {
+        let data = test_archive(&[("docs/", b"", false), ("readme.txt", TEXT, false), ("game.gb", &[0xAB; 0x1000], true)]);
+        let archive = ZipArchive::parse(&data).unwrap();
+        let names: Vec<&str> = archive.entries().iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["docs/", "readme.txt", "game.gb"]);
+        assert!(archive.entries()[0].is_dir());
+
+        assert_eq!(archive.read("readme.txt").unwrap(), TEXT);
+        let game = archive.find("game.gb").unwrap();
+        assert_eq!(game.method, METHOD_DEFLATE);
+        assert!(game.compressed_size < 100);
+        assert_eq!(archive.extract(game).unwrap(), [0xAB; 0x1000]);
+        assert_eq!(archive.read("missing"), None);
+    }
+
+    #[test]
+    fn rejects_damaged_archives() {
+        assert!(ZipArchive::parse(b"PK\x03\x04 not really").is_none());
+        let data = test_archive(&[("readme.txt", TEXT, false)]);
+        assert!(ZipArchive::parse(&data[..data.len() - 40]).is_none());
+
+        // Flipped contents fail the CRC
+        let mut damaged = data.clone();
+        damaged[LOCAL_FILE_HEADER_SIZE + "readme.txt".len()] ^= 0x20;
+        assert_eq!(ZipArchive::parse(&damaged).unwrap().read("readme.txt"), None);
+
+        let mut entry = ZipArchive::parse(&data).unwrap().entries()[0].clone();
+        entry.method = 14;  // LZMA
+        assert_eq!(ZipArchive::parse(&data).unwrap().extract(&entry), None);
+    }
+}